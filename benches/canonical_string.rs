@@ -0,0 +1,82 @@
+//! Compares building the canonical string into a reused buffer with the allocating approach it
+//! replaced.
+//!
+//! Run with `cargo bench --bench canonical_string`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use webidentity::{write_canonical_string, RequestDigest};
+
+const METHOD: &str = "post";
+const HOST: &str = "api.example.com";
+const PATH: &str = "/v1/messages/";
+const LOCATION: &str = "amy.carroted.org";
+const TIMESTAMP: &str = "1700000000";
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("WebIdentity-Algorithm", "ed25519"),
+    ("WebIdentity-Headers", "content-type"),
+    ("content-type", "application/json"),
+];
+
+/// The canonical string as it was built before: an uppercased method, a hex-encoded body hash
+/// and a formatted string per line.
+fn allocating(body_sha256: &[u8; 32]) -> String {
+    let clean_path = PATH.trim_end_matches('/');
+    let mut canonical = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        METHOD.to_uppercase(),
+        HOST,
+        clean_path,
+        hex::encode(body_sha256),
+        LOCATION,
+        TIMESTAMP
+    );
+    for (name, value) in EXTENSIONS {
+        canonical.push_str(&format!("\n{}:{}", name.to_lowercase(), value));
+    }
+    canonical
+}
+
+fn canonical_string(c: &mut Criterion) {
+    let body_sha256 = *RequestDigest::of(br#"{"message":"Hello, world!"}"#).as_bytes();
+
+    let mut group = c.benchmark_group("canonical_string");
+    group.bench_function("allocating", |b| {
+        b.iter(|| allocating(black_box(&body_sha256)))
+    });
+    group.bench_function("fresh_buffer", |b| {
+        b.iter(|| {
+            let mut buf = String::new();
+            write_canonical_string(
+                &mut buf,
+                METHOD,
+                HOST,
+                PATH,
+                black_box(&body_sha256),
+                LOCATION,
+                TIMESTAMP,
+                EXTENSIONS,
+            );
+            buf
+        })
+    });
+    let mut buf = String::new();
+    group.bench_function("reused_buffer", |b| {
+        b.iter(|| {
+            write_canonical_string(
+                &mut buf,
+                METHOD,
+                HOST,
+                PATH,
+                black_box(&body_sha256),
+                LOCATION,
+                TIMESTAMP,
+                EXTENSIONS,
+            );
+            buf.len()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, canonical_string);
+criterion_main!(benches);
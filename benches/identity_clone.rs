@@ -0,0 +1,37 @@
+//! Compares cloning an [`Identity`] 1M times with cloning the `Arc<Identity>` that resolvers
+//! and caches hand out.
+//!
+//! Run with `cargo bench --bench identity_clone --features testing`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use std::sync::Arc;
+use webidentity::testing::TestIdentity;
+
+const CLONES: u64 = 1_000_000;
+
+fn identity_clone(c: &mut Criterion) {
+    let identity = TestIdentity::generate("amy.carroted.org").identity;
+    let shared = Arc::new(identity.clone());
+
+    let mut group = c.benchmark_group("identity_clone");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(CLONES));
+    group.bench_function("identity", |b| {
+        b.iter(|| {
+            for _ in 0..CLONES {
+                black_box(black_box(&identity).clone());
+            }
+        })
+    });
+    group.bench_function("arc", |b| {
+        b.iter(|| {
+            for _ in 0..CLONES {
+                black_box(Arc::clone(black_box(&shared)));
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, identity_clone);
+criterion_main!(benches);
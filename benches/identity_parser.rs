@@ -0,0 +1,31 @@
+//! Compares parsing identity pages with [`get_identity`], which sets up a parser for each
+//! call, with a reused [`IdentityParser`].
+//!
+//! Run with `cargo bench --bench identity_parser --features testing`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use url::Url;
+use webidentity::testing::TestIdentity;
+use webidentity::{get_identity, IdentityParser};
+
+fn identity_parser(c: &mut Criterion) {
+    let identity = TestIdentity::generate("amy.carroted.org");
+    let url = Url::parse("https://amy.carroted.org/").unwrap();
+
+    let mut group = c.benchmark_group("identity_parser");
+    group.bench_function("per_call", |b| {
+        b.iter(|| get_identity(&url, black_box(&identity.page)).unwrap())
+    });
+    let parser = IdentityParser::default();
+    group.bench_function("reused", |b| {
+        b.iter(|| {
+            parser
+                .parse(&url, black_box(identity.page.as_bytes()))
+                .unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, identity_parser);
+criterion_main!(benches);
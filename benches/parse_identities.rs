@@ -0,0 +1,50 @@
+//! Compares parsing 10k small identity pages one by one with [`parse_identities`], which
+//! parses them in parallel with the `rayon` feature.
+//!
+//! Run with `cargo bench --bench parse_identities --features rayon,testing`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use url::Url;
+use webidentity::testing::TestIdentity;
+use webidentity::{get_identity, parse_identities, IdentityOptions};
+
+const PAGES: usize = 10_000;
+
+fn pages() -> Vec<(Url, Vec<u8>)> {
+    (0..PAGES)
+        .map(|i| {
+            let identity = TestIdentity::generate(&format!("user{}.example.com", i));
+            let url = Url::parse(&format!("https://{}/", identity.location)).unwrap();
+            (url, identity.page.into_bytes())
+        })
+        .collect()
+}
+
+fn parse_identities_batch(c: &mut Criterion) {
+    let pages = pages();
+    let options = IdentityOptions::default();
+
+    let mut group = c.benchmark_group("parse_identities");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(PAGES as u64));
+    group.bench_function("loop", |b| {
+        b.iter(|| {
+            pages
+                .iter()
+                .map(|(url, page)| get_identity(url, std::str::from_utf8(page).unwrap()))
+                .filter(Result::is_ok)
+                .count()
+        })
+    });
+    group.bench_function("batch", |b| {
+        b.iter_batched(
+            || pages.clone(),
+            |pages| parse_identities(pages, &options),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse_identities_batch);
+criterion_main!(benches);
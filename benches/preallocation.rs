@@ -0,0 +1,81 @@
+//! Compares parsing an identity page with a tag split across parsing chunks, with the default
+//! preallocated parser buffer and with larger ones, by time and by how many allocations a
+//! parse makes.
+//!
+//! Run with `cargo bench --bench preallocation --features testing`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use url::Url;
+use webidentity::testing::TestIdentity;
+use webidentity::{IdentityOptions, IdentityParser};
+
+/// Counts allocations, to report how many a parse makes.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// A page whose description tag, a few KiB long, is split across the chunks the parser is fed
+/// (8 KiB), so it has to be buffered: the case the preallocated buffer is for.
+fn page_with_split_tag() -> String {
+    let page = TestIdentity::generate("amy.carroted.org").page;
+    let description = "<meta name=\"identity:description\" content=\"An identity for tests.\">";
+    let offset = page.find(description).unwrap();
+    let style = format!(
+        "<style>{}</style>\n    ",
+        "/**/".repeat((7 * 1024 - offset) / 4)
+    );
+    let long_description = format!(
+        "{}<meta name=\"identity:description\" content=\"{}\">",
+        style,
+        "Hello! ".repeat(400)
+    );
+    page.replace(description, &long_description)
+}
+
+fn preallocation(c: &mut Criterion) {
+    let url = Url::parse("https://amy.carroted.org/").unwrap();
+    let page = page_with_split_tag();
+
+    let mut group = c.benchmark_group("preallocation");
+    for size in [1024, 4 * 1024, 16 * 1024] {
+        let parser =
+            IdentityParser::new(IdentityOptions::new().with_preallocated_buffer_size(size));
+
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        parser.parse(&url, page.as_bytes()).unwrap();
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+        println!(
+            "{} byte buffer: {} allocations per parse",
+            size, allocations
+        );
+
+        group.bench_function(format!("{}_bytes", size), |b| {
+            b.iter(|| parser.parse(&url, black_box(page.as_bytes())).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, preallocation);
+criterion_main!(benches);
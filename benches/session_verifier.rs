@@ -0,0 +1,54 @@
+//! Compares verifying a burst of requests from the same identity with [`verify_request`],
+//! which prepares the key for each request, with a [`SessionVerifier`] created once.
+//!
+//! Run with `cargo bench --bench session_verifier --features testing`.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::time::Duration;
+use webidentity::testing::TestIdentity;
+use webidentity::{
+    verify_request, RequestDigest, SessionVerifier, SimpleHeaderProvider, VerifyOptions,
+};
+
+const HOST: &str = "api.example.com";
+const PATH: &str = "/v1/events";
+const REQUESTS: usize = 100;
+const MAX_AGE: Duration = Duration::from_secs(300);
+
+fn session_verifier(c: &mut Criterion) {
+    let amy = TestIdentity::generate("amy.carroted.org");
+    let requests: Vec<(Vec<u8>, SimpleHeaderProvider)> = (0..REQUESTS)
+        .map(|i| {
+            let body = format!(r#"{{"event":{}}}"#, i).into_bytes();
+            let headers = amy
+                .signed_headers_for("POST", HOST, PATH, &body)
+                .into_headers();
+            (body, headers)
+        })
+        .collect();
+    let public_key = amy.signing_key.verifying_key().to_bytes();
+
+    let mut group = c.benchmark_group("session_verifier");
+    group.throughput(Throughput::Elements(REQUESTS as u64));
+    group.bench_function("verify_request", |b| {
+        b.iter(|| {
+            for (body, headers) in &requests {
+                verify_request("POST", HOST, PATH, body, headers, &public_key, MAX_AGE).unwrap();
+            }
+        })
+    });
+    group.bench_function("session_verifier", |b| {
+        b.iter(|| {
+            let verifier = SessionVerifier::new(&amy.identity, VerifyOptions::new(MAX_AGE));
+            for (body, headers) in &requests {
+                verifier
+                    .verify("POST", HOST, PATH, &RequestDigest::of(body), headers)
+                    .unwrap();
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, session_verifier);
+criterion_main!(benches);
@@ -0,0 +1,45 @@
+//! Checks test vectors produced by another implementation against this one.
+//!
+//! Usage: `cargo run --example conformance -- <vectors.json>`, or `--generate` to print the
+//! vectors shipped in `vectors/canonical-v1.json`.
+
+use std::env;
+use std::fs;
+use std::process::ExitCode;
+
+use webidentity::conformance;
+
+fn main() -> ExitCode {
+    let Some(arg) = env::args().nth(1) else {
+        eprintln!("Usage: conformance <vectors.json> | --generate");
+        return ExitCode::FAILURE;
+    };
+
+    if arg == "--generate" {
+        println!("{}", conformance::generate_vectors());
+        return ExitCode::SUCCESS;
+    }
+
+    let vectors = match fs::read_to_string(&arg) {
+        Ok(vectors) => vectors,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", arg, e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match conformance::run(&vectors) {
+        Ok(report) => {
+            println!("{}", report);
+            if report.is_success() {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+        Err(e) => {
+            eprintln!("Invalid vectors: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
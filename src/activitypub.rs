@@ -0,0 +1,224 @@
+//! Mapping between identities and ActivityPub actors.
+//!
+//! [`Identity::to_actor`] maps an identity to a `Person` actor:
+//!
+//! | Actor field         | Identity                                                     |
+//! |---------------------|--------------------------------------------------------------|
+//! | `id`                | the actor URL given by the caller                            |
+//! | `preferredUsername` | the location, with `.` and `/` replaced by `_`               |
+//! | `name`              | the display name                                             |
+//! | `summary`           | the description, if any                                      |
+//! | `icon`              | an `Image` with the avatar URL, if any                       |
+//! | `url`               | the location URL                                             |
+//! | `attachment`        | a `PropertyValue` named `WebIdentity` holding the location   |
+//! | `assertionMethod`   | each key as a `Multikey`, see below                          |
+//!
+//! The primary key has the id `<actor>#webidentity`, and the other keys of an identity with
+//! several (see [`Identity::public_keys`]) `<actor>#webidentity-2`, `<actor>#webidentity-3`,
+//! and so on, in the order they are listed.
+//! [`identity_hint_from_actor`] reads the location back from the `WebIdentity` attachment,
+//! falling back to the first HTTP(S) entry of `alsoKnownAs`.
+
+use super::error::WebIdentityError;
+use super::identity::{location_from_url, Identity};
+use super::public_key::{PublicKey, ED25519_MULTICODEC};
+use super::resolve::resolve_location_url;
+use serde_json::{json, Map, Value};
+use url::Url;
+
+/// A WebIdentity found on an ActivityPub actor. The key is unverified until the identity is
+/// resolved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActorIdentityHint {
+    pub location: String,
+    /// The first Ed25519 key from the actor's `assertionMethod`, if it has one
+    pub public_key: Option<PublicKey>,
+}
+
+impl Identity {
+    /// Creates an ActivityPub `Person` actor for the identity, served at `actor_url`.
+    pub fn to_actor(&self, actor_url: &Url) -> Value {
+        let mut actor = Map::new();
+        actor.insert(
+            "@context".into(),
+            json!([
+                "https://www.w3.org/ns/activitystreams",
+                "https://w3id.org/security/multikey/v1"
+            ]),
+        );
+        actor.insert("type".into(), json!("Person"));
+        actor.insert("id".into(), json!(actor_url.as_str()));
+        actor.insert(
+            "preferredUsername".into(),
+            json!(self.location.replace(['.', '/'], "_")),
+        );
+        actor.insert("name".into(), json!(self.display_name));
+        if let Some(description) = &self.description {
+            actor.insert("summary".into(), json!(description));
+        }
+        if let Some(avatar) = &self.avatar {
+            actor.insert(
+                "icon".into(),
+                json!({ "type": "Image", "url": avatar.as_str() }),
+            );
+        }
+        actor.insert("url".into(), json!(self.location_url.as_str()));
+        actor.insert(
+            "attachment".into(),
+            json!([{
+                "type": "PropertyValue",
+                "name": "WebIdentity",
+                "value": self.location,
+            }]),
+        );
+        let keys: Vec<Value> = self
+            .public_keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| {
+                let suffix = if i == 0 {
+                    String::new()
+                } else {
+                    format!("-{}", i + 1)
+                };
+                json!({
+                    "id": format!("{}#webidentity{}", actor_url, suffix),
+                    "type": "Multikey",
+                    "controller": actor_url.as_str(),
+                    "publicKeyMultibase": key.to_multibase(),
+                })
+            })
+            .collect();
+        actor.insert("assertionMethod".into(), Value::Array(keys));
+        Value::Object(actor)
+    }
+}
+
+/// Finds the WebIdentity of an ActivityPub actor.
+///
+/// # Errors
+/// Returns `Err` if the actor doesn't reference a WebIdentity location.
+pub fn identity_hint_from_actor(actor: &Value) -> Result<ActorIdentityHint, WebIdentityError> {
+    let attachment = actor
+        .get("attachment")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .find(|attachment| {
+            attachment.get("type").and_then(Value::as_str) == Some("PropertyValue")
+                && attachment.get("name").and_then(Value::as_str) == Some("WebIdentity")
+        })
+        .and_then(|attachment| attachment.get("value"))
+        .and_then(Value::as_str);
+    let also_known_as = || {
+        actor
+            .get("alsoKnownAs")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .find(|aka| aka.starts_with("https://") || aka.starts_with("http://"))
+    };
+
+    let location = attachment
+        .or_else(also_known_as)
+        .ok_or_else(|| WebIdentityError::Parse("The actor has no WebIdentity location.".into()))?;
+    let location = location_from_url(&resolve_location_url(location.trim())?);
+
+    let public_key = actor
+        .get("assertionMethod")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter(|method| method.get("type").and_then(Value::as_str) == Some("Multikey"))
+        .filter_map(|method| method.get("publicKeyMultibase").and_then(Value::as_str))
+        .find_map(decode_ed25519_multikey);
+
+    Ok(ActorIdentityHint {
+        location,
+        public_key,
+    })
+}
+
+fn decode_ed25519_multikey(multibase: &str) -> Option<PublicKey> {
+    let bytes = bs58::decode(multibase.strip_prefix('z')?).into_vec().ok()?;
+    PublicKey::from_bytes(bytes.strip_prefix(&ED25519_MULTICODEC[..])?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::get_identity;
+    use crate::testing::{SharedTestIdentity, TestIdentity};
+
+    const PERSON: &str = include_str!("../snapshots/activitypub/person.json");
+    const PERSON_MULTI_KEY: &str = include_str!("../snapshots/activitypub/person-multi-key.json");
+
+    fn actor_url(name: &str) -> Url {
+        Url::parse(&format!("https://bridge.example/users/{}", name)).unwrap()
+    }
+
+    /// Amy's identity, with an avatar.
+    fn amy() -> Identity {
+        let amy = TestIdentity::generate("amy.carroted.org");
+        let page = amy.page.replace(
+            "</head>",
+            "    <meta name=\"identity:avatar\" content=\"/avatar.png\">\n</head>",
+        );
+        get_identity(&Url::parse("https://amy.carroted.org/").unwrap(), &page).unwrap()
+    }
+
+    #[test]
+    fn matches_the_person_snapshot() {
+        let snapshot: Value = serde_json::from_str(PERSON).unwrap();
+        assert_eq!(amy().to_actor(&actor_url("amy")), snapshot);
+    }
+
+    #[test]
+    fn lists_every_key_of_shared_identities() {
+        let team = SharedTestIdentity::generate("team.example.com", 3, 2);
+        let snapshot: Value = serde_json::from_str(PERSON_MULTI_KEY).unwrap();
+        assert_eq!(team.identity.to_actor(&actor_url("team")), snapshot);
+    }
+
+    #[test]
+    fn reads_back_the_identity_of_an_actor() {
+        let amy = amy();
+        let hint = identity_hint_from_actor(&amy.to_actor(&actor_url("amy"))).unwrap();
+        assert_eq!(hint.location, "amy.carroted.org");
+        assert_eq!(hint.public_key, Some(amy.public_key));
+
+        let team = SharedTestIdentity::generate("team.example.com", 3, 2).identity;
+        let hint = identity_hint_from_actor(&team.to_actor(&actor_url("team"))).unwrap();
+        assert_eq!(hint.public_key, Some(team.public_key));
+    }
+
+    #[test]
+    fn falls_back_to_also_known_as() {
+        let actor = json!({
+            "type": "Person",
+            "alsoKnownAs": ["acct:amy@social.example", "https://Amy.Carroted.org/"],
+            "assertionMethod": [{
+                "type": "Multikey",
+                "publicKeyMultibase": "z6LSbysY2xFMRpGMhb7tFTLMpeuPRaqaWM1yECx2AtzE3KCc",
+            }],
+        });
+        let hint = identity_hint_from_actor(&actor).unwrap();
+        assert_eq!(hint.location, "amy.carroted.org");
+        // Only Ed25519 keys are read
+        assert_eq!(hint.public_key, None);
+    }
+
+    #[test]
+    fn rejects_actors_without_a_location() {
+        for actor in [
+            json!({ "type": "Person" }),
+            json!({ "alsoKnownAs": ["acct:amy@social.example"] }),
+            json!({
+                "attachment": [{ "type": "PropertyValue", "name": "Website", "value": "amy.carroted.org" }],
+            }),
+        ] {
+            assert!(identity_hint_from_actor(&actor).is_err(), "{}", actor);
+        }
+    }
+}
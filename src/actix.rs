@@ -0,0 +1,165 @@
+use super::authenticate::{AuthenticatedRequest, Authenticator};
+use super::error::{SignatureError, WebIdentityError};
+use super::sign::HeaderProvider;
+use actix_web::dev::Payload;
+use actix_web::error::ErrorUnauthorized;
+use actix_web::error::{ErrorBadRequest, ErrorInternalServerError, ErrorPayloadTooLarge};
+use actix_web::http::header::HeaderMap;
+use actix_web::{web, FromRequest, HttpRequest};
+use std::future::Future;
+use std::pin::Pin;
+
+impl HeaderProvider for HeaderMap {
+    fn get_header(&self, name: &str) -> Option<&str> {
+        self.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    fn has_duplicate_header(&self, name: &str) -> bool {
+        self.get_all(name).nth(1).is_some()
+    }
+
+    fn header_names(&self) -> Option<Vec<&str>> {
+        Some(self.keys().map(|name| name.as_str()).collect())
+    }
+}
+
+/// Authenticates the request with the `web::Data<Authenticator>` of the app, or responds with
+/// `401 Unauthorized`.
+///
+/// The whole body is read to verify the signature, up to
+/// [`Authenticator::max_body_size`], so handlers take it from [`AuthenticatedRequest::body`]
+/// instead of using another body extractor. The signed host is the authority of the request
+/// URI when it has one, otherwise the `Host` header. Forwarding headers are only trusted with
+/// [`Authenticator::with_trusted_proxy`].
+///
+/// The identity is resolved on actix's blocking thread pool (`web::block`), so fetching it
+/// doesn't hold up the worker.
+impl FromRequest for AuthenticatedRequest {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(request: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let payload = web::Payload::from_request(request, payload);
+        let request = request.clone();
+
+        Box::pin(async move {
+            let authenticator = request
+                .app_data::<web::Data<Authenticator>>()
+                .cloned()
+                .ok_or_else(|| ErrorInternalServerError(WebIdentityError::MissingAuthenticator))?;
+            let limit = usize::try_from(authenticator.max_body_size()).unwrap_or(usize::MAX);
+            let body = payload
+                .await?
+                .to_bytes_limited(limit)
+                .await
+                .map_err(|_| {
+                    ErrorPayloadTooLarge(WebIdentityError::BodyTooLarge(
+                        authenticator.max_body_size(),
+                    ))
+                })?
+                .map_err(|e| ErrorBadRequest(WebIdentityError::Body(e.to_string())))?;
+
+            let host = match request.uri().authority() {
+                Some(authority) => authority.as_str(),
+                None => request.headers().get_header("Host").ok_or_else(|| {
+                    ErrorUnauthorized(SignatureError::MissingHeader("Host".into()))
+                })?,
+            };
+            let host = authenticator
+                .external_host(host, request.headers())
+                .map_err(ErrorUnauthorized)?;
+            let path = request
+                .uri()
+                .path_and_query()
+                .map_or("/", |p| p.as_str())
+                .to_string();
+            let method = request.method().clone();
+            let headers = request.headers().clone();
+
+            web::block(move || {
+                authenticator.authenticate(method.as_str(), &host, &path, body.to_vec(), &headers)
+            })
+            .await?
+            .map_err(ErrorUnauthorized)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sign::VerifyOptions;
+    use crate::testing::{StaticFetcher, TestIdentity};
+    use actix_web::test::TestRequest;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn authenticates_on_the_blocking_pool() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let authenticator = Authenticator::new(
+            StaticFetcher::new().with_identity(&alice),
+            VerifyOptions::new(Duration::from_secs(300)),
+        );
+        let signed = alice.signed_headers_for("POST", "api.example.com", "/notes", b"hello");
+
+        let mut request = TestRequest::post()
+            .uri("/notes")
+            .insert_header(("Host", "api.example.com"))
+            .app_data(web::Data::new(authenticator))
+            .set_payload("hello");
+        for (name, value) in signed.headers() {
+            request = request.insert_header((name.as_str(), value.as_str()));
+        }
+        let (request, mut payload) = request.to_http_parts();
+        let authenticated = AuthenticatedRequest::from_request(&request, &mut payload)
+            .await
+            .unwrap();
+        assert_eq!(authenticated.identity.id, alice.identity.id);
+        assert_eq!(authenticated.body, b"hello");
+
+        let (request, mut payload) = TestRequest::post()
+            .uri("/notes")
+            .insert_header(("Host", "api.example.com"))
+            .app_data(
+                request
+                    .app_data::<web::Data<Authenticator>>()
+                    .unwrap()
+                    .clone(),
+            )
+            .set_payload("hello")
+            .to_http_parts();
+        let error = AuthenticatedRequest::from_request(&request, &mut payload)
+            .await
+            .unwrap_err();
+        assert_eq!(error.as_response_error().status_code(), 401);
+    }
+
+    #[tokio::test]
+    async fn trusts_forwarding_headers_only_behind_a_trusted_proxy() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let signed = alice.signed_headers_for("POST", "api.example.com", "/notes", b"hello");
+        let authenticate = |authenticator: Authenticator| {
+            let mut request = TestRequest::post()
+                .uri("/notes")
+                .insert_header(("Host", "internal:8080"))
+                .insert_header(("X-Forwarded-Host", "api.example.com"))
+                .app_data(web::Data::new(authenticator))
+                .set_payload("hello");
+            for (name, value) in signed.headers() {
+                request = request.insert_header((name.as_str(), value.as_str()));
+            }
+            let (request, mut payload) = request.to_http_parts();
+            async move { AuthenticatedRequest::from_request(&request, &mut payload).await }
+        };
+        let authenticator = Authenticator::new(
+            StaticFetcher::new().with_identity(&alice),
+            VerifyOptions::new(Duration::from_secs(300)),
+        );
+
+        assert!(authenticate(authenticator.clone()).await.is_err());
+        let authenticated = authenticate(authenticator.with_trusted_proxy(&["api.example.com"]))
+            .await
+            .unwrap();
+        assert_eq!(authenticated.identity.id, alice.identity.id);
+    }
+}
@@ -0,0 +1,36 @@
+use super::error::SignatureError;
+use std::fmt;
+use std::str::FromStr;
+
+/// The signature scheme of a request, sent in the `WebIdentity-Algorithm` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[non_exhaustive]
+pub enum SignatureAlgorithm {
+    #[default]
+    Ed25519,
+}
+
+impl SignatureAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SignatureAlgorithm::Ed25519 => "ed25519",
+        }
+    }
+}
+
+impl fmt::Display for SignatureAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for SignatureAlgorithm {
+    type Err = SignatureError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "ed25519" => Ok(SignatureAlgorithm::Ed25519),
+            other => Err(SignatureError::UnsupportedAlgorithm(other.to_string())),
+        }
+    }
+}
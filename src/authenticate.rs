@@ -0,0 +1,524 @@
+use super::authorization::AuthorizationHeaders;
+use super::digest::RequestDigest;
+use super::error::{SignatureError, WebIdentityError};
+use super::forwarded::derive_external_host;
+use super::identity::Identity;
+use super::rate_limit::{check_request_rate, RateLimiter};
+use super::resolve::IdentityResolver;
+use super::sign::{body_digest, identity_keys, HeaderProvider};
+use super::sign::{SignedRequest, VerifiedRequest, VerifyOptions};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often a [`RetryPolicy`] refreshes the same identity at most, by default.
+pub const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The largest body an [`Authenticator`] reads, by default.
+pub const DEFAULT_MAX_BODY_SIZE: u64 = 1024 * 1024;
+
+/// How many locations a [`RetryPolicy`] remembers refreshing at once.
+const MAX_TRACKED_REFRESHES: usize = 10_000;
+
+/// What [`authenticate_request`] does when a request isn't signed by any key of the resolved
+/// identity, which happens when the identity was cached before the key was added to it.
+///
+/// With [`RetryPolicy::refresh_once`], the identity is invalidated in the resolver and resolved
+/// again, once per request and at most once per interval for each location, so clients
+/// sending requests signed with unknown keys can't make the server refetch identities at will.
+#[derive(Debug)]
+pub struct RetryPolicy {
+    min_interval: Option<Duration>,
+    refreshed: Mutex<HashMap<String, Instant>>,
+}
+
+impl RetryPolicy {
+    /// Never refreshes the identity, the request is rejected.
+    pub fn never() -> Self {
+        RetryPolicy {
+            min_interval: None,
+            refreshed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Refreshes the identity and verifies the request again, unless it was already refreshed
+    /// less than `min_interval` ago.
+    pub fn refresh_once(min_interval: Duration) -> Self {
+        RetryPolicy {
+            min_interval: Some(min_interval),
+            refreshed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records a refresh of `location` if one is allowed now.
+    fn try_refresh(&self, location: &str) -> bool {
+        let Some(min_interval) = self.min_interval else {
+            return false;
+        };
+
+        let mut refreshed = self.refreshed.lock().unwrap();
+        if refreshed
+            .get(location)
+            .is_some_and(|at| at.elapsed() < min_interval)
+        {
+            return false;
+        }
+        if refreshed.len() >= MAX_TRACKED_REFRESHES {
+            refreshed.retain(|_, at| at.elapsed() < min_interval);
+            // Every tracked refresh is recent, so refreshes are being requested faster than
+            // they are allowed
+            if refreshed.len() >= MAX_TRACKED_REFRESHES {
+                return false;
+            }
+        }
+        refreshed.insert(location.to_string(), Instant::now());
+        true
+    }
+}
+
+/// Refreshes an identity at most once per [`DEFAULT_REFRESH_INTERVAL`].
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::refresh_once(DEFAULT_REFRESH_INTERVAL)
+    }
+}
+
+/// Resolves the identity a request claims to come from and verifies the request against any
+/// of its keys, returning the identity along with the verified request.
+///
+/// Identities with a [`threshold`](Identity::threshold) above one need that many of their keys
+/// to have signed the request, as with [`verify_request_threshold`](crate::verify_request_threshold).
+///
+/// Checks that don't depend on the key, such as the required headers, the timestamp window
+/// and the allowed methods, are done first, so requests failing them are rejected without
+/// resolving the identity.
+///
+/// If the request isn't signed by any listed key, `on_unknown_key` decides whether the identity
+/// is invalidated in `resolver` (see [`IdentityResolver::invalidate`]) and resolved again
+/// before the request is verified one more time. Other failures, such as an expired
+/// timestamp, are returned without refreshing.
+///
+/// # Errors
+/// Returns `Err` if the identity can't be resolved, any header is missing, the timestamp is
+/// invalid or outside the allowed window, or the signature is incorrect.
+#[allow(clippy::too_many_arguments)]
+pub fn authenticate_request(
+    resolver: &(impl IdentityResolver + ?Sized),
+    http_method: &str,
+    host: &str,
+    path: &str,
+    body_digest: &RequestDigest,
+    headers: &impl HeaderProvider,
+    options: &VerifyOptions,
+    on_unknown_key: &RetryPolicy,
+) -> Result<(Arc<Identity>, VerifiedRequest), WebIdentityError> {
+    authenticate_limited_request(
+        resolver,
+        http_method,
+        host,
+        path,
+        body_digest,
+        headers,
+        options,
+        on_unknown_key,
+        None,
+    )
+}
+
+/// [`authenticate_request`], checking the request against `rate_limiter` once its headers are
+/// parsed, before the identity is resolved.
+#[allow(clippy::too_many_arguments)]
+fn authenticate_limited_request(
+    resolver: &(impl IdentityResolver + ?Sized),
+    http_method: &str,
+    host: &str,
+    path: &str,
+    body_digest: &RequestDigest,
+    headers: &impl HeaderProvider,
+    options: &VerifyOptions,
+    on_unknown_key: &RetryPolicy,
+    rate_limiter: Option<&dyn RateLimiter>,
+) -> Result<(Arc<Identity>, VerifiedRequest), WebIdentityError> {
+    // Everything that doesn't need a key is checked before the identity is resolved, so
+    // requests with missing headers or stale timestamps never cause a fetch or cache lookup
+    let signed_headers = AuthorizationHeaders::new(headers)?;
+    let request = SignedRequest::parse(
+        http_method,
+        host,
+        path,
+        body_digest,
+        &signed_headers,
+        options,
+    )?;
+    if let Some(rate_limiter) = rate_limiter {
+        check_request_rate(rate_limiter, headers)?;
+    }
+    let location = &request.location;
+    let verify = |identity: &Identity| {
+        request.verify_for_keys(&identity_keys(identity), identity.threshold, options)
+    };
+
+    let identity = resolver.resolve_identity(location)?;
+    match verify(&identity) {
+        Ok(request) => Ok((identity, request)),
+        Err(error) if is_unknown_key(&error) && on_unknown_key.try_refresh(&identity.location) => {
+            resolver.invalidate(location);
+            let identity = resolver.resolve_identity(location)?;
+            let request = verify(&identity)?;
+            Ok((identity, request))
+        }
+        Err(error) => Err(error),
+    }
+}
+
+/// The resolver and verification settings used by the web framework integrations to
+/// authenticate requests with [`authenticate_request`].
+///
+/// With the `actix-web` feature, it is read from the app data as `web::Data<Authenticator>`
+/// by the [`AuthenticatedRequest`] extractor. With the `rocket` feature, it is read from the
+/// managed state by the [`AuthenticatedRequest`] data guard.
+///
+/// Clones share the resolver, the [`RetryPolicy`] and the timestamp store, if any.
+#[derive(Clone)]
+pub struct Authenticator {
+    resolver: Arc<dyn IdentityResolver + Send + Sync>,
+    options: VerifyOptions,
+    on_unknown_key: Arc<RetryPolicy>,
+    max_body_size: u64,
+    trusted_proxy: Option<Vec<String>>,
+    rate_limiter: Option<Arc<dyn RateLimiter>>,
+}
+
+impl Authenticator {
+    /// Resolves identities with `resolver`, refreshing them as in [`RetryPolicy::default`].
+    ///
+    /// [`Authenticator::authenticate`] blocks while the identity is resolved, which can mean
+    /// fetching it. The actix-web and Rocket integrations run it on their blocking thread pool,
+    /// so `resolver` may block, including with
+    /// [`FetcherResolver::with_runtime`](crate::FetcherResolver). Code calling it directly
+    /// from an async task must do the same, e.g. with `tokio::task::spawn_blocking`.
+    pub fn new(
+        resolver: impl IdentityResolver + Send + Sync + 'static,
+        options: VerifyOptions,
+    ) -> Self {
+        Authenticator {
+            resolver: Arc::new(resolver),
+            options,
+            on_unknown_key: Arc::new(RetryPolicy::default()),
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            trusted_proxy: None,
+            rate_limiter: None,
+        }
+    }
+
+    pub fn with_retry_policy(mut self, on_unknown_key: RetryPolicy) -> Self {
+        self.on_unknown_key = Arc::new(on_unknown_key);
+        self
+    }
+
+    /// Rejects requests with a body larger than `max_body_size` bytes, instead of
+    /// [`DEFAULT_MAX_BODY_SIZE`].
+    pub fn with_max_body_size(mut self, max_body_size: u64) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    pub fn max_body_size(&self) -> u64 {
+        self.max_body_size
+    }
+
+    /// Checks requests against `rate_limiter` with [`check_request_rate`] once their headers
+    /// are parsed, so requests over the limit are rejected before the identity is resolved.
+    pub fn with_rate_limiter(mut self, rate_limiter: impl RateLimiter + 'static) -> Self {
+        self.rate_limiter = Some(Arc::new(rate_limiter));
+        self
+    }
+
+    /// Trusts the forwarding headers of requests, for servers that are only reachable through
+    /// their own reverse proxy. The signed host is then derived with [`derive_external_host`],
+    /// and must be one of `allowed_hosts`.
+    pub fn with_trusted_proxy(mut self, allowed_hosts: &[&str]) -> Self {
+        self.trusted_proxy = Some(allowed_hosts.iter().map(|host| host.to_string()).collect());
+        self
+    }
+
+    /// The host a request was signed for, given `host`, the one it was received on.
+    ///
+    /// Without [`Authenticator::with_trusted_proxy`], this is `host`. Otherwise it is read from
+    /// the forwarding headers by [`derive_external_host`], falling back to `host`.
+    ///
+    /// # Errors
+    /// Returns `Err` if a forwarding header was sent more than once, or the host isn't allowed.
+    pub fn external_host(
+        &self,
+        host: &str,
+        headers: &impl HeaderProvider,
+    ) -> Result<String, WebIdentityError> {
+        let Some(allowed_hosts) = &self.trusted_proxy else {
+            return Ok(host.to_string());
+        };
+        let allowed_hosts: Vec<&str> = allowed_hosts.iter().map(String::as_str).collect();
+        derive_external_host(&ReceivedOn { headers, host }, true, &allowed_hosts)
+    }
+
+    /// Authenticates a request whose whole body was read, see [`authenticate_request`].
+    ///
+    /// This blocks while the identity is resolved, see [`Authenticator::new`].
+    ///
+    /// # Errors
+    /// Returns `Err` if the request is over the rate limit, the identity can't be resolved or
+    /// the request isn't correctly signed by one of its keys.
+    pub fn authenticate(
+        &self,
+        http_method: &str,
+        host: &str,
+        path: &str,
+        body: Vec<u8>,
+        headers: &impl HeaderProvider,
+    ) -> Result<AuthenticatedRequest, WebIdentityError> {
+        let (identity, request) = authenticate_limited_request(
+            self.resolver.as_ref(),
+            http_method,
+            host,
+            path,
+            &body_digest(&body, headers)?,
+            headers,
+            &self.options,
+            &self.on_unknown_key,
+            self.rate_limiter.as_deref(),
+        )?;
+        Ok(AuthenticatedRequest {
+            identity,
+            request,
+            body,
+        })
+    }
+}
+
+impl fmt::Debug for Authenticator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Authenticator")
+            .field("options", &self.options)
+            .field("on_unknown_key", &self.on_unknown_key)
+            .field("max_body_size", &self.max_body_size)
+            .field("trusted_proxy", &self.trusted_proxy)
+            .field("rate_limited", &self.rate_limiter.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A request authenticated by an [`Authenticator`].
+///
+/// The framework integrations read the body to verify the signature, so it is handed back
+/// here for the handler.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedRequest {
+    pub identity: Arc<Identity>,
+    pub request: VerifiedRequest,
+    pub body: Vec<u8>,
+}
+
+/// Whether verification failed because the request was signed by a key the identity doesn't
+/// list.
+fn is_unknown_key(error: &WebIdentityError) -> bool {
+    matches!(
+        error,
+        WebIdentityError::Signature(
+            SignatureError::SignatureMismatch
+                | SignatureError::KeyFingerprintMismatch
+                | SignatureError::DelegationSignatureMismatch
+                | SignatureError::ThresholdNotMet { .. }
+        )
+    )
+}
+
+/// The headers of a request, with `Host` replaced by the host it was received on.
+struct ReceivedOn<'a, H> {
+    headers: &'a H,
+    host: &'a str,
+}
+
+impl<H: HeaderProvider> HeaderProvider for ReceivedOn<'_, H> {
+    fn get_header(&self, name: &str) -> Option<&str> {
+        if name.eq_ignore_ascii_case("Host") {
+            Some(self.host)
+        } else {
+            self.headers.get_header(name)
+        }
+    }
+
+    fn has_duplicate_header(&self, name: &str) -> bool {
+        !name.eq_ignore_ascii_case("Host") && self.headers.has_duplicate_header(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rate_limit::MemoryRateLimiter;
+    use crate::sign::SimpleHeaderProvider;
+    use crate::testing::{StaticFetcher, TestIdentity};
+
+    const HOST: &str = "api.example.com";
+    const PATH: &str = "/notes";
+    const BODY: &[u8] = b"{\"text\":\"hello\"}";
+
+    fn authenticate(
+        resolver: &StaticFetcher,
+        headers: &impl HeaderProvider,
+    ) -> Result<(Arc<Identity>, VerifiedRequest), WebIdentityError> {
+        authenticate_request(
+            resolver,
+            "POST",
+            HOST,
+            PATH,
+            &RequestDigest::of(BODY),
+            headers,
+            &VerifyOptions::new(Duration::from_secs(300)),
+            &RetryPolicy::never(),
+        )
+    }
+
+    #[test]
+    fn authenticates_single_key_identity() {
+        let identity = TestIdentity::generate("alice.example.com");
+        let resolver = StaticFetcher::new().with_identity(&identity);
+        let headers = identity.signed_headers_for("POST", HOST, PATH, BODY);
+
+        let (resolved, request) = authenticate(&resolver, headers.headers()).unwrap();
+        assert_eq!(resolved.id, identity.identity.id);
+        assert_eq!(request.location, "alice.example.com");
+    }
+
+    #[test]
+    fn refreshes_identity_once_on_unknown_key() {
+        let old = TestIdentity::generate("alice.example.com");
+        let new = TestIdentity::generate("alice.example.com#rotated");
+        let rotated = new
+            .page
+            .replace("alice.example.com#rotated", "alice.example.com");
+        let resolver =
+            StaticFetcher::new().with_pages("alice.example.com", vec![old.page.clone(), rotated]);
+        let headers = TestIdentity {
+            location: "alice.example.com".into(),
+            ..new
+        }
+        .signed_headers_for("POST", HOST, PATH, BODY);
+
+        let result = authenticate_request(
+            &resolver,
+            "POST",
+            HOST,
+            PATH,
+            &RequestDigest::of(BODY),
+            headers.headers(),
+            &VerifyOptions::new(Duration::from_secs(300)),
+            &RetryPolicy::refresh_once(Duration::from_secs(60)),
+        );
+        assert!(result.is_ok(), "{:?}", result);
+        assert_eq!(resolver.fetch_count("alice.example.com"), 2);
+    }
+
+    #[test]
+    fn derives_the_host_behind_a_trusted_proxy() {
+        let headers: SimpleHeaderProvider = [
+            ("Host", "internal:8080"),
+            ("X-Forwarded-Host", HOST),
+            ("X-Forwarded-Proto", "https"),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+        let authenticator = Authenticator::new(
+            StaticFetcher::new(),
+            VerifyOptions::new(Duration::from_secs(300)),
+        );
+        assert_eq!(
+            authenticator
+                .external_host("internal:8080", &headers)
+                .unwrap(),
+            "internal:8080"
+        );
+
+        let authenticator = authenticator.with_trusted_proxy(&[HOST]);
+        assert_eq!(
+            authenticator
+                .external_host("internal:8080", &headers)
+                .unwrap(),
+            HOST
+        );
+        assert!(matches!(
+            authenticator.external_host("internal:8080", &SimpleHeaderProvider::new()),
+            Err(WebIdentityError::HostNotAllowed(host)) if host == "internal:8080"
+        ));
+        assert_eq!(
+            authenticator
+                .external_host(HOST, &SimpleHeaderProvider::new())
+                .unwrap(),
+            HOST
+        );
+    }
+
+    #[test]
+    fn rate_limits_before_resolving_the_identity() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let mallory = TestIdentity::generate("mallory.example.com");
+        let authenticator = Authenticator::new(
+            StaticFetcher::new().with_identity(&alice),
+            VerifyOptions::new(Duration::from_secs(300)),
+        )
+        .with_rate_limiter(MemoryRateLimiter::new(1, Duration::from_secs(60)));
+        let is_limited = |result: Result<AuthenticatedRequest, WebIdentityError>| {
+            matches!(result, Err(WebIdentityError::RateLimited { .. }))
+        };
+        let authenticate = |identity: &TestIdentity| {
+            let headers = identity.signed_headers_for("POST", HOST, PATH, BODY);
+            authenticator.authenticate("POST", HOST, PATH, BODY.to_vec(), headers.headers())
+        };
+
+        authenticate(&alice).unwrap();
+        assert!(is_limited(authenticate(&alice)));
+
+        // Mallory isn't resolvable, so only the first request gets as far as resolving
+        let first = authenticate(&mallory);
+        assert!(first.is_err() && !is_limited(first));
+        assert!(is_limited(authenticate(&mallory)));
+
+        // Requests that can't be parsed don't count
+        let unsigned = SimpleHeaderProvider::new();
+        for _ in 0..3 {
+            let result = authenticator.authenticate("POST", HOST, PATH, BODY.to_vec(), &unsigned);
+            assert!(result.is_err() && !is_limited(result));
+        }
+    }
+
+    #[test]
+    fn rate_limits_concurrent_requests() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let authenticator = Authenticator::new(
+            StaticFetcher::new().with_identity(&alice),
+            VerifyOptions::new(Duration::from_secs(300)),
+        )
+        .with_rate_limiter(MemoryRateLimiter::new(5, Duration::from_secs(60)));
+        let headers = alice.signed_headers_for("POST", HOST, PATH, BODY);
+
+        let accepted = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..16)
+                .map(|_| {
+                    scope.spawn(|| {
+                        authenticator
+                            .authenticate("POST", HOST, PATH, BODY.to_vec(), headers.headers())
+                            .is_ok()
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .filter(|accepted| *accepted)
+                .count()
+        });
+        assert_eq!(accepted, 5);
+    }
+}
@@ -0,0 +1,456 @@
+use super::challenge::Challenge;
+use super::error::SignatureError;
+use super::sign::HeaderProvider;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// The version of the `Authorization` encoding.
+const AUTHORIZATION_VERSION: &str = "1";
+
+/// The `Authorization` parameters and the `WebIdentity-*` headers they stand for.
+const PARAMS: &[(&str, &str)] = &[
+    ("location", "WebIdentity-Location"),
+    ("ts", "WebIdentity-Timestamp"),
+    ("sig", "WebIdentity-Signature"),
+    ("alg", "WebIdentity-Algorithm"),
+    ("key", "WebIdentity-Key"),
+    ("delegation", "WebIdentity-Delegation"),
+    ("digest", "WebIdentity-Digest"),
+    ("length", "WebIdentity-Body-Length"),
+    ("canon", "WebIdentity-Canonicalization"),
+    ("body", "WebIdentity-Body"),
+    ("headers", "WebIdentity-Headers"),
+    ("expires", "WebIdentity-Expires"),
+    ("trailers", "WebIdentity-Trailers"),
+];
+
+/// Encodes `WebIdentity-*` headers as a single `Authorization` header value:
+/// `WebIdentity location="...", ts="...", sig="...", v="1"`, plus `alg`, `key`, `delegation`,
+/// `digest`, `length`, `canon`, `body`, `headers`, `expires` and `trailers` for the optional
+/// headers.
+pub(crate) fn to_authorization(headers: &HashMap<String, String>) -> String {
+    let mut params: Vec<String> = PARAMS
+        .iter()
+        .filter_map(|(param, header)| {
+            let value = headers.get(*header)?;
+            Some(format!("{}=\"{}\"", param, escape(value)))
+        })
+        .collect();
+    params.push(format!("v=\"{}\"", AUTHORIZATION_VERSION));
+    format!("WebIdentity {}", params.join(", "))
+}
+
+/// What a server advertises in the `WWW-Authenticate` header of a 401 response, see
+/// [`www_authenticate_challenge`].
+#[derive(Debug, Clone, Default)]
+pub struct WwwAuthenticateOptions {
+    challenge: Option<Challenge>,
+    max_age: Option<Duration>,
+}
+
+impl WwwAuthenticateOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Includes a challenge for the client to sign, for the proof-of-possession flow.
+    ///
+    /// Use a fresh one from [`generate_challenge`](crate::generate_challenge) for each response,
+    /// and keep it to check the answer with
+    /// [`verify_challenge_response`](crate::verify_challenge_response).
+    pub fn with_challenge(mut self, challenge: Challenge) -> Self {
+        self.challenge = Some(challenge);
+        self
+    }
+
+    /// Tells the client how old a signed request may be, usually the `max_age` of the server's
+    /// [`VerifyOptions`](crate::VerifyOptions).
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+}
+
+/// Creates a `WWW-Authenticate` header value for a 401 response, so clients can tell the
+/// endpoint expects WebIdentity signatures:
+/// `WebIdentity realm="...", v="1"`, plus `challenge` and `expires` when a challenge is
+/// included, and `max-age` in seconds.
+pub fn www_authenticate_challenge(realm: &str, options: &WwwAuthenticateOptions) -> String {
+    let mut params = vec![
+        format!("realm=\"{}\"", escape(realm)),
+        format!("v=\"{}\"", AUTHORIZATION_VERSION),
+    ];
+    if let Some(challenge) = &options.challenge {
+        params.push(format!("challenge=\"{}\"", hex::encode(challenge.bytes)));
+        params.push(format!("expires=\"{}\"", challenge.expires_at));
+    }
+    if let Some(max_age) = options.max_age {
+        params.push(format!("max-age=\"{}\"", max_age.as_secs()));
+    }
+    format!("WebIdentity {}", params.join(", "))
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Headers of a request, with the `WebIdentity-*` headers read from a `WebIdentity`
+/// `Authorization` header when the request has no `WebIdentity-Signature` header.
+///
+/// The canonical string and signature are the same in both encodings, so verification
+/// doesn't need to know which one the client used.
+pub(crate) struct AuthorizationHeaders<'a, H> {
+    headers: &'a H,
+    authorization: Option<HashMap<&'static str, String>>,
+}
+
+impl<'a, H: HeaderProvider> AuthorizationHeaders<'a, H> {
+    pub(crate) fn new(headers: &'a H) -> Result<Self, SignatureError> {
+        let authorization = match headers.get_header("WebIdentity-Signature") {
+            Some(_) => None,
+            None => match headers.get_header("Authorization") {
+                Some(value) => {
+                    if headers.has_duplicate_header("Authorization") {
+                        return Err(SignatureError::DuplicateHeader("Authorization".into()));
+                    }
+                    parse_authorization(value)?
+                }
+                None => None,
+            },
+        };
+        Ok(AuthorizationHeaders {
+            headers,
+            authorization,
+        })
+    }
+}
+
+impl<H: HeaderProvider> HeaderProvider for AuthorizationHeaders<'_, H> {
+    fn get_header(&self, name: &str) -> Option<&str> {
+        match &self.authorization {
+            Some(params) if is_webidentity_header(name) => PARAMS
+                .iter()
+                .find(|(_, header)| header.eq_ignore_ascii_case(name))
+                .and_then(|(_, header)| params.get(header))
+                .map(String::as_str),
+            _ => self.headers.get_header(name),
+        }
+    }
+
+    fn has_duplicate_header(&self, name: &str) -> bool {
+        match &self.authorization {
+            // Repeated parameters are rejected when parsing
+            Some(_) if is_webidentity_header(name) => false,
+            _ => self.headers.has_duplicate_header(name),
+        }
+    }
+
+    fn header_names(&self) -> Option<Vec<&str>> {
+        self.headers.header_names()
+    }
+
+    fn get_trailer(&self, name: &str) -> Option<&str> {
+        self.headers.get_trailer(name)
+    }
+}
+
+fn is_webidentity_header(name: &str) -> bool {
+    name.get(..12)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("WebIdentity-"))
+}
+
+/// Parses the parameters of a `WebIdentity` `Authorization` header (RFC 7235 auth-params),
+/// keyed by the header they stand for. Returns `None` for other schemes.
+fn parse_authorization(
+    value: &str,
+) -> Result<Option<HashMap<&'static str, String>>, SignatureError> {
+    let invalid = |reason: &str| SignatureError::InvalidAuthorization(reason.to_string());
+
+    let value = value.trim();
+    let (scheme, mut rest) = value.split_once(' ').unwrap_or((value, ""));
+    if !scheme.eq_ignore_ascii_case("WebIdentity") {
+        return Ok(None);
+    }
+
+    let mut params = HashMap::new();
+    let mut version = None;
+    loop {
+        rest = rest.trim_start_matches([' ', '\t', ',']);
+        if rest.is_empty() {
+            break;
+        }
+
+        let (name, after) = rest
+            .split_once('=')
+            .ok_or_else(|| invalid("Expected a parameter."))?;
+        let name = name.trim().to_ascii_lowercase();
+        if name.is_empty() || !name.bytes().all(is_token_char) {
+            return Err(invalid("Invalid parameter name."));
+        }
+        let (value, after) = parse_param_value(after.trim_start())
+            .ok_or_else(|| invalid("Invalid parameter value."))?;
+        rest = after.trim_start();
+        if !(rest.is_empty() || rest.starts_with(',')) {
+            return Err(invalid("Expected a comma between parameters."));
+        }
+
+        if name == "v" {
+            if version.replace(value).is_some() {
+                return Err(invalid("The 'v' parameter is repeated."));
+            }
+        } else if let Some((_, header)) = PARAMS.iter().find(|(param, _)| *param == name) {
+            if params.insert(*header, value).is_some() {
+                return Err(invalid(&format!("The '{}' parameter is repeated.", name)));
+            }
+        }
+        // Unknown parameters are ignored, so they can be added later
+    }
+
+    if version
+        .as_deref()
+        .is_some_and(|v| v != AUTHORIZATION_VERSION)
+    {
+        return Err(invalid("Unsupported version."));
+    }
+    Ok(Some(params))
+}
+
+/// Parses a token or quoted-string, returning it and what follows.
+fn parse_param_value(input: &str) -> Option<(String, &str)> {
+    let Some(quoted) = input.strip_prefix('"') else {
+        let end = input
+            .bytes()
+            .position(|b| !is_token_char(b))
+            .unwrap_or(input.len());
+        return (end > 0).then(|| (input[..end].to_string(), &input[end..]));
+    };
+
+    let mut value = String::new();
+    let mut chars = quoted.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, &quoted[i + 1..])),
+            '\\' => value.push(chars.next()?.1),
+            c => value.push(c),
+        }
+    }
+    // Unterminated quoted-string
+    None
+}
+
+/// Whether a byte is a `tchar` (RFC 7230, section 3.2.6).
+fn is_token_char(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::RequestDigest;
+    use crate::error::WebIdentityError;
+    use crate::sign::{
+        create_signed_headers_with_options, verify_request_with_key, MultiHeaderProvider,
+        SignOptions, VerifyOptions,
+    };
+    use ed25519_dalek::SigningKey;
+
+    fn parse(value: &str) -> HashMap<&'static str, String> {
+        parse_authorization(value).unwrap().unwrap()
+    }
+
+    fn parse_error(value: &str) -> String {
+        match parse_authorization(value) {
+            Err(SignatureError::InvalidAuthorization(reason)) => reason,
+            other => panic!("{}: {:?}", value, other),
+        }
+    }
+
+    #[test]
+    fn parses_tokens_and_quoted_strings() {
+        let params = parse(
+            r#"webidentity LOCATION="amy.carroted.org", ts=1700000000,sig="a\"b\\c" , future="x", v=1"#,
+        );
+        assert_eq!(params.len(), 3);
+        assert_eq!(params["WebIdentity-Location"], "amy.carroted.org");
+        assert_eq!(params["WebIdentity-Timestamp"], "1700000000");
+        assert_eq!(params["WebIdentity-Signature"], r#"a"b\c"#);
+
+        assert_eq!(parse_authorization("Bearer abc").unwrap(), None);
+        assert!(parse("WebIdentity").is_empty());
+    }
+
+    #[test]
+    fn round_trips_escaped_values() {
+        let headers = HashMap::from([
+            (
+                "WebIdentity-Location".to_string(),
+                r#"odd"\location"#.to_string(),
+            ),
+            (
+                "WebIdentity-Timestamp".to_string(),
+                "1700000000".to_string(),
+            ),
+            ("X-Other".to_string(), "ignored".to_string()),
+        ]);
+        let authorization = to_authorization(&headers);
+        assert_eq!(
+            authorization,
+            r#"WebIdentity location="odd\"\\location", ts="1700000000", v="1""#
+        );
+        let params = parse(&authorization);
+        assert_eq!(params.len(), 2);
+        assert_eq!(
+            params["WebIdentity-Location"],
+            headers["WebIdentity-Location"]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        for (value, reason) in [
+            (
+                r#"WebIdentity ts="1", ts="2""#,
+                "The 'ts' parameter is repeated.",
+            ),
+            (
+                r#"WebIdentity v="1", v="1""#,
+                "The 'v' parameter is repeated.",
+            ),
+            (r#"WebIdentity v="2""#, "Unsupported version."),
+            (r#"WebIdentity ts="1"#, "Invalid parameter value."),
+            ("WebIdentity ts=", "Invalid parameter value."),
+            (
+                r#"WebIdentity ts="1" sig="2""#,
+                "Expected a comma between parameters.",
+            ),
+            ("WebIdentity ts", "Expected a parameter."),
+            (r#"WebIdentity t s="1""#, "Invalid parameter name."),
+        ] {
+            assert_eq!(parse_error(value), reason, "{}", value);
+        }
+    }
+
+    #[test]
+    fn verifies_either_encoding_of_the_same_signature() {
+        let key = SigningKey::from_bytes(&[3; 32]);
+        let options = VerifyOptions::new(Duration::from_secs(300));
+        let verify = |headers: &HashMap<String, String>| {
+            verify_request_with_key(
+                "POST",
+                "example.com",
+                "/notes",
+                &RequestDigest::of(b"hello"),
+                headers,
+                &key.verifying_key(),
+                &options,
+            )
+        };
+
+        let headers = create_signed_headers_with_options(
+            "amy.carroted.org",
+            "POST",
+            "example.com",
+            "/notes",
+            b"hello",
+            &key,
+            &SignOptions::default().with_key_fingerprint(),
+        )
+        .unwrap();
+        let authorization = to_authorization(&headers);
+        verify(&headers).unwrap();
+        verify(&HashMap::from([(
+            "Authorization".to_string(),
+            authorization.clone(),
+        )]))
+        .unwrap();
+
+        // Both encodings sign the same canonical string
+        let encoded = create_signed_headers_with_options(
+            "amy.carroted.org",
+            "POST",
+            "example.com",
+            "/notes",
+            b"hello",
+            &key,
+            &SignOptions::default()
+                .with_key_fingerprint()
+                .with_authorization_header(),
+        )
+        .unwrap();
+        let params = parse(&encoded["Authorization"]);
+        assert_eq!(params.len(), headers.len());
+        assert!(params
+            .iter()
+            .all(|(header, value)| headers.get(*header).is_some_and(|v| {
+                *header == "WebIdentity-Timestamp"
+                    || *header == "WebIdentity-Signature"
+                    || v == value
+            })));
+        verify(&encoded).unwrap();
+
+        // The headers win over the `Authorization` header
+        let mut both = headers.clone();
+        both.insert(
+            "Authorization".to_string(),
+            "WebIdentity v=\"2\"".to_string(),
+        );
+        verify(&both).unwrap();
+    }
+
+    #[test]
+    fn rejects_repeated_authorization_headers() {
+        let mut headers = MultiHeaderProvider::new();
+        headers.insert(
+            "Authorization".to_string(),
+            vec![
+                r#"WebIdentity ts="1""#.to_string(),
+                "Bearer abc".to_string(),
+            ],
+        );
+        assert!(matches!(
+            AuthorizationHeaders::new(&headers),
+            Err(SignatureError::DuplicateHeader(name)) if name == "Authorization"
+        ));
+
+        let key = SigningKey::from_bytes(&[3; 32]);
+        let error = verify_request_with_key(
+            "GET",
+            "example.com",
+            "/",
+            &RequestDigest::of(b""),
+            &HashMap::from([(
+                "Authorization".to_string(),
+                "WebIdentity v=\"2\"".to_string(),
+            )]),
+            &key.verifying_key(),
+            &VerifyOptions::new(Duration::from_secs(300)),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error,
+            WebIdentityError::Signature(SignatureError::InvalidAuthorization(_))
+        ));
+    }
+
+    #[test]
+    fn advertises_challenges() {
+        let challenge = Challenge {
+            bytes: [0xab; 32],
+            expires_at: 1700000000,
+        };
+        let options = WwwAuthenticateOptions::new()
+            .with_challenge(challenge)
+            .with_max_age(Duration::from_secs(300));
+        assert_eq!(
+            www_authenticate_challenge("notes \"app\"", &options),
+            format!(
+                r#"WebIdentity realm="notes \"app\"", v="1", challenge="{}", expires="1700000000", max-age="300""#,
+                "ab".repeat(32)
+            )
+        );
+        assert_eq!(
+            www_authenticate_challenge("notes", &WwwAuthenticateOptions::new()),
+            r#"WebIdentity realm="notes", v="1""#
+        );
+    }
+}
@@ -0,0 +1,246 @@
+use super::error::WebIdentityError;
+use super::identity::{Identity, PK_PREFIX};
+use super::resolve::IdentityResolver;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+/// The rule of a [`SubjectBlocklist`] that matched an identity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockRule {
+    IdentityId(String),
+    /// A hex-encoded public key, listed anywhere on the identity
+    PublicKey(String),
+}
+
+impl fmt::Display for BlockRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockRule::IdentityId(id) => write!(f, "identity id {}", id),
+            BlockRule::PublicKey(key) => write!(f, "public key {}{}", PK_PREFIX, key),
+        }
+    }
+}
+
+/// Blocks people rather than locations: identities are matched by their id and by every key
+/// they list, so moving a banned key to another page, or hiding it as a secondary key, doesn't
+/// get around the block.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubjectBlocklist {
+    #[serde(default)]
+    identity_ids: BTreeSet<String>,
+    /// Hex-encoded public keys
+    #[serde(default)]
+    public_keys: BTreeSet<String>,
+}
+
+impl SubjectBlocklist {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn block_identity_id(&mut self, id: &str) {
+        self.identity_ids.insert(id.to_ascii_lowercase());
+    }
+
+    pub fn block_public_key(&mut self, public_key: &[u8]) {
+        self.public_keys.insert(hex::encode(public_key));
+    }
+
+    pub fn unblock_identity_id(&mut self, id: &str) {
+        self.identity_ids.remove(&id.to_ascii_lowercase());
+    }
+
+    pub fn unblock_public_key(&mut self, public_key: &[u8]) {
+        self.public_keys.remove(&hex::encode(public_key));
+    }
+
+    /// Returns the first rule matching `identity`, checking its id and then each of its keys.
+    pub fn matching_rule(&self, identity: &Identity) -> Option<BlockRule> {
+        if self
+            .identity_ids
+            .contains(&identity.id.to_ascii_lowercase())
+        {
+            return Some(BlockRule::IdentityId(identity.id.clone()));
+        }
+        identity
+            .public_keys
+            .iter()
+            .map(hex::encode)
+            .find(|key| self.public_keys.contains(key))
+            .map(BlockRule::PublicKey)
+    }
+
+    /// # Errors
+    /// Returns [`WebIdentityError::SubjectBlocked`] with the matching rule if `identity` is
+    /// blocked.
+    pub fn check(&self, identity: &Identity) -> Result<(), WebIdentityError> {
+        match self.matching_rule(identity) {
+            Some(rule) => Err(WebIdentityError::SubjectBlocked(rule)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn from_json(json: &str) -> Result<SubjectBlocklist, WebIdentityError> {
+        let mut blocklist: SubjectBlocklist =
+            serde_json::from_str(json).map_err(|e| WebIdentityError::Parse(e.to_string()))?;
+        // Lists edited by hand may use either case
+        blocklist.identity_ids = blocklist
+            .identity_ids
+            .iter()
+            .map(|id| id.to_ascii_lowercase())
+            .collect();
+        blocklist.public_keys = blocklist
+            .public_keys
+            .iter()
+            .map(|key| key.to_ascii_lowercase())
+            .collect();
+        Ok(blocklist)
+    }
+
+    pub fn to_json(&self) -> Result<String, WebIdentityError> {
+        serde_json::to_string_pretty(self).map_err(|e| WebIdentityError::Parse(e.to_string()))
+    }
+}
+
+/// Rejects identities on a [`SubjectBlocklist`] once another resolver parsed them, before any
+/// signature is verified with them.
+///
+/// The blocklist can be replaced at runtime with [`BlocklistResolver::set_blocklist`], and
+/// applies to identities the inner resolver had already cached.
+#[derive(Debug)]
+pub struct BlocklistResolver<R> {
+    inner: R,
+    blocklist: RwLock<Arc<SubjectBlocklist>>,
+}
+
+impl<R> BlocklistResolver<R> {
+    pub fn new(inner: R, blocklist: SubjectBlocklist) -> Self {
+        BlocklistResolver {
+            inner,
+            blocklist: RwLock::new(Arc::new(blocklist)),
+        }
+    }
+
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn blocklist(&self) -> Arc<SubjectBlocklist> {
+        Arc::clone(&self.blocklist.read().unwrap())
+    }
+
+    pub fn set_blocklist(&self, blocklist: SubjectBlocklist) {
+        *self.blocklist.write().unwrap() = Arc::new(blocklist);
+    }
+}
+
+impl<R: IdentityResolver> IdentityResolver for BlocklistResolver<R> {
+    fn resolve_identity(&self, location: &str) -> Result<Arc<Identity>, WebIdentityError> {
+        let identity = self.inner.resolve_identity(location)?;
+        self.blocklist().check(&identity)?;
+        Ok(identity)
+    }
+
+    fn invalidate(&self, location: &str) {
+        self.inner.invalidate(location);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{SharedTestIdentity, StaticFetcher, TestIdentity};
+    use crate::CachingResolver;
+
+    #[test]
+    fn blocks_the_identity_wherever_it_is_served() {
+        let alice = TestIdentity::generate("alice.example");
+        let mut blocklist = SubjectBlocklist::new();
+        blocklist.block_identity_id(&alice.identity.id.to_ascii_uppercase());
+
+        // The same page served from a mirror is the same person
+        let resolver = BlocklistResolver::new(
+            StaticFetcher::new()
+                .with_identity(&alice)
+                .with_page("mirror.example", alice.page.clone()),
+            blocklist,
+        );
+        for location in ["alice.example", "mirror.example"] {
+            match resolver.resolve_identity(location) {
+                Err(WebIdentityError::SubjectBlocked(BlockRule::IdentityId(id))) => {
+                    assert_eq!(id, alice.identity.id)
+                }
+                other => panic!("expected {} to be blocked, got {:?}", location, other),
+            }
+        }
+    }
+
+    #[test]
+    fn blocks_identities_listing_a_blocked_secondary_key() {
+        let shared = SharedTestIdentity::generate("team.example", 3, 2);
+        let mut blocklist = SubjectBlocklist::new();
+        blocklist.block_public_key(shared.signing_keys[2].verifying_key().as_bytes());
+
+        let rule = blocklist.matching_rule(&shared.identity).unwrap();
+        assert_eq!(
+            rule,
+            BlockRule::PublicKey(hex::encode(shared.signing_keys[2].verifying_key()))
+        );
+        assert!(rule.to_string().starts_with("public key ed25519-pub:"));
+
+        blocklist.unblock_public_key(shared.signing_keys[2].verifying_key().as_bytes());
+        blocklist.check(&shared.identity).unwrap();
+        blocklist
+            .check(&TestIdentity::generate("alice.example").identity)
+            .unwrap();
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let alice = TestIdentity::generate("alice.example");
+        let mut blocklist = SubjectBlocklist::new();
+        blocklist.block_identity_id(&alice.identity.id);
+        blocklist.block_public_key(alice.signing_key.verifying_key().as_bytes());
+
+        let json = blocklist.to_json().unwrap();
+        assert_eq!(SubjectBlocklist::from_json(&json).unwrap(), blocklist);
+
+        // Lists edited by hand may use uppercase, and leave out either field
+        let json = format!(
+            r#"{{"public_keys": ["{}"]}}"#,
+            hex::encode(alice.signing_key.verifying_key()).to_ascii_uppercase()
+        );
+        let parsed = SubjectBlocklist::from_json(&json).unwrap();
+        assert!(parsed.check(&alice.identity).is_err());
+
+        assert!(matches!(
+            SubjectBlocklist::from_json(r#"{"identity_ids": "abc"}"#),
+            Err(WebIdentityError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn swaps_the_blocklist_at_runtime() {
+        let alice = TestIdentity::generate("alice.example");
+        let resolver = BlocklistResolver::new(
+            CachingResolver::new(StaticFetcher::new().with_identity(&alice)),
+            SubjectBlocklist::new(),
+        );
+        resolver.resolve_identity("alice.example").unwrap();
+
+        // The block applies to the identity the inner resolver already cached
+        let mut blocklist = SubjectBlocklist::new();
+        blocklist.block_identity_id(&alice.identity.id);
+        resolver.set_blocklist(blocklist.clone());
+        assert_eq!(*resolver.blocklist(), blocklist);
+        assert!(matches!(
+            resolver.resolve_identity("alice.example"),
+            Err(WebIdentityError::SubjectBlocked(_))
+        ));
+
+        resolver.set_blocklist(SubjectBlocklist::new());
+        resolver.resolve_identity("alice.example").unwrap();
+    }
+}
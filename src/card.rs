@@ -0,0 +1,74 @@
+use super::identity::Identity;
+use serde::{Deserialize, Serialize};
+
+/// The public summary of an identity, made by [`Identity::card`], for directories and social
+/// apps to embed.
+///
+/// It holds the fields worth displaying, and identifies the key by its fingerprint (the id)
+/// rather than its bytes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdentityCard {
+    pub id: String,
+    pub handle: String,
+    pub display_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub location: String,
+}
+
+impl Identity {
+    /// Summarizes the identity for display, see [`IdentityCard`].
+    pub fn card(&self) -> IdentityCard {
+        IdentityCard {
+            id: self.id.clone(),
+            handle: self.handle(),
+            display_name: self.display_name.clone(),
+            avatar: self.avatar.as_ref().map(|url| url.to_string()),
+            description: self.description.clone(),
+            location: self.location.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestIdentity;
+    use url::Url;
+
+    #[test]
+    fn round_trips_through_json() {
+        let mut amy = TestIdentity::generate("carroted.org/amy").identity;
+        amy.avatar = Some(Url::parse("https://carroted.org/amy.png").unwrap());
+        amy.description = Some("Writes about carrots.".into());
+
+        let card = amy.card();
+        assert_eq!(card.id, amy.id);
+        assert_eq!(card.handle, "@amy@carroted.org");
+        assert_eq!(card.location, "carroted.org/amy");
+
+        let json = serde_json::to_string(&card).unwrap();
+        assert_eq!(serde_json::from_str::<IdentityCard>(&json).unwrap(), card);
+    }
+
+    #[test]
+    fn leaves_out_missing_fields() {
+        let mut amy = TestIdentity::generate("carroted.org").identity;
+        amy.avatar = None;
+        amy.description = None;
+
+        let json = serde_json::to_value(amy.card()).unwrap();
+        assert_eq!(
+            json.as_object().unwrap().keys().collect::<Vec<_>>(),
+            ["display_name", "handle", "id", "location"]
+        );
+        // Keys are identified by their fingerprint only
+        assert!(!json.to_string().contains(&hex::encode(amy.public_key)));
+
+        let card: IdentityCard = serde_json::from_value(json).unwrap();
+        assert_eq!(card.avatar, None);
+        assert!(serde_json::from_str::<IdentityCard>(r#"{"id": "x"}"#).is_err());
+    }
+}
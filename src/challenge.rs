@@ -0,0 +1,159 @@
+use super::error::{SignatureError, WebIdentityError};
+use super::identity::Identity;
+use super::sign::{verify_identity_signature, RequestSigner};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long a challenge from [`generate_challenge`] can be answered.
+pub const DEFAULT_CHALLENGE_TTL: Duration = Duration::from_secs(300);
+
+/// A random challenge a server issues for a client to sign, proving it controls a key
+/// listed on its identity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Challenge {
+    pub bytes: [u8; 32],
+    /// Seconds since the UNIX epoch after which the challenge is no longer accepted
+    pub expires_at: u64,
+}
+
+impl Challenge {
+    /// The message the client signs in response to this challenge.
+    ///
+    /// It is prefixed so a signed challenge can never be mistaken for a signed request.
+    pub fn signing_message(&self) -> Vec<u8> {
+        format!(
+            "WebIdentity-Challenge\n{}\n{}",
+            hex::encode(self.bytes),
+            self.expires_at
+        )
+        .into_bytes()
+    }
+
+    /// Signs the challenge on the client side.
+    pub fn sign(&self, signer: &impl RequestSigner) -> Result<[u8; 64], WebIdentityError> {
+        signer.sign_message(&self.signing_message())
+    }
+}
+
+/// Generates a challenge that expires after [`DEFAULT_CHALLENGE_TTL`].
+pub fn generate_challenge() -> Challenge {
+    generate_challenge_with_ttl(DEFAULT_CHALLENGE_TTL)
+}
+
+/// Generates a challenge that expires after `ttl`.
+pub fn generate_challenge_with_ttl(ttl: Duration) -> Challenge {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+
+    Challenge {
+        bytes,
+        expires_at: now().saturating_add(ttl.as_secs()),
+    }
+}
+
+/// Verifies a client's signature over a challenge against the keys listed on the identity.
+///
+/// The server is responsible for only accepting each challenge once. A challenge has a single
+/// signer, so it can't authenticate an identity whose threshold is above one.
+///
+/// # Errors
+/// Returns `Err` if the challenge expired, the signature is incorrect, or the identity requires
+/// several signers.
+pub fn verify_challenge_response(
+    identity: &Identity,
+    challenge: &Challenge,
+    signature: &[u8],
+) -> Result<(), WebIdentityError> {
+    if now() > challenge.expires_at {
+        return Err(SignatureError::ChallengeExpired.into());
+    }
+
+    verify_identity_signature(identity, &challenge.signing_message(), signature)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{SharedTestIdentity, TestIdentity};
+
+    #[test]
+    fn verifies_signed_challenges() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let challenge = generate_challenge();
+        assert_ne!(challenge.bytes, generate_challenge().bytes);
+        assert!(challenge.expires_at >= now() + DEFAULT_CHALLENGE_TTL.as_secs() - 1);
+
+        let signature = challenge.sign(&alice.signing_key).unwrap();
+        verify_challenge_response(&alice.identity, &challenge, &signature).unwrap();
+
+        // Long lifetimes saturate instead of overflowing
+        let challenge = generate_challenge_with_ttl(Duration::MAX);
+        assert_eq!(challenge.expires_at, u64::MAX);
+    }
+
+    #[test]
+    fn rejects_expired_or_mismatched_responses() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let bob = TestIdentity::generate("bob.example.com");
+        let challenge = generate_challenge();
+        let signature = challenge.sign(&alice.signing_key).unwrap();
+
+        let expired = Challenge {
+            expires_at: now() - 1,
+            ..challenge.clone()
+        };
+        let expired_signature = expired.sign(&alice.signing_key).unwrap();
+        assert!(matches!(
+            verify_challenge_response(&alice.identity, &expired, &expired_signature),
+            Err(WebIdentityError::Signature(
+                SignatureError::ChallengeExpired
+            ))
+        ));
+
+        // Another identity, another challenge, the unprefixed bytes or a truncated signature
+        let other = generate_challenge();
+        let bare_signature = alice.signing_key.sign_message(&challenge.bytes).unwrap();
+        for (identity, challenge, signature) in [
+            (&bob.identity, &challenge, &signature[..]),
+            (&alice.identity, &other, &signature[..]),
+            (&alice.identity, &challenge, &bare_signature[..]),
+            (&alice.identity, &challenge, &signature[..32]),
+        ] {
+            assert!(matches!(
+                verify_challenge_response(identity, challenge, signature),
+                Err(WebIdentityError::Signature(
+                    SignatureError::SignatureMismatch
+                ))
+            ));
+        }
+    }
+
+    #[test]
+    fn one_signer_does_not_meet_a_threshold() {
+        let challenge = generate_challenge();
+        let team = SharedTestIdentity::generate("team.example.com", 2, 2);
+        let signature = challenge.sign(&team.signing_keys[0]).unwrap();
+        assert!(matches!(
+            verify_challenge_response(&team.identity, &challenge, &signature),
+            Err(WebIdentityError::Signature(
+                SignatureError::ThresholdNotMet {
+                    valid: 1,
+                    required: 2
+                }
+            ))
+        ));
+
+        // Any listed key answers for an identity without a threshold
+        let pair = SharedTestIdentity::generate("pair.example.com", 2, 1);
+        let signature = challenge.sign(&pair.signing_keys[1]).unwrap();
+        verify_challenge_response(&pair.identity, &challenge, &signature).unwrap();
+    }
+}
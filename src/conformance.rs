@@ -0,0 +1,410 @@
+//! Interoperability test vectors for the canonical string and signatures.
+//!
+//! The vectors shipped in `vectors/canonical-v1.json` are generated with
+//! [`generate_vectors`]. Other implementations can check their output against this one by
+//! producing the same JSON document and passing it to [`run`], e.g. through the
+//! `conformance` example: `cargo run --example conformance -- vectors.json`.
+
+use super::digest::RequestDigest;
+use super::sign::build_canonical_string;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ed25519_dalek::{Signer, SigningKey};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// The version of the test vector format, and of the canonical string it describes.
+pub const VECTORS_VERSION: u32 = 1;
+
+/// A document of test vectors.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Vectors {
+    pub version: u32,
+    pub vectors: Vec<Vector>,
+}
+
+/// The inputs of a signed request, and the outputs every implementation must produce for it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Vector {
+    pub name: String,
+    pub method: String,
+    pub host: String,
+    pub path: String,
+    pub body_hex: String,
+    pub location: String,
+    pub timestamp: String,
+    /// The signed optional headers, as `[name, value]` pairs in canonical string order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extensions: Vec<(String, String)>,
+    /// The hex-encoded Ed25519 seed of the signing key
+    pub seed_hex: String,
+    pub canonical_string: String,
+    pub body_sha256: String,
+    pub signature: String,
+}
+
+/// A field of a vector that doesn't match this implementation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub vector: String,
+    pub field: &'static str,
+    pub expected: String,
+    pub actual: String,
+}
+
+/// The result of [`run`].
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub passed: usize,
+    pub mismatches: Vec<Mismatch>,
+    /// Vectors that couldn't be checked, with the reason
+    pub errors: Vec<(String, String)>,
+}
+
+impl Report {
+    pub fn is_success(&self) -> bool {
+        self.mismatches.is_empty() && self.errors.is_empty()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for mismatch in &self.mismatches {
+            writeln!(
+                f,
+                "{}: {} differs\n  expected: {:?}\n  actual:   {:?}",
+                mismatch.vector, mismatch.field, mismatch.expected, mismatch.actual
+            )?;
+        }
+        for (vector, error) in &self.errors {
+            writeln!(f, "{}: {}", vector, error)?;
+        }
+        write!(
+            f,
+            "{} passed, {} failed",
+            self.passed,
+            self.mismatches.len() + self.errors.len()
+        )
+    }
+}
+
+/// Checks every vector in `vectors_json` against this implementation.
+///
+/// # Errors
+/// Returns `Err` if the document can't be parsed or is from an unsupported version.
+pub fn run(vectors_json: &str) -> Result<Report, String> {
+    let vectors: Vectors = serde_json::from_str(vectors_json).map_err(|e| e.to_string())?;
+    if vectors.version != VECTORS_VERSION {
+        return Err(format!("Unsupported vectors version {}.", vectors.version));
+    }
+
+    let mut report = Report::default();
+    for vector in &vectors.vectors {
+        let expected = match compute(
+            &vector.name,
+            &vector.method,
+            &vector.host,
+            &vector.path,
+            &vector.body_hex,
+            &vector.location,
+            &vector.timestamp,
+            &vector.extensions,
+            &vector.seed_hex,
+        ) {
+            Ok(expected) => expected,
+            Err(error) => {
+                report.errors.push((vector.name.clone(), error));
+                continue;
+            }
+        };
+
+        let before = report.mismatches.len();
+        for (field, expected, actual) in [
+            (
+                "canonical_string",
+                &expected.canonical_string,
+                &vector.canonical_string,
+            ),
+            ("body_sha256", &expected.body_sha256, &vector.body_sha256),
+            ("signature", &expected.signature, &vector.signature),
+        ] {
+            // Hex may use either case, the canonical string must match exactly
+            let matches = if field == "canonical_string" {
+                expected == actual
+            } else {
+                expected.eq_ignore_ascii_case(actual)
+            };
+            if !matches {
+                report.mismatches.push(Mismatch {
+                    vector: vector.name.clone(),
+                    field,
+                    expected: expected.clone(),
+                    actual: actual.clone(),
+                });
+            }
+        }
+        if report.mismatches.len() == before {
+            report.passed += 1;
+        }
+    }
+
+    Ok(report)
+}
+
+/// Generates the test vectors shipped with this crate.
+pub fn generate_vectors() -> String {
+    let seed = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+    let cases: &[(&str, &str, &str, &str, &[u8])] = &[
+        (
+            "basic",
+            "POST",
+            "example.com",
+            "/v1/messages",
+            br#"{"message":"Hello, world!"}"#,
+        ),
+        ("empty-body", "GET", "example.com", "/v1/messages", b""),
+        ("root-path", "GET", "example.com", "/", b""),
+        ("trailing-slash", "GET", "example.com", "/v1/messages/", b""),
+        (
+            "trailing-slashes",
+            "GET",
+            "example.com",
+            "/v1/messages///",
+            b"",
+        ),
+        (
+            "lowercase-method",
+            "post",
+            "example.com",
+            "/v1/messages",
+            b"hello",
+        ),
+        (
+            "mixed-case-method",
+            "Delete",
+            "example.com",
+            "/v1/messages/1",
+            b"",
+        ),
+        (
+            "query",
+            "GET",
+            "example.com",
+            "/search?q=web+identity&page=2",
+            b"",
+        ),
+        (
+            "unicode-path",
+            "GET",
+            "example.com",
+            "/users/amélie/✨",
+            b"",
+        ),
+        (
+            "encoded-path",
+            "GET",
+            "example.com",
+            "/users/am%C3%A9lie",
+            b"",
+        ),
+        ("port", "PUT", "example.com:8443", "/v1/profile", b"{}"),
+        (
+            "binary-body",
+            "POST",
+            "example.com",
+            "/upload",
+            &[0, 159, 146, 150, 255],
+        ),
+    ];
+
+    let body = br#"{"message":"Hello, world!"}"#;
+    let content_digest = format!(
+        "sha-256=:{}:",
+        BASE64.encode(RequestDigest::of(body).as_bytes())
+    );
+    // Requests with signed optional headers, which are covered as extra lines
+    let extension_cases: &[(&str, &[(&str, &str)])] = &[
+        ("algorithm", &[("WebIdentity-Algorithm", "ed25519")]),
+        (
+            "content-digest",
+            &[
+                ("WebIdentity-Algorithm", "ed25519"),
+                ("WebIdentity-Digest", "content-digest"),
+                ("Content-Digest", &content_digest),
+            ],
+        ),
+        (
+            "covered-headers",
+            &[
+                ("WebIdentity-Algorithm", "ed25519"),
+                ("WebIdentity-Headers", "content-type accept-language"),
+                ("content-type", "application/json"),
+                ("accept-language", "en-GB, fr;q=0.8"),
+            ],
+        ),
+        (
+            "trailers",
+            &[
+                ("WebIdentity-Algorithm", "ed25519"),
+                ("WebIdentity-Headers", "content-type"),
+                ("WebIdentity-Trailers", "x-upload-crc32c"),
+                ("content-type", "application/json"),
+                ("x-upload-crc32c", "yZRlqg=="),
+            ],
+        ),
+        (
+            "all-extensions",
+            &[
+                ("WebIdentity-Algorithm", "ed25519"),
+                ("WebIdentity-Digest", "content-digest"),
+                ("WebIdentity-Headers", "content-type"),
+                ("WebIdentity-Expires", "1700000300"),
+                ("Content-Digest", &content_digest),
+                ("content-type", "application/json"),
+            ],
+        ),
+    ];
+
+    let location = "amy.carroted.org";
+    let timestamp = "1700000000";
+    let plain = cases.iter().map(|(name, method, host, path, body)| {
+        compute(
+            name,
+            method,
+            host,
+            path,
+            &hex::encode(body),
+            location,
+            timestamp,
+            &[],
+            seed,
+        )
+    });
+    let extended = extension_cases.iter().map(|(name, extensions)| {
+        let extensions: Vec<(String, String)> = extensions
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        compute(
+            name,
+            "POST",
+            "example.com",
+            "/v1/messages",
+            &hex::encode(body),
+            location,
+            timestamp,
+            &extensions,
+            seed,
+        )
+    });
+    let vectors = plain
+        .chain(extended)
+        .map(|vector| vector.expect("The built-in vectors are valid"))
+        .collect();
+
+    serde_json::to_string_pretty(&Vectors {
+        version: VECTORS_VERSION,
+        vectors,
+    })
+    .unwrap()
+}
+
+#[allow(clippy::too_many_arguments)]
+fn compute(
+    name: &str,
+    method: &str,
+    host: &str,
+    path: &str,
+    body_hex: &str,
+    location: &str,
+    timestamp: &str,
+    extensions: &[(String, String)],
+    seed_hex: &str,
+) -> Result<Vector, String> {
+    let body = hex::decode(body_hex).map_err(|_| "Invalid body_hex.".to_string())?;
+    let mut seed = [0u8; 32];
+    hex::decode_to_slice(seed_hex, &mut seed).map_err(|_| "Invalid seed_hex.".to_string())?;
+
+    let body_digest = RequestDigest::of(&body);
+    let canonical_string = build_canonical_string(
+        method,
+        host,
+        path,
+        body_digest.as_bytes(),
+        location,
+        timestamp,
+        &extensions
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect::<Vec<_>>(),
+    );
+    let signature = SigningKey::from_bytes(&seed).sign(canonical_string.as_bytes());
+
+    Ok(Vector {
+        name: name.to_string(),
+        method: method.to_string(),
+        host: host.to_string(),
+        path: path.to_string(),
+        body_hex: body_hex.to_string(),
+        location: location.to_string(),
+        timestamp: timestamp.to_string(),
+        extensions: extensions.to_vec(),
+        seed_hex: seed_hex.to_string(),
+        canonical_string,
+        body_sha256: hex::encode(body_digest.as_bytes()),
+        signature: hex::encode(signature.to_bytes()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHIPPED: &str = include_str!("../vectors/canonical-v1.json");
+
+    #[test]
+    fn shipped_vectors_pass() {
+        let report = run(SHIPPED).unwrap();
+        assert!(report.is_success(), "{}", report);
+        let vectors: Vectors = serde_json::from_str(SHIPPED).unwrap();
+        assert_eq!(report.passed, vectors.vectors.len());
+    }
+
+    #[test]
+    fn shipped_vectors_are_up_to_date() {
+        assert_eq!(SHIPPED.trim_end(), generate_vectors());
+    }
+
+    #[test]
+    fn covers_extension_lines() {
+        let vectors: Vectors = serde_json::from_str(SHIPPED).unwrap();
+        let vector = vectors
+            .vectors
+            .iter()
+            .find(|vector| vector.name == "all-extensions")
+            .unwrap();
+        let lines: Vec<&str> = vector.canonical_string.lines().skip(6).collect();
+        assert_eq!(
+            lines,
+            [
+                "webidentity-algorithm:ed25519",
+                "webidentity-digest:content-digest",
+                "webidentity-headers:content-type",
+                "webidentity-expires:1700000300",
+                "content-digest:sha-256=:PO3kuCDUMn3NrbwOJuoZRNwO9flziZXGyuImTX/HnVw=:",
+                "content-type:application/json",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_mismatches() {
+        let mut vectors: Vectors = serde_json::from_str(SHIPPED).unwrap();
+        vectors.vectors[0].signature = "00".repeat(64);
+        vectors.vectors[1].seed_hex = "zz".to_string();
+        let report = run(&serde_json::to_string(&vectors).unwrap()).unwrap();
+        assert!(!report.is_success());
+        assert_eq!(report.mismatches.len(), 1);
+        assert_eq!(report.mismatches[0].field, "signature");
+        assert_eq!(report.errors.len(), 1);
+    }
+}
@@ -0,0 +1,340 @@
+//! CBOR Web Token (RFC 8392) encoding of identities, for CBOR-native systems like CoAP.
+//!
+//! The claims set is a CBOR map wrapped in the CWT tag (61):
+//! - `1` (`iss`): the location
+//! - `8` (`cnf`): `{1: COSE_Key}` with the primary key as an OKP Ed25519 key
+//!   (`{1: 1, 3: -8, -1: 6, -2: <key bytes>}`)
+//! - `"wid-keys"`: every key as byte strings, when there are several
+//! - `"wid-threshold"`, `"wid-name"`, `"wid-avatar"`, `"wid-description"`,
+//!   `"wid-descriptions"` (language to description), `"wid-backup"` (backup locations) and
+//!   `"wid-version"`, when set
+//!
+//! The token is not signed: like the HTML page, it describes the identity, and its keys must
+//! be checked against the page before they are trusted.
+
+use super::error::WebIdentityError;
+use super::identity::{identity_id, location_from_url, Identity, IdentitySource};
+use super::public_key::PublicKey;
+use super::resolve::resolve_location_url;
+use ciborium::value::{Integer, Value};
+use std::collections::HashMap;
+
+/// The CBOR tag of a CWT.
+const CWT_TAG: u64 = 61;
+
+const ISS: i64 = 1;
+const CNF: i64 = 8;
+const COSE_KEY: i64 = 1;
+
+impl Identity {
+    /// Encodes the identity as an unsigned CWT claims set, see the [module](self) for the layout.
+    pub fn to_cwt(&self) -> Vec<u8> {
+        let cose_key = Value::Map(vec![
+            (int(1), int(1)),
+            (int(3), int(-8)),
+            (int(-1), int(6)),
+            (int(-2), Value::Bytes(self.public_key.to_vec())),
+        ]);
+        let mut claims = vec![
+            (int(ISS), Value::Text(self.location.clone())),
+            (int(CNF), Value::Map(vec![(int(COSE_KEY), cose_key)])),
+        ];
+
+        if self.public_keys.len() > 1 {
+            claims.push((
+                text("wid-keys"),
+                Value::Array(
+                    self.public_keys
+                        .iter()
+                        .map(|key| Value::Bytes(key.to_vec()))
+                        .collect(),
+                ),
+            ));
+        }
+        if let Some(threshold) = self.threshold {
+            claims.push((text("wid-threshold"), int(threshold.into())));
+        }
+        claims.push((text("wid-name"), text(&self.display_name)));
+        if let Some(avatar) = &self.avatar {
+            claims.push((text("wid-avatar"), text(avatar.as_str())));
+        }
+        if let Some(description) = &self.description {
+            claims.push((text("wid-description"), text(description)));
+        }
+        if !self.descriptions.is_empty() {
+            let mut descriptions: Vec<_> = self.descriptions.iter().collect();
+            descriptions.sort();
+            claims.push((
+                text("wid-descriptions"),
+                Value::Map(
+                    descriptions
+                        .into_iter()
+                        .map(|(lang, description)| (text(lang), text(description)))
+                        .collect(),
+                ),
+            ));
+        }
+        if !self.backup_locations.is_empty() {
+            claims.push((
+                text("wid-backup"),
+                Value::Array(self.backup_locations.iter().map(|b| text(b)).collect()),
+            ));
+        }
+        if let Some(version) = self.spec_version {
+            claims.push((text("wid-version"), int(version.into())));
+        }
+
+        let mut bytes = Vec::new();
+        ciborium::into_writer(
+            &Value::Tag(CWT_TAG, Box::new(Value::Map(claims))),
+            &mut bytes,
+        )
+        .expect("Writing to a Vec can't fail");
+        bytes
+    }
+
+    /// Decodes an identity from a CWT made by [`Identity::to_cwt`]. The CWT tag is optional.
+    ///
+    /// # Errors
+    /// Returns [`WebIdentityError::InvalidCwt`] if the token is malformed or has no valid key.
+    pub fn from_cwt(bytes: &[u8]) -> Result<Identity, WebIdentityError> {
+        let invalid = |reason: &str| WebIdentityError::InvalidCwt(reason.to_string());
+
+        let value: Value = ciborium::from_reader(bytes)
+            .map_err(|e| WebIdentityError::InvalidCwt(e.to_string()))?;
+        let value = match value {
+            Value::Tag(CWT_TAG, value) => *value,
+            value => value,
+        };
+        let Value::Map(claims) = value else {
+            return Err(invalid("The claims set is not a map."));
+        };
+        let claim = |key: &Value| claims.iter().find(|(k, _)| k == key).map(|(_, v)| v);
+        let text_claim = |name: &str| claim(&text(name)).and_then(Value::as_text);
+
+        let location = claim(&int(ISS))
+            .and_then(Value::as_text)
+            .ok_or_else(|| invalid("Missing 'iss'."))?;
+        let location_url = resolve_location_url(location)?;
+
+        let key_bytes = claim(&int(CNF))
+            .and_then(Value::as_map)
+            .and_then(|cnf| find(cnf, &int(COSE_KEY)))
+            .and_then(Value::as_map)
+            .and_then(|cose_key| find(cose_key, &int(-2)))
+            .and_then(Value::as_bytes)
+            .ok_or_else(|| invalid("Missing the 'cnf' key."))?;
+        let public_key = PublicKey::from_bytes(key_bytes)?;
+
+        let public_keys = match claim(&text("wid-keys")) {
+            Some(keys) => keys
+                .as_array()
+                .ok_or_else(|| invalid("'wid-keys' is not an array."))?
+                .iter()
+                .map(|key| {
+                    let key = key.as_bytes().ok_or_else(|| invalid("Invalid key."))?;
+                    PublicKey::from_bytes(key)
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            None => vec![public_key],
+        };
+        if public_keys.first() != Some(&public_key) {
+            return Err(invalid("'wid-keys' doesn't start with the 'cnf' key."));
+        }
+
+        let threshold = match claim(&text("wid-threshold")) {
+            Some(threshold) => Some(
+                threshold
+                    .as_integer()
+                    .and_then(|t| u8::try_from(t).ok())
+                    .filter(|t| *t >= 1 && *t as usize <= public_keys.len())
+                    .ok_or_else(|| invalid("Invalid 'wid-threshold'."))?,
+            ),
+            None => None,
+        };
+
+        let descriptions = claim(&text("wid-descriptions"))
+            .and_then(Value::as_map)
+            .map(|descriptions| {
+                descriptions
+                    .iter()
+                    .filter_map(|(lang, description)| {
+                        Some((
+                            lang.as_text()?.to_ascii_lowercase(),
+                            description.as_text()?.to_string(),
+                        ))
+                    })
+                    .collect()
+            })
+            .unwrap_or_else(HashMap::new);
+        let backup_locations = claim(&text("wid-backup"))
+            .and_then(Value::as_array)
+            .map(|backups| {
+                backups
+                    .iter()
+                    .filter_map(Value::as_text)
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let spec_version = claim(&text("wid-version"))
+            .and_then(Value::as_integer)
+            .and_then(|v| u32::try_from(v).ok());
+
+        let location = location_from_url(&location_url);
+        Ok(Identity {
+            id: identity_id(&public_key),
+            public_key,
+            public_keys,
+            threshold,
+            display_name: text_claim("wid-name")
+                .filter(|name| !name.is_empty())
+                .map_or_else(|| location.clone(), str::to_string),
+            avatar: text_claim("wid-avatar").and_then(|avatar| location_url.join(avatar).ok()),
+            description: text_claim("wid-description").map(str::to_string),
+            descriptions,
+            location_url,
+            location,
+            backup_locations,
+            spec_version,
+            verified_links: Vec::new(),
+            source: IdentitySource::Cwt,
+            fetched_at: None,
+            certificate_fingerprint: None,
+        })
+    }
+}
+
+fn find<'a>(map: &'a [(Value, Value)], key: &Value) -> Option<&'a Value> {
+    map.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+}
+
+fn int(value: i64) -> Value {
+    Value::Integer(Integer::from(value))
+}
+
+fn text(value: &str) -> Value {
+    Value::Text(value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::get_identity;
+    use crate::testing::{SharedTestIdentity, TestIdentity};
+
+    /// Checks that `decoded` is the same identity as `parsed`, apart from where it was read from.
+    fn assert_same_identity(decoded: &Identity, parsed: &Identity) {
+        assert_eq!(decoded.id, parsed.id);
+        assert_eq!(decoded.public_key, parsed.public_key);
+        assert_eq!(decoded.public_keys, parsed.public_keys);
+        assert_eq!(decoded.threshold, parsed.threshold);
+        assert_eq!(decoded.display_name, parsed.display_name);
+        assert_eq!(decoded.avatar, parsed.avatar);
+        assert_eq!(decoded.description, parsed.description);
+        assert_eq!(decoded.descriptions, parsed.descriptions);
+        assert_eq!(decoded.location_url, parsed.location_url);
+        assert_eq!(decoded.location, parsed.location);
+        assert_eq!(decoded.backup_locations, parsed.backup_locations);
+        assert_eq!(decoded.spec_version, parsed.spec_version);
+        assert_eq!(decoded.source, IdentitySource::Cwt);
+    }
+
+    #[test]
+    fn round_trips_the_html_identity() {
+        let alice = TestIdentity::generate("alice.example.com/me");
+        let page = alice.page.replace(
+            "</head>",
+            r#"    <meta name="identity:avatar" content="/avatar.png">
+    <meta name="identity:description:fr" content="Une identité de test.">
+    <meta name="identity:backup-location" content="mirror.example.net/alice">
+</head>"#,
+        );
+        let parsed = get_identity(&alice.identity.location_url, &page).unwrap();
+        assert!(parsed.avatar.is_some() && !parsed.descriptions.is_empty());
+
+        let cwt = parsed.to_cwt();
+        // Tag 61, then a map
+        assert_eq!(&cwt[..2], &[0xd8, 61]);
+        assert_same_identity(&Identity::from_cwt(&cwt).unwrap(), &parsed);
+        assert_eq!(Identity::from_cwt(&cwt).unwrap().to_cwt(), cwt);
+
+        // The tag is optional
+        assert_same_identity(&Identity::from_cwt(&cwt[2..]).unwrap(), &parsed);
+    }
+
+    #[test]
+    fn round_trips_shared_identities() {
+        let team = SharedTestIdentity::generate("team.example.com", 3, 2);
+        let decoded = Identity::from_cwt(&team.identity.to_cwt()).unwrap();
+        assert_same_identity(&decoded, &team.identity);
+        assert_eq!(decoded.public_keys.len(), 3);
+        assert_eq!(decoded.threshold, Some(2));
+    }
+
+    fn claims(identity: &Identity) -> Vec<(Value, Value)> {
+        match ciborium::from_reader(identity.to_cwt().as_slice()).unwrap() {
+            Value::Tag(CWT_TAG, claims) => claims.into_map().unwrap(),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    fn encode(claims: Vec<(Value, Value)>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&Value::Map(claims), &mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn rejects_malformed_tokens() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let team = SharedTestIdentity::generate("team.example.com", 2, 2);
+        let without = |claims: Vec<(Value, Value)>, key: Value| {
+            claims.into_iter().filter(|(k, _)| *k != key).collect()
+        };
+
+        let mut other_first = claims(&team.identity);
+        for (key, value) in &mut other_first {
+            if *key == text("wid-keys") {
+                let keys = value.as_array_mut().unwrap();
+                keys.swap(0, 1);
+            }
+        }
+        let mut bad_threshold = claims(&team.identity);
+        for (key, value) in &mut bad_threshold {
+            if *key == text("wid-threshold") {
+                *value = int(3);
+            }
+        }
+
+        for (bytes, reason) in [
+            (b"\xff".to_vec(), None),
+            (encode(Vec::new()), Some("Missing 'iss'.")),
+            (
+                encode(without(claims(&alice.identity), int(CNF))),
+                Some("Missing the 'cnf' key."),
+            ),
+            (
+                encode(other_first),
+                Some("'wid-keys' doesn't start with the 'cnf' key."),
+            ),
+            (encode(bad_threshold), Some("Invalid 'wid-threshold'.")),
+        ] {
+            match Identity::from_cwt(&bytes) {
+                Err(WebIdentityError::InvalidCwt(error)) => {
+                    if let Some(reason) = reason {
+                        assert_eq!(error, reason);
+                    }
+                }
+                other => panic!("{:?}", other.map(|identity| identity.location)),
+            }
+        }
+
+        let mut array = Vec::new();
+        ciborium::into_writer(&Value::Array(Vec::new()), &mut array).unwrap();
+        assert!(matches!(
+            Identity::from_cwt(&array),
+            Err(WebIdentityError::InvalidCwt(_))
+        ));
+    }
+}
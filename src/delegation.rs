@@ -0,0 +1,289 @@
+use super::error::{SignatureError, WebIdentityError};
+use super::identity::PK_PREFIX;
+use super::sign::{strip_hex_prefix, RequestSigner};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+/// Authority delegated by an identity's key to a subkey (e.g. a per-device key) until it
+/// expires, so the subkey can sign requests without being listed on the identity page.
+///
+/// It is sent in the `WebIdentity-Delegation` header as
+/// `ed25519-pub:<subkey hex>;<expires_at>;<signature hex>`, where the signature is made by a
+/// key listed on the identity over `WebIdentity-Delegation\n<location>\n<subkey hex>\n<expires_at>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delegation {
+    pub subkey: VerifyingKey,
+    /// Seconds since the UNIX epoch after which the delegation is no longer accepted
+    pub expires_at: u64,
+    signature: [u8; 64],
+}
+
+impl Delegation {
+    /// Delegates to `subkey` until `expires_at`, signed by the identity at `location`.
+    pub fn issue(
+        location: &str,
+        subkey: VerifyingKey,
+        expires_at: u64,
+        identity_signer: &impl RequestSigner,
+    ) -> Result<Delegation, WebIdentityError> {
+        let signature =
+            identity_signer.sign_message(&signing_message(location, &subkey, expires_at))?;
+        Ok(Delegation {
+            subkey,
+            expires_at,
+            signature,
+        })
+    }
+
+    /// Parses the value of a `WebIdentity-Delegation` header.
+    ///
+    /// # Errors
+    /// Returns [`SignatureError::InvalidDelegation`] if the value is malformed.
+    pub fn parse(header_value: &str) -> Result<Delegation, SignatureError> {
+        let invalid = |reason: &str| SignatureError::InvalidDelegation(reason.to_string());
+
+        let mut parts = header_value.trim().split(';').map(str::trim);
+        let (Some(subkey), Some(expires_at), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid("Expected a subkey, an expiry and a signature."));
+        };
+
+        let subkey = subkey
+            .strip_prefix(PK_PREFIX)
+            .ok_or_else(|| invalid("Unsupported subkey type."))?;
+        let mut subkey_bytes = [0u8; 32];
+        hex::decode_to_slice(strip_hex_prefix(subkey), &mut subkey_bytes)
+            .map_err(|_| invalid("Invalid subkey."))?;
+        let subkey =
+            VerifyingKey::from_bytes(&subkey_bytes).map_err(|_| invalid("Invalid subkey."))?;
+
+        let expires_at = expires_at
+            .parse::<u64>()
+            .map_err(|_| invalid("Invalid expiry."))?;
+
+        let mut signature_bytes = [0u8; 64];
+        hex::decode_to_slice(strip_hex_prefix(signature), &mut signature_bytes)
+            .map_err(|_| invalid("Invalid signature."))?;
+
+        Ok(Delegation {
+            subkey,
+            expires_at,
+            signature: signature_bytes,
+        })
+    }
+
+    /// The value of the `WebIdentity-Delegation` header, to pass to
+    /// [`SignOptions::with_delegation`](crate::SignOptions::with_delegation).
+    pub fn to_header_value(&self) -> String {
+        format!(
+            "{}{};{};{}",
+            PK_PREFIX,
+            hex::encode(self.subkey.as_bytes()),
+            self.expires_at,
+            hex::encode(self.signature)
+        )
+    }
+
+    /// Checks the delegation was made by `identity_key` for `location` and hasn't expired
+    /// at `now` (seconds since the UNIX epoch).
+    ///
+    /// # Errors
+    /// Returns [`SignatureError::DelegationExpired`] or
+    /// [`SignatureError::DelegationSignatureMismatch`].
+    pub fn verify(
+        &self,
+        location: &str,
+        identity_key: &VerifyingKey,
+        now: u64,
+    ) -> Result<(), SignatureError> {
+        if now > self.expires_at {
+            return Err(SignatureError::DelegationExpired);
+        }
+        self.verify_signed_by(location, identity_key)
+    }
+
+    pub(crate) fn verify_signed_by(
+        &self,
+        location: &str,
+        identity_key: &VerifyingKey,
+    ) -> Result<(), SignatureError> {
+        let message = signing_message(location, &self.subkey, self.expires_at);
+        identity_key
+            .verify(&message, &Signature::from_bytes(&self.signature))
+            .map_err(|_| SignatureError::DelegationSignatureMismatch)
+    }
+}
+
+fn signing_message(location: &str, subkey: &VerifyingKey, expires_at: u64) -> Vec<u8> {
+    format!(
+        "WebIdentity-Delegation\n{}\n{}\n{}",
+        location,
+        hex::encode(subkey.as_bytes()),
+        expires_at
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::RequestDigest;
+    use crate::sign::{create_signed_headers_with_options, verify_request_with_key, SignOptions};
+    use crate::VerifyOptions;
+    use ed25519_dalek::SigningKey;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    const LOCATION: &str = "amy.carroted.org";
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn keys() -> (SigningKey, SigningKey) {
+        (
+            SigningKey::from_bytes(&[1; 32]),
+            SigningKey::from_bytes(&[2; 32]),
+        )
+    }
+
+    fn verify_signed_by(
+        subkey: &SigningKey,
+        delegation: &Delegation,
+        identity_key: &VerifyingKey,
+    ) -> Result<(), WebIdentityError> {
+        let headers = create_signed_headers_with_options(
+            LOCATION,
+            "POST",
+            "example.com",
+            "/notes",
+            b"hello",
+            subkey,
+            &SignOptions::default().with_delegation(delegation),
+        )?;
+        verify_request_with_key(
+            "POST",
+            "example.com",
+            "/notes",
+            &RequestDigest::of(b"hello"),
+            &headers,
+            identity_key,
+            &VerifyOptions::new(Duration::from_secs(300)),
+        )
+    }
+
+    #[test]
+    fn accepts_requests_signed_by_a_delegated_subkey() {
+        let (identity_key, subkey) = keys();
+        let delegation =
+            Delegation::issue(LOCATION, subkey.verifying_key(), now() + 60, &identity_key).unwrap();
+
+        let parsed = Delegation::parse(&delegation.to_header_value()).unwrap();
+        assert_eq!(parsed, delegation);
+        parsed
+            .verify(LOCATION, &identity_key.verifying_key(), now())
+            .unwrap();
+        verify_signed_by(&subkey, &delegation, &identity_key.verifying_key()).unwrap();
+    }
+
+    #[test]
+    fn rejects_expired_delegations() {
+        let (identity_key, subkey) = keys();
+        let expires_at = now() - 1;
+        let delegation =
+            Delegation::issue(LOCATION, subkey.verifying_key(), expires_at, &identity_key).unwrap();
+
+        assert!(matches!(
+            delegation.verify(LOCATION, &identity_key.verifying_key(), expires_at + 1),
+            Err(SignatureError::DelegationExpired)
+        ));
+        assert!(matches!(
+            verify_signed_by(&subkey, &delegation, &identity_key.verifying_key()),
+            Err(WebIdentityError::Signature(
+                SignatureError::DelegationExpired
+            ))
+        ));
+    }
+
+    #[test]
+    fn rejects_delegations_not_signed_by_the_identity() {
+        let (identity_key, subkey) = keys();
+        let other_key = SigningKey::from_bytes(&[3; 32]);
+        let delegation =
+            Delegation::issue(LOCATION, subkey.verifying_key(), now() + 60, &other_key).unwrap();
+
+        assert!(matches!(
+            delegation.verify(LOCATION, &identity_key.verifying_key(), now()),
+            Err(SignatureError::DelegationSignatureMismatch)
+        ));
+        assert!(matches!(
+            verify_signed_by(&subkey, &delegation, &identity_key.verifying_key()),
+            Err(WebIdentityError::Signature(
+                SignatureError::DelegationSignatureMismatch
+            ))
+        ));
+
+        // A delegation for another location doesn't carry over
+        let delegation = Delegation::issue(
+            "bob.example",
+            subkey.verifying_key(),
+            now() + 60,
+            &identity_key,
+        )
+        .unwrap();
+        assert!(matches!(
+            delegation.verify(LOCATION, &identity_key.verifying_key(), now()),
+            Err(SignatureError::DelegationSignatureMismatch)
+        ));
+    }
+
+    #[test]
+    fn rejects_requests_signed_by_another_subkey() {
+        let (identity_key, subkey) = keys();
+        let delegation =
+            Delegation::issue(LOCATION, subkey.verifying_key(), now() + 60, &identity_key).unwrap();
+
+        let other_subkey = SigningKey::from_bytes(&[3; 32]);
+        assert!(matches!(
+            verify_signed_by(&other_subkey, &delegation, &identity_key.verifying_key()),
+            Err(WebIdentityError::Signature(
+                SignatureError::SignatureMismatch
+            ))
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        let (identity_key, subkey) = keys();
+        let valid = Delegation::issue(LOCATION, subkey.verifying_key(), 1, &identity_key)
+            .unwrap()
+            .to_header_value();
+        let parts: Vec<&str> = valid.split(';').collect();
+
+        for value in [
+            String::new(),
+            parts[..2].join(";"),
+            format!("{};extra", valid),
+            format!(
+                "x25519-pub:{};{};{}",
+                &parts[0][PK_PREFIX.len()..],
+                parts[1],
+                parts[2]
+            ),
+            format!("{}zz;{};{}", PK_PREFIX, parts[1], parts[2]),
+            format!("{};soon;{}", parts[0], parts[2]),
+            format!("{};{};{}", parts[0], parts[1], &parts[2][2..]),
+        ] {
+            assert!(
+                matches!(
+                    Delegation::parse(&value),
+                    Err(SignatureError::InvalidDelegation(_))
+                ),
+                "{}",
+                value
+            );
+        }
+    }
+}
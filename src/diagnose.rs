@@ -0,0 +1,213 @@
+use super::authorization::AuthorizationHeaders;
+use super::delegation::Delegation;
+use super::error::{SignatureError, WebIdentityError};
+use super::public_key::PublicKey;
+use super::sign::{
+    body_digest, build_canonical_string, check_algorithm, extension, required_header,
+    signed_extensions, HeaderProvider, SignedRequest, VerifyOptions, EXTENSION_HEADERS,
+};
+use std::borrow::Cow;
+use std::time::Duration;
+
+/// The full report of [`diagnose_request`].
+///
+/// Every value was read from the request without being verified, and must only be used for
+/// debugging.
+#[derive(Debug)]
+pub struct RequestDiagnosis {
+    /// The `WebIdentity-*` and `Content-Digest` headers present, including those sent as
+    /// parameters of the `Authorization` header
+    pub headers_present: Vec<String>,
+    pub location: Option<String>,
+    /// Seconds since the UNIX epoch
+    pub timestamp: Option<u64>,
+    /// How long ago the request was signed, zero if its timestamp is in the future
+    pub age: Option<Duration>,
+    /// The canonical string the signature should cover, when all its parts could be read
+    pub canonical_string: Option<String>,
+    /// The hex-encoded digest of the body, as used in the canonical string
+    pub body_hash: Option<String>,
+    /// Whether the signature matched the key, `None` if it couldn't be checked
+    pub signature_valid: Option<bool>,
+    /// Every failed check, in the order verification makes them
+    pub findings: Vec<WebIdentityError>,
+}
+
+impl RequestDiagnosis {
+    /// Whether the request would pass verification with the same key and options.
+    pub fn is_valid(&self) -> bool {
+        self.signature_valid == Some(true) && self.findings.is_empty()
+    }
+}
+
+/// Checks a signed request like [`verify_request_with_options`](crate::verify_request_with_options),
+/// but collects every finding instead of stopping at the first error, for integrators debugging
+/// why a request was rejected.
+///
+/// The timestamp store of [`VerifyOptions::with_monotonic_timestamps`] is neither checked nor
+/// updated, so diagnosing a request doesn't affect its later verification.
+pub fn diagnose_request(
+    http_method: &str,
+    host: &str,
+    path: &str,
+    body: &[u8],
+    headers: &impl HeaderProvider,
+    public_key_bytes: &[u8],
+    options: &VerifyOptions,
+) -> RequestDiagnosis {
+    let request = Request {
+        http_method,
+        host,
+        path,
+        body,
+    };
+    match AuthorizationHeaders::new(headers) {
+        Ok(headers) => diagnose(&request, &headers, public_key_bytes, options, Vec::new()),
+        // Read what can still be read from the separate headers
+        Err(error) => diagnose(
+            &request,
+            headers,
+            public_key_bytes,
+            options,
+            vec![error.into()],
+        ),
+    }
+}
+
+struct Request<'a> {
+    http_method: &'a str,
+    host: &'a str,
+    path: &'a str,
+    body: &'a [u8],
+}
+
+/// Keeps the value of a check that passed, or records why it failed.
+fn record<T, E: Into<WebIdentityError>>(
+    findings: &mut Vec<WebIdentityError>,
+    result: Result<T, E>,
+) -> Option<T> {
+    result.map_err(|error| findings.push(error.into())).ok()
+}
+
+fn diagnose(
+    request: &Request<'_>,
+    headers: &impl HeaderProvider,
+    public_key_bytes: &[u8],
+    options: &VerifyOptions,
+    mut findings: Vec<WebIdentityError>,
+) -> RequestDiagnosis {
+    let headers_present = ["WebIdentity-Location", "WebIdentity-Timestamp"]
+        .iter()
+        .chain(EXTENSION_HEADERS)
+        .chain(&["Content-Digest", "WebIdentity-Signature"])
+        .filter(|name| headers.get_header(name).is_some())
+        .map(|name| name.to_string())
+        .collect();
+
+    record(&mut findings, options.check_method(request.http_method));
+    record(
+        &mut findings,
+        options.check_target(request.host, request.path),
+    );
+    let location = record(&mut findings, options.location(headers, request.host));
+    let timestamp_str = record(
+        &mut findings,
+        required_header(headers, "WebIdentity-Timestamp"),
+    );
+    let timestamp = timestamp_str.and_then(|timestamp| {
+        let parsed = timestamp
+            .parse::<u64>()
+            .map_err(|_| SignatureError::InvalidTimestamp(timestamp.to_string()));
+        record(&mut findings, parsed)
+    });
+    let signature = record(
+        &mut findings,
+        required_header(headers, "WebIdentity-Signature"),
+    );
+    let extensions = record(&mut findings, signed_extensions(headers));
+    let body_digest = record(&mut findings, body_digest(request.body, headers));
+
+    let mut delegation = Some(None);
+    if let Some(extensions) = &extensions {
+        if let Some(timestamp) = timestamp {
+            let expires = extension(extensions, "WebIdentity-Expires");
+            record(&mut findings, options.check_window(timestamp, expires));
+        }
+        record(&mut findings, check_algorithm(extensions));
+        if let Some(body_digest) = &body_digest {
+            record(
+                &mut findings,
+                options.check_body(extensions, body_digest, headers),
+            );
+        }
+        record(&mut findings, options.check_coverage(headers, extensions));
+        if let Some(value) = extension(extensions, "WebIdentity-Delegation") {
+            // An expired delegation is still used to check the signature
+            delegation = record(&mut findings, Delegation::parse(value)).map(|delegation| {
+                record(&mut findings, options.check_delegation(&delegation));
+                Some(delegation)
+            });
+        }
+    }
+
+    let canonical_string = match (&location, timestamp_str, &extensions, &body_digest) {
+        (Some(location), Some(timestamp), Some(extensions), Some(body_digest)) => {
+            Some(build_canonical_string(
+                request.http_method,
+                request.host,
+                request.path,
+                body_digest.as_bytes(),
+                location,
+                timestamp,
+                extensions,
+            ))
+        }
+        _ => None,
+    };
+
+    let public_key = record(&mut findings, PublicKey::from_bytes(public_key_bytes));
+    let signed = (
+        &location,
+        &canonical_string,
+        signature,
+        timestamp,
+        delegation,
+    );
+    let signature_valid = match (signed, public_key) {
+        (
+            (
+                Some(location),
+                Some(canonical_string),
+                Some(signature),
+                Some(timestamp),
+                Some(delegation),
+            ),
+            Some(key),
+        ) => {
+            let signed = SignedRequest {
+                location: Cow::Borrowed(location),
+                signature,
+                timestamp,
+                key_fingerprint: extensions
+                    .as_deref()
+                    .and_then(|extensions| extension(extensions, "WebIdentity-Key")),
+                delegation,
+                canonical_string: canonical_string.clone(),
+            };
+            Some(record(&mut findings, signed.verify(key.verifying_key())).is_some())
+        }
+        _ => None,
+    };
+
+    RequestDiagnosis {
+        headers_present,
+        location: location.map(Cow::into_owned),
+        timestamp,
+        age: timestamp
+            .map(|timestamp| Duration::from_secs(options.now().saturating_sub(timestamp))),
+        canonical_string,
+        body_hash: body_digest.map(|digest| hex::encode(digest.as_bytes())),
+        signature_valid,
+        findings,
+    }
+}
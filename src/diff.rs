@@ -0,0 +1,296 @@
+use super::identity::Identity;
+use serde::{Deserialize, Serialize};
+
+/// How much a [`FieldChange`] matters to someone relying on the identity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// Cosmetic changes, like the display name or avatar
+    Low,
+    /// Changes to where the identity can be found
+    Medium,
+    /// Changes to which keys can sign for the identity
+    High,
+}
+
+/// A change to one field between two snapshots of an identity. Keys are hex-encoded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum FieldChange {
+    LocationChanged {
+        old: String,
+        new: String,
+    },
+    PrimaryKeyChanged {
+        old: String,
+        new: String,
+    },
+    KeyRemoved {
+        key: String,
+    },
+    KeyAdded {
+        key: String,
+    },
+    ThresholdChanged {
+        old: Option<u8>,
+        new: Option<u8>,
+    },
+    DisplayNameChanged {
+        old: String,
+        new: String,
+    },
+    AvatarChanged {
+        old: Option<String>,
+        new: Option<String>,
+    },
+    DescriptionChanged {
+        old: Option<String>,
+        new: Option<String>,
+    },
+    LocalizedDescriptionChanged {
+        lang: String,
+        old: Option<String>,
+        new: Option<String>,
+    },
+    BackupLocationRemoved {
+        location: String,
+    },
+    BackupLocationAdded {
+        location: String,
+    },
+}
+
+impl FieldChange {
+    pub fn severity(&self) -> Severity {
+        match self {
+            FieldChange::LocationChanged { .. }
+            | FieldChange::PrimaryKeyChanged { .. }
+            | FieldChange::KeyRemoved { .. }
+            | FieldChange::KeyAdded { .. }
+            | FieldChange::ThresholdChanged { .. } => Severity::High,
+            FieldChange::BackupLocationRemoved { .. } | FieldChange::BackupLocationAdded { .. } => {
+                Severity::Medium
+            }
+            FieldChange::DisplayNameChanged { .. }
+            | FieldChange::AvatarChanged { .. }
+            | FieldChange::DescriptionChanged { .. }
+            | FieldChange::LocalizedDescriptionChanged { .. } => Severity::Low,
+        }
+    }
+}
+
+/// The changes between two snapshots of an identity, made by [`Identity::diff`].
+///
+/// Changes are ordered by field: location, keys, threshold, display name, avatar, descriptions
+/// (localized ones by language) and backup locations.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdentityDiff {
+    pub changes: Vec<FieldChange>,
+}
+
+impl IdentityDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// The severity of the most important change, or `None` if nothing changed.
+    pub fn severity(&self) -> Option<Severity> {
+        self.changes.iter().map(FieldChange::severity).max()
+    }
+}
+
+impl Identity {
+    /// Lists what changed from this snapshot of the identity to `newer`.
+    pub fn diff(&self, newer: &Identity) -> IdentityDiff {
+        let mut changes = Vec::new();
+
+        if self.location != newer.location {
+            changes.push(FieldChange::LocationChanged {
+                old: self.location.clone(),
+                new: newer.location.clone(),
+            });
+        }
+
+        if self.public_key != newer.public_key {
+            changes.push(FieldChange::PrimaryKeyChanged {
+                old: hex::encode(self.public_key),
+                new: hex::encode(newer.public_key),
+            });
+        }
+        for key in removed(&self.public_keys, &newer.public_keys) {
+            changes.push(FieldChange::KeyRemoved {
+                key: hex::encode(key),
+            });
+        }
+        for key in removed(&newer.public_keys, &self.public_keys) {
+            changes.push(FieldChange::KeyAdded {
+                key: hex::encode(key),
+            });
+        }
+
+        if self.threshold != newer.threshold {
+            changes.push(FieldChange::ThresholdChanged {
+                old: self.threshold,
+                new: newer.threshold,
+            });
+        }
+        if self.display_name != newer.display_name {
+            changes.push(FieldChange::DisplayNameChanged {
+                old: self.display_name.clone(),
+                new: newer.display_name.clone(),
+            });
+        }
+        if self.avatar != newer.avatar {
+            changes.push(FieldChange::AvatarChanged {
+                old: self.avatar.as_ref().map(|url| url.to_string()),
+                new: newer.avatar.as_ref().map(|url| url.to_string()),
+            });
+        }
+        if self.description != newer.description {
+            changes.push(FieldChange::DescriptionChanged {
+                old: self.description.clone(),
+                new: newer.description.clone(),
+            });
+        }
+        let mut langs: Vec<&String> = self
+            .descriptions
+            .keys()
+            .chain(newer.descriptions.keys())
+            .collect();
+        langs.sort();
+        langs.dedup();
+        for lang in langs {
+            let (old, new) = (self.descriptions.get(lang), newer.descriptions.get(lang));
+            if old != new {
+                changes.push(FieldChange::LocalizedDescriptionChanged {
+                    lang: lang.clone(),
+                    old: old.cloned(),
+                    new: new.cloned(),
+                });
+            }
+        }
+
+        for location in removed(&self.backup_locations, &newer.backup_locations) {
+            changes.push(FieldChange::BackupLocationRemoved {
+                location: location.clone(),
+            });
+        }
+        for location in removed(&newer.backup_locations, &self.backup_locations) {
+            changes.push(FieldChange::BackupLocationAdded {
+                location: location.clone(),
+            });
+        }
+
+        IdentityDiff { changes }
+    }
+}
+
+/// The items of `old` missing from `new`, in order.
+fn removed<'a, T: PartialEq>(old: &'a [T], new: &'a [T]) -> impl Iterator<Item = &'a T> {
+    old.iter().filter(move |item| !new.contains(item))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestIdentity;
+
+    #[test]
+    fn lists_added_removed_and_changed_keys() {
+        let alice = TestIdentity::generate("alice.example").identity;
+        let second = TestIdentity::generate("alice.example#second")
+            .identity
+            .public_key;
+        let third = TestIdentity::generate("alice.example#third")
+            .identity
+            .public_key;
+        assert!(alice.diff(&alice).is_empty());
+        assert_eq!(alice.diff(&alice).severity(), None);
+
+        let mut added = alice.clone();
+        added.public_keys.push(second);
+        assert_eq!(
+            alice.diff(&added).changes,
+            [FieldChange::KeyAdded {
+                key: hex::encode(second)
+            }]
+        );
+        assert_eq!(
+            added.diff(&alice).changes,
+            [FieldChange::KeyRemoved {
+                key: hex::encode(second)
+            }]
+        );
+
+        // The second key is promoted and the third replaces the first
+        let mut changed = added.clone();
+        changed.public_key = second;
+        changed.public_keys = vec![second, third];
+        assert_eq!(
+            added.diff(&changed).changes,
+            [
+                FieldChange::PrimaryKeyChanged {
+                    old: hex::encode(alice.public_key),
+                    new: hex::encode(second)
+                },
+                FieldChange::KeyRemoved {
+                    key: hex::encode(alice.public_key)
+                },
+                FieldChange::KeyAdded {
+                    key: hex::encode(third)
+                },
+            ]
+        );
+        assert_eq!(added.diff(&changed).severity(), Some(Severity::High));
+    }
+
+    #[test]
+    fn orders_changes_by_field_with_their_severity() {
+        let alice = TestIdentity::generate("alice.example").identity;
+        let mut newer = alice.clone();
+        newer.display_name = "Alice".into();
+        newer.backup_locations.push("mirror.example/alice".into());
+        newer.descriptions.insert("fr".into(), "Bonjour".into());
+
+        let diff = alice.diff(&newer);
+        assert_eq!(
+            diff.changes,
+            [
+                FieldChange::DisplayNameChanged {
+                    old: alice.display_name.clone(),
+                    new: "Alice".into()
+                },
+                FieldChange::LocalizedDescriptionChanged {
+                    lang: "fr".into(),
+                    old: None,
+                    new: Some("Bonjour".into())
+                },
+                FieldChange::BackupLocationAdded {
+                    location: "mirror.example/alice".into()
+                },
+            ]
+        );
+        let severities: Vec<Severity> = diff.changes.iter().map(FieldChange::severity).collect();
+        assert_eq!(severities, [Severity::Low, Severity::Low, Severity::Medium]);
+        assert_eq!(diff.severity(), Some(Severity::Medium));
+    }
+
+    #[test]
+    fn serializes_changes_with_a_tag() {
+        let diff = IdentityDiff {
+            changes: vec![FieldChange::ThresholdChanged {
+                old: None,
+                new: Some(2),
+            }],
+        };
+        let json = serde_json::to_value(&diff).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "changes": [{ "change": "threshold_changed", "old": null, "new": 2 }]
+            })
+        );
+        assert_eq!(serde_json::from_value::<IdentityDiff>(json).unwrap(), diff);
+    }
+}
@@ -0,0 +1,313 @@
+use super::error::SignatureError;
+#[cfg(feature = "async")]
+use super::error::WebIdentityError;
+use super::jcs::canonicalize_json;
+use super::sign::{content_digest_sha256, HeaderProvider};
+#[cfg(feature = "async")]
+use futures_util::{AsyncReadExt as _, Stream, StreamExt};
+use sha2::{Digest, Sha256};
+#[cfg(feature = "async")]
+use std::fmt::Display;
+use std::hash::{Hash, Hasher};
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The hash algorithm used for the body line of the canonical string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[non_exhaustive]
+pub enum DigestAlgorithm {
+    #[default]
+    Sha256,
+}
+
+/// The digest of a request body, computed once and reused across verification attempts.
+///
+/// Trying several keys, or retrying after refreshing an identity, doesn't need to hash a large
+/// body again. It is `Copy`, and remembers which algorithm produced it so it can't be used with
+/// a verification configured for a different one.
+///
+/// Digests are equal when their algorithm and bytes are, whether or not the body length is
+/// known.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestDigest {
+    algorithm: DigestAlgorithm,
+    bytes: [u8; 32],
+    /// The length of the body, when it was hashed here
+    length: Option<u64>,
+}
+
+impl PartialEq for RequestDigest {
+    fn eq(&self, other: &Self) -> bool {
+        self.algorithm == other.algorithm && self.bytes == other.bytes
+    }
+}
+
+impl Eq for RequestDigest {}
+
+impl Hash for RequestDigest {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.algorithm.hash(state);
+        self.bytes.hash(state);
+    }
+}
+
+impl RequestDigest {
+    /// Hashes a complete body with SHA-256.
+    pub fn of(body: &[u8]) -> Self {
+        let mut hasher = BodyHasher::new();
+        hasher.update(body);
+        hasher.finish()
+    }
+
+    /// Hashes a JSON body canonicalized with RFC 8785 (JCS), for requests signed with
+    /// [`SignOptions::with_json_canonicalization`](crate::SignOptions::with_json_canonicalization).
+    ///
+    /// # Errors
+    /// Returns [`SignatureError::InvalidJsonBody`] if the body isn't JSON.
+    pub fn of_json(body: &[u8]) -> Result<Self, SignatureError> {
+        Ok(RequestDigest::of(canonicalize_json(body)?.as_bytes()))
+    }
+
+    /// Wraps a SHA-256 digest computed elsewhere, e.g. by middleware that consumed the body.
+    pub fn from_sha256(bytes: [u8; 32]) -> Self {
+        RequestDigest {
+            algorithm: DigestAlgorithm::Sha256,
+            bytes,
+            length: None,
+        }
+    }
+
+    /// Reads the SHA-256 digest from a request's `Content-Digest` (RFC 9530) header, or the
+    /// legacy `Digest` header, for servers whose middleware already checked it against the body.
+    ///
+    /// # Errors
+    /// Returns `Err` if neither header is present, or it has no valid SHA-256 entry.
+    pub fn from_content_digest(headers: &impl HeaderProvider) -> Result<Self, SignatureError> {
+        let bytes = content_digest_sha256(headers)?;
+        let bytes = bytes
+            .try_into()
+            .map_err(|_| SignatureError::DigestMismatch)?;
+        Ok(RequestDigest::from_sha256(bytes))
+    }
+
+    pub fn algorithm(&self) -> DigestAlgorithm {
+        self.algorithm
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.bytes
+    }
+
+    /// The length of the body, known when it was hashed by [`RequestDigest::of`] or a
+    /// [`BodyHasher`] rather than wrapped from elsewhere.
+    pub fn body_length(&self) -> Option<u64> {
+        self.length
+    }
+
+    /// Whether this is the digest of an empty body.
+    pub fn is_empty_body(&self) -> bool {
+        match self.length {
+            Some(length) => length == 0,
+            None => self.bytes == EMPTY_SHA256,
+        }
+    }
+}
+
+/// The SHA-256 digest of an empty body.
+const EMPTY_SHA256: [u8; 32] = [
+    0xe3, 0xb0, 0xc4, 0x42, 0x98, 0xfc, 0x1c, 0x14, 0x9a, 0xfb, 0xf4, 0xc8, 0x99, 0x6f, 0xb9, 0x24,
+    0x27, 0xae, 0x41, 0xe4, 0x64, 0x9b, 0x93, 0x4c, 0xa4, 0x95, 0x99, 0x1b, 0x78, 0x52, 0xb8, 0x55,
+];
+
+/// Incrementally hashes a body that arrives in chunks.
+#[derive(Debug, Clone, Default)]
+pub struct BodyHasher {
+    hasher: Sha256,
+    length: u64,
+}
+
+impl BodyHasher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, chunk: &[u8]) {
+        self.hasher.update(chunk);
+        self.length += chunk.len() as u64;
+    }
+
+    pub fn finish(self) -> RequestDigest {
+        RequestDigest {
+            length: Some(self.length),
+            ..RequestDigest::from_sha256(self.hasher.finalize().into())
+        }
+    }
+}
+
+/// Hashes a body that arrives as a stream of chunks (e.g. the frames of a hyper body) without
+/// buffering it, for use with the prehashed verification functions.
+///
+/// # Errors
+/// Returns `Err` if the stream yields an error, or once more than `max_bytes` were read.
+#[cfg(feature = "async")]
+pub async fn hash_body_stream<S, B, E>(
+    stream: S,
+    max_bytes: u64,
+) -> Result<RequestDigest, WebIdentityError>
+where
+    S: Stream<Item = Result<B, E>>,
+    B: AsRef<[u8]>,
+    E: Display,
+{
+    let mut stream = std::pin::pin!(stream);
+    let mut hasher = BodyHasher::new();
+    let mut len = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| WebIdentityError::Body(e.to_string()))?;
+        len = checked_len(len, chunk.as_ref().len(), max_bytes)?;
+        hasher.update(chunk.as_ref());
+    }
+    Ok(hasher.finish())
+}
+
+/// Hashes a body read from a tokio `reader` without buffering it, for use with the prehashed
+/// verification functions.
+///
+/// # Errors
+/// Returns `Err` if reading fails, or once more than `max_bytes` were read.
+#[cfg(feature = "async")]
+pub async fn hash_body_async_read(
+    reader: impl AsyncRead,
+    max_bytes: u64,
+) -> Result<RequestDigest, WebIdentityError> {
+    let mut reader = std::pin::pin!(reader);
+    let mut hasher = BodyHasher::new();
+    let mut buf = [0u8; 8 * 1024];
+    let mut len = 0;
+    loop {
+        let read = reader
+            .read(&mut buf)
+            .await
+            .map_err(|e| WebIdentityError::Body(e.to_string()))?;
+        if read == 0 {
+            return Ok(hasher.finish());
+        }
+        len = checked_len(len, read, max_bytes)?;
+        hasher.update(&buf[..read]);
+    }
+}
+
+/// Hashes a body read from a `futures` `reader` without buffering it, for runtimes other than
+/// tokio such as smol or async-std.
+///
+/// # Errors
+/// Returns `Err` if reading fails, or once more than `max_bytes` were read.
+#[cfg(feature = "async")]
+pub async fn hash_body_futures_read(
+    reader: impl futures_util::AsyncRead,
+    max_bytes: u64,
+) -> Result<RequestDigest, WebIdentityError> {
+    let mut reader = std::pin::pin!(reader);
+    let mut hasher = BodyHasher::new();
+    let mut buf = [0u8; 8 * 1024];
+    let mut len = 0;
+    loop {
+        let read = reader
+            .read(&mut buf)
+            .await
+            .map_err(|e| WebIdentityError::Body(e.to_string()))?;
+        if read == 0 {
+            return Ok(hasher.finish());
+        }
+        len = checked_len(len, read, max_bytes)?;
+        hasher.update(&buf[..read]);
+    }
+}
+
+#[cfg(feature = "async")]
+fn checked_len(len: u64, chunk_len: usize, max_bytes: u64) -> Result<u64, WebIdentityError> {
+    let len = len + chunk_len as u64;
+    if len > max_bytes {
+        return Err(WebIdentityError::BodyTooLarge(max_bytes));
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"The quick brown fox jumps over the lazy dog, several frames at a time.";
+
+    #[test]
+    fn incremental_digest_matches_one_shot() {
+        for split in [1, 7, BODY.len()] {
+            let mut hasher = BodyHasher::new();
+            for chunk in BODY.chunks(split) {
+                hasher.update(chunk);
+            }
+            let digest = hasher.finish();
+            assert_eq!(digest, RequestDigest::of(BODY));
+            assert_eq!(digest.body_length(), Some(BODY.len() as u64));
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn stream_digest_matches_one_shot() {
+        let frames = BODY
+            .chunks(5)
+            .map(Ok::<_, std::io::Error>)
+            .collect::<Vec<_>>();
+        let digest = hash_body_stream(futures_util::stream::iter(frames), 1024)
+            .await
+            .unwrap();
+        assert_eq!(digest, RequestDigest::of(BODY));
+        assert_eq!(digest.body_length(), Some(BODY.len() as u64));
+
+        let empty = futures_util::stream::iter(Vec::<Result<&[u8], std::io::Error>>::new());
+        assert_eq!(
+            hash_body_stream(empty, 0).await.unwrap(),
+            RequestDigest::of(b"")
+        );
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn reader_digests_match_one_shot() {
+        let body = BODY.repeat(300);
+        let digest = hash_body_async_read(body.as_slice(), body.len() as u64)
+            .await
+            .unwrap();
+        assert_eq!(digest, RequestDigest::of(&body));
+
+        let cursor = futures_util::io::Cursor::new(&body);
+        let digest = hash_body_futures_read(cursor, body.len() as u64)
+            .await
+            .unwrap();
+        assert_eq!(digest, RequestDigest::of(&body));
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn stops_reading_past_the_limit() {
+        let frames = BODY.chunks(5).map(Ok::<_, std::io::Error>);
+        assert!(matches!(
+            hash_body_stream(futures_util::stream::iter(frames), 10).await,
+            Err(WebIdentityError::BodyTooLarge(10))
+        ));
+        assert!(matches!(
+            hash_body_async_read(BODY, 10).await,
+            Err(WebIdentityError::BodyTooLarge(10))
+        ));
+
+        let failing = futures_util::stream::iter([
+            Ok(&b"first"[..]),
+            Err(std::io::Error::other("connection reset")),
+        ]);
+        assert!(matches!(
+            hash_body_stream(failing, 1024).await,
+            Err(WebIdentityError::Body(reason)) if reason == "connection reset"
+        ));
+    }
+}
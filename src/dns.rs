@@ -0,0 +1,210 @@
+use super::error::WebIdentityError;
+use super::identity::{identity_id, location_from_url, parse_public_key, Identity, IdentitySource};
+use super::resolve::{resolve_location_url, IdentityResolver};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// The DNS label holding an identity's keys, under the identity's host.
+pub const DNS_TXT_LABEL: &str = "_webidentity";
+
+/// Resolves identities from `_webidentity.<host>` TXT records, each holding a key as
+/// `ed25519-pub:<hex>`. The first record is the primary key.
+///
+/// A record covers its whole host, so only identities at the root of a host (e.g.
+/// `example.com`, not `example.com/amy`) are resolved this way. The identity has no profile
+/// fields: its display name is its location. The DNS lookup itself is done by `lookup`, which
+/// receives the record name and returns the TXT strings.
+pub struct DnsTxtResolver<F> {
+    lookup: F,
+}
+
+impl<F> DnsTxtResolver<F>
+where
+    F: Fn(&str) -> Result<Vec<String>, WebIdentityError>,
+{
+    pub fn new(lookup: F) -> Self {
+        DnsTxtResolver { lookup }
+    }
+}
+
+impl<F> fmt::Debug for DnsTxtResolver<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DnsTxtResolver").finish_non_exhaustive()
+    }
+}
+
+impl<F> IdentityResolver for DnsTxtResolver<F>
+where
+    F: Fn(&str) -> Result<Vec<String>, WebIdentityError>,
+{
+    fn resolve_identity(&self, location: &str) -> Result<Arc<Identity>, WebIdentityError> {
+        let location_url = resolve_location_url(location)?;
+        let location = location_from_url(&location_url);
+        let host = match location_url.host_str() {
+            Some(host) if location_url.path() == "/" => host,
+            _ => return Err(WebIdentityError::UnsupportedLocation(location)),
+        };
+
+        let records = (self.lookup)(&format!("{}.{}", DNS_TXT_LABEL, host))?;
+        let public_keys = records
+            .iter()
+            .map(|record| record.trim())
+            .filter(|record| !record.is_empty())
+            .map(parse_public_key)
+            .collect::<Result<Vec<_>, _>>()?;
+        let Some(public_key) = public_keys.first().copied() else {
+            return Err(WebIdentityError::MissingPublicKey);
+        };
+
+        Ok(Arc::new(Identity {
+            id: identity_id(&public_key),
+            public_key,
+            public_keys,
+            threshold: None,
+            display_name: location.clone(),
+            avatar: None,
+            description: None,
+            descriptions: HashMap::new(),
+            location_url,
+            location,
+            backup_locations: Vec::new(),
+            spec_version: None,
+            verified_links: Vec::new(),
+            source: IdentitySource::DnsTxt,
+            fetched_at: Some(SystemTime::now()),
+            certificate_fingerprint: None,
+        }))
+    }
+}
+
+/// Tries a first resolver, and a second one if the first fails, e.g. an HTML page resolver and
+/// a [`DnsTxtResolver`] so an identity stays resolvable while its web host is down.
+///
+/// The order is the order of the arguments: pass the page resolver first for HTML-first, or
+/// the DNS resolver first for DNS-first. [`Identity::source`] tells which one provided the key.
+/// If both fail, the first resolver's error is returned.
+#[derive(Debug)]
+pub struct FallbackResolver<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A, B> FallbackResolver<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        FallbackResolver { first, second }
+    }
+}
+
+impl<A: IdentityResolver, B: IdentityResolver> IdentityResolver for FallbackResolver<A, B> {
+    fn resolve_identity(&self, location: &str) -> Result<Arc<Identity>, WebIdentityError> {
+        self.first
+            .resolve_identity(location)
+            .or_else(|error| self.second.resolve_identity(location).map_err(|_| error))
+    }
+
+    fn invalidate(&self, location: &str) {
+        self.first.invalidate(location);
+        self.second.invalidate(location);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{StaticFetcher, TestIdentity};
+    use std::sync::Mutex;
+
+    fn txt(records: &[String]) -> impl Fn(&str) -> Result<Vec<String>, WebIdentityError> + '_ {
+        move |name| {
+            assert_eq!(name, "_webidentity.alice.example");
+            Ok(records.to_vec())
+        }
+    }
+
+    #[test]
+    fn resolves_keys_from_txt_records() {
+        let alice = TestIdentity::generate("alice.example");
+        let second = TestIdentity::generate("alice.example#second");
+        let records = [
+            alice.identity.public_key.to_prefixed(),
+            String::new(),
+            format!(" {} ", second.identity.public_key.to_prefixed()),
+        ];
+        let resolver = DnsTxtResolver::new(txt(&records));
+
+        for location in ["alice.example", "https://alice.example/", "alice.example."] {
+            let identity = resolver.resolve_identity(location).unwrap();
+            assert_eq!(identity.id, alice.identity.id);
+            assert_eq!(
+                identity.public_keys,
+                [alice.identity.public_key, second.identity.public_key]
+            );
+            assert_eq!(identity.display_name, "alice.example");
+            assert_eq!(identity.source, IdentitySource::DnsTxt);
+        }
+    }
+
+    #[test]
+    fn rejects_paths_and_invalid_records() {
+        let resolver = DnsTxtResolver::new(|_: &str| -> Result<Vec<String>, WebIdentityError> {
+            panic!("looked up a location with a path")
+        });
+        assert!(matches!(
+            resolver.resolve_identity("alice.example/amy"),
+            Err(WebIdentityError::UnsupportedLocation(location)) if location == "alice.example/amy"
+        ));
+
+        let records = [String::new()];
+        assert!(matches!(
+            DnsTxtResolver::new(txt(&records)).resolve_identity("alice.example"),
+            Err(WebIdentityError::MissingPublicKey)
+        ));
+        let records = ["v=spf1 -all".to_string()];
+        assert!(matches!(
+            DnsTxtResolver::new(txt(&records)).resolve_identity("alice.example"),
+            Err(WebIdentityError::InvalidPublicKeyFormat(_))
+        ));
+    }
+
+    #[test]
+    fn falls_back_to_the_second_resolver() {
+        let alice = TestIdentity::generate("alice.example");
+        let records = [alice.identity.public_key.to_prefixed()];
+        let lookups = Mutex::new(0);
+        let dns = || {
+            DnsTxtResolver::new(|name: &str| {
+                *lookups.lock().unwrap() += 1;
+                txt(&records)(name)
+            })
+        };
+
+        let resolver = FallbackResolver::new(StaticFetcher::new().with_identity(&alice), dns());
+        let identity = resolver.resolve_identity("alice.example").unwrap();
+        assert_eq!(identity.source, alice.identity.source);
+        assert_eq!(*lookups.lock().unwrap(), 0);
+
+        // The page can't be found, its keys are still in DNS
+        let resolver = FallbackResolver::new(StaticFetcher::new(), dns());
+        let identity = resolver.resolve_identity("alice.example").unwrap();
+        assert_eq!(identity.source, IdentitySource::DnsTxt);
+        assert_eq!(identity.public_key, alice.identity.public_key);
+
+        // When both fail, the first error is kept
+        let first_error = StaticFetcher::new()
+            .resolve_identity("bob.example")
+            .unwrap_err();
+        let failing = DnsTxtResolver::new(|_: &str| -> Result<Vec<String>, WebIdentityError> {
+            Err(WebIdentityError::Fetch("SERVFAIL".into()))
+        });
+        let resolver = FallbackResolver::new(StaticFetcher::new(), failing);
+        assert_eq!(
+            resolver
+                .resolve_identity("bob.example")
+                .unwrap_err()
+                .to_string(),
+            first_error.to_string()
+        );
+    }
+}
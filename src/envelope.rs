@@ -0,0 +1,291 @@
+use super::error::{SignatureError, WebIdentityError};
+use super::sign::{verify_signature, RequestSigner, VerifyOptions};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The version byte of the encoding written by [`SignedEnvelope::to_bytes`].
+const ENVELOPE_VERSION: u8 = 1;
+
+/// A message signed by an identity, for transports without HTTP headers such as message queues.
+///
+/// The channel takes the place of the host and path of a request: it names where the message
+/// is meant to be delivered (e.g. a queue or topic), so it can't be replayed to another one.
+/// The signature covers
+/// `WebIdentity-Envelope\n<channel>\n<payload SHA-256 hex>\n<location>\n<timestamp>`, which
+/// can never be mistaken for the canonical string of a request, a challenge or a token.
+///
+/// The envelope can be serialized with any serde format (e.g. CBOR or bincode), or with
+/// [`SignedEnvelope::to_bytes`] which needs no extra dependency.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedEnvelope {
+    pub location: String,
+    /// Seconds since the UNIX epoch
+    pub timestamp: u64,
+    pub channel: String,
+    pub payload: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl SignedEnvelope {
+    /// Signs `payload` for `channel` as the identity at `location`.
+    pub fn seal(
+        location: &str,
+        channel: &str,
+        payload: Vec<u8>,
+        signer: &impl RequestSigner,
+    ) -> Result<SignedEnvelope, WebIdentityError> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let message = signing_message(location, timestamp, channel, &payload);
+
+        Ok(SignedEnvelope {
+            location: location.to_string(),
+            timestamp,
+            channel: channel.to_string(),
+            payload,
+            signature: signer.sign_message(&message)?.to_vec(),
+        })
+    }
+
+    /// Verifies the envelope was signed for `channel` by `public_key` within the window allowed
+    /// by `options`, and returns its payload.
+    ///
+    /// The host and path expected by `options` are not used, only its timestamp checks.
+    ///
+    /// # Errors
+    /// Returns `Err` if the envelope is meant for another channel, the timestamp is outside the
+    /// allowed window, or the signature is incorrect.
+    pub fn open(
+        &self,
+        public_key: &[u8],
+        channel: &str,
+        options: &VerifyOptions,
+    ) -> Result<&[u8], WebIdentityError> {
+        if self.channel != channel {
+            return Err(SignatureError::RequestMismatch("channel".into()).into());
+        }
+        options.check_timestamp(self.timestamp)?;
+
+        let message = signing_message(&self.location, self.timestamp, &self.channel, &self.payload);
+        verify_signature(public_key, &message, &self.signature)?;
+        Ok(&self.payload)
+    }
+
+    /// Encodes the envelope as a version byte followed by the location, timestamp, channel,
+    /// payload and signature, with big-endian lengths and timestamp.
+    ///
+    /// # Errors
+    /// Returns `Err` if the location or channel is longer than 65535 bytes, or the payload
+    /// is longer than 4 GiB.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, WebIdentityError> {
+        let too_long =
+            |field: &str| SignatureError::InvalidEnvelope(format!("The {} is too long.", field));
+        let location_len = u16::try_from(self.location.len()).map_err(|_| too_long("location"))?;
+        let channel_len = u16::try_from(self.channel.len()).map_err(|_| too_long("channel"))?;
+        let payload_len = u32::try_from(self.payload.len()).map_err(|_| too_long("payload"))?;
+
+        let mut bytes = Vec::with_capacity(
+            17 + self.location.len()
+                + self.channel.len()
+                + self.payload.len()
+                + self.signature.len(),
+        );
+        bytes.push(ENVELOPE_VERSION);
+        bytes.extend_from_slice(&location_len.to_be_bytes());
+        bytes.extend_from_slice(self.location.as_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&channel_len.to_be_bytes());
+        bytes.extend_from_slice(self.channel.as_bytes());
+        bytes.extend_from_slice(&payload_len.to_be_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes.extend_from_slice(&self.signature);
+        Ok(bytes)
+    }
+
+    /// Decodes an envelope encoded with [`SignedEnvelope::to_bytes`], without verifying it.
+    ///
+    /// # Errors
+    /// Returns `Err` if the encoding is truncated, malformed or from a newer version.
+    pub fn from_bytes(bytes: &[u8]) -> Result<SignedEnvelope, WebIdentityError> {
+        let mut reader = Reader(bytes);
+        let version = reader.take(1)?[0];
+        if version != ENVELOPE_VERSION {
+            return Err(SignatureError::InvalidEnvelope(format!(
+                "Version {} is not supported.",
+                version
+            ))
+            .into());
+        }
+
+        let location_len = u16::from_be_bytes(reader.array()?) as usize;
+        let location = reader.string(location_len)?;
+        let timestamp = u64::from_be_bytes(reader.array()?);
+        let channel_len = u16::from_be_bytes(reader.array()?) as usize;
+        let channel = reader.string(channel_len)?;
+        let payload_len = u32::from_be_bytes(reader.array()?) as usize;
+        let payload = reader.take(payload_len)?.to_vec();
+
+        Ok(SignedEnvelope {
+            location,
+            timestamp,
+            channel,
+            payload,
+            signature: reader.0.to_vec(),
+        })
+    }
+}
+
+fn signing_message(location: &str, timestamp: u64, channel: &str, payload: &[u8]) -> Vec<u8> {
+    format!(
+        "WebIdentity-Envelope\n{}\n{}\n{}\n{}",
+        channel,
+        hex::encode(Sha256::digest(payload)),
+        location,
+        timestamp
+    )
+    .into_bytes()
+}
+
+/// Reads the fields of an encoded envelope in order.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SignatureError> {
+        if self.0.len() < len {
+            return Err(SignatureError::InvalidEnvelope(
+                "Truncated envelope.".into(),
+            ));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], SignatureError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn string(&mut self, len: usize) -> Result<String, SignatureError> {
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| SignatureError::InvalidEnvelope("Invalid UTF-8.".into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+    use std::time::Duration;
+
+    fn options() -> VerifyOptions {
+        VerifyOptions::new(Duration::from_secs(300))
+    }
+
+    fn sealed(key: &SigningKey) -> SignedEnvelope {
+        SignedEnvelope::seal("amy.carroted.org", "queue/notes", b"hello".to_vec(), key).unwrap()
+    }
+
+    #[test]
+    fn opens_sealed_envelopes() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let envelope = sealed(&key);
+        let payload = envelope
+            .open(key.verifying_key().as_bytes(), "queue/notes", &options())
+            .unwrap();
+        assert_eq!(payload, b"hello");
+    }
+
+    #[test]
+    fn rejects_tampered_envelopes() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let public_key = key.verifying_key();
+        let envelope = sealed(&key);
+
+        assert!(matches!(
+            envelope.open(public_key.as_bytes(), "queue/other", &options()),
+            Err(WebIdentityError::Signature(
+                SignatureError::RequestMismatch(_)
+            ))
+        ));
+
+        let tampered: [fn(&mut SignedEnvelope); 5] = [
+            |e| e.payload = b"goodbye".to_vec(),
+            |e| e.location = "bob.example".into(),
+            |e| e.channel = "queue/other".into(),
+            |e| e.timestamp -= 1,
+            |e| e.signature[0] ^= 1,
+        ];
+        for tamper in tampered {
+            let mut envelope = envelope.clone();
+            tamper(&mut envelope);
+            let channel = envelope.channel.clone();
+            assert!(matches!(
+                envelope.open(public_key.as_bytes(), &channel, &options()),
+                Err(WebIdentityError::Signature(
+                    SignatureError::SignatureMismatch
+                ))
+            ));
+        }
+
+        let other_key = SigningKey::from_bytes(&[2; 32]).verifying_key();
+        assert!(matches!(
+            envelope.open(other_key.as_bytes(), "queue/notes", &options()),
+            Err(WebIdentityError::Signature(
+                SignatureError::SignatureMismatch
+            ))
+        ));
+
+        let mut stale = envelope.clone();
+        stale.timestamp -= 600;
+        assert!(matches!(
+            stale.open(public_key.as_bytes(), "queue/notes", &options()),
+            Err(WebIdentityError::Signature(
+                SignatureError::TimestampExpired { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let envelope = sealed(&key);
+        let bytes = envelope.to_bytes().unwrap();
+        assert_eq!(bytes[0], ENVELOPE_VERSION);
+
+        let decoded = SignedEnvelope::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded, envelope);
+        decoded
+            .open(key.verifying_key().as_bytes(), "queue/notes", &options())
+            .unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_encodings() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let bytes = sealed(&key).to_bytes().unwrap();
+
+        let mut newer = bytes.clone();
+        newer[0] = ENVELOPE_VERSION + 1;
+        // Cut inside the payload, and before any field
+        for invalid in [&newer[..], &bytes[..46], &[][..]] {
+            assert!(matches!(
+                SignedEnvelope::from_bytes(invalid),
+                Err(WebIdentityError::Signature(
+                    SignatureError::InvalidEnvelope(_)
+                ))
+            ));
+        }
+
+        let mut envelope = sealed(&key);
+        envelope.channel = "a".repeat(usize::from(u16::MAX) + 1);
+        assert!(matches!(
+            envelope.to_bytes(),
+            Err(WebIdentityError::Signature(
+                SignatureError::InvalidEnvelope(_)
+            ))
+        ));
+    }
+}
@@ -1,3 +1,5 @@
+use super::blocklist::BlockRule;
+use super::diff::IdentityDiff;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -8,20 +10,116 @@ pub enum WebIdentityError {
     #[error("UProtocol '{0}' is not supported. Please use https:// or http://.")]
     UnsupportedProtocol(String),
 
+    #[error("The location '{0}' cannot be resolved by this resolver.")]
+    UnsupportedLocation(String),
+
+    #[error("Failed to read identity document: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Failed to fetch the identity page: {0}")]
+    Fetch(String),
+
+    #[error("The server responded to the identity page request with status {0}.")]
+    HttpStatus(u16),
+
+    #[error("The identity page redirected to '{0}', which isn't allowed.")]
+    RedirectNotAllowed(String),
+
+    #[error("The identity page has the content type '{0}', not HTML.")]
+    UnexpectedContentType(String),
+
+    #[error("Failed to parse the identity document: {0}")]
+    Parse(String),
+
+    #[error("The identity document exceeded the parser's memory limit.")]
+    DocumentTooComplex,
+
     #[error("The required 'identity:public-key' meta tag was not found.")]
     MissingPublicKey,
 
     #[error("Public key format is invalid: {0}")]
     InvalidPublicKeyFormat(String),
 
+    #[error("The threshold '{0}' is invalid, it must be between 1 and the number of keys.")]
+    InvalidThreshold(String),
+
+    #[error("The spec version '{0}' is invalid, it must be a positive integer.")]
+    InvalidSpecVersion(String),
+
+    #[error("The page declares spec version {0}, newer than the supported version.")]
+    UnsupportedSpecVersion(u32),
+
+    #[error("The page declares spec version {version}, which doesn't support {feature}.")]
+    SpecVersionMismatch { version: u32, feature: &'static str },
+
+    #[error("The key {0} is not listed on the identity.")]
+    KeyNotListed(String),
+
     #[error("Could not find a display name from any fallback source.")]
     MissingDisplayName,
 
+    #[error("Failed to read the request body: {0}")]
+    Body(String),
+
+    #[error("The request body is larger than the {0} byte limit.")]
+    BodyTooLarge(u64),
+
+    /// `diff` lists the key and threshold changes from the identity to the mirror.
+    #[error(
+        "The mirror '{mirror}' lists different keys or a different threshold than the identity."
+    )]
+    MirrorKeyMismatch { mirror: String, diff: IdentityDiff },
+
+    #[error("The identity is blocked by the {0} rule.")]
+    SubjectBlocked(BlockRule),
+
+    #[error("The identity URI is invalid: {0}")]
+    InvalidIdentityUri(String),
+
+    #[error("The identity doesn't match the {0} of the identity reference.")]
+    IdentityRefMismatch(String),
+
+    #[error("The host '{0}' is not one this server accepts requests for.")]
+    HostNotAllowed(String),
+
+    #[error("'{0}' is not a valid account, it must be user@host.")]
+    InvalidAccount(String),
+
+    #[error("The WebFinger response has no WebIdentity link.")]
+    NoWebIdentityLink,
+
+    #[error("The CWT is invalid: {0}")]
+    InvalidCwt(String),
+
+    #[error("No Authenticator is configured for the server.")]
+    MissingAuthenticator,
+
+    #[error("Too many requests, retry after {retry_after:?}.")]
+    RateLimited { retry_after: std::time::Duration },
+
     #[error("Signature verification failed: {0}")]
     Signature(#[from] SignatureError),
 
     #[error("Cryptography error: {0}")]
     Crypto(String),
+
+    #[error("The signer failed to sign: {0}")]
+    Signer(String),
+
+    #[error("The keyfile is invalid: {0}")]
+    InvalidKeyfile(String),
+
+    #[error("Keyfile version {0} is not supported by this version of the library.")]
+    UnsupportedKeyfileVersion(u64),
+
+    #[error("Could not decrypt the keyfile. The passphrase may be wrong.")]
+    KeyfileDecryption,
+
+    #[error("Environment variable {0} is not set.")]
+    MissingEnvVar(String),
+
+    #[error("Environment variable {variable} is invalid: {reason}")]
+    InvalidEnvVar { variable: String, reason: String },
 }
 
 #[derive(Error, Debug)]
@@ -29,12 +127,128 @@ pub enum SignatureError {
     #[error("Missing required header: {0}")]
     MissingHeader(String),
 
+    #[error(
+        "Missing signed trailer: {0}. Trailers can only be checked once the body was received."
+    )]
+    MissingTrailer(String),
+
+    #[error("The header {0} was sent more than once.")]
+    DuplicateHeader(String),
+
+    #[error("The WebIdentity Authorization header is malformed: {0}")]
+    InvalidAuthorization(String),
+
     #[error("The timestamp '{0}' is invalid.")]
     InvalidTimestamp(String),
 
-    #[error("The request timestamp is too old.")]
-    TimestampExpired,
+    /// `age` and `max_age` are in seconds, `max_age` including the server's clock uncertainty
+    #[error("The request timestamp is {age} seconds old, more than the allowed {max_age}.")]
+    TimestampExpired { age: u64, max_age: u64 },
+
+    /// `ahead_by` and `max_skew` are in seconds, `max_skew` including the server's clock
+    /// uncertainty
+    #[error(
+        "The request timestamp is {ahead_by} seconds in the future, more than the allowed \
+         {max_skew}. The client's clock may be fast."
+    )]
+    TimestampInFuture { ahead_by: u64, max_skew: u64 },
+
+    #[error("The expiry '{0}' is invalid.")]
+    InvalidExpiry(String),
+
+    /// `ahead_by` and `max_skew` are in seconds, `max_skew` including the server's clock
+    /// uncertainty
+    #[error("The request was created {ahead_by} seconds in the future, more than the allowed {max_skew}.")]
+    NotYetValid { ahead_by: u64, max_skew: u64 },
+
+    /// `expired_for` is in seconds
+    #[error("The request expired {expired_for} seconds ago.")]
+    RequestExpired { expired_for: u64 },
+
+    /// `lifetime` and `max_lifetime` are in seconds, `max_lifetime` including the server's
+    /// clock uncertainty
+    #[error("The request was created {lifetime} seconds ago, more than the server's maximum lifetime of {max_lifetime}.")]
+    LifetimeExceeded { lifetime: u64, max_lifetime: u64 },
+
+    /// `last_accepted` is in seconds since the UNIX epoch
+    #[error(
+        "The request timestamp is not newer than the last accepted one ({last_accepted}), it \
+         may be a replay."
+    )]
+    TimestampReplayed { last_accepted: u64 },
 
     #[error("The provided signature does not match the request.")]
     SignatureMismatch,
+
+    #[error("The method {0} is not allowed.")]
+    MethodNotAllowed(String),
+
+    #[error("The request was signed for a different {0} than the one it was sent to.")]
+    RequestMismatch(String),
+
+    #[error("Only {valid} of the {required} required keys signed the request.")]
+    ThresholdNotMet { valid: usize, required: usize },
+
+    #[error("The challenge has expired.")]
+    ChallengeExpired,
+
+    #[error("The WebIdentity-Key header does not match the identity's key.")]
+    KeyFingerprintMismatch,
+
+    #[error("The '{0}' header does not contain a supported digest algorithm.")]
+    UnsupportedDigest(String),
+
+    #[error("The request's signature doesn't cover its body, which is not accepted.")]
+    UnboundBody,
+
+    #[error("The '{0}' header is not covered by the signature.")]
+    UncoveredHeader(String),
+
+    #[error("The request's headers can't be listed to check they are all signed.")]
+    HeaderListUnavailable,
+
+    #[error("The body digest does not match the digest header.")]
+    DigestMismatch,
+
+    #[error("The body digest was computed with a different algorithm than configured.")]
+    DigestAlgorithmMismatch,
+
+    #[error("The signature algorithm '{0}' is not supported.")]
+    UnsupportedAlgorithm(String),
+
+    #[error("The WebIdentity-Delegation header is malformed: {0}")]
+    InvalidDelegation(String),
+
+    #[error("The delegation has expired.")]
+    DelegationExpired,
+
+    #[error("The delegation was not signed by the identity's key.")]
+    DelegationSignatureMismatch,
+
+    #[error("The body was signed as canonical JSON, but is not valid JSON: {0}")]
+    InvalidJsonBody(String),
+
+    #[error("The key rotation proof is malformed: {0}")]
+    InvalidRotation(String),
+
+    #[error("The key rotation proof is not signed by both keys.")]
+    RotationSignatureMismatch,
+
+    #[error("The envelope is malformed: {0}")]
+    InvalidEnvelope(String),
+
+    #[error("The signed URL is malformed: {0}")]
+    InvalidSignedUrl(String),
+
+    #[error("The signed URL has expired.")]
+    UrlExpired,
+
+    #[error("The token is malformed: {0}")]
+    InvalidToken(String),
+
+    #[error("The token has expired.")]
+    TokenExpired,
+
+    #[error("The token was issued for a different audience.")]
+    AudienceMismatch,
 }
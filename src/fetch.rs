@@ -0,0 +1,1286 @@
+use super::error::WebIdentityError;
+use super::identity::{Identity, IdentityOptions, IdentityParser};
+use super::redirect::RedirectPolicy;
+use super::resolve::{resolve_location_url, IdentityResolver};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::future::Future;
+use std::pin::pin;
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::SystemTime;
+use url::Url;
+
+/// The largest identity page the built-in fetchers read, in bytes.
+pub const MAX_IDENTITY_PAGE_SIZE: u64 = 1024 * 1024;
+
+/// The `User-Agent` the built-in fetchers send, unless another is set with
+/// [`FetchOptions::with_user_agent`].
+pub const DEFAULT_USER_AGENT: &str = concat!("webidentity-rs/", env!("CARGO_PKG_VERSION"));
+
+/// The media types identity pages are served with.
+const HTML_TYPES: &[&str] = &["text/html", "application/xhtml+xml"];
+
+/// Parses an identity page fetched from `url` by any HTTP client, given the response's
+/// `Content-Type` header.
+///
+/// The identity's [`fetched_at`](Identity::fetched_at) is set to now. Responses without a
+/// content type are parsed as HTML.
+///
+/// # Errors
+/// Returns [`WebIdentityError::UnexpectedContentType`] if the response isn't HTML, or `Err` if
+/// the page isn't a valid identity.
+pub fn identity_from_response(
+    url: &Url,
+    content_type: Option<&str>,
+    body: &[u8],
+) -> Result<Identity, WebIdentityError> {
+    parse_response(
+        default_parser(),
+        url,
+        &FetchedPage {
+            content_type: content_type.map(str::to_string),
+            body: body.to_vec(),
+            peer_certificate: None,
+        },
+    )
+}
+
+fn parse_response(
+    parser: &IdentityParser,
+    url: &Url,
+    page: &FetchedPage,
+) -> Result<Identity, WebIdentityError> {
+    check_content_type(page.content_type.as_deref())?;
+    Ok(Identity {
+        fetched_at: Some(SystemTime::now()),
+        certificate_fingerprint: page.certificate_fingerprint(),
+        ..parser.parse(url, &page.body)?
+    })
+}
+
+fn default_parser() -> &'static IdentityParser {
+    static PARSER: OnceLock<IdentityParser> = OnceLock::new();
+    PARSER.get_or_init(IdentityParser::default)
+}
+
+pub(crate) fn check_content_type(content_type: Option<&str>) -> Result<(), WebIdentityError> {
+    let Some(content_type) = content_type else {
+        return Ok(());
+    };
+    let media_type = content_type.split(';').next().unwrap_or("").trim();
+    if HTML_TYPES
+        .iter()
+        .any(|html| media_type.eq_ignore_ascii_case(html))
+    {
+        Ok(())
+    } else {
+        Err(WebIdentityError::UnexpectedContentType(
+            content_type.to_string(),
+        ))
+    }
+}
+
+/// An identity page retrieved by an [`IdentityFetcher`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct FetchedPage {
+    /// The `Content-Type` of the response, if it had one
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+    /// The DER-encoded certificate the server presented, for pages fetched over HTTPS by a
+    /// fetcher that exposes it. [`HttpFetcher`](crate::HttpFetcher) does, `ureq` doesn't so
+    /// [`BlockingFetcher`](crate::BlockingFetcher) never sets it
+    pub peer_certificate: Option<Vec<u8>>,
+}
+
+impl FetchedPage {
+    /// The hex-encoded SHA-256 fingerprint of the peer certificate, as set on the fetched
+    /// [`Identity::certificate_fingerprint`].
+    pub fn certificate_fingerprint(&self) -> Option<String> {
+        self.peer_certificate
+            .as_ref()
+            .map(|certificate| hex::encode(Sha256::digest(certificate)))
+    }
+}
+
+/// Retrieves identity pages, so custom transports (an internal proxy, a cache, a test double)
+/// reuse the parsing of [`fetch_identity_with`].
+///
+/// [`HttpFetcher`](crate::HttpFetcher) fetches them over HTTP with the `reqwest` feature.
+pub trait IdentityFetcher {
+    /// Fetches the page at `url`, which was resolved from a location with
+    /// [`resolve_location_url`](crate::resolve_location_url).
+    ///
+    /// Fetchers should fail with [`WebIdentityError::Fetch`] when the page can't be retrieved,
+    /// with [`WebIdentityError::HttpStatus`] when the server responds with an error status,
+    /// and limit how much they read, e.g. to [`MAX_IDENTITY_PAGE_SIZE`].
+    fn fetch(
+        &self,
+        url: &Url,
+    ) -> impl Future<Output = Result<FetchedPage, WebIdentityError>> + Send;
+
+    /// The parser for the pages this fetcher retrieves, with the default [`IdentityOptions`]
+    /// unless overridden.
+    fn parser(&self) -> &IdentityParser {
+        default_parser()
+    }
+}
+
+/// Fetches the identity at `location` with `fetcher`, and parses it.
+///
+/// # Errors
+/// Returns `Err` if the location is invalid, `fetcher` fails, the page isn't HTML, or it isn't
+/// a valid identity.
+pub async fn fetch_identity_with(
+    fetcher: &impl IdentityFetcher,
+    location: &str,
+) -> Result<Identity, WebIdentityError> {
+    let url = resolve_location_url(location)?;
+    let page = fetcher.fetch(&url).await?;
+    parse_response(fetcher.parser(), &url, &page)
+}
+
+/// Resolves identities with an [`IdentityFetcher`], so fetched identities can be cached with a
+/// [`CachingResolver`](crate::CachingResolver) or fall back to mirrors with a
+/// [`MirrorResolver`](crate::MirrorResolver).
+///
+/// [`IdentityResolver`] is synchronous, so each fetch is driven to completion on the calling
+/// thread. That is enough for fetchers that don't need an async runtime, like
+/// [`BlockingFetcher`](crate::BlockingFetcher), [`SurfFetcher`](crate::SurfFetcher) or
+/// [`StaticFetcher`](crate::testing::StaticFetcher). Fetchers that do, like
+/// [`HttpFetcher`](crate::HttpFetcher), must be given a runtime with
+/// [`FetcherResolver::with_runtime`].
+#[derive(Debug)]
+pub struct FetcherResolver<F> {
+    fetcher: F,
+    #[cfg(feature = "reqwest")]
+    runtime: Option<tokio::runtime::Handle>,
+}
+
+impl<F: IdentityFetcher> FetcherResolver<F> {
+    pub fn new(fetcher: F) -> Self {
+        FetcherResolver {
+            fetcher,
+            #[cfg(feature = "reqwest")]
+            runtime: None,
+        }
+    }
+
+    pub fn fetcher(&self) -> &F {
+        &self.fetcher
+    }
+}
+
+#[cfg(feature = "reqwest")]
+impl<F: IdentityFetcher> FetcherResolver<F> {
+    /// Drives fetches on a multi-threaded Tokio `runtime`, which must be running.
+    ///
+    /// Identities must then be resolved from outside the runtime's worker threads, e.g. from
+    /// `tokio::task::spawn_blocking` or a thread of its own, as blocking on a fetch from
+    /// async code would panic.
+    pub fn with_runtime(fetcher: F, runtime: tokio::runtime::Handle) -> Self {
+        FetcherResolver {
+            fetcher,
+            runtime: Some(runtime),
+        }
+    }
+}
+
+impl<F: IdentityFetcher> IdentityResolver for FetcherResolver<F> {
+    fn resolve_identity(&self, location: &str) -> Result<Arc<Identity>, WebIdentityError> {
+        let fetch = fetch_identity_with(&self.fetcher, location);
+        #[cfg(feature = "reqwest")]
+        if let Some(runtime) = &self.runtime {
+            return runtime.block_on(fetch).map(Arc::new);
+        }
+        block_on(fetch).map(Arc::new)
+    }
+}
+
+/// Polls `future` on the current thread until it completes, parking the thread while it is
+/// pending.
+fn block_on<T>(future: impl Future<Output = T>) -> T {
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+/// How the built-in fetchers request identity pages and parse them.
+///
+/// Redirects are only followed on the same host by default, see
+/// [`FetchOptions::with_redirect_policy`].
+#[derive(Debug, Clone)]
+pub struct FetchOptions {
+    user_agent: String,
+    redirects: RedirectPolicy,
+    headers: Vec<(String, String)>,
+    /// Host patterns and the credentials sent to them
+    credentials: Vec<(String, Credentials)>,
+    parser: IdentityParser,
+}
+
+/// Sends [`DEFAULT_USER_AGENT`] and parses pages with the default [`IdentityOptions`].
+impl Default for FetchOptions {
+    fn default() -> Self {
+        FetchOptions {
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            redirects: RedirectPolicy::default(),
+            headers: Vec::new(),
+            credentials: Vec::new(),
+            parser: IdentityParser::default(),
+        }
+    }
+}
+
+impl FetchOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sends `user_agent` instead of [`DEFAULT_USER_AGENT`], e.g. with a contact address for
+    /// the hosts being crawled.
+    pub fn with_user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    /// Follows redirects as `policy` allows. Redirects it doesn't allow fail with
+    /// [`WebIdentityError::RedirectNotAllowed`] instead of being followed.
+    pub fn with_redirect_policy(mut self, policy: RedirectPolicy) -> Self {
+        self.redirects = policy;
+        self
+    }
+
+    /// Sends an extra header with every request, e.g. to identify the operator to the hosts'
+    /// firewalls. `Accept`, `User-Agent` and `Authorization` are set by the fetcher and can't
+    /// be overridden here.
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Authenticates requests to the hosts matching `host_pattern` with `credentials`, for
+    /// identity pages behind a login, such as an intranet directory.
+    ///
+    /// The pattern is a host name (`directory.corp.example`), or `*.` followed by a domain to
+    /// match its subdomains (`*.corp.example`). The first matching pattern is used. Credentials
+    /// are only sent to the origin of the location being fetched, never after a redirect to
+    /// another one.
+    pub fn with_credentials(
+        mut self,
+        host_pattern: impl Into<String>,
+        credentials: Credentials,
+    ) -> Self {
+        self.credentials.push((host_pattern.into(), credentials));
+        self
+    }
+
+    /// Parses pages with `options`, e.g. to accept other key prefixes (see
+    /// [`IdentityOptions::with_key_prefixes`]).
+    pub fn with_identity_options(mut self, options: IdentityOptions) -> Self {
+        self.parser = IdentityParser::new(options);
+        self
+    }
+
+    pub fn user_agent(&self) -> &str {
+        &self.user_agent
+    }
+
+    pub fn redirect_policy(&self) -> RedirectPolicy {
+        self.redirects
+    }
+
+    pub fn identity_options(&self) -> &IdentityOptions {
+        self.parser.options()
+    }
+
+    /// The headers to send when requesting `current`, reached from `url`.
+    #[cfg(any(feature = "reqwest", feature = "surf", feature = "blocking"))]
+    fn request_headers(&self, url: &Url, current: &Url) -> Vec<(&str, String)> {
+        let mut headers = vec![
+            ("Accept", HTML_TYPES.join(", ")),
+            ("User-Agent", self.user_agent.clone()),
+        ];
+        let extra = self.headers.iter().filter(|(name, _)| {
+            !["Accept", "User-Agent", "Authorization"]
+                .iter()
+                .any(|set| name.eq_ignore_ascii_case(set))
+        });
+        headers.extend(extra.map(|(name, value)| (name.as_str(), value.clone())));
+        if current.origin() == url.origin() {
+            let host = current.host_str().unwrap_or("");
+            let credentials = self
+                .credentials
+                .iter()
+                .find(|(pattern, _)| host_matches(pattern, host));
+            if let Some((_, credentials)) = credentials {
+                headers.push(("Authorization", credentials.authorization()));
+            }
+        }
+        headers
+    }
+
+    /// Checks the response to the request for `current`, reached from `url`: `None` if it
+    /// holds the page, or the page it redirects to.
+    #[cfg(any(feature = "reqwest", feature = "surf", feature = "blocking"))]
+    fn next_url(
+        &self,
+        url: &Url,
+        current: &Url,
+        status: u16,
+        location: Option<&str>,
+        redirects: u8,
+    ) -> Result<Option<Url>, WebIdentityError> {
+        if (200..300).contains(&status) {
+            return Ok(None);
+        }
+        if !matches!(status, 301 | 302 | 303 | 307 | 308) {
+            return Err(WebIdentityError::HttpStatus(status));
+        }
+        let location = location.ok_or_else(|| {
+            WebIdentityError::Fetch(format!("The {} redirect has no location.", status))
+        })?;
+        let target = current.join(location)?;
+        self.redirects.check(url, &target, redirects)?;
+        Ok(Some(target))
+    }
+}
+
+/// Credentials for identity pages behind HTTP authentication, see
+/// [`FetchOptions::with_credentials`].
+///
+/// They are never printed, their `Debug` output only shows the scheme.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Credentials {
+    Basic { username: String, password: String },
+    Bearer(String),
+}
+
+impl Credentials {
+    /// The value of the `Authorization` header.
+    #[cfg(any(feature = "reqwest", feature = "surf", feature = "blocking"))]
+    fn authorization(&self) -> String {
+        use base64::prelude::{Engine, BASE64_STANDARD};
+
+        match self {
+            Credentials::Basic { username, password } => format!(
+                "Basic {}",
+                BASE64_STANDARD.encode(format!("{}:{}", username, password))
+            ),
+            Credentials::Bearer(token) => format!("Bearer {}", token),
+        }
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credentials::Basic { .. } => f.write_str("Credentials::Basic(..)"),
+            Credentials::Bearer(_) => f.write_str("Credentials::Bearer(..)"),
+        }
+    }
+}
+
+/// Whether `host` matches a pattern of [`FetchOptions::with_credentials`].
+#[cfg(any(feature = "reqwest", feature = "surf", feature = "blocking"))]
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host.len().checked_sub(domain.len() + 1).is_some_and(|dot| {
+            host.as_bytes()[dot] == b'.' && host[dot + 1..].eq_ignore_ascii_case(domain)
+        }),
+        None => host.eq_ignore_ascii_case(pattern),
+    }
+}
+
+/// The default [`IdentityFetcher`], fetching pages over HTTP with `reqwest`.
+///
+/// Redirects are followed as described on [`FetchOptions`].
+#[cfg(feature = "reqwest")]
+#[derive(Debug, Clone)]
+pub struct HttpFetcher {
+    client: reqwest::Client,
+    options: FetchOptions,
+}
+
+#[cfg(feature = "reqwest")]
+impl Default for HttpFetcher {
+    fn default() -> Self {
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .tls_info(true)
+            .build()
+            .expect("TLS backend cannot be initialized");
+        HttpFetcher::with_client(client)
+    }
+}
+
+#[cfg(feature = "reqwest")]
+impl HttpFetcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Uses a configured client, e.g. with timeouts or a proxy.
+    ///
+    /// The client should be built with `reqwest::redirect::Policy::none()`, so redirects are
+    /// checked before they are followed. Pages a client reached through its own redirects
+    /// are rejected unless they would have been followed. The peer certificate is only
+    /// exposed if the client was built with `tls_info(true)`.
+    pub fn with_client(client: reqwest::Client) -> Self {
+        HttpFetcher {
+            client,
+            options: FetchOptions::default(),
+        }
+    }
+
+    pub fn with_options(mut self, options: FetchOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn options(&self) -> &FetchOptions {
+        &self.options
+    }
+}
+
+#[cfg(feature = "reqwest")]
+impl IdentityFetcher for HttpFetcher {
+    async fn fetch(&self, url: &Url) -> Result<FetchedPage, WebIdentityError> {
+        use reqwest::header::{CONTENT_TYPE, LOCATION};
+
+        let fetch_error = |e: reqwest::Error| WebIdentityError::Fetch(e.to_string());
+        let mut current = url.clone();
+        let mut redirects = 0;
+        let mut response = loop {
+            let mut request = self.client.get(current.clone());
+            for (name, value) in self.options.request_headers(url, &current) {
+                request = request.header(name, value);
+            }
+            let response = request.send().await.map_err(fetch_error)?;
+            // Redirects the client followed on its own
+            if response.url() != &current {
+                self.options
+                    .redirects
+                    .check(url, response.url(), redirects)?;
+            }
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|value| value.to_str().ok());
+            let status = response.status().as_u16();
+            match self
+                .options
+                .next_url(url, response.url(), status, location, redirects)?
+            {
+                Some(next) => {
+                    current = next;
+                    redirects += 1;
+                }
+                None => break response,
+            }
+        };
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        // Don't download what won't be parsed
+        check_content_type(content_type.as_deref())?;
+        let peer_certificate = response
+            .extensions()
+            .get::<reqwest::tls::TlsInfo>()
+            .and_then(|info| info.peer_certificate())
+            .map(<[u8]>::to_vec);
+
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(fetch_error)? {
+            if (body.len() + chunk.len()) as u64 > MAX_IDENTITY_PAGE_SIZE {
+                return Err(WebIdentityError::BodyTooLarge(MAX_IDENTITY_PAGE_SIZE));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(FetchedPage {
+            content_type,
+            body,
+            peer_certificate,
+        })
+    }
+
+    fn parser(&self) -> &IdentityParser {
+        &self.options.parser
+    }
+}
+
+/// Fetches and parses the identity at `location` over HTTP, with a default `reqwest` client.
+///
+/// # Errors
+/// Returns [`WebIdentityError::Fetch`] if the page can't be retrieved,
+/// [`WebIdentityError::HttpStatus`] if the server doesn't respond with a success status,
+/// [`WebIdentityError::RedirectNotAllowed`] if it redirects elsewhere,
+/// [`WebIdentityError::UnexpectedContentType`] if it isn't HTML,
+/// [`WebIdentityError::BodyTooLarge`] if it is larger than [`MAX_IDENTITY_PAGE_SIZE`], or `Err`
+/// if the location is invalid or the page isn't a valid identity.
+#[cfg(feature = "reqwest")]
+pub async fn fetch_identity(location: &str) -> Result<Identity, WebIdentityError> {
+    fetch_identity_with(&HttpFetcher::new(), location).await
+}
+
+/// Like [`fetch_identity`], but with a configured `reqwest` client, e.g. with timeouts or a
+/// proxy.
+///
+/// # Errors
+/// Same as [`fetch_identity`].
+#[cfg(feature = "reqwest")]
+pub async fn fetch_identity_with_client(
+    client: &reqwest::Client,
+    location: &str,
+) -> Result<Identity, WebIdentityError> {
+    fetch_identity_with(&HttpFetcher::with_client(client.clone()), location).await
+}
+
+/// An [`IdentityFetcher`] fetching pages over HTTP with `surf`, for async runtimes other than
+/// Tokio, such as smol or async-std.
+///
+/// Its requests are driven by async-std's own reactor, so it also works behind a
+/// [`FetcherResolver`] without a runtime. Redirects are followed as described on
+/// [`FetchOptions`].
+#[cfg(feature = "surf")]
+#[derive(Debug, Clone)]
+pub struct SurfFetcher {
+    client: surf::Client,
+    options: FetchOptions,
+}
+
+#[cfg(feature = "surf")]
+impl Default for SurfFetcher {
+    fn default() -> Self {
+        // Identity fetches are one-off, and reusing a connection the server closed would fail
+        let client = surf::Config::new()
+            .set_http_keep_alive(false)
+            .try_into()
+            .expect("HTTP client cannot be initialized");
+        SurfFetcher::with_client(client)
+    }
+}
+
+#[cfg(feature = "surf")]
+impl SurfFetcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Uses a configured client, e.g. with timeouts. It shouldn't have the `Redirect`
+    /// middleware, so redirects are checked before they are followed.
+    pub fn with_client(client: surf::Client) -> Self {
+        SurfFetcher {
+            client,
+            options: FetchOptions::default(),
+        }
+    }
+
+    pub fn with_options(mut self, options: FetchOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn options(&self) -> &FetchOptions {
+        &self.options
+    }
+}
+
+#[cfg(feature = "surf")]
+impl IdentityFetcher for SurfFetcher {
+    async fn fetch(&self, url: &Url) -> Result<FetchedPage, WebIdentityError> {
+        use futures_util::AsyncReadExt;
+
+        let fetch_error = |e: surf::Error| WebIdentityError::Fetch(e.to_string());
+        let header = |response: &surf::Response, name: &str| {
+            response
+                .header(name)
+                .map(|values| values.last().as_str().to_string())
+        };
+        let mut current = url.clone();
+        let mut redirects = 0;
+        let mut response = loop {
+            let mut request = self.client.get(current.as_str());
+            for (name, value) in self.options.request_headers(url, &current) {
+                request = request.header(name, value);
+            }
+            let response = request.await.map_err(fetch_error)?;
+            let location = header(&response, "Location");
+            let status = u16::from(response.status());
+            match self
+                .options
+                .next_url(url, &current, status, location.as_deref(), redirects)?
+            {
+                Some(next) => {
+                    current = next;
+                    redirects += 1;
+                }
+                None => break response,
+            }
+        };
+        let content_type = header(&response, "Content-Type");
+        // Don't download what won't be parsed
+        check_content_type(content_type.as_deref())?;
+
+        let mut body = Vec::new();
+        response
+            .take_body()
+            .into_reader()
+            .take(MAX_IDENTITY_PAGE_SIZE + 1)
+            .read_to_end(&mut body)
+            .await
+            .map_err(|e| WebIdentityError::Fetch(e.to_string()))?;
+        if body.len() as u64 > MAX_IDENTITY_PAGE_SIZE {
+            return Err(WebIdentityError::BodyTooLarge(MAX_IDENTITY_PAGE_SIZE));
+        }
+        Ok(FetchedPage {
+            content_type,
+            body,
+            peer_certificate: None,
+        })
+    }
+
+    fn parser(&self) -> &IdentityParser {
+        &self.options.parser
+    }
+}
+
+/// An [`IdentityFetcher`] fetching pages over HTTP with `ureq`, without an async runtime.
+///
+/// Its [`IdentityFetcher::fetch`] blocks the calling thread until the page is read, so it is
+/// meant for synchronous code, e.g. [`BlockingFetcher::fetch_identity`] or behind an
+/// [`IdentityResolver`](crate::IdentityResolver). Redirects are followed as described on
+/// [`FetchOptions`].
+#[cfg(feature = "blocking")]
+#[derive(Debug, Clone)]
+pub struct BlockingFetcher {
+    agent: ureq::Agent,
+    options: FetchOptions,
+}
+
+#[cfg(feature = "blocking")]
+impl Default for BlockingFetcher {
+    fn default() -> Self {
+        BlockingFetcher::with_agent(ureq::Agent::new_with_defaults())
+    }
+}
+
+#[cfg(feature = "blocking")]
+impl BlockingFetcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Uses a configured agent, e.g. with timeouts or a proxy. Its redirect settings are
+    /// replaced by those of [`FetchOptions`].
+    pub fn with_agent(agent: ureq::Agent) -> Self {
+        BlockingFetcher {
+            agent,
+            options: FetchOptions::default(),
+        }
+    }
+
+    pub fn with_options(mut self, options: FetchOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn options(&self) -> &FetchOptions {
+        &self.options
+    }
+
+    /// Fetches the page at `url`, see [`IdentityFetcher::fetch`].
+    ///
+    /// # Errors
+    /// Same as [`fetch_identity_blocking`], except for the page not being a valid identity.
+    pub fn fetch_page(&self, url: &Url) -> Result<FetchedPage, WebIdentityError> {
+        let fetch_error = |e: ureq::Error| WebIdentityError::Fetch(e.to_string());
+        let mut current = url.clone();
+        let mut redirects = 0;
+        let mut response = loop {
+            let mut request = self.agent.get(current.as_str());
+            for (name, value) in self.options.request_headers(url, &current) {
+                request = request.header(name, value);
+            }
+            let response = request
+                .config()
+                .max_redirects(0)
+                .http_status_as_error(false)
+                .build()
+                .call()
+                .map_err(fetch_error)?;
+            let location = response
+                .headers()
+                .get("Location")
+                .and_then(|value| value.to_str().ok());
+            let status = response.status().as_u16();
+            match self
+                .options
+                .next_url(url, &current, status, location, redirects)?
+            {
+                Some(next) => {
+                    current = next;
+                    redirects += 1;
+                }
+                None => break response,
+            }
+        };
+        let content_type = response
+            .headers()
+            .get("Content-Type")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        // Don't download what won't be parsed
+        check_content_type(content_type.as_deref())?;
+
+        let body = response
+            .body_mut()
+            .with_config()
+            .limit(MAX_IDENTITY_PAGE_SIZE)
+            .read_to_vec()
+            .map_err(|e| match e {
+                ureq::Error::BodyExceedsLimit(_) => {
+                    WebIdentityError::BodyTooLarge(MAX_IDENTITY_PAGE_SIZE)
+                }
+                e => fetch_error(e),
+            })?;
+        Ok(FetchedPage {
+            content_type,
+            body,
+            peer_certificate: None,
+        })
+    }
+
+    /// Fetches and parses the identity at `location`.
+    ///
+    /// # Errors
+    /// Same as [`fetch_identity_blocking`].
+    pub fn fetch_identity(&self, location: &str) -> Result<Identity, WebIdentityError> {
+        let url = resolve_location_url(location)?;
+        let page = self.fetch_page(&url)?;
+        parse_response(&self.options.parser, &url, &page)
+    }
+}
+
+#[cfg(feature = "blocking")]
+impl IdentityFetcher for BlockingFetcher {
+    async fn fetch(&self, url: &Url) -> Result<FetchedPage, WebIdentityError> {
+        self.fetch_page(url)
+    }
+
+    fn parser(&self) -> &IdentityParser {
+        &self.options.parser
+    }
+}
+
+/// Fetches and parses the identity at `location` over HTTP without an async runtime, for CLI
+/// tools and synchronous servers.
+///
+/// # Errors
+/// Returns [`WebIdentityError::Fetch`] if the page can't be retrieved,
+/// [`WebIdentityError::HttpStatus`] if the server doesn't respond with a success status,
+/// [`WebIdentityError::RedirectNotAllowed`] if it redirects elsewhere,
+/// [`WebIdentityError::UnexpectedContentType`] if it isn't HTML,
+/// [`WebIdentityError::BodyTooLarge`] if it is larger than [`MAX_IDENTITY_PAGE_SIZE`], or `Err`
+/// if the location is invalid or the page isn't a valid identity.
+#[cfg(feature = "blocking")]
+pub fn fetch_identity_blocking(location: &str) -> Result<Identity, WebIdentityError> {
+    BlockingFetcher::new().fetch_identity(location)
+}
+
+/// Like [`fetch_identity_blocking`], but with a configured `ureq` agent, e.g. with timeouts or
+/// a proxy.
+///
+/// # Errors
+/// Same as [`fetch_identity_blocking`].
+#[cfg(feature = "blocking")]
+pub fn fetch_identity_blocking_with_agent(
+    agent: &ureq::Agent,
+    location: &str,
+) -> Result<Identity, WebIdentityError> {
+    BlockingFetcher::with_agent(agent.clone()).fetch_identity(location)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{StaticFetcher, TestIdentity};
+    use crate::CachingResolver;
+
+    #[test]
+    fn sends_descriptive_user_agent_by_default() {
+        assert_eq!(
+            FetchOptions::new().user_agent(),
+            format!("webidentity-rs/{}", env!("CARGO_PKG_VERSION"))
+        );
+    }
+
+    #[test]
+    fn exposes_certificate_fingerprint() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let url = resolve_location_url("alice.example.com").unwrap();
+        let page = FetchedPage {
+            content_type: Some("text/html".into()),
+            body: alice.page.into_bytes(),
+            peer_certificate: Some(b"certificate".to_vec()),
+        };
+
+        let fingerprint = hex::encode(Sha256::digest(b"certificate"));
+        assert_eq!(page.certificate_fingerprint(), Some(fingerprint.clone()));
+        let identity = parse_response(default_parser(), &url, &page).unwrap();
+        assert_eq!(identity.certificate_fingerprint, Some(fingerprint));
+
+        let plain = identity_from_response(&url, None, &page.body).unwrap();
+        assert_eq!(plain.certificate_fingerprint, None);
+    }
+
+    #[test]
+    fn rejects_non_html_responses() {
+        assert!(check_content_type(Some("text/html; charset=utf-8")).is_ok());
+        assert!(check_content_type(None).is_ok());
+        assert!(matches!(
+            check_content_type(Some("application/json")),
+            Err(WebIdentityError::UnexpectedContentType(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn parses_with_fetcher_parser() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let fetcher = StaticFetcher::new().with_identity(&alice);
+
+        let identity = fetch_identity_with(&fetcher, "alice.example.com")
+            .await
+            .unwrap();
+        assert_eq!(identity.id, alice.identity.id);
+        assert!(identity.fetched_at.is_some());
+    }
+
+    #[test]
+    fn resolves_through_caching_resolver() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let resolver = CachingResolver::new(FetcherResolver::new(
+            StaticFetcher::new().with_identity(&alice),
+        ));
+
+        for _ in 0..2 {
+            let identity = resolver.resolve_identity("alice.example.com").unwrap();
+            assert_eq!(identity.id, alice.identity.id);
+        }
+        assert_eq!(
+            resolver.inner().fetcher().fetch_count("alice.example.com"),
+            1
+        );
+    }
+
+    #[cfg(any(feature = "reqwest", feature = "surf", feature = "blocking"))]
+    #[test]
+    fn matches_credential_host_patterns() {
+        assert!(host_matches(
+            "directory.corp.example",
+            "Directory.corp.example"
+        ));
+        assert!(host_matches("*.corp.example", "directory.corp.example"));
+        assert!(host_matches("*.corp.example", "a.b.corp.example"));
+        assert!(!host_matches("*.corp.example", "corp.example"));
+        assert!(!host_matches("*.corp.example", "evilcorp.example"));
+        assert!(!host_matches(
+            "directory.corp.example",
+            "directory.corp.example.evil"
+        ));
+    }
+
+    #[cfg(any(feature = "reqwest", feature = "surf", feature = "blocking"))]
+    #[test]
+    fn sends_credentials_only_to_the_same_origin() {
+        use base64::prelude::{Engine, BASE64_STANDARD};
+
+        let options = FetchOptions::new()
+            .with_header("Authorization", "Bearer leaked")
+            .with_credentials(
+                "*.corp.example",
+                Credentials::Basic {
+                    username: "crawler".into(),
+                    password: "hunter2".into(),
+                },
+            );
+        let authorization = |url: &str, current: &str| {
+            let url = Url::parse(url).unwrap();
+            let current = Url::parse(current).unwrap();
+            options
+                .request_headers(&url, &current)
+                .into_iter()
+                .filter(|(name, _)| *name == "Authorization")
+                .map(|(_, value)| value)
+                .collect::<Vec<_>>()
+        };
+
+        let page = "https://directory.corp.example/alice";
+        assert_eq!(
+            authorization(page, "https://directory.corp.example/people/alice"),
+            vec![format!(
+                "Basic {}",
+                BASE64_STANDARD.encode("crawler:hunter2")
+            )]
+        );
+        assert!(authorization(page, "https://other.corp.example/alice").is_empty());
+        assert!(authorization(page, "http://directory.corp.example/alice").is_empty());
+        assert!(authorization("https://alice.example.com", "https://alice.example.com").is_empty());
+    }
+
+    #[test]
+    fn never_prints_credentials() {
+        let credentials = format!(
+            "{:?}",
+            FetchOptions::new().with_credentials("*", Credentials::Bearer("s3cr3t".into()))
+        );
+        assert!(!credentials.contains("s3cr3t"));
+    }
+
+    #[cfg(feature = "blocking")]
+    mod blocking {
+        use super::*;
+        use crate::testing::{serve_identity, ServedPage};
+        use std::collections::HashMap;
+
+        #[test]
+        fn follows_same_host_redirect() {
+            let alice = TestIdentity::generate("alice.example.com");
+            let server = serve_identity(HashMap::from([
+                ("/old".to_string(), ServedPage::redirect("/alice")),
+                ("/alice".to_string(), ServedPage::html(alice.page.clone())),
+            ]))
+            .unwrap();
+
+            let identity = fetch_identity_blocking(&server.location("/old")).unwrap();
+            assert_eq!(identity.public_key, alice.identity.public_key);
+        }
+
+        #[test]
+        fn rejects_cross_host_redirect() {
+            let server = serve_identity(HashMap::from([(
+                "/old".to_string(),
+                ServedPage::redirect("http://localhost:1/alice"),
+            )]))
+            .unwrap();
+
+            assert!(matches!(
+                fetch_identity_blocking(&server.location("/old")),
+                Err(WebIdentityError::RedirectNotAllowed(_))
+            ));
+        }
+
+        #[test]
+        fn rejects_any_redirect_when_off() {
+            let alice = TestIdentity::generate("alice.example.com");
+            let server = serve_identity(HashMap::from([
+                ("/old".to_string(), ServedPage::redirect("/alice")),
+                ("/alice".to_string(), ServedPage::html(alice.page.clone())),
+            ]))
+            .unwrap();
+            let fetcher = BlockingFetcher::new()
+                .with_options(FetchOptions::new().with_redirect_policy(RedirectPolicy::None));
+
+            assert!(matches!(
+                fetcher.fetch_identity(&server.location("/old")),
+                Err(WebIdentityError::RedirectNotAllowed(target)) if target.ends_with("/alice")
+            ));
+            assert_eq!(server.requests().len(), 1);
+        }
+
+        #[test]
+        fn never_sends_credentials_cross_origin() {
+            let alice = TestIdentity::generate("alice.example.com");
+            let server = serve_identity(HashMap::from([(
+                "/alice".to_string(),
+                ServedPage::html(alice.page.clone()),
+            )]))
+            .unwrap();
+            let elsewhere = server.location("/alice").replace("127.0.0.1", "localhost");
+            let origin = serve_identity(HashMap::from([(
+                "/old".to_string(),
+                ServedPage::redirect(elsewhere),
+            )]))
+            .unwrap();
+            let fetcher = BlockingFetcher::new().with_options(
+                FetchOptions::new()
+                    .with_redirect_policy(RedirectPolicy::Limited(1))
+                    .with_credentials("127.0.0.1", Credentials::Bearer("t0k3n".into()))
+                    .with_credentials("localhost", Credentials::Bearer("t0k3n".into())),
+            );
+
+            fetcher.fetch_identity(&origin.location("/old")).unwrap();
+            assert_eq!(
+                origin.requests()[0].header("Authorization"),
+                Some("Bearer t0k3n")
+            );
+            assert_eq!(server.requests()[0].header("Authorization"), None);
+        }
+
+        #[test]
+        fn reports_error_status_and_limits_size() {
+            let server = serve_identity(HashMap::from([(
+                "/large".to_string(),
+                ServedPage::html("x".repeat(MAX_IDENTITY_PAGE_SIZE as usize + 1)),
+            )]))
+            .unwrap();
+
+            assert!(matches!(
+                fetch_identity_blocking(&server.location("/missing")),
+                Err(WebIdentityError::HttpStatus(404))
+            ));
+            assert!(matches!(
+                fetch_identity_blocking(&server.location("/large")),
+                Err(WebIdentityError::BodyTooLarge(_))
+            ));
+        }
+
+        #[test]
+        fn sends_user_agent_and_extra_headers() {
+            let alice = TestIdentity::generate("alice.example.com");
+            let server = serve_identity(HashMap::from([(
+                "/alice".to_string(),
+                ServedPage::html(alice.page.clone()),
+            )]))
+            .unwrap();
+            let fetcher = BlockingFetcher::new().with_options(
+                FetchOptions::new()
+                    .with_user_agent("directory-crawler/2.0 (ops@example.com)")
+                    .with_header("From", "ops@example.com")
+                    .with_header("User-Agent", "ignored"),
+            );
+
+            fetcher.fetch_identity(&server.location("/alice")).unwrap();
+            let request = &server.requests()[0];
+            assert_eq!(
+                request.header("User-Agent"),
+                Some("directory-crawler/2.0 (ops@example.com)")
+            );
+            assert_eq!(request.header("From"), Some("ops@example.com"));
+            assert!(request.header("Accept").unwrap().contains("text/html"));
+        }
+
+        #[test]
+        fn authenticates_to_matching_hosts() {
+            let alice = TestIdentity::generate("alice.example.com");
+            let server = serve_identity(HashMap::from([
+                ("/old".to_string(), ServedPage::redirect("/alice")),
+                ("/alice".to_string(), ServedPage::html(alice.page.clone())),
+            ]))
+            .unwrap();
+            let fetcher = BlockingFetcher::new().with_options(
+                FetchOptions::new()
+                    .with_credentials("127.0.0.1", Credentials::Bearer("t0k3n".into())),
+            );
+
+            fetcher.fetch_identity(&server.location("/old")).unwrap();
+            for request in server.requests() {
+                assert_eq!(request.header("Authorization"), Some("Bearer t0k3n"));
+            }
+        }
+
+        #[test]
+        fn resolves_without_runtime() {
+            let alice = TestIdentity::generate("alice.example.com");
+            let server = serve_identity(HashMap::from([(
+                "/alice".to_string(),
+                ServedPage::html(alice.page.clone()),
+            )]))
+            .unwrap();
+
+            let resolver = FetcherResolver::new(BlockingFetcher::new());
+            let identity = resolver
+                .resolve_identity(&server.location("/alice"))
+                .unwrap();
+            assert_eq!(identity.id, alice.identity.id);
+        }
+    }
+
+    #[cfg(feature = "surf")]
+    mod surf {
+        use super::*;
+        use crate::testing::{serve_identity, ServedPage};
+        use crate::IdentityResolver;
+        use std::collections::HashMap;
+        use std::time::Duration;
+
+        #[test]
+        fn fetches_on_smol() {
+            let alice = TestIdentity::generate("alice.example.com");
+            let server = serve_identity(HashMap::from([
+                ("/old".to_string(), ServedPage::redirect("/alice")),
+                ("/alice".to_string(), ServedPage::html(alice.page.clone())),
+            ]))
+            .unwrap();
+
+            let identity = smol::block_on(fetch_identity_with(
+                &SurfFetcher::new(),
+                &server.location("/old"),
+            ))
+            .unwrap();
+            assert_eq!(identity.id, alice.identity.id);
+            assert_eq!(
+                server.requests()[0].header("User-Agent"),
+                Some(DEFAULT_USER_AGENT)
+            );
+            assert!(matches!(
+                smol::block_on(fetch_identity_with(
+                    &SurfFetcher::new(),
+                    &server.location("/missing"),
+                )),
+                Err(WebIdentityError::HttpStatus(404))
+            ));
+        }
+
+        #[test]
+        fn refreshes_on_the_smol_blocking_pool() {
+            let alice = TestIdentity::generate("alice.example.com");
+            let server = serve_identity(HashMap::from([(
+                "/alice".to_string(),
+                ServedPage::html(alice.page.clone()),
+            )]))
+            .unwrap();
+            let location = server.location("/alice");
+            let resolver = Arc::new(
+                CachingResolver::new(FetcherResolver::new(SurfFetcher::new()))
+                    .with_ttl(Duration::ZERO)
+                    .with_stale_window(Duration::from_secs(60))
+                    .with_spawner(|task: Box<dyn FnOnce() + Send>| smol::unblock(task).detach()),
+            );
+
+            smol::block_on(async {
+                for _ in 0..2 {
+                    let resolver = Arc::clone(&resolver);
+                    let location = location.clone();
+                    let identity = smol::unblock(move || resolver.resolve_identity(&location))
+                        .await
+                        .unwrap();
+                    assert_eq!(identity.id, alice.identity.id);
+                }
+                // The stale identity was returned while it is refreshed in the background
+                while server.requests().len() < 2 {
+                    smol::Timer::after(Duration::from_millis(10)).await;
+                }
+            });
+        }
+    }
+
+    #[cfg(feature = "reqwest")]
+    mod http {
+        use super::*;
+        use crate::testing::{serve_identity, ServedPage};
+        use crate::KeyPrefix;
+        use std::collections::HashMap;
+
+        #[tokio::test]
+        async fn follows_same_host_redirect() {
+            let alice = TestIdentity::generate("alice.example.com");
+            let server = serve_identity(HashMap::from([
+                ("/old".to_string(), ServedPage::redirect("/alice")),
+                ("/alice".to_string(), ServedPage::html(alice.page.clone())),
+            ]))
+            .unwrap();
+
+            let identity = fetch_identity(&server.location("/old")).await.unwrap();
+            assert_eq!(identity.public_key, alice.identity.public_key);
+            assert_eq!(identity.location_url.path(), "/old");
+            // Served over plain HTTP
+            assert_eq!(identity.certificate_fingerprint, None);
+        }
+
+        #[tokio::test]
+        async fn rejects_cross_host_redirect() {
+            let alice = TestIdentity::generate("alice.example.com");
+            let mut pages =
+                HashMap::from([("/alice".to_string(), ServedPage::html(alice.page.clone()))]);
+            let server = serve_identity(pages.clone()).unwrap();
+            // Same server, under another host name
+            let elsewhere = server.location("/alice").replace("127.0.0.1", "localhost");
+            pages.insert("/old".to_string(), ServedPage::redirect(elsewhere.clone()));
+            let server = serve_identity(pages).unwrap();
+
+            assert!(matches!(
+                fetch_identity(&server.location("/old")).await,
+                Err(WebIdentityError::RedirectNotAllowed(target)) if target.starts_with("http://localhost:")
+            ));
+        }
+
+        #[test]
+        fn resolves_on_runtime() {
+            let alice = TestIdentity::generate("alice.example.com");
+            let server = serve_identity(HashMap::from([(
+                "/alice".to_string(),
+                ServedPage::html(alice.page.clone()),
+            )]))
+            .unwrap();
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+
+            let resolver =
+                FetcherResolver::with_runtime(HttpFetcher::new(), runtime.handle().clone());
+            let identity = resolver
+                .resolve_identity(&server.location("/alice"))
+                .unwrap();
+            assert_eq!(identity.id, alice.identity.id);
+        }
+
+        #[tokio::test]
+        async fn sends_default_user_agent() {
+            let server = serve_identity(HashMap::new()).unwrap();
+            let fetcher = HttpFetcher::new()
+                .with_options(FetchOptions::new().with_header("X-Crawler-Contact", "ops"));
+
+            let _ = fetch_identity_with(&fetcher, &server.location("/alice")).await;
+            let request = &server.requests()[0];
+            assert_eq!(request.header("User-Agent"), Some(DEFAULT_USER_AGENT));
+            assert_eq!(request.header("X-Crawler-Contact"), Some("ops"));
+        }
+
+        #[tokio::test]
+        async fn reports_error_status() {
+            let server = serve_identity(HashMap::new()).unwrap();
+            assert!(matches!(
+                fetch_identity(&server.location("/missing")).await,
+                Err(WebIdentityError::HttpStatus(404))
+            ));
+        }
+
+        #[tokio::test]
+        async fn parses_with_configured_identity_options() {
+            let alice = TestIdentity::generate("alice.example.com");
+            let server = serve_identity(HashMap::from([(
+                "/alice".to_string(),
+                ServedPage::html(alice.page.clone()),
+            )]))
+            .unwrap();
+            let options = IdentityOptions::default().with_key_prefixes(&[KeyPrefix::X25519]);
+            let fetcher =
+                HttpFetcher::new().with_options(FetchOptions::new().with_identity_options(options));
+
+            assert!(fetch_identity_with(&fetcher, &server.location("/alice"))
+                .await
+                .is_err());
+            assert!(
+                fetch_identity_with(&HttpFetcher::new(), &server.location("/alice"))
+                    .await
+                    .is_ok()
+            );
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use super::error::{SignatureError, WebIdentityError};
+use super::sign::{canonical_host, HeaderProvider};
+
+/// Derives the host a client sent its request to, for servers behind a reverse proxy, so it
+/// can be passed to [`verify_request`](crate::verify_request) as the signed host.
+///
+/// When `trusted_proxy` is `true`, the caller asserts the request came from its own proxy, and
+/// the host is read from, in order:
+/// 1. the `host` parameter of the last element of the RFC 7239 `Forwarded` header,
+/// 2. the last value of `X-Forwarded-Host`, with `X-Forwarded-Port` appended unless it is the
+///    default port for `X-Forwarded-Proto` (`https` if missing),
+/// 3. the `Host` header.
+///
+/// The last element is the one added by the trusted proxy, earlier ones may have been sent by
+/// the client. When `trusted_proxy` is `false`, forwarding headers are ignored since anyone can
+/// send them, and only `Host` is used.
+///
+/// The host must be one of `allowed_hosts` (compared case-insensitively, including the port if
+/// any), and the matching entry is returned. An empty allowlist rejects every host.
+///
+/// # Errors
+/// Returns `Err` if no host header is present, a forwarding header was sent more than once, or
+/// the host isn't allowed.
+pub fn derive_external_host(
+    headers: &impl HeaderProvider,
+    trusted_proxy: bool,
+    allowed_hosts: &[&str],
+) -> Result<String, WebIdentityError> {
+    let forwarded = if trusted_proxy {
+        forwarded_host(headers)?.or(x_forwarded_host(headers)?)
+    } else {
+        None
+    };
+    let host = match forwarded {
+        Some(host) => host,
+        None => single_header(headers, "Host")?
+            .ok_or_else(|| SignatureError::MissingHeader("Host".into()))?
+            .trim()
+            .to_string(),
+    };
+    check_allowed_host(&host, allowed_hosts)
+}
+
+/// Returns the entry of `allowed_hosts` matching `host`.
+pub(crate) fn check_allowed_host(
+    host: &str,
+    allowed_hosts: &[&str],
+) -> Result<String, WebIdentityError> {
+    allowed_hosts
+        .iter()
+        .find(|allowed| canonical_host(allowed).eq_ignore_ascii_case(&canonical_host(host)))
+        .map(|allowed| allowed.to_string())
+        .ok_or_else(|| WebIdentityError::HostNotAllowed(host.to_string()))
+}
+
+/// Reads the `host` parameter of the last element of the `Forwarded` header.
+fn forwarded_host(headers: &impl HeaderProvider) -> Result<Option<String>, SignatureError> {
+    let Some(forwarded) = single_header(headers, "Forwarded")? else {
+        return Ok(None);
+    };
+    let Some(element) = forwarded.rsplit(',').next() else {
+        return Ok(None);
+    };
+
+    Ok(element.split(';').find_map(|pair| {
+        let (name, value) = pair.split_once('=')?;
+        if !name.trim().eq_ignore_ascii_case("host") {
+            return None;
+        }
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .unwrap_or(value);
+        (!value.is_empty()).then(|| value.to_string())
+    }))
+}
+
+/// Reads the last value of `X-Forwarded-Host`, with a non-default `X-Forwarded-Port`.
+fn x_forwarded_host(headers: &impl HeaderProvider) -> Result<Option<String>, SignatureError> {
+    let Some(host) = single_header(headers, "X-Forwarded-Host")?
+        .and_then(|hosts| hosts.rsplit(',').next())
+        .map(str::trim)
+        .filter(|host| !host.is_empty())
+    else {
+        return Ok(None);
+    };
+
+    // A port is already present unless the host ends with an IPv6 literal
+    let has_port = host
+        .rsplit_once(':')
+        .is_some_and(|(_, port)| !port.contains(']'));
+    let port = last_value(single_header(headers, "X-Forwarded-Port")?);
+    let proto = last_value(single_header(headers, "X-Forwarded-Proto")?).unwrap_or("https");
+    let default_port = if proto.eq_ignore_ascii_case("http") {
+        "80"
+    } else {
+        "443"
+    };
+
+    Ok(Some(match port {
+        Some(port) if !has_port && port != default_port => format!("{}:{}", host, port),
+        _ => host.to_string(),
+    }))
+}
+
+fn last_value(header: Option<&str>) -> Option<&str> {
+    header
+        .and_then(|values| values.rsplit(',').next())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+/// Gets a header the host is derived from, rejecting it if it was sent more than once, since
+/// the proxy and the client may each have sent one.
+fn single_header<'a>(
+    headers: &'a impl HeaderProvider,
+    name: &str,
+) -> Result<Option<&'a str>, SignatureError> {
+    if headers.has_duplicate_header(name) {
+        return Err(SignatureError::DuplicateHeader(name.to_string()));
+    }
+    Ok(headers.get_header(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sign::MultiHeaderProvider;
+
+    const ALLOWED: &[&str] = &[
+        "api.example.com",
+        "api.example.com:8443",
+        "[2001:db8::1]:8080",
+    ];
+
+    fn headers(pairs: &[(&str, &str)]) -> MultiHeaderProvider {
+        let mut headers = MultiHeaderProvider::new();
+        for (name, value) in pairs {
+            headers
+                .entry(name.to_string())
+                .or_default()
+                .push(value.to_string());
+        }
+        headers
+    }
+
+    fn derive(pairs: &[(&str, &str)], trusted_proxy: bool) -> Result<String, WebIdentityError> {
+        derive_external_host(&headers(pairs), trusted_proxy, ALLOWED)
+    }
+
+    #[test]
+    fn ignores_forwarding_headers_from_untrusted_peers() {
+        let spoofed = [
+            ("Host", "api.example.com"),
+            ("Forwarded", "host=evil.example"),
+            ("X-Forwarded-Host", "evil.example"),
+        ];
+        assert_eq!(derive(&spoofed, false).unwrap(), "api.example.com");
+
+        // Even an allowed host can't be claimed through them
+        let spoofed = [
+            ("Host", "internal.example"),
+            ("X-Forwarded-Host", "api.example.com"),
+        ];
+        assert!(matches!(
+            derive(&spoofed, false),
+            Err(WebIdentityError::HostNotAllowed(host)) if host == "internal.example"
+        ));
+    }
+
+    #[test]
+    fn reads_the_element_added_by_the_trusted_proxy() {
+        let forwarded = [
+            ("Host", "internal:8080"),
+            (
+                "Forwarded",
+                "for=1.2.3.4;host=evil.example, for=10.0.0.1;proto=https;host=\"API.example.com\"",
+            ),
+            ("X-Forwarded-Host", "evil.example"),
+        ];
+        assert_eq!(derive(&forwarded, true).unwrap(), "api.example.com");
+
+        let x_forwarded = [
+            ("Host", "internal:8080"),
+            ("X-Forwarded-Host", "evil.example, api.example.com"),
+            ("X-Forwarded-Port", "8443"),
+        ];
+        assert_eq!(derive(&x_forwarded, true).unwrap(), "api.example.com:8443");
+    }
+
+    #[test]
+    fn appends_non_default_forwarded_ports() {
+        let host = |port: &str, proto: &str| {
+            let mut pairs = vec![
+                ("X-Forwarded-Host", "api.example.com"),
+                ("X-Forwarded-Port", port),
+            ];
+            if !proto.is_empty() {
+                pairs.push(("X-Forwarded-Proto", proto));
+            }
+            derive(&pairs, true)
+        };
+        assert_eq!(host("443", "").unwrap(), "api.example.com");
+        assert_eq!(host("80", "http").unwrap(), "api.example.com");
+        assert!(matches!(
+            host("80", "https"),
+            Err(WebIdentityError::HostNotAllowed(host)) if host == "api.example.com:80"
+        ));
+
+        let ipv6 = [
+            ("X-Forwarded-Host", "[2001:db8::1]"),
+            ("X-Forwarded-Port", "8080"),
+        ];
+        assert_eq!(derive(&ipv6, true).unwrap(), "[2001:db8::1]:8080");
+        let with_port = [
+            ("X-Forwarded-Host", "[2001:db8::1]:8080"),
+            ("X-Forwarded-Port", "9999"),
+        ];
+        assert_eq!(derive(&with_port, true).unwrap(), "[2001:db8::1]:8080");
+    }
+
+    #[test]
+    fn rejects_repeated_forwarding_headers() {
+        let repeated = [
+            ("Host", "api.example.com"),
+            ("X-Forwarded-Host", "api.example.com"),
+            ("X-Forwarded-Host", "evil.example"),
+        ];
+        assert!(matches!(
+            derive(&repeated, true),
+            Err(WebIdentityError::Signature(SignatureError::DuplicateHeader(name)))
+                if name == "X-Forwarded-Host"
+        ));
+
+        let repeated = [("Host", "api.example.com"), ("Host", "evil.example")];
+        assert!(matches!(
+            derive(&repeated, false),
+            Err(WebIdentityError::Signature(
+                SignatureError::DuplicateHeader(_)
+            ))
+        ));
+    }
+
+    #[test]
+    fn checks_the_allowlist() {
+        assert_eq!(
+            derive(&[("Host", "API.Example.COM.")], false).unwrap(),
+            "api.example.com"
+        );
+        assert!(matches!(
+            derive(&[], false),
+            Err(WebIdentityError::Signature(SignatureError::MissingHeader(name))) if name == "Host"
+        ));
+        assert!(matches!(
+            derive_external_host(&headers(&[("Host", "api.example.com")]), false, &[]),
+            Err(WebIdentityError::HostNotAllowed(_))
+        ));
+    }
+}
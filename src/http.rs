@@ -0,0 +1,90 @@
+use super::error::{SignatureError, WebIdentityError};
+use super::forwarded::{check_allowed_host, derive_external_host};
+use super::sign::{verify_request_with_options, HeaderProvider, VerifyOptions};
+use http::{HeaderMap, Request};
+
+impl HeaderProvider for HeaderMap {
+    fn get_header(&self, name: &str) -> Option<&str> {
+        self.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    fn has_duplicate_header(&self, name: &str) -> bool {
+        self.get_all(name).iter().nth(1).is_some()
+    }
+
+    fn header_names(&self) -> Option<Vec<&str>> {
+        Some(self.keys().map(|name| name.as_str()).collect())
+    }
+}
+
+/// Verifies a signed request from the `http` crate against a public key, as configured by
+/// `options`.
+///
+/// The method, body and `WebIdentity-*` headers are read from the request. The signed host is
+/// the authority of the request URI (including the port, if any) when it has one, as with
+/// absolute-form and HTTP/2 requests, otherwise the `Host` header. The signed path is the
+/// URI's path and query.
+///
+/// # Errors
+/// Returns `Err` if the request has no host, any header is missing, the timestamp is invalid
+/// or outside the allowed window, or the signature is incorrect.
+pub fn verify_http_request<B: AsRef<[u8]>>(
+    request: &Request<B>,
+    public_key_bytes: &[u8],
+    options: &VerifyOptions,
+) -> Result<(), WebIdentityError> {
+    verify_with_host(request, request_host(request)?, public_key_bytes, options)
+}
+
+/// Like [`verify_http_request`], for servers behind a reverse proxy.
+///
+/// When `trusted_proxy` is `true`, the signed host is derived from the forwarding headers as
+/// described in [`derive_external_host`]. Otherwise it is read from the request as in
+/// [`verify_http_request`]. Either way it must be one of `allowed_hosts`.
+///
+/// # Errors
+/// Returns `Err` if the host is missing or not allowed, any header is missing, the timestamp
+/// is invalid or outside the allowed window, or the signature is incorrect.
+pub fn verify_http_request_behind_proxy<B: AsRef<[u8]>>(
+    request: &Request<B>,
+    trusted_proxy: bool,
+    allowed_hosts: &[&str],
+    public_key_bytes: &[u8],
+    options: &VerifyOptions,
+) -> Result<(), WebIdentityError> {
+    let host = if trusted_proxy {
+        derive_external_host(request.headers(), true, allowed_hosts)?
+    } else {
+        check_allowed_host(request_host(request)?, allowed_hosts)?
+    };
+    verify_with_host(request, &host, public_key_bytes, options)
+}
+
+fn request_host<B>(request: &Request<B>) -> Result<&str, SignatureError> {
+    match request.uri().authority() {
+        Some(authority) => Ok(authority.as_str()),
+        None => request
+            .headers()
+            .get_header("Host")
+            .ok_or_else(|| SignatureError::MissingHeader("Host".into())),
+    }
+}
+
+fn verify_with_host<B: AsRef<[u8]>>(
+    request: &Request<B>,
+    host: &str,
+    public_key_bytes: &[u8],
+    options: &VerifyOptions,
+) -> Result<(), WebIdentityError> {
+    let path = request.uri().path_and_query().map_or("/", |p| p.as_str());
+
+    verify_request_with_options(
+        request.method().as_str(),
+        host,
+        path,
+        request.body().as_ref(),
+        request.headers(),
+        public_key_bytes,
+        options,
+    )
+}
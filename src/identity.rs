@@ -1,29 +1,266 @@
-use crate::sign::as_array;
+use crate::sign::{as_array, strip_hex_prefix};
 
 use super::error::WebIdentityError;
+use super::public_key::PublicKey;
+use super::resolve::resolve_location_url;
+use super::sign::RequestSigner;
+use curve25519_dalek::montgomery::MontgomeryPoint;
 use ed25519_dalek::VerifyingKey;
-use lol_html::{element, HtmlRewriter, Settings};
+use lol_html::errors::RewritingError;
+use lol_html::html_content::Element;
+use lol_html::{ElementContentHandlers, HtmlRewriter, MemorySettings, Selector, Settings};
+use serde::Deserialize;
+use serde_json::Value;
 use sha2::{Digest, Sha256};
+use std::borrow::Cow;
 use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ops::BitOr;
+use std::panic;
 use std::rc::Rc;
+use std::time::{Duration, SystemTime};
 use url::Url;
 
-const PK_PREFIX: &str = "ed25519-pub:";
+pub(crate) const PK_PREFIX: &str = "ed25519-pub:";
+
+/// The prefix of X25519 keys, which are converted to Ed25519 keys when parsed.
+const X25519_PK_PREFIX: &str = "x25519-pub:";
+
+/// A format of the keys listed on identity pages, named after its prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum KeyPrefix {
+    /// `ed25519-pub:<hex>`
+    Ed25519,
+    /// `x25519-pub:<hex>`, converted to the Ed25519 key that verifies its XEdDSA signatures
+    X25519,
+}
+
+impl KeyPrefix {
+    /// Every supported format, which are all accepted by default.
+    pub const ALL: &'static [KeyPrefix] = &[KeyPrefix::Ed25519, KeyPrefix::X25519];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyPrefix::Ed25519 => PK_PREFIX,
+            KeyPrefix::X25519 => X25519_PK_PREFIX,
+        }
+    }
+}
+
+/// The newest version of the identity page rules this library understands, declared by pages
+/// with the `identity:version` meta tag.
+///
+/// Version 1 pages list a single key with the `identity:*` tags. Version 2 adds several keys,
+/// thresholds, backup locations and the `identity` JSON tag. Pages that declare version 1 but
+/// use any of these are rejected with [`WebIdentityError::SpecVersionMismatch`], rather than
+/// read with rules their author didn't write them for. Pages without a version are read with
+/// the current rules.
+pub const SPEC_VERSION: u32 = 2;
+
+const PARSE_CHUNK_SIZE: usize = 8 * 1024;
 
 #[derive(Debug, Clone)]
 pub struct Identity {
     pub id: String,
-    pub public_key: Vec<u8>,
+    /// The primary public key, the first one listed on the page
+    pub public_key: PublicKey,
+    /// Every public key listed on the page, starting with the primary one
+    pub public_keys: Vec<PublicKey>,
+    /// How many distinct keys must sign a request, for identities shared by several people
+    pub threshold: Option<u8>,
     pub display_name: String,
     pub avatar: Option<Url>,
     pub description: Option<String>,
+    /// Descriptions in other languages, from `identity:description:<lang>` tags, keyed by the
+    /// lowercase language tag
+    pub descriptions: HashMap<String, String>,
     pub location_url: Url,
     pub location: String,
+    /// Mirrors of the identity page, tried by [`MirrorResolver`](crate::MirrorResolver) when
+    /// the location is unavailable
+    pub backup_locations: Vec<String>,
+    /// The version of the rules the page was written for, from the `identity:version` tag
+    pub spec_version: Option<u32>,
+    /// Other profiles of the same person, from the page's `rel="me"` links. They are only
+    /// claims until [`Identity::is_linked_back`] confirms a profile links back
+    pub verified_links: Vec<Url>,
+    /// Where the identity's keys were read from
+    pub source: IdentitySource,
+    /// When the identity was read by a resolver, `None` for identities parsed directly, e.g.
+    /// with [`get_identity`]
+    pub fetched_at: Option<SystemTime>,
+    /// The hex-encoded SHA-256 fingerprint of the TLS certificate the page was served with,
+    /// when it was fetched over HTTPS by a fetcher that exposes it (see
+    /// [`FetchedPage::peer_certificate`](crate::FetchedPage::peer_certificate)), e.g. to pin
+    /// it on first use
+    pub certificate_fingerprint: Option<String>,
+}
+
+/// Where an [`Identity`] was read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[non_exhaustive]
+pub enum IdentitySource {
+    /// The identity page
+    #[default]
+    Page,
+    /// A `_webidentity` DNS TXT record, see [`DnsTxtResolver`](crate::DnsTxtResolver)
+    DnsTxt,
+    /// A CWT, see [`Identity::from_cwt`]
+    Cwt,
+}
+
+impl Identity {
+    /// Parses the primary public key, so it can be kept for verifying many requests with
+    /// [`verify_request_with_key`](crate::verify_request_with_key).
+    ///
+    /// # Errors
+    /// Returns `Err` if `public_key` is not a valid Ed25519 key.
+    pub fn verifying_key(&self) -> Result<VerifyingKey, WebIdentityError> {
+        to_verifying_key(&self.public_key)
+    }
+
+    /// Lists the identity's keys with their fingerprints, starting with the primary one.
+    pub fn keys(&self) -> impl Iterator<Item = KeyInfo<'_>> {
+        self.public_keys
+            .iter()
+            .enumerate()
+            .map(|(i, public_key)| KeyInfo {
+                public_key,
+                fingerprint: identity_id(public_key),
+                is_primary: i == 0,
+            })
+    }
+
+    /// Whether the identity was read more than `ttl` ago, so it should be resolved again before
+    /// it is trusted.
+    ///
+    /// Identities without a [`fetched_at`](Identity::fetched_at) time are always stale, since
+    /// their age is unknown.
+    pub fn is_stale(&self, ttl: Duration) -> bool {
+        match self.fetched_at {
+            // A time in the future means the clock went back, the identity is recent
+            Some(fetched_at) => fetched_at.elapsed().is_ok_and(|age| age > ttl),
+            None => true,
+        }
+    }
+
+    /// A compact handle to show instead of the full location.
+    ///
+    /// The host is used without a leading `www.`. An identity at the root of its host is shown
+    /// as the host (`example.com`), one a single path segment deep as `@user@host`
+    /// (`example.com/amy` and `example.com/~amy` are `@amy@example.com`), and deeper ones as
+    /// the host and path (`example.com/people/amy`).
+    ///
+    /// The `www.` label is dropped like [`strip_www`] does, and a segment with no user name
+    /// left once its `~` or `@` is removed is shown as a path.
+    pub fn handle(&self) -> String {
+        let host = self.location_url.host_str().unwrap_or_default();
+        let host = strip_www(host.strip_suffix('.').unwrap_or(host));
+        let path = self.location_url.path().trim_matches('/');
+        let user = path.trim_start_matches(['~', '@']);
+
+        if path.is_empty() {
+            host.to_string()
+        } else if !path.contains('/') && !user.is_empty() {
+            format!("@{}@{}", user, host)
+        } else {
+            format!("{}/{}", host, path)
+        }
+    }
+
+    /// A stable seed for rendering a placeholder avatar (identicon), the same in every app.
+    ///
+    /// It is the SHA-256 hash of the primary key, the bytes of [`Identity::id`].
+    pub fn identicon_seed(&self) -> [u8; 32] {
+        IdentityIdHash::digest(self.public_key).into()
+    }
+
+    /// An RGB color derived from [`Identity::identicon_seed`], for a placeholder avatar's
+    /// background.
+    pub fn identicon_color(&self) -> [u8; 3] {
+        let seed = self.identicon_seed();
+        [seed[0], seed[1], seed[2]]
+    }
+
+    /// Picks the description for the first of `languages` (in order of preference) the identity
+    /// has one in, falling back to the default description.
+    ///
+    /// A language matches an exact tag (`fr-ca`) first, then its primary subtag (`fr`).
+    pub fn description_for(&self, languages: &[&str]) -> Option<&str> {
+        languages
+            .iter()
+            .find_map(|lang| {
+                let lang = lang.to_ascii_lowercase();
+                let primary = lang.split('-').next().unwrap_or(&lang);
+                self.descriptions
+                    .get(&lang)
+                    .or_else(|| self.descriptions.get(primary))
+            })
+            .or(self.description.as_ref())
+            .map(String::as_str)
+    }
+
+    /// Checks that the identity can be used to sign requests with `signer`, for clients to
+    /// run once at startup instead of having every request rejected.
+    ///
+    /// The signer's key must be listed, every listed key must be a valid Ed25519 key, the
+    /// display name must not be blank and the threshold, if any, must be reachable.
+    ///
+    /// # Errors
+    /// Returns `Err` describing the first problem found.
+    pub fn self_check(&self, signer: &impl RequestSigner) -> Result<(), WebIdentityError> {
+        let signing_key = signer.verifying_key();
+        if !self
+            .public_keys
+            .iter()
+            .any(|key| key[..] == signing_key.as_bytes()[..])
+        {
+            return Err(WebIdentityError::KeyNotListed(identity_id(
+                signing_key.as_bytes(),
+            )));
+        }
+        for key in &self.public_keys {
+            to_verifying_key(key)?;
+        }
+
+        if self.display_name.trim().is_empty() {
+            return Err(WebIdentityError::MissingDisplayName);
+        }
+        if let Some(threshold) = self.threshold {
+            if threshold == 0 || threshold as usize > self.public_keys.len() {
+                return Err(WebIdentityError::InvalidThreshold(threshold.to_string()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A key listed on an identity, from [`Identity::keys`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyInfo<'a> {
+    pub public_key: &'a [u8],
+    /// The key's fingerprint, as sent in the `WebIdentity-Key` header
+    pub fingerprint: String,
+    /// Whether this is the primary key, whose fingerprint is the identity's id
+    pub is_primary: bool,
+}
+
+fn to_verifying_key(public_key: &[u8]) -> Result<VerifyingKey, WebIdentityError> {
+    let bytes = as_array::<u8, 32>(public_key).ok_or(WebIdentityError::InvalidPublicKeyFormat(
+        "Wrong key size".into(),
+    ))?;
+    VerifyingKey::from_bytes(bytes).map_err(|_| {
+        WebIdentityError::InvalidPublicKeyFormat("Not a valid Ed25519 public key.".into())
+    })
 }
 
 #[derive(Default, Debug)]
 struct RawIdentityData {
-    public_key: Option<String>,
+    public_keys: Vec<String>,
+    backup_locations: Vec<String>,
+    threshold: Option<String>,
     display_name: Option<String>,
     author: Option<String>,
     og_author: Option<String>,
@@ -31,96 +268,542 @@ struct RawIdentityData {
     avatar: Option<String>,
     og_image: Option<String>,
     favicon: Option<String>,
+    me_links: Vec<String>,
     description: Option<String>,
+    descriptions: HashMap<String, String>,
     og_description: Option<String>,
+    version: Option<String>,
+    /// The `identity` meta tag, holding the other fields as JSON
+    json: Option<String>,
+    /// Where the parser is relative to the `<head>`, tracked for
+    /// [`IdentityOptions::with_head_only`]
+    head: HeadPosition,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+enum HeadPosition {
+    #[default]
+    Before,
+    Inside,
+    After,
+}
+
+/// The fields of the `identity` meta tag's JSON, named like the `identity:*` tags.
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct JsonIdentityData {
+    #[serde(default)]
+    public_key: OneOrMany,
+    threshold: Option<Value>,
+    display_name: Option<String>,
+    avatar: Option<String>,
+    description: Option<String>,
+    #[serde(default)]
+    backup_location: OneOrMany,
+    version: Option<Value>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Default for OneOrMany {
+    fn default() -> Self {
+        OneOrMany::Many(Vec::new())
+    }
+}
+
+impl OneOrMany {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        }
+    }
+}
+
+/// Which optional fields of an identity page are parsed, see
+/// [`IdentityOptions::with_fields`].
+///
+/// The keys, threshold and version are always parsed. Fields can be combined with `|`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ParseFields(u8);
+
+impl ParseFields {
+    /// Only the keys, threshold and version, for verifiers that don't show profiles
+    pub const KEYS_ONLY: ParseFields = ParseFields(0);
+    pub const DISPLAY_NAME: ParseFields = ParseFields(1);
+    pub const AVATAR: ParseFields = ParseFields(1 << 1);
+    /// The default description and the ones in other languages
+    pub const DESCRIPTION: ParseFields = ParseFields(1 << 2);
+    pub const BACKUP_LOCATIONS: ParseFields = ParseFields(1 << 3);
+    /// The `rel="me"` links
+    pub const VERIFIED_LINKS: ParseFields = ParseFields(1 << 4);
+    pub const ALL: ParseFields = ParseFields(0b11111);
+
+    pub fn contains(self, other: ParseFields) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Whether the meta tag `name` is needed for these fields.
+    fn wants_meta(self, name: &str) -> bool {
+        match name {
+            "identity:public-key" | "identity" | "identity:threshold" | "identity:version" => true,
+            "identity:backup-location" => self.contains(Self::BACKUP_LOCATIONS),
+            "identity:display-name" | "author" | "og:author" | "og:title" => {
+                self.contains(Self::DISPLAY_NAME)
+            }
+            "identity:avatar" | "og:image" => self.contains(Self::AVATAR),
+            "identity:description" | "description" | "og:description" => {
+                self.contains(Self::DESCRIPTION)
+            }
+            name => name.starts_with("identity:description:") && self.contains(Self::DESCRIPTION),
+        }
+    }
+}
+
+impl Default for ParseFields {
+    fn default() -> Self {
+        ParseFields::ALL
+    }
+}
+
+impl BitOr for ParseFields {
+    type Output = ParseFields;
+
+    fn bitor(self, other: ParseFields) -> ParseFields {
+        ParseFields(self.0 | other.0)
+    }
+}
+
+/// Options controlling how identity pages are parsed.
+#[derive(Debug, Clone)]
+pub struct IdentityOptions {
+    preallocated_buffer_size: usize,
+    max_memory: usize,
+    fields: ParseFields,
+    https_avatars_only: bool,
+    head_only: bool,
+    key_prefixes: Vec<KeyPrefix>,
+}
+
+impl Default for IdentityOptions {
+    fn default() -> Self {
+        IdentityOptions {
+            preallocated_buffer_size: 1024,
+            max_memory: usize::MAX,
+            fields: ParseFields::ALL,
+            https_avatars_only: false,
+            head_only: false,
+            key_prefixes: KeyPrefix::ALL.to_vec(),
+        }
+    }
+}
+
+impl IdentityOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets how many bytes the HTML parser preallocates for buffering tags split across input
+    /// chunks (1 KiB by default). A few KiB avoids reallocations for typical identity pages.
+    pub fn with_preallocated_buffer_size(mut self, bytes: usize) -> Self {
+        self.preallocated_buffer_size = bytes;
+        self
+    }
+
+    /// Sets a limit on the memory the HTML parser may use (unlimited by default). Parsing fails
+    /// with [`WebIdentityError::DocumentTooComplex`] once it is exceeded.
+    ///
+    /// Servers parsing pages from untrusted hosts should set this; identity pages rarely need
+    /// more than 64 KiB, so 256 KiB to 1 MiB leaves plenty of headroom.
+    pub fn with_max_memory(mut self, bytes: usize) -> Self {
+        self.max_memory = bytes;
+        self
+    }
+
+    /// Sets which optional fields are parsed (all by default). Fields that aren't parsed are
+    /// left empty, and the display name falls back to the location.
+    ///
+    /// Verifiers that only need the keys can use [`ParseFields::KEYS_ONLY`] to skip the
+    /// profile tags and the favicon and `rel="me"` links.
+    pub fn with_fields(mut self, fields: ParseFields) -> Self {
+        self.fields = fields;
+        self
+    }
+
+    /// Leaves out avatars that aren't served over HTTPS, whichever tag they come from, so apps
+    /// can embed them in HTTPS pages without mixed content.
+    ///
+    /// Avatars are resolved against the page's URL first, so a scheme-relative
+    /// `//cdn.example/a.png` on an HTTPS page is kept, while an absolute `http://` one is not.
+    pub fn with_https_avatars_only(mut self) -> Self {
+        self.https_avatars_only = true;
+        self
+    }
+
+    /// Only reads the `identity` and `identity:*` meta tags found in the page's `<head>`, so a
+    /// key injected into the `<body>` by user-generated content can't be used to impersonate
+    /// the site owner.
+    ///
+    /// Only the first `<head>` counts, and it must be written out in the page: tags after a
+    /// `<body>` start tag, or in a page without a `<head>` tag, are ignored.
+    pub fn with_head_only(mut self) -> Self {
+        self.head_only = true;
+        self
+    }
+
+    /// Sets which key formats are accepted ([`KeyPrefix::ALL`] by default), e.g. only
+    /// [`KeyPrefix::Ed25519`] for deployments that don't want to honor converted X25519 keys.
+    ///
+    /// A page listing a key in any other format fails with
+    /// [`WebIdentityError::InvalidPublicKeyFormat`], which names the accepted prefixes.
+    pub fn with_key_prefixes(mut self, prefixes: &[KeyPrefix]) -> Self {
+        self.key_prefixes = prefixes.to_vec();
+        self
+    }
 }
 
 pub fn get_identity(source_url: &Url, content: &str) -> Result<Identity, WebIdentityError> {
-    let raw_data = Rc::new(RefCell::new(RawIdentityData::default()));
-
-    let element_content_handlers = vec![
-        element!("meta", |el| {
-            let name = el.get_attribute("name");
-            let property = el.get_attribute("property");
-            let content = el.get_attribute("content");
-
-            if let Some(content) = content {
-                // Prioritize property for OG tags, then fall back to name
-                let key = property.or(name);
-                if let Some(key) = key {
-                    let mut data = raw_data.borrow_mut();
-                    match key.as_str() {
-                        "identity:public-key" => data.public_key = Some(content),
-                        "identity:display-name" => data.display_name = Some(content),
-                        "identity:avatar" => data.avatar = Some(content),
-                        "identity:description" => data.description = Some(content),
-                        "author" => data.author = Some(content),
-                        "og:author" => data.og_author = Some(content),
-                        "og:title" => data.og_title = Some(content),
-                        "og:image" => data.og_image = Some(content),
-                        "og:description" => data.og_description = Some(content),
-                        "description" => data.description = Some(content),
-                        _ => {}
+    IdentityParser::default().parse(source_url, content.as_bytes())
+}
+
+/// Like [`get_identity`], but configured by `options`.
+pub fn get_identity_with_options(
+    source_url: &Url,
+    content: &str,
+    options: &IdentityOptions,
+) -> Result<Identity, WebIdentityError> {
+    IdentityParser::new(options.clone()).parse(source_url, content.as_bytes())
+}
+
+/// Like [`get_identity`], but also runs `extra_handlers` during the same parse of the page.
+///
+/// This allows extracting additional data (e.g. custom meta tags or JSON-LD) without parsing
+/// the page twice. The extra handlers are appended after the built-in ones, so for an element
+/// matched by both, the built-in handler runs first. They only observe the document: the
+/// rewritten output is discarded.
+///
+/// # Errors
+/// Returns `Err` if the identity is invalid, or if one of the handlers returns an error.
+pub fn get_identity_with_handlers<'h>(
+    source_url: &Url,
+    content: &str,
+    options: &IdentityOptions,
+    extra_handlers: Vec<(Cow<'_, Selector>, ElementContentHandlers<'h>)>,
+) -> Result<Identity, WebIdentityError> {
+    IdentityParser::new(options.clone()).parse_with_handlers(
+        source_url,
+        content.as_bytes(),
+        extra_handlers,
+    )
+}
+
+/// Parses identity pages with the same options, reusing what can be shared between pages.
+///
+/// Services parsing many pages should keep one parser around instead of calling
+/// [`get_identity`] for each page: the selectors are parsed once, and only the per-page
+/// rewriter is built on each call. A parser can be shared between threads.
+#[derive(Debug, Clone)]
+pub struct IdentityParser {
+    options: IdentityOptions,
+    meta_selector: Selector,
+    link_selector: Selector,
+    anchor_selector: Selector,
+    head_selector: Selector,
+    body_selector: Selector,
+}
+
+impl Default for IdentityParser {
+    fn default() -> Self {
+        IdentityParser::new(IdentityOptions::default())
+    }
+}
+
+impl IdentityParser {
+    pub fn new(options: IdentityOptions) -> Self {
+        IdentityParser {
+            options,
+            meta_selector: "meta".parse().unwrap(),
+            link_selector: "link".parse().unwrap(),
+            anchor_selector: "a[rel]".parse().unwrap(),
+            head_selector: "head".parse().unwrap(),
+            body_selector: "body".parse().unwrap(),
+        }
+    }
+
+    pub fn options(&self) -> &IdentityOptions {
+        &self.options
+    }
+
+    /// Parses the identity page `content` served from `source_url`.
+    ///
+    /// # Errors
+    /// Returns `Err` if the identity is invalid.
+    pub fn parse(&self, source_url: &Url, content: &[u8]) -> Result<Identity, WebIdentityError> {
+        self.parse_with_handlers(source_url, content, Vec::new())
+    }
+
+    /// Like [`IdentityParser::parse`], but also runs `extra_handlers`, see
+    /// [`get_identity_with_handlers`].
+    ///
+    /// # Errors
+    /// Returns `Err` if the identity is invalid, or if one of the handlers returns an error.
+    pub fn parse_with_handlers<'h>(
+        &self,
+        source_url: &Url,
+        content: &[u8],
+        extra_handlers: Vec<(Cow<'_, Selector>, ElementContentHandlers<'h>)>,
+    ) -> Result<Identity, WebIdentityError> {
+        let raw_data = Rc::new(RefCell::new(RawIdentityData::default()));
+        let (meta_data, link_data, anchor_data) = (
+            Rc::clone(&raw_data),
+            Rc::clone(&raw_data),
+            Rc::clone(&raw_data),
+        );
+        let fields = self.options.fields;
+        let head_only = self.options.head_only;
+
+        let mut element_content_handlers = vec![
+            (
+                Cow::Borrowed(&self.meta_selector),
+                ElementContentHandlers::default().element(move |el: &mut Element| {
+                    // Prioritize property for OG tags, then fall back to name
+                    let key = el
+                        .get_attribute("property")
+                        .or_else(|| el.get_attribute("name"));
+                    let Some(key) = key.filter(|key| fields.wants_meta(key)) else {
+                        return Ok(());
+                    };
+
+                    if let Some(content) = el.get_attribute("content") {
+                        let mut data = meta_data.borrow_mut();
+                        if head_only
+                            && data.head != HeadPosition::Inside
+                            && (key == "identity" || key.starts_with("identity:"))
+                        {
+                            return Ok(());
+                        }
+                        match key.as_str() {
+                            "identity:public-key" => data.public_keys.push(content),
+                            "identity:backup-location" => data.backup_locations.push(content),
+                            "identity" => data.json = Some(content),
+                            "identity:threshold" => data.threshold = Some(content),
+                            "identity:display-name" => data.display_name = Some(content),
+                            "identity:avatar" => data.avatar = Some(content),
+                            "identity:description" => data.description = Some(content),
+                            "identity:version" => data.version = Some(content),
+                            "author" => data.author = Some(content),
+                            "og:author" => data.og_author = Some(content),
+                            "og:title" => data.og_title = Some(content),
+                            "og:image" => data.og_image = Some(content),
+                            "og:description" => data.og_description = Some(content),
+                            "description" => data.description = Some(content),
+                            key => {
+                                if let Some(lang) = key.strip_prefix("identity:description:") {
+                                    data.descriptions.insert(lang.to_ascii_lowercase(), content);
+                                }
+                            }
+                        }
                     }
-                }
-            }
-            Ok(())
-        }),
-        element!("link", |el| {
-            if let Some(rel) = el.get_attribute("rel") {
-                if rel == "icon" || rel == "shortcut icon" {
-                    if let Some(href) = el.get_attribute("href") {
-                        raw_data.borrow_mut().favicon = Some(href);
+                    Ok(())
+                }),
+            ),
+            (
+                Cow::Borrowed(&self.link_selector),
+                ElementContentHandlers::default().element(move |el: &mut Element| {
+                    if let Some(rel) = el.get_attribute("rel") {
+                        if fields.contains(ParseFields::AVATAR)
+                            && (rel == "icon" || rel == "shortcut icon")
+                        {
+                            if let Some(href) = el.get_attribute("href") {
+                                link_data.borrow_mut().favicon = Some(href);
+                            }
+                        } else if fields.contains(ParseFields::VERIFIED_LINKS) && is_rel_me(&rel) {
+                            if let Some(href) = el.get_attribute("href") {
+                                link_data.borrow_mut().me_links.push(href);
+                            }
+                        }
                     }
-                }
-            }
-            Ok(())
-        }),
-    ];
-
-    let mut rewriter = HtmlRewriter::new(
-        Settings {
-            element_content_handlers,
-            ..Settings::default()
-        },
-        |_: &[u8]| {},
-    );
-    rewriter.write(content.as_bytes()).unwrap();
-    rewriter.end().unwrap();
-
-    let data = Rc::try_unwrap(raw_data).unwrap().into_inner();
-
-    // Public key (the only mandatory value)
-    let pk_hex = data.public_key.ok_or(WebIdentityError::MissingPublicKey)?;
-    if !pk_hex.starts_with(PK_PREFIX) {
-        return Err(WebIdentityError::InvalidPublicKeyFormat(format!(
-            "This server only supports keys that start with '{}'.",
-            PK_PREFIX
-        )));
+                    Ok(())
+                }),
+            ),
+            (
+                Cow::Borrowed(&self.anchor_selector),
+                ElementContentHandlers::default().element(move |el: &mut Element| {
+                    if !fields.contains(ParseFields::VERIFIED_LINKS) {
+                        return Ok(());
+                    }
+                    if el.get_attribute("rel").is_some_and(|rel| is_rel_me(&rel)) {
+                        if let Some(href) = el.get_attribute("href") {
+                            anchor_data.borrow_mut().me_links.push(href);
+                        }
+                    }
+                    Ok(())
+                }),
+            ),
+        ];
+        if head_only {
+            let (head_data, body_data) = (Rc::clone(&raw_data), Rc::clone(&raw_data));
+            element_content_handlers.push((
+                Cow::Borrowed(&self.head_selector),
+                ElementContentHandlers::default().element(move |el: &mut Element| {
+                    if head_data.borrow().head != HeadPosition::Before {
+                        return Ok(());
+                    }
+                    head_data.borrow_mut().head = HeadPosition::Inside;
+                    if let Some(handlers) = el.end_tag_handlers() {
+                        let head_data = Rc::clone(&head_data);
+                        handlers.push(Box::new(move |_| {
+                            head_data.borrow_mut().head = HeadPosition::After;
+                            Ok(())
+                        }));
+                    }
+                    Ok(())
+                }),
+            ));
+            // The end tag of the head is optional
+            element_content_handlers.push((
+                Cow::Borrowed(&self.body_selector),
+                ElementContentHandlers::default().element(move |_: &mut Element| {
+                    body_data.borrow_mut().head = HeadPosition::After;
+                    Ok(())
+                }),
+            ));
+        }
+        element_content_handlers.extend(extra_handlers);
+
+        let mut rewriter = HtmlRewriter::new(
+            Settings {
+                element_content_handlers,
+                memory_settings: MemorySettings {
+                    preallocated_parsing_buffer_size: self.options.preallocated_buffer_size,
+                    max_allowed_memory_usage: self.options.max_memory,
+                },
+                ..Settings::default()
+            },
+            |_: &[u8]| {},
+        );
+        // Feed the page in chunks, like it would arrive from the network, so the memory limit
+        // applies to tags that have to be buffered across chunks
+        content
+            .chunks(PARSE_CHUNK_SIZE)
+            .try_for_each(|chunk| rewriter.write(chunk))
+            .and_then(|_| rewriter.end())
+            .map_err(|e| match e {
+                RewritingError::MemoryLimitExceeded(_) => WebIdentityError::DocumentTooComplex,
+                e => WebIdentityError::Parse(e.to_string()),
+            })?;
+
+        let data = Rc::try_unwrap(raw_data).unwrap().into_inner();
+        identity_from_raw(source_url, data, &self.options)
     }
-    let public_key_bytes: Vec<u8> = hex::decode(&pk_hex[PK_PREFIX.len()..])
-        .map_err(|_| WebIdentityError::InvalidPublicKeyFormat("Invalid hex encoding.".into()))?;
+}
 
-    let bytes = as_array::<u8, 32>(&public_key_bytes).ok_or(
-        WebIdentityError::InvalidPublicKeyFormat("Wrong key size".into()),
-    )?;
+fn identity_from_raw(
+    source_url: &Url,
+    mut data: RawIdentityData,
+    options: &IdentityOptions,
+) -> Result<Identity, WebIdentityError> {
+    let fields = options.fields;
+    let has_json = data.json.is_some();
+    // The per-field tags take precedence over the JSON tag
+    if let Some(json) = data.json.take() {
+        let mut json: JsonIdentityData = serde_json::from_str(&json).map_err(|e| {
+            WebIdentityError::Parse(format!("Invalid JSON in the 'identity' meta tag: {}", e))
+        })?;
+        if !fields.contains(ParseFields::DISPLAY_NAME) {
+            json.display_name = None;
+        }
+        if !fields.contains(ParseFields::AVATAR) {
+            json.avatar = None;
+        }
+        if !fields.contains(ParseFields::DESCRIPTION) {
+            json.description = None;
+        }
+        if !fields.contains(ParseFields::BACKUP_LOCATIONS) {
+            json.backup_location = OneOrMany::default();
+        }
+        if data.public_keys.is_empty() {
+            data.public_keys = json.public_key.into_vec();
+        }
+        if data.backup_locations.is_empty() {
+            data.backup_locations = json.backup_location.into_vec();
+        }
+        data.threshold = data.threshold.or(json.threshold.map(json_to_string));
+        data.version = data.version.or(json.version.map(json_to_string));
+        data.display_name = data.display_name.or(json.display_name);
+        data.avatar = data.avatar.or(json.avatar);
+        data.description = data.description.or(json.description);
+    }
 
-    VerifyingKey::from_bytes(bytes).map_err(|_| {
-        WebIdentityError::InvalidPublicKeyFormat("Not a valid Ed25519 public key.".into())
-    })?;
+    // Check the version first, a newer page may not follow these rules
+    let spec_version = match data.version {
+        Some(version) => {
+            let version = version
+                .trim()
+                .parse::<u32>()
+                .ok()
+                .filter(|v| *v >= 1)
+                .ok_or(WebIdentityError::InvalidSpecVersion(version))?;
+            if version > SPEC_VERSION {
+                return Err(WebIdentityError::UnsupportedSpecVersion(version));
+            }
+            Some(version)
+        }
+        None => None,
+    };
+    if spec_version == Some(1) {
+        let feature = if has_json {
+            Some("the 'identity' JSON tag")
+        } else if data.public_keys.len() > 1 {
+            Some("several public keys")
+        } else if data.threshold.is_some() {
+            Some("thresholds")
+        } else if !data.backup_locations.is_empty() {
+            Some("backup locations")
+        } else {
+            None
+        };
+        if let Some(feature) = feature {
+            return Err(WebIdentityError::SpecVersionMismatch {
+                version: 1,
+                feature,
+            });
+        }
+    }
 
-    // ID is derived from the public key
-    let mut hasher = Sha256::new();
-    hasher.update(&public_key_bytes);
-    let id_hash = hasher.finalize();
-    let id = hex::encode(&id_hash);
+    // Public key (the only mandatory value), the first one listed is the primary key
+    if data.public_keys.is_empty() {
+        return Err(WebIdentityError::MissingPublicKey);
+    }
+    let public_keys = data
+        .public_keys
+        .iter()
+        .map(|pk| parse_public_key_with(pk, &options.key_prefixes))
+        .collect::<Result<Vec<_>, _>>()?;
+    let public_key_bytes = public_keys[0];
 
-    let location = {
-        let mut host = source_url.host_str().unwrap_or("").to_string();
-        host.push_str(source_url.path());
-        host.trim_end_matches('/').to_string()
+    let threshold = match data.threshold {
+        Some(threshold) => {
+            let threshold = threshold
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|t| *t >= 1 && *t as usize <= public_keys.len())
+                .ok_or(WebIdentityError::InvalidThreshold(threshold))?;
+            Some(threshold)
+        }
+        None => None,
     };
 
+    let id = identity_id(&public_key_bytes);
+
+    let location = location_from_url(source_url);
+
     let display_name = data
         .display_name
         .or(data.author)
@@ -129,22 +812,649 @@ pub fn get_identity(source_url: &Url, content: &str) -> Result<Identity, WebIden
         .filter(|s| !s.is_empty())
         .unwrap_or_else(|| location.clone());
 
+    // Scheme-relative avatars take the page's scheme when joined
     let avatar_str = data.avatar.or(data.og_image).or(data.favicon);
     let avatar = if let Some(href) = avatar_str {
-        source_url.join(&href).ok()
+        source_url
+            .join(&href)
+            .ok()
+            .filter(|avatar| !options.https_avatars_only || avatar.scheme() == "https")
     } else {
         None
     };
 
     let description = data.description.or(data.og_description);
 
+    // Only links to other web pages can link back
+    let mut verified_links: Vec<Url> = Vec::new();
+    for href in &data.me_links {
+        if let Ok(link) = source_url.join(href.trim()) {
+            if matches!(link.scheme(), "http" | "https") && !verified_links.contains(&link) {
+                verified_links.push(link);
+            }
+        }
+    }
+
+    // Mirrors that aren't valid locations could never be resolved
+    let backup_locations = data
+        .backup_locations
+        .iter()
+        .map(|backup| backup.trim())
+        .filter(|backup| resolve_location_url(backup).is_ok())
+        .map(str::to_string)
+        .collect();
+
     Ok(Identity {
         id,
         public_key: public_key_bytes,
+        public_keys,
+        threshold,
         display_name,
         avatar,
         description,
+        descriptions: data.descriptions,
         location_url: source_url.clone(),
         location,
+        backup_locations,
+        spec_version,
+        verified_links,
+        source: IdentitySource::Page,
+        fetched_at: None,
+        certificate_fingerprint: None,
     })
 }
+
+/// Whether a `rel` attribute, a space-separated list of link types, includes `me`.
+pub(crate) fn is_rel_me(rel: &str) -> bool {
+    rel.split_ascii_whitespace()
+        .any(|token| token.eq_ignore_ascii_case("me"))
+}
+
+fn json_to_string(value: Value) -> String {
+    match value {
+        Value::String(value) => value,
+        value => value.to_string(),
+    }
+}
+
+/// Parses many identity pages at once, e.g. when refreshing stored profiles.
+///
+/// With the `rayon` feature, pages are parsed in parallel. The results are in the same order
+/// as `batch`, and a page that fails to parse (or panics the parser) only affects its own entry.
+pub fn parse_identities(
+    batch: Vec<(Url, Vec<u8>)>,
+    options: &IdentityOptions,
+) -> Vec<Result<Identity, WebIdentityError>> {
+    let parser = IdentityParser::new(options.clone());
+
+    #[cfg(feature = "rayon")]
+    {
+        use rayon::prelude::*;
+        batch
+            .into_par_iter()
+            .map(|(url, content)| parse_isolated(&parser, &url, &content))
+            .collect()
+    }
+
+    #[cfg(not(feature = "rayon"))]
+    {
+        batch
+            .into_iter()
+            .map(|(url, content)| parse_isolated(&parser, &url, &content))
+            .collect()
+    }
+}
+
+fn parse_isolated(
+    parser: &IdentityParser,
+    source_url: &Url,
+    content: &[u8],
+) -> Result<Identity, WebIdentityError> {
+    panic::catch_unwind(|| parser.parse(source_url, content))
+        .unwrap_or_else(|_| Err(WebIdentityError::Parse("The parser panicked.".into())))
+}
+
+pub(crate) fn parse_public_key(pk_hex: &str) -> Result<PublicKey, WebIdentityError> {
+    parse_public_key_with(pk_hex, KeyPrefix::ALL)
+}
+
+/// Parses a listed key, if its format is one of `accepted`.
+fn parse_public_key_with(
+    pk_hex: &str,
+    accepted: &[KeyPrefix],
+) -> Result<PublicKey, WebIdentityError> {
+    let prefixed = accepted
+        .iter()
+        .find_map(|prefix| Some((*prefix, pk_hex.strip_prefix(prefix.as_str())?)));
+    let Some((prefix, key_hex)) = prefixed else {
+        let accepted: Vec<String> = accepted
+            .iter()
+            .map(|prefix| format!("'{}'", prefix.as_str()))
+            .collect();
+        return Err(WebIdentityError::InvalidPublicKeyFormat(format!(
+            "This server only supports keys that start with {}.",
+            accepted.join(" or ")
+        )));
+    };
+    if prefix == KeyPrefix::X25519 {
+        return parse_x25519_public_key(key_hex);
+    }
+    let public_key_bytes: Vec<u8> = hex::decode(strip_hex_prefix(key_hex))
+        .map_err(|_| WebIdentityError::InvalidPublicKeyFormat("Invalid hex encoding.".into()))?;
+
+    let bytes = as_array::<u8, 32>(&public_key_bytes).ok_or(
+        WebIdentityError::InvalidPublicKeyFormat("Wrong key size".into()),
+    )?;
+
+    // Any 32 bytes are a valid X25519 key, so an encryption key listed by mistake is only
+    // caught when it isn't also a valid Ed25519 key
+    let verifying_key = VerifyingKey::from_bytes(bytes).map_err(|_| {
+        WebIdentityError::InvalidPublicKeyFormat(format!(
+            "Not a valid Ed25519 public key. If this is an X25519 (encryption) key, list it \
+             with the '{}' prefix instead.",
+            X25519_PK_PREFIX
+        ))
+    })?;
+
+    Ok(PublicKey::from(verifying_key))
+}
+
+/// Converts an X25519 key to the Ed25519 key that verifies its XEdDSA signatures: the
+/// birationally equivalent Edwards point with a sign bit of zero.
+fn parse_x25519_public_key(x25519_hex: &str) -> Result<PublicKey, WebIdentityError> {
+    let bytes = hex::decode(strip_hex_prefix(x25519_hex))
+        .map_err(|_| WebIdentityError::InvalidPublicKeyFormat("Invalid hex encoding.".into()))?;
+    let bytes = as_array::<u8, 32>(&bytes).ok_or(WebIdentityError::InvalidPublicKeyFormat(
+        "Wrong key size".into(),
+    ))?;
+
+    let point = MontgomeryPoint(*bytes)
+        .to_edwards(0)
+        .filter(|point| !point.is_small_order())
+        .ok_or_else(|| {
+            WebIdentityError::InvalidPublicKeyFormat("Not a valid X25519 public key.".into())
+        })?;
+    PublicKey::from_bytes(point.compress().as_bytes())
+}
+
+/// The hash identity ids are derived with.
+///
+/// It is fixed to SHA-256 and deliberately separate from
+/// [`DigestAlgorithm`](crate::DigestAlgorithm), which only selects how request bodies are
+/// hashed: changing that must never change every identity's id.
+type IdentityIdHash = Sha256;
+
+/// Derives an identity's id (also used as its key fingerprint) from its public key: the
+/// hex-encoded SHA-256 hash of the key bytes.
+///
+/// The id doesn't depend on the body hash used for signed requests.
+pub fn identity_id(public_key: &[u8]) -> String {
+    let mut hasher = IdentityIdHash::new();
+    hasher.update(public_key);
+    hex::encode(hasher.finalize())
+}
+
+/// Strips a leading `www.` label from a location's host, so `www.example.com/me` and
+/// `example.com/me` normalize to the same location.
+///
+/// This is a heuristic: sites usually serve the same content on both, but nothing guarantees
+/// it. Only the `www` label is treated this way, never other subdomains, and a location whose
+/// host is nothing but `www.` plus a single label (`www.com`) is kept as is.
+pub fn strip_www(location: &str) -> &str {
+    match location.strip_prefix("www.") {
+        Some(apex)
+            if apex
+                .split('/')
+                .next()
+                .is_some_and(|host| host.contains('.')) =>
+        {
+            apex
+        }
+        _ => location,
+    }
+}
+
+/// The location of an identity page: its host and path, without a trailing slash.
+pub(crate) fn location_from_url(url: &Url) -> String {
+    let host = url.host_str().unwrap_or("");
+    let mut host = host.strip_suffix('.').unwrap_or(host).to_string();
+    host.push_str(url.path());
+    host.trim_end_matches('/').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_only_the_www_label() {
+        assert_eq!(strip_www("www.example.com/me"), "example.com/me");
+        assert_eq!(strip_www("www.example.com"), "example.com");
+        assert_eq!(strip_www("example.com/me"), "example.com/me");
+        assert_eq!(strip_www("blog.example.com"), "blog.example.com");
+        assert_eq!(strip_www("www2.example.com"), "www2.example.com");
+        assert_eq!(strip_www("www.com"), "www.com");
+        assert_eq!(strip_www("www.com/www.example"), "www.com/www.example");
+    }
+
+    #[test]
+    fn id_is_independent_of_the_body_hash() {
+        use crate::digest::{DigestAlgorithm, RequestDigest};
+        use crate::sign::{
+            create_signed_headers_with_options, verify_request_with_key, SignOptions, VerifyOptions,
+        };
+        use std::time::Duration;
+
+        // The key of RFC 8032's first test vector, and the SHA-256 hash of it
+        let signing_key = ed25519_dalek::SigningKey::from_bytes(
+            &hex::decode("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60")
+                .unwrap()
+                .try_into()
+                .unwrap(),
+        );
+        let id = "21fe31dfa154a261626bf854046fd2271b7bed4b6abe45aa58877ef47f9721b9";
+        let public_key = signing_key.verifying_key();
+        assert_eq!(identity_id(public_key.as_bytes()), id);
+
+        let page = format!(
+            r#"<html><head><meta name="identity:public-key" content="{}{}"></head></html>"#,
+            PK_PREFIX,
+            hex::encode(public_key.as_bytes())
+        );
+        let identity = parse(&page).unwrap();
+        assert_eq!(identity.id, id);
+        assert_eq!(hex::encode(identity.identicon_seed()), id);
+
+        let headers = create_signed_headers_with_options(
+            "amy.carroted.org",
+            "POST",
+            "example.com",
+            "/notes",
+            b"hello",
+            &signing_key,
+            &SignOptions::default().with_key_fingerprint(),
+        )
+        .unwrap();
+        assert_eq!(headers["WebIdentity-Key"], id);
+
+        // The body hash is chosen separately, and never feeds into the id
+        verify_request_with_key(
+            "POST",
+            "example.com",
+            "/notes",
+            &RequestDigest::of(b"hello"),
+            &headers,
+            &public_key,
+            &VerifyOptions::new(Duration::from_secs(300))
+                .with_digest_algorithm(DigestAlgorithm::Sha256),
+        )
+        .unwrap();
+    }
+
+    fn key(seed: u8) -> String {
+        let key = ed25519_dalek::SigningKey::from_bytes(&[seed; 32]).verifying_key();
+        format!("{}{}", PK_PREFIX, hex::encode(key.as_bytes()))
+    }
+
+    fn page(version: Option<&str>, extra: &str) -> String {
+        let version = version
+            .map(|v| format!(r#"<meta name="identity:version" content="{}">"#, v))
+            .unwrap_or_default();
+        format!(
+            r#"<html><head>{}<meta name="identity:public-key" content="{}">{}<meta name="identity:display-name" content="Amy"></head></html>"#,
+            version,
+            key(1),
+            extra
+        )
+    }
+
+    fn parse(page: &str) -> Result<Identity, WebIdentityError> {
+        get_identity(&Url::parse("https://amy.carroted.org").unwrap(), page)
+    }
+
+    #[test]
+    fn reads_pages_without_a_version_with_the_current_rules() {
+        let extra = format!(
+            r#"<meta name="identity:public-key" content="{}"><meta name="identity:threshold" content="2">"#,
+            key(2)
+        );
+        let identity = parse(&page(None, &extra)).unwrap();
+        assert_eq!(identity.spec_version, None);
+        assert_eq!(identity.public_keys.len(), 2);
+        assert_eq!(identity.threshold, Some(2));
+    }
+
+    #[test]
+    fn reads_pages_of_the_current_version() {
+        let extra = format!(
+            r#"<meta name="identity:public-key" content="{}"><meta name="identity:backup-location" content="mirror.example.com/amy">"#,
+            key(2)
+        );
+        let identity = parse(&page(Some(&SPEC_VERSION.to_string()), &extra)).unwrap();
+        assert_eq!(identity.spec_version, Some(SPEC_VERSION));
+        assert_eq!(identity.public_keys.len(), 2);
+        assert_eq!(identity.backup_locations, ["mirror.example.com/amy"]);
+    }
+
+    #[test]
+    fn reads_older_pages_with_their_rules() {
+        let identity = parse(&page(Some("1"), "")).unwrap();
+        assert_eq!(identity.spec_version, Some(1));
+        assert_eq!(identity.public_keys.len(), 1);
+
+        for (extra, expected) in [
+            (
+                format!(r#"<meta name="identity:public-key" content="{}">"#, key(2)),
+                "several public keys",
+            ),
+            (
+                r#"<meta name="identity:threshold" content="1">"#.to_string(),
+                "thresholds",
+            ),
+            (
+                r#"<meta name="identity:backup-location" content="mirror.example.com">"#
+                    .to_string(),
+                "backup locations",
+            ),
+            (
+                r#"<meta name="identity" content='{"display-name":"Amy"}'>"#.to_string(),
+                "the 'identity' JSON tag",
+            ),
+        ] {
+            match parse(&page(Some("1"), &extra)) {
+                Err(WebIdentityError::SpecVersionMismatch { version, feature }) => {
+                    assert_eq!(version, 1);
+                    assert_eq!(feature, expected);
+                }
+                other => panic!("expected a version mismatch, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_newer_and_invalid_versions() {
+        let future = SPEC_VERSION + 1;
+        assert!(matches!(
+            parse(&page(Some(&future.to_string()), "")),
+            Err(WebIdentityError::UnsupportedSpecVersion(v)) if v == future
+        ));
+        for invalid in ["0", "-1", "two", "2.0"] {
+            assert!(matches!(
+                parse(&page(Some(invalid), "")),
+                Err(WebIdentityError::InvalidSpecVersion(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_pages_over_the_memory_limit() {
+        // A tag longer than a parsing chunk has to be buffered whole
+        let description = "a".repeat(4 * PARSE_CHUNK_SIZE);
+        let extra = format!(
+            r#"<meta name="identity:description" content="{}">"#,
+            description
+        );
+        let page = page(Some("2"), &extra);
+        let url = Url::parse("https://amy.carroted.org").unwrap();
+
+        let parser = IdentityParser::new(IdentityOptions::new().with_max_memory(4 * 1024));
+        assert!(matches!(
+            parser.parse(&url, page.as_bytes()),
+            Err(WebIdentityError::DocumentTooComplex)
+        ));
+
+        let parser = IdentityParser::new(IdentityOptions::new().with_max_memory(256 * 1024));
+        let identity = parser.parse(&url, page.as_bytes()).unwrap();
+        assert_eq!(identity.description.as_deref(), Some(description.as_str()));
+    }
+
+    #[test]
+    fn parser_is_reusable_across_pages_and_threads() {
+        let parser = IdentityParser::default();
+        let url = Url::parse("https://amy.carroted.org").unwrap();
+        let pages: Vec<String> = (1..=4)
+            .map(|i| {
+                page(
+                    Some("2"),
+                    &format!(r#"<meta name="identity:avatar" content="/{}.png">"#, i),
+                )
+            })
+            .collect();
+
+        std::thread::scope(|scope| {
+            for page in &pages {
+                let (parser, url) = (&parser, &url);
+                scope.spawn(move || {
+                    let parsed = parser.parse(url, page.as_bytes()).unwrap();
+                    let expected = get_identity(url, page).unwrap();
+                    assert_eq!(parsed.public_key, expected.public_key);
+                    assert_eq!(parsed.display_name, "Amy");
+                    assert_eq!(parsed.avatar, expected.avatar);
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn parser_only_reads_the_requested_fields() {
+        let parser =
+            IdentityParser::new(IdentityOptions::new().with_fields(ParseFields::KEYS_ONLY));
+        assert_eq!(parser.options().fields, ParseFields::KEYS_ONLY);
+        let url = Url::parse("https://amy.carroted.org").unwrap();
+        let extra = r#"<meta name="identity:description" content="Hi"><a rel="me" href="https://social.example/@amy">"#;
+
+        let identity = parser.parse(&url, page(None, extra).as_bytes()).unwrap();
+        assert_eq!(identity.display_name, "amy.carroted.org");
+        assert_eq!(identity.description, None);
+        assert!(identity.verified_links.is_empty());
+    }
+
+    #[test]
+    fn parser_runs_extra_handlers() {
+        let parser = IdentityParser::default();
+        let url = Url::parse("https://amy.carroted.org").unwrap();
+        let extra = r#"<meta name="custom:pronouns" content="she/her">"#;
+        let found = RefCell::new(None);
+        let selector: Selector = r#"meta[name="custom:pronouns"]"#.parse().unwrap();
+        let handlers = vec![(
+            Cow::Owned(selector),
+            ElementContentHandlers::default().element(|el: &mut Element| {
+                *found.borrow_mut() = el.get_attribute("content");
+                Ok(())
+            }),
+        )];
+
+        parser
+            .parse_with_handlers(&url, page(None, extra).as_bytes(), handlers)
+            .unwrap();
+        assert_eq!(found.into_inner().as_deref(), Some("she/her"));
+    }
+
+    #[test]
+    fn https_avatars_only_drops_insecure_avatars() {
+        let parser = IdentityParser::new(IdentityOptions::new().with_https_avatars_only());
+        let https = Url::parse("https://amy.carroted.org").unwrap();
+        let http = Url::parse("http://amy.carroted.org").unwrap();
+
+        for (url, extra, expected) in [
+            (
+                &https,
+                r#"<meta name="identity:avatar" content="https://cdn.example/a.png">"#,
+                Some("https://cdn.example/a.png"),
+            ),
+            (
+                &https,
+                r#"<meta name="identity:avatar" content="//cdn.example/a.png">"#,
+                Some("https://cdn.example/a.png"),
+            ),
+            (
+                &http,
+                r#"<meta name="identity:avatar" content="//cdn.example/a.png">"#,
+                None,
+            ),
+            (
+                &https,
+                r#"<meta name="identity:avatar" content="http://cdn.example/a.png">"#,
+                None,
+            ),
+            (
+                &https,
+                r#"<meta property="og:image" content="http://cdn.example/og.png">"#,
+                None,
+            ),
+            (
+                &https,
+                r#"<meta property="og:image" content="https://cdn.example/og.png">"#,
+                Some("https://cdn.example/og.png"),
+            ),
+            (
+                &https,
+                r#"<link rel="icon" href="/favicon.ico">"#,
+                Some("https://amy.carroted.org/favicon.ico"),
+            ),
+            (&http, r#"<link rel="icon" href="/favicon.ico">"#, None),
+        ] {
+            let identity = parser.parse(url, page(None, extra).as_bytes()).unwrap();
+            assert_eq!(
+                identity.avatar.as_ref().map(Url::as_str),
+                expected,
+                "{}",
+                extra
+            );
+        }
+
+        // Without the option, insecure avatars are kept
+        let extra = r#"<meta name="identity:avatar" content="http://cdn.example/a.png">"#;
+        let identity = get_identity(&https, &page(None, extra)).unwrap();
+        assert_eq!(
+            identity.avatar.as_ref().map(Url::as_str),
+            Some("http://cdn.example/a.png")
+        );
+    }
+
+    #[test]
+    fn head_only_ignores_keys_outside_the_first_head() {
+        let parser = IdentityParser::new(IdentityOptions::new().with_head_only());
+        let url = Url::parse("https://amy.carroted.org").unwrap();
+        let meta = |seed| {
+            format!(
+                r#"<meta name="identity:public-key" content="{}">"#,
+                key(seed)
+            )
+        };
+        let keys = |identity: Identity| {
+            identity
+                .public_keys
+                .iter()
+                .map(PublicKey::to_prefixed)
+                .collect::<Vec<_>>()
+        };
+
+        for page in [
+            // A key in the body, with or without the end tag of the head
+            format!(
+                "<html><head>{}</head><body>{}</body></html>",
+                meta(1),
+                meta(2)
+            ),
+            format!("<html><head>{}<body>{}</body></html>", meta(1), meta(2)),
+            // A key in a second head
+            format!(
+                "<html><head>{}</head><head>{}</head></html>",
+                meta(1),
+                meta(2)
+            ),
+            // A key before the head
+            format!("<html>{}<head>{}</head></html>", meta(2), meta(1)),
+        ] {
+            let identity = parser.parse(&url, page.as_bytes()).unwrap();
+            assert_eq!(keys(identity), [key(1)], "{}", page);
+        }
+
+        // A page without a head has no keys
+        let page = format!("<html><body>{}</body></html>", meta(1));
+        assert!(matches!(
+            parser.parse(&url, page.as_bytes()),
+            Err(WebIdentityError::MissingPublicKey)
+        ));
+
+        // The lenient default reads keys from anywhere
+        let page = format!(
+            "<html><head>{}</head><body>{}</body></html>",
+            meta(1),
+            meta(2)
+        );
+        let identity = get_identity(&url, &page).unwrap();
+        assert_eq!(keys(identity), [key(1), key(2)]);
+    }
+
+    #[test]
+    fn handles_are_compact_locations() {
+        let handle = |location: &str| {
+            let mut identity = parse(&page(None, "")).unwrap();
+            identity.location_url = resolve_location_url(location).unwrap();
+            identity.handle()
+        };
+
+        for (location, expected) in [
+            ("example.com", "example.com"),
+            ("https://www.example.com/", "example.com"),
+            ("example.com./", "example.com"),
+            ("example.com/amy", "@amy@example.com"),
+            ("www.example.com/~amy/", "@amy@example.com"),
+            ("example.com/@amy", "@amy@example.com"),
+            ("example.com/people/amy", "example.com/people/amy"),
+        ] {
+            assert_eq!(handle(location), expected, "{}", location);
+        }
+
+        // Nothing that would read as another host or an empty user
+        for (location, expected) in [
+            ("www.com", "www.com"),
+            ("www.com/amy", "@amy@www.com"),
+            ("example.com/~", "example.com/~"),
+            ("example.com/@@", "example.com/@@"),
+        ] {
+            assert_eq!(handle(location), expected, "{}", location);
+        }
+    }
+
+    #[test]
+    fn accepts_only_the_configured_key_prefixes() {
+        let url = Url::parse("https://amy.carroted.org").unwrap();
+        let ed25519 = ed25519_dalek::SigningKey::from_bytes(&[1; 32]).verifying_key();
+        let montgomery = ed25519.to_montgomery();
+        let x25519 = format!("{}{}", X25519_PK_PREFIX, hex::encode(montgomery.as_bytes()));
+        let x25519_page = format!(
+            r#"<html><head><meta name="identity:public-key" content="{}"></head></html>"#,
+            x25519
+        );
+
+        // The X25519 key is read as the Ed25519 key with a sign bit of zero
+        let identity = get_identity(&url, &x25519_page).unwrap();
+        let converted = montgomery.to_edwards(0).unwrap().compress();
+        assert_eq!(identity.public_key.as_bytes(), converted.as_bytes());
+        assert_eq!(
+            parse_public_key_with(&x25519, &[KeyPrefix::X25519]).unwrap(),
+            identity.public_key
+        );
+
+        let ed25519_only =
+            IdentityParser::new(IdentityOptions::new().with_key_prefixes(&[KeyPrefix::Ed25519]));
+        match ed25519_only.parse(&url, x25519_page.as_bytes()) {
+            Err(WebIdentityError::InvalidPublicKeyFormat(reason)) => {
+                assert!(reason.contains("'ed25519-pub:'"), "{}", reason);
+                assert!(!reason.contains("x25519"), "{}", reason);
+            }
+            other => panic!("expected the X25519 key to be rejected, got {:?}", other),
+        }
+        ed25519_only.parse(&url, page(None, "").as_bytes()).unwrap();
+
+        let x25519_only =
+            IdentityParser::new(IdentityOptions::new().with_key_prefixes(&[KeyPrefix::X25519]));
+        assert!(matches!(
+            x25519_only.parse(&url, page(None, "").as_bytes()),
+            Err(WebIdentityError::InvalidPublicKeyFormat(_))
+        ));
+    }
+}
@@ -0,0 +1,275 @@
+use super::error::WebIdentityError;
+use super::identity::{location_from_url, Identity};
+use super::resolve::resolve_location_url;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+
+/// The longest URI [`IdentityRef::to_uri`] produces, so it always fits a version 7 QR code
+/// with medium error correction, even in byte mode.
+pub const MAX_IDENTITY_URI_LENGTH: usize = 122;
+
+/// How many bytes of the key fingerprint [`IdentityRef::with_key`] keeps.
+const FINGERPRINT_PREFIX_BYTES: usize = 16;
+
+/// The fewest fingerprint bytes a parsed reference may carry, shorter prefixes are too easy to
+/// collide with.
+const MIN_FINGERPRINT_PREFIX_BYTES: usize = 8;
+
+/// A compact reference to an identity, for sharing it out of band (QR codes, NFC tags).
+///
+/// It is written as `webid:<location>[?k=<fingerprint prefix>]`, where the location is
+/// percent-encoded where needed and the optional fingerprint prefix is the start of the
+/// SHA-256 hash of one of the identity's keys, base58-encoded. With a fingerprint, the
+/// reference is self-certifying: [`IdentityRef::verify`] checks the resolved identity lists the
+/// key the reference was made for.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct IdentityRef {
+    location: String,
+    key_fingerprint: Option<Vec<u8>>,
+}
+
+impl IdentityRef {
+    /// Creates a reference to the identity at `location`, without a key fingerprint.
+    ///
+    /// # Errors
+    /// Returns `Err` if the location is not a valid URL.
+    pub fn new(location: &str) -> Result<IdentityRef, WebIdentityError> {
+        Ok(IdentityRef {
+            location: location_from_url(&resolve_location_url(location)?),
+            key_fingerprint: None,
+        })
+    }
+
+    /// Adds the fingerprint of `public_key` to the reference.
+    pub fn with_key(mut self, public_key: &[u8]) -> Self {
+        self.key_fingerprint =
+            Some(Sha256::digest(public_key)[..FINGERPRINT_PREFIX_BYTES].to_vec());
+        self
+    }
+
+    /// Creates a reference to `identity`, with the fingerprint of its primary key.
+    pub fn from_identity(identity: &Identity) -> IdentityRef {
+        IdentityRef {
+            location: identity.location.clone(),
+            key_fingerprint: None,
+        }
+        .with_key(&identity.public_key)
+    }
+
+    /// Parses a `webid:` URI.
+    ///
+    /// # Errors
+    /// Returns `Err` if the URI is longer than [`MAX_IDENTITY_URI_LENGTH`], doesn't use the
+    /// `webid:` scheme, or its location or fingerprint is invalid.
+    pub fn parse(uri: &str) -> Result<IdentityRef, WebIdentityError> {
+        let invalid = |reason: &str| WebIdentityError::InvalidIdentityUri(reason.to_string());
+
+        let uri = uri.trim();
+        if uri.len() > MAX_IDENTITY_URI_LENGTH {
+            return Err(invalid("The URI is too long."));
+        }
+        let rest = strip_scheme(uri).ok_or_else(|| invalid("The scheme must be 'webid:'."))?;
+        let (location, query) = match rest.split_once('?') {
+            Some((location, query)) => (location, Some(query)),
+            None => (rest, None),
+        };
+
+        let location =
+            percent_decode(location).ok_or_else(|| invalid("Invalid percent-encoding."))?;
+        if location.is_empty() || location.contains("://") || strip_scheme(&location).is_some() {
+            return Err(invalid("The location must be a host and path."));
+        }
+        let mut identity_ref = IdentityRef::new(&location)?;
+
+        for pair in query.into_iter().flat_map(|query| query.split('&')) {
+            // Unknown parameters are ignored, so they can be added later
+            if let Some(fingerprint) = pair.strip_prefix("k=") {
+                let fingerprint = bs58::decode(fingerprint)
+                    .into_vec()
+                    .map_err(|_| invalid("Invalid key fingerprint."))?;
+                if !(MIN_FINGERPRINT_PREFIX_BYTES..=32).contains(&fingerprint.len()) {
+                    return Err(invalid("Invalid key fingerprint length."));
+                }
+                identity_ref.key_fingerprint = Some(fingerprint);
+            }
+        }
+
+        Ok(identity_ref)
+    }
+
+    /// Writes the reference as a `webid:` URI.
+    ///
+    /// # Errors
+    /// Returns `Err` if the URI would be longer than [`MAX_IDENTITY_URI_LENGTH`].
+    pub fn to_uri(&self) -> Result<String, WebIdentityError> {
+        let mut uri = format!("webid:{}", percent_encode(&self.location));
+        if let Some(fingerprint) = &self.key_fingerprint {
+            write!(uri, "?k={}", bs58::encode(fingerprint).into_string()).unwrap();
+        }
+        if uri.len() > MAX_IDENTITY_URI_LENGTH {
+            return Err(WebIdentityError::InvalidIdentityUri(
+                "The URI is too long.".into(),
+            ));
+        }
+        Ok(uri)
+    }
+
+    pub fn location(&self) -> &str {
+        &self.location
+    }
+
+    /// The key fingerprint prefix, if the reference has one.
+    pub fn key_fingerprint(&self) -> Option<&[u8]> {
+        self.key_fingerprint.as_deref()
+    }
+
+    /// Checks a resolved identity is the one the reference points to: it must have the same
+    /// location and, if the reference has a key fingerprint, list a key matching it.
+    ///
+    /// # Errors
+    /// Returns `Err` naming what doesn't match.
+    pub fn verify(&self, identity: &Identity) -> Result<(), WebIdentityError> {
+        if identity.location != self.location {
+            return Err(WebIdentityError::IdentityRefMismatch("location".into()));
+        }
+        if let Some(fingerprint) = &self.key_fingerprint {
+            let listed = identity
+                .public_keys
+                .iter()
+                .any(|key| Sha256::digest(key).starts_with(fingerprint));
+            if !listed {
+                return Err(WebIdentityError::IdentityRefMismatch("key".into()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns what follows the `webid:` scheme, matched case-insensitively.
+pub(crate) fn strip_scheme(uri: &str) -> Option<&str> {
+    let scheme = uri.get(..6)?;
+    scheme.eq_ignore_ascii_case("webid:").then(|| &uri[6..])
+}
+
+/// Percent-encodes everything but unreserved characters and path separators.
+fn percent_encode(location: &str) -> String {
+    let mut encoded = String::with_capacity(location.len());
+    for byte in location.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~/:".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            write!(encoded, "%{:02X}", byte).unwrap();
+        }
+    }
+    encoded
+}
+
+fn percent_decode(encoded: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{SharedTestIdentity, TestIdentity};
+
+    #[test]
+    fn round_trips_through_a_uri() {
+        let alice = TestIdentity::generate("alice.example.com/me");
+        let reference = IdentityRef::from_identity(&alice.identity);
+        let uri = reference.to_uri().unwrap();
+        assert!(uri.starts_with("webid:alice.example.com/me?k="), "{}", uri);
+        assert!(uri.len() <= MAX_IDENTITY_URI_LENGTH);
+
+        let parsed = IdentityRef::parse(&uri).unwrap();
+        assert_eq!(parsed, reference);
+        assert_eq!(parsed.key_fingerprint().map(<[u8]>::len), Some(16));
+        parsed.verify(&alice.identity).unwrap();
+
+        // Unknown parameters are ignored, and the scheme is case-insensitive
+        let upper = format!("WEBID:{}&future=1", &uri["webid:".len()..]);
+        assert_eq!(IdentityRef::parse(&upper).unwrap(), reference);
+    }
+
+    #[test]
+    fn percent_encodes_the_location() {
+        let reference = IdentityRef::new("example.com/amélie?x").unwrap();
+        assert_eq!(reference.location(), "example.com/am%C3%A9lie");
+        let uri = reference.to_uri().unwrap();
+        assert_eq!(uri, "webid:example.com/am%25C3%25A9lie");
+        assert_eq!(IdentityRef::parse(&uri).unwrap(), reference);
+        assert_eq!(reference.key_fingerprint(), None);
+    }
+
+    #[test]
+    fn rejects_identities_with_another_key_or_location() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let impostor = TestIdentity::generate("alice.example.com/impostor");
+        let reference = IdentityRef::new("alice.example.com")
+            .unwrap()
+            .with_key(impostor.signing_key.verifying_key().as_bytes());
+        assert!(matches!(
+            reference.verify(&alice.identity),
+            Err(WebIdentityError::IdentityRefMismatch(what)) if what == "key"
+        ));
+
+        let reference = IdentityRef::from_identity(&alice.identity);
+        assert!(matches!(
+            reference.verify(&impostor.identity),
+            Err(WebIdentityError::IdentityRefMismatch(what)) if what == "location"
+        ));
+    }
+
+    #[test]
+    fn accepts_any_listed_key() {
+        let team = SharedTestIdentity::generate("team.example.com", 3, 2);
+        let reference = IdentityRef::new("team.example.com")
+            .unwrap()
+            .with_key(team.signing_keys[2].verifying_key().as_bytes());
+        let parsed = IdentityRef::parse(&reference.to_uri().unwrap()).unwrap();
+        parsed.verify(&team.identity).unwrap();
+    }
+
+    #[test]
+    fn rejects_malformed_uris() {
+        let long = format!("webid:{}.example.com", "a".repeat(MAX_IDENTITY_URI_LENGTH));
+        let short_fingerprint = format!(
+            "webid:example.com?k={}",
+            bs58::encode([1u8; 4]).into_string()
+        );
+        for uri in [
+            "https://example.com",
+            "webid:",
+            "webid:https://example.com",
+            "webid:webid:example.com",
+            "webid:example.com/%zz",
+            "webid:example.com?k=0OIl",
+            &short_fingerprint,
+            &long,
+        ] {
+            assert!(
+                matches!(
+                    IdentityRef::parse(uri),
+                    Err(WebIdentityError::InvalidIdentityUri(_))
+                ),
+                "{}",
+                uri
+            );
+        }
+
+        let long_location = format!("{}.example.com", "a".repeat(MAX_IDENTITY_URI_LENGTH));
+        assert!(IdentityRef::new(&long_location).unwrap().to_uri().is_err());
+    }
+}
@@ -0,0 +1,96 @@
+use super::error::SignatureError;
+use serde_json::Value;
+
+/// Canonicalizes a JSON document with the JSON Canonicalization Scheme (RFC 8785): no
+/// whitespace, object members sorted by their UTF-16 code units, and numbers written like
+/// ECMAScript does.
+///
+/// Semantically equal documents canonicalize to the same bytes, whatever key order or
+/// whitespace middleware reserialized them with.
+pub(crate) fn canonicalize_json(body: &[u8]) -> Result<String, SignatureError> {
+    let value: Value =
+        serde_json::from_slice(body).map_err(|e| SignatureError::InvalidJsonBody(e.to_string()))?;
+    let mut canonical = String::new();
+    write_value(&mut canonical, &value)?;
+    Ok(canonical)
+}
+
+fn write_value(out: &mut String, value: &Value) -> Result<(), SignatureError> {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(value) => out.push_str(if *value { "true" } else { "false" }),
+        Value::Number(number) => {
+            let number = number.as_f64().filter(|n| n.is_finite()).ok_or_else(|| {
+                SignatureError::InvalidJsonBody("Number out of range.".to_string())
+            })?;
+            write_number(out, number);
+        }
+        // serde_json escapes strings as RFC 8785 requires
+        Value::String(value) => out.push_str(&Value::String(value.clone()).to_string()),
+        Value::Array(values) => {
+            out.push('[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_value(out, value)?;
+            }
+            out.push(']');
+        }
+        Value::Object(members) => {
+            let mut members: Vec<_> = members.iter().collect();
+            members.sort_by(|(a, _), (b, _)| a.encode_utf16().cmp(b.encode_utf16()));
+            out.push('{');
+            for (i, (name, value)) in members.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::String(name.clone()).to_string());
+                out.push(':');
+                write_value(out, value)?;
+            }
+            out.push('}');
+        }
+    }
+    Ok(())
+}
+
+/// Writes a number like ECMAScript's `Number.prototype.toString`.
+fn write_number(out: &mut String, number: f64) {
+    if number == 0.0 {
+        out.push('0');
+        return;
+    }
+    if number < 0.0 {
+        out.push('-');
+    }
+
+    // The shortest digits that round-trip, and the decimal exponent of the first one
+    let scientific = format!("{:e}", number.abs());
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let digits: String = mantissa.chars().filter(|c| *c != '.').collect();
+    let k = digits.len() as i32;
+    let n = exponent.parse::<i32>().unwrap() + 1;
+
+    if k <= n && n <= 21 {
+        out.push_str(&digits);
+        out.extend(std::iter::repeat_n('0', (n - k) as usize));
+    } else if 0 < n && n <= 21 {
+        out.push_str(&digits[..n as usize]);
+        out.push('.');
+        out.push_str(&digits[n as usize..]);
+    } else if -6 < n && n <= 0 {
+        out.push_str("0.");
+        out.extend(std::iter::repeat_n('0', -n as usize));
+        out.push_str(&digits);
+    } else {
+        out.push_str(&digits[..1]);
+        if k > 1 {
+            out.push('.');
+            out.push_str(&digits[1..]);
+        }
+        out.push('e');
+        out.push(if n > 0 { '+' } else { '-' });
+        out.push_str(&(n - 1).abs().to_string());
+    }
+}
@@ -4,13 +4,140 @@
 //! using a public key in it to allow verifying their signatures. This library provides
 //! the tools to work with this standard.
 
+#[cfg(feature = "activitypub")]
+mod activitypub;
+#[cfg(feature = "actix-web")]
+mod actix;
+mod algorithm;
+mod authenticate;
+mod authorization;
+mod blocklist;
+mod card;
+mod challenge;
+pub mod conformance;
+#[cfg(feature = "cwt")]
+pub mod cwt;
+mod delegation;
+mod diagnose;
+mod diff;
+mod digest;
+mod dns;
+mod envelope;
 mod error;
+mod fetch;
+mod forwarded;
+#[cfg(feature = "http")]
+mod http;
 mod identity;
+mod identity_ref;
+mod jcs;
+mod keyfile;
+mod lint;
+#[cfg(feature = "piv")]
+mod piv;
+mod public_key;
+mod rate_limit;
+mod redirect;
+mod rel_me;
 mod resolve;
+#[cfg(feature = "rocket")]
+mod rocket;
+mod rotation;
+mod session;
+mod session_verifier;
 mod sign;
+mod signed_url;
+#[cfg(feature = "arbitrary")]
+pub mod strategy;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod timestamp_store;
+mod token;
+#[cfg(feature = "tonic")]
+mod tonic;
+#[cfg(feature = "vcard")]
+mod vcard;
+mod verifier_cache;
+#[cfg(feature = "webfinger")]
+mod webfinger;
 
-pub use error::WebIdentityError;
-pub use identity::{get_identity, Identity};
-pub use resolve::resolve_location_url;
-pub use sign::{create_signed_headers, verify_request, HeaderProvider, SimpleHeaderProvider};
-pub use sign::{sign_bytes, verify_signature};
+/// Re-exported so custom handlers for [`get_identity_with_handlers`] use the same version.
+pub use lol_html;
+
+#[cfg(feature = "activitypub")]
+pub use activitypub::{identity_hint_from_actor, ActorIdentityHint};
+pub use algorithm::SignatureAlgorithm;
+pub use authenticate::{authenticate_request, RetryPolicy, DEFAULT_REFRESH_INTERVAL};
+pub use authenticate::{AuthenticatedRequest, Authenticator, DEFAULT_MAX_BODY_SIZE};
+pub use authorization::{www_authenticate_challenge, WwwAuthenticateOptions};
+pub use blocklist::{BlockRule, BlocklistResolver, SubjectBlocklist};
+pub use card::IdentityCard;
+pub use challenge::{generate_challenge, generate_challenge_with_ttl, verify_challenge_response};
+pub use challenge::{Challenge, DEFAULT_CHALLENGE_TTL};
+pub use delegation::Delegation;
+pub use diagnose::{diagnose_request, RequestDiagnosis};
+pub use diff::{FieldChange, IdentityDiff, Severity};
+#[cfg(feature = "async")]
+pub use digest::{hash_body_async_read, hash_body_futures_read, hash_body_stream};
+pub use digest::{BodyHasher, DigestAlgorithm, RequestDigest};
+pub use dns::{DnsTxtResolver, FallbackResolver, DNS_TXT_LABEL};
+pub use envelope::SignedEnvelope;
+pub use error::{SignatureError, WebIdentityError};
+pub use fetch::IdentityFetcher;
+#[cfg(feature = "surf")]
+pub use fetch::SurfFetcher;
+#[cfg(feature = "reqwest")]
+pub use fetch::{fetch_identity, fetch_identity_with_client, HttpFetcher};
+#[cfg(feature = "blocking")]
+pub use fetch::{fetch_identity_blocking, fetch_identity_blocking_with_agent, BlockingFetcher};
+pub use fetch::{fetch_identity_with, identity_from_response, FetchedPage, FetcherResolver};
+pub use fetch::{Credentials, FetchOptions, DEFAULT_USER_AGENT, MAX_IDENTITY_PAGE_SIZE};
+pub use forwarded::derive_external_host;
+#[cfg(feature = "http")]
+pub use http::{verify_http_request, verify_http_request_behind_proxy};
+pub use identity::KeyInfo;
+pub use identity::{get_identity, get_identity_with_handlers, get_identity_with_options};
+pub use identity::{identity_id, parse_identities, Identity, IdentityOptions, IdentityParser};
+pub use identity::{strip_www, IdentitySource, KeyPrefix, ParseFields, SPEC_VERSION};
+pub use identity_ref::{IdentityRef, MAX_IDENTITY_URI_LENGTH};
+pub use keyfile::{EncryptedKey, KdfParams, Keyfile, KEYFILE_VERSION};
+pub use lint::{lint_identity_page, Lint, RECOMMENDED_DESCRIPTION_LENGTH};
+#[cfg(feature = "piv")]
+pub use piv::{PivAlgorithm, PivDevice, PivError, PivSigner, PIV_SIGNATURE_SLOT};
+pub use public_key::PublicKey;
+pub use rate_limit::DEFAULT_RATE_LIMITER_CAPACITY;
+pub use rate_limit::{check_request_rate, MemoryRateLimiter, RateLimiter};
+pub use redirect::{RedirectPolicy, MAX_REDIRECTS};
+pub use resolve::{resolve_location_url, FileNaming, FileSystemResolver, IdentityResolver};
+pub use resolve::{CachingResolver, MirrorResolver, ResolutionStatus, DEFAULT_IDENTITY_TTL};
+pub use resolve::{Spawner, ThreadSpawner};
+pub use rotation::{rotate_identity, RotationProof};
+pub use session::{ClientConfig, SigningSession};
+pub use session_verifier::{verify_batch_for_identity, BatchRequest, SessionVerifier};
+pub use sign::{add_cosignature, create_signed_headers_with_options, RequestSigner, SignOptions};
+pub use sign::{
+    create_signed_headers, verify_request, HeaderProvider, MultiHeaderProvider,
+    SimpleHeaderProvider, WithTrailers,
+};
+#[cfg(feature = "async")]
+pub use sign::{create_signed_headers_async, AsyncRequestSigner};
+pub use sign::{key_fingerprint_hint, verify_request_threshold};
+pub use sign::{sign_bytes, verify_signature, write_canonical_string};
+pub use sign::{verify_content_digest, verify_request_headers, verify_request_prehashed};
+pub use sign::{verify_request_with_context, VerificationFailure, VerifiedRequest};
+pub use sign::{verify_request_with_key, verify_request_with_options, VerifyOptions};
+pub use signed_url::{create_signed_url, verify_signed_url, VerifiedUrl};
+pub use signed_url::{sign_url, verify_url, verify_url_for_identity};
+pub use timestamp_store::DEFAULT_TIMESTAMP_STORE_CAPACITY;
+pub use timestamp_store::{MemoryTimestampStore, StaleTimestamp, TimestampStore};
+pub use token::{issue_token, verify_token, verify_token_for_identity, TokenClaims};
+#[cfg(feature = "tonic")]
+pub use tonic::{grpc_interceptor, GRPC_SIGNED_PATH};
+#[cfg(feature = "vcard")]
+pub use vcard::{identity_hint_from_vcard, VcardIdentityHint};
+pub use verifier_cache::DEFAULT_VERIFIER_CACHE_CAPACITY;
+pub use verifier_cache::{verify_request_with_identity, VerifierCache};
+#[cfg(feature = "webfinger")]
+pub use webfinger::{discover_webfinger, location_from_jrd, webfinger_jrd, webfinger_url};
+#[cfg(feature = "webfinger")]
+pub use webfinger::{MAX_JRD_SIZE, WEBFINGER_LOCATION_REL};
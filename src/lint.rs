@@ -0,0 +1,317 @@
+use lol_html::{element, HtmlRewriter, Settings};
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+use url::Url;
+
+/// Descriptions longer than this are usually cut off when shown in a profile card.
+pub const RECOMMENDED_DESCRIPTION_LENGTH: usize = 160;
+
+/// An advisory issue found on an identity page by [`lint_identity_page`].
+///
+/// Unlike the errors returned by [`get_identity`](crate::get_identity), lints don't prevent
+/// the page from being used as an identity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Lint {
+    /// No `identity:public-key` is listed, so the page can't be used as an identity at all
+    MissingPublicKey,
+    /// A key is listed but no display name, so the location is shown instead
+    MissingDisplayName,
+    MissingDescription,
+    DescriptionTooLong {
+        length: usize,
+        recommended: usize,
+    },
+    /// The avatar is hosted on a different origin than the identity page
+    OffOriginAvatar(Url),
+    /// The avatar is loaded over plain HTTP
+    InsecureAvatar(Url),
+    /// A meta tag that should be listed once is listed several times
+    DuplicateMeta(String),
+    MissingVersion,
+    /// An `identity:*` meta tag that isn't part of the declared spec version, likely a typo.
+    /// Only reported for pages declaring version 2 or later
+    UnknownTag(String),
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Lint::MissingPublicKey => write!(f, "No 'identity:public-key' is listed."),
+            Lint::MissingDisplayName => write!(
+                f,
+                "No display name is set, the location will be shown instead."
+            ),
+            Lint::MissingDescription => write!(f, "No description is set."),
+            Lint::DescriptionTooLong {
+                length,
+                recommended,
+            } => write!(
+                f,
+                "The description is {} characters long, more than the recommended {}.",
+                length, recommended
+            ),
+            Lint::OffOriginAvatar(url) => write!(
+                f,
+                "The avatar '{}' is hosted on a different origin than the page.",
+                url
+            ),
+            Lint::InsecureAvatar(url) => {
+                write!(f, "The avatar '{}' is not served over HTTPS.", url)
+            }
+            Lint::DuplicateMeta(name) => {
+                write!(f, "The '{}' meta tag is listed more than once.", name)
+            }
+            Lint::MissingVersion => write!(f, "No 'identity:version' is set."),
+            Lint::UnknownTag(name) => write!(f, "The '{}' meta tag is not known.", name),
+        }
+    }
+}
+
+/// Meta tags that are only read once, so listing them several times is likely a mistake.
+const SINGLE_META: &[&str] = &[
+    "identity:threshold",
+    "identity:display-name",
+    "identity:avatar",
+    "identity:description",
+    "identity:version",
+    "author",
+    "description",
+    "og:author",
+    "og:title",
+    "og:image",
+    "og:description",
+];
+
+/// The `identity:*` meta tags of the current spec version, besides `identity:description:<lang>`.
+const KNOWN_TAGS: &[&str] = &[
+    "identity:public-key",
+    "identity:backup-location",
+    "identity:threshold",
+    "identity:display-name",
+    "identity:avatar",
+    "identity:description",
+    "identity:version",
+    "identity:retired-key",
+    "identity:rotation",
+];
+
+#[derive(Default, Debug)]
+struct PageMeta {
+    meta: Vec<(String, String)>,
+    favicon: Option<String>,
+}
+
+impl PageMeta {
+    fn first(&self, name: &str) -> Option<&str> {
+        self.meta
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, content)| content.as_str())
+    }
+
+    fn count(&self, name: &str) -> usize {
+        self.meta.iter().filter(|(key, _)| key == name).count()
+    }
+}
+
+/// Checks an identity page served from `source_url` against recommended practices, for page
+/// authors to run before publishing it.
+///
+/// This is advisory and never fails: a page that can't be parsed just produces lints for what
+/// couldn't be found.
+pub fn lint_identity_page(source_url: &Url, content: &str) -> Vec<Lint> {
+    let page = Rc::new(RefCell::new(PageMeta::default()));
+    let (meta_page, link_page) = (Rc::clone(&page), Rc::clone(&page));
+
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers: vec![
+                element!("meta", move |el| {
+                    let key = el
+                        .get_attribute("property")
+                        .or_else(|| el.get_attribute("name"));
+                    if let (Some(key), Some(content)) = (key, el.get_attribute("content")) {
+                        meta_page.borrow_mut().meta.push((key, content));
+                    }
+                    Ok(())
+                }),
+                element!("link", move |el| {
+                    if let Some(rel) = el.get_attribute("rel") {
+                        if rel == "icon" || rel == "shortcut icon" {
+                            if let Some(href) = el.get_attribute("href") {
+                                link_page.borrow_mut().favicon = Some(href);
+                            }
+                        }
+                    }
+                    Ok(())
+                }),
+            ],
+            ..Settings::default()
+        },
+        |_: &[u8]| {},
+    );
+    // Lints are best effort, keep whatever was collected before a parse error
+    let _ = rewriter
+        .write(content.as_bytes())
+        .and_then(|_| rewriter.end());
+    let page = Rc::try_unwrap(page).unwrap().into_inner();
+
+    let mut lints = Vec::new();
+
+    if page.first("identity:public-key").is_none() {
+        lints.push(Lint::MissingPublicKey);
+    } else if ["identity:display-name", "author", "og:author", "og:title"]
+        .iter()
+        .all(|name| page.first(name).is_none_or(str::is_empty))
+    {
+        lints.push(Lint::MissingDisplayName);
+    }
+
+    match page
+        .first("identity:description")
+        .or_else(|| page.first("description"))
+        .or_else(|| page.first("og:description"))
+    {
+        None => lints.push(Lint::MissingDescription),
+        Some(description) => {
+            let length = description.chars().count();
+            if length > RECOMMENDED_DESCRIPTION_LENGTH {
+                lints.push(Lint::DescriptionTooLong {
+                    length,
+                    recommended: RECOMMENDED_DESCRIPTION_LENGTH,
+                });
+            }
+        }
+    }
+
+    let avatar = page
+        .first("identity:avatar")
+        .or_else(|| page.first("og:image"))
+        .or(page.favicon.as_deref())
+        .and_then(|href| source_url.join(href).ok());
+    if let Some(avatar) = avatar {
+        if avatar.scheme() == "http" {
+            lints.push(Lint::InsecureAvatar(avatar.clone()));
+        }
+        if avatar.origin() != source_url.origin() {
+            lints.push(Lint::OffOriginAvatar(avatar));
+        }
+    }
+
+    for name in SINGLE_META {
+        if page.count(name) > 1 {
+            lints.push(Lint::DuplicateMeta(name.to_string()));
+        }
+    }
+
+    match page.first("identity:version") {
+        None => lints.push(Lint::MissingVersion),
+        Some(version) if version.trim().parse::<u32>().is_ok_and(|v| v >= 2) => {
+            let mut unknown: Vec<&str> = page
+                .meta
+                .iter()
+                .map(|(key, _)| key.as_str())
+                .filter(|key| key.starts_with("identity:"))
+                .filter(|key| {
+                    !KNOWN_TAGS.contains(key) && !key.starts_with("identity:description:")
+                })
+                .collect();
+            unknown.sort_unstable();
+            unknown.dedup();
+            lints.extend(unknown.into_iter().map(|key| Lint::UnknownTag(key.into())));
+        }
+        Some(_) => {}
+    }
+
+    lints
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestIdentity;
+
+    fn lint(head: &str) -> Vec<Lint> {
+        let key = TestIdentity::generate("amy.carroted.org")
+            .identity
+            .public_key
+            .to_prefixed();
+        let page = format!(
+            r#"<html><head><meta name="identity:public-key" content="{}">{}</head></html>"#,
+            key, head
+        );
+        lint_identity_page(&Url::parse("https://amy.carroted.org").unwrap(), &page)
+    }
+
+    #[test]
+    fn accepts_a_complete_page() {
+        let head = r#"
+            <meta name="identity:version" content="2">
+            <meta name="identity:display-name" content="Amy">
+            <meta name="identity:description" content="Writes about carrots.">
+            <meta name="identity:description:fr" content="Écrit sur les carottes.">
+            <meta name="identity:avatar" content="/avatar.png">
+        "#;
+        assert_eq!(lint(head), []);
+    }
+
+    #[test]
+    fn reports_each_issue() {
+        let description = "a".repeat(RECOMMENDED_DESCRIPTION_LENGTH + 1);
+        let head = format!(
+            r#"
+            <meta name="identity:version" content="2">
+            <meta name="identity:description" content="{}">
+            <meta name="identity:avatar" content="http://cdn.example/a.png">
+            <meta property="og:title" content="">
+            <meta property="og:title" content="">
+            <meta name="identity:dispaly-name" content="Amy">
+            "#,
+            description
+        );
+        let avatar = Url::parse("http://cdn.example/a.png").unwrap();
+        assert_eq!(
+            lint(&head),
+            [
+                Lint::MissingDisplayName,
+                Lint::DescriptionTooLong {
+                    length: RECOMMENDED_DESCRIPTION_LENGTH + 1,
+                    recommended: RECOMMENDED_DESCRIPTION_LENGTH
+                },
+                Lint::InsecureAvatar(avatar.clone()),
+                Lint::OffOriginAvatar(avatar),
+                Lint::DuplicateMeta("og:title".into()),
+                Lint::UnknownTag("identity:dispaly-name".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn only_reports_unknown_tags_from_version_2() {
+        let head = r#"<meta name="identity:description" content="Hi"><meta name="identity:pronouns" content="she/her">"#;
+        assert_eq!(lint(head), [Lint::MissingDisplayName, Lint::MissingVersion]);
+        let versioned = format!(r#"<meta name="identity:version" content="1">{}"#, head);
+        assert_eq!(lint(&versioned), [Lint::MissingDisplayName]);
+    }
+
+    #[test]
+    fn never_fails_on_pages_that_are_not_identities() {
+        let url = Url::parse("https://amy.carroted.org").unwrap();
+        for page in ["", "<html><body>Hello</body></html>", "<meta <<<"] {
+            assert_eq!(
+                lint_identity_page(&url, page),
+                [
+                    Lint::MissingPublicKey,
+                    Lint::MissingDescription,
+                    Lint::MissingVersion
+                ]
+            );
+        }
+        assert_eq!(
+            Lint::MissingPublicKey.to_string(),
+            "No 'identity:public-key' is listed."
+        );
+    }
+}
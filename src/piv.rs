@@ -0,0 +1,318 @@
+//! Signing through the PIV interface of a device the application drives.
+//!
+//! This crate ships no device driver and never talks to a device itself: the application
+//! implements [`PivDevice`] on top of one (e.g. the `yubikey` crate), and [`PivSigner`] turns
+//! it into a [`RequestSigner`]. Other kinds of keys (FIDO2 authenticators, HSMs) can implement
+//! [`RequestSigner`] or [`AsyncRequestSigner`](crate::AsyncRequestSigner) directly.
+
+use super::error::WebIdentityError;
+use super::sign::RequestSigner;
+use ed25519_dalek::{Signature, VerifyingKey};
+use std::fmt;
+use std::sync::Mutex;
+use zeroize::Zeroizing;
+
+/// The PIV slot for digital signatures (9c), where the identity key is expected by default.
+pub const PIV_SIGNATURE_SLOT: u8 = 0x9c;
+
+/// The algorithm of the key in a PIV slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PivAlgorithm {
+    Ed25519,
+    Rsa,
+    EccP256,
+    EccP384,
+    /// Another algorithm, by its PIV algorithm identifier
+    Other(u8),
+}
+
+/// A failed PIV device operation.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum PivError {
+    #[error("The PIN must be verified first.")]
+    PinRequired,
+
+    #[error("The PIN is incorrect ({} tries left).", .0.map_or("unknown".to_string(), |n| n.to_string()))]
+    WrongPin(Option<u8>),
+
+    #[error("The slot holds no key.")]
+    EmptySlot,
+
+    #[error("The device doesn't support this operation.")]
+    Unsupported,
+
+    #[error("{0}")]
+    Device(String),
+}
+
+/// A PIV device, implemented outside this crate on top of its driver.
+pub trait PivDevice: Send {
+    /// The algorithm and public key of the key in `slot`.
+    fn slot_key(&mut self, slot: u8) -> Result<(PivAlgorithm, Vec<u8>), PivError>;
+
+    /// Verifies the PIN, which unlocks signing until the device is reset or removed.
+    fn verify_pin(&mut self, pin: &[u8]) -> Result<(), PivError>;
+
+    /// Signs `message` with the key in `slot`. Ed25519 signs the message itself, so it must
+    /// be passed to the device as is, never pre-hashed.
+    ///
+    /// Returns [`PivError::PinRequired`] if the PIN has to be verified first.
+    fn sign(&mut self, slot: u8, message: &[u8]) -> Result<Vec<u8>, PivError>;
+}
+
+/// Signs requests with an Ed25519 key held in a PIV slot.
+///
+/// The PIN is asked for through a callback the first time the device requires it, and is
+/// zeroized once it was passed to the device. Every signature is checked against the slot's
+/// public key, so a device that doesn't produce raw Ed25519 signatures (e.g. one that only
+/// does Ed25519ph) fails with a clear error instead of sending requests nobody can verify.
+pub struct PivSigner<D> {
+    device: Mutex<D>,
+    slot: u8,
+    verifying_key: VerifyingKey,
+    pin: Box<dyn Fn() -> Option<Zeroizing<String>> + Send + Sync>,
+}
+
+impl<D: PivDevice> PivSigner<D> {
+    /// Opens the key in the signature slot (9c). `pin` is called when the device requires its
+    /// PIN, and can return `None` if the user declines to enter it.
+    ///
+    /// # Errors
+    /// Returns [`WebIdentityError::Signer`] if the slot is empty or doesn't hold an Ed25519
+    /// key.
+    pub fn open(
+        device: D,
+        pin: impl Fn() -> Option<Zeroizing<String>> + Send + Sync + 'static,
+    ) -> Result<Self, WebIdentityError> {
+        Self::open_slot(device, PIV_SIGNATURE_SLOT, pin)
+    }
+
+    /// Opens the key in `slot`, see [`PivSigner::open`].
+    pub fn open_slot(
+        mut device: D,
+        slot: u8,
+        pin: impl Fn() -> Option<Zeroizing<String>> + Send + Sync + 'static,
+    ) -> Result<Self, WebIdentityError> {
+        let (algorithm, public_key) = device.slot_key(slot).map_err(signer_error)?;
+        if algorithm != PivAlgorithm::Ed25519 {
+            return Err(WebIdentityError::Signer(format!(
+                "Slot {:02x} holds a {:?} key, not an Ed25519 one.",
+                slot, algorithm
+            )));
+        }
+        let verifying_key = <[u8; 32]>::try_from(public_key.as_slice())
+            .ok()
+            .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+            .ok_or_else(|| {
+                WebIdentityError::Signer(format!("Slot {:02x} holds an invalid key.", slot))
+            })?;
+
+        Ok(PivSigner {
+            device: Mutex::new(device),
+            slot,
+            verifying_key,
+            pin: Box::new(pin),
+        })
+    }
+
+    /// The slot the key is in.
+    pub fn slot(&self) -> u8 {
+        self.slot
+    }
+}
+
+impl<D: PivDevice> RequestSigner for PivSigner<D> {
+    fn verifying_key(&self) -> VerifyingKey {
+        self.verifying_key
+    }
+
+    fn sign_message(&self, message: &[u8]) -> Result<[u8; 64], WebIdentityError> {
+        let mut device = self
+            .device
+            .lock()
+            .map_err(|_| WebIdentityError::Signer("The device is unavailable.".to_string()))?;
+        let signature = match device.sign(self.slot, message) {
+            Err(PivError::PinRequired) => {
+                let pin = (self.pin)().ok_or_else(|| {
+                    WebIdentityError::Signer("The device requires a PIN.".to_string())
+                })?;
+                device.verify_pin(pin.as_bytes()).map_err(signer_error)?;
+                device.sign(self.slot, message)
+            }
+            result => result,
+        }
+        .map_err(signer_error)?;
+
+        let signature = <[u8; 64]>::try_from(signature.as_slice())
+            .ok()
+            .filter(|bytes| {
+                self.verifying_key
+                    .verify_strict(message, &Signature::from_bytes(bytes))
+                    .is_ok()
+            })
+            .ok_or_else(|| {
+                WebIdentityError::Signer(
+                    "The device didn't produce a raw Ed25519 signature.".to_string(),
+                )
+            })?;
+        Ok(signature)
+    }
+}
+
+impl<D> fmt::Debug for PivSigner<D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PivSigner")
+            .field("slot", &self.slot)
+            .field("verifying_key", &self.verifying_key)
+            .finish_non_exhaustive()
+    }
+}
+
+fn signer_error(error: PivError) -> WebIdentityError {
+    WebIdentityError::Signer(error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::RequestDigest;
+    use crate::sign::{create_signed_headers, verify_request_with_key, VerifyOptions};
+    use ed25519_dalek::{Signer, SigningKey};
+    use sha2::{Digest, Sha512};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const PIN: &str = "123456";
+
+    struct FakeDevice {
+        key: SigningKey,
+        algorithm: PivAlgorithm,
+        unlocked: bool,
+        tries: u8,
+        prehash: bool,
+    }
+
+    impl FakeDevice {
+        fn new() -> Self {
+            FakeDevice {
+                key: SigningKey::from_bytes(&[9; 32]),
+                algorithm: PivAlgorithm::Ed25519,
+                unlocked: false,
+                tries: 3,
+                prehash: false,
+            }
+        }
+    }
+
+    impl PivDevice for FakeDevice {
+        fn slot_key(&mut self, slot: u8) -> Result<(PivAlgorithm, Vec<u8>), PivError> {
+            if slot != PIV_SIGNATURE_SLOT {
+                return Err(PivError::EmptySlot);
+            }
+            Ok((self.algorithm, self.key.verifying_key().to_bytes().to_vec()))
+        }
+
+        fn verify_pin(&mut self, pin: &[u8]) -> Result<(), PivError> {
+            if pin != PIN.as_bytes() {
+                self.tries -= 1;
+                return Err(PivError::WrongPin(Some(self.tries)));
+            }
+            self.unlocked = true;
+            Ok(())
+        }
+
+        fn sign(&mut self, _slot: u8, message: &[u8]) -> Result<Vec<u8>, PivError> {
+            if !self.unlocked {
+                return Err(PivError::PinRequired);
+            }
+            let signature = if self.prehash {
+                self.key.sign(&Sha512::digest(message))
+            } else {
+                self.key.sign(message)
+            };
+            Ok(signature.to_bytes().to_vec())
+        }
+    }
+
+    fn counting_pin(
+        pin: Option<&'static str>,
+    ) -> (
+        Arc<AtomicUsize>,
+        impl Fn() -> Option<Zeroizing<String>> + Send + Sync,
+    ) {
+        let asked = Arc::new(AtomicUsize::new(0));
+        let counter = asked.clone();
+        let callback = move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            pin.map(|pin| Zeroizing::new(pin.to_string()))
+        };
+        (asked, callback)
+    }
+
+    #[test]
+    fn signs_verifiable_requests_asking_for_the_pin_once() {
+        let (asked, pin) = counting_pin(Some(PIN));
+        let signer = PivSigner::open(FakeDevice::new(), pin).unwrap();
+
+        for _ in 0..2 {
+            let headers = create_signed_headers(
+                "amy.carroted.org",
+                "POST",
+                "example.com",
+                "/notes",
+                b"hello",
+                &signer,
+            )
+            .unwrap();
+            verify_request_with_key(
+                "POST",
+                "example.com",
+                "/notes",
+                &RequestDigest::of(b"hello"),
+                &headers,
+                &signer.verifying_key(),
+                &VerifyOptions::new(Duration::from_secs(300)),
+            )
+            .unwrap();
+        }
+        assert_eq!(asked.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn rejects_keys_that_are_not_ed25519() {
+        let mut device = FakeDevice::new();
+        device.algorithm = PivAlgorithm::EccP256;
+        let error = PivSigner::open(device, || None).unwrap_err();
+        assert!(
+            error.to_string().contains("not an Ed25519 one"),
+            "{}",
+            error
+        );
+
+        let error = PivSigner::open_slot(FakeDevice::new(), 0x9a, || None).unwrap_err();
+        assert!(error.to_string().contains("holds no key"), "{}", error);
+    }
+
+    #[test]
+    fn reports_pin_failures() {
+        let signer = PivSigner::open(FakeDevice::new(), || None).unwrap();
+        let error = signer.sign_message(b"message").unwrap_err();
+        assert!(error.to_string().contains("requires a PIN"), "{}", error);
+
+        let (_, pin) = counting_pin(Some("000000"));
+        let signer = PivSigner::open(FakeDevice::new(), pin).unwrap();
+        let error = signer.sign_message(b"message").unwrap_err();
+        assert!(error.to_string().contains("2 tries left"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_signatures_that_are_not_raw_ed25519() {
+        let mut device = FakeDevice::new();
+        device.prehash = true;
+        let (_, pin) = counting_pin(Some(PIN));
+        let signer = PivSigner::open(device, pin).unwrap();
+        let error = signer.sign_message(b"message").unwrap_err();
+        assert!(error.to_string().contains("raw Ed25519"), "{}", error);
+    }
+}
@@ -0,0 +1,154 @@
+use super::error::WebIdentityError;
+use super::identity::{identity_id, parse_public_key, PK_PREFIX};
+use ed25519_dalek::VerifyingKey;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+
+/// The multicodec prefix of an Ed25519 public key.
+pub(crate) const ED25519_MULTICODEC: [u8; 2] = [0xed, 0x01];
+
+/// A validated Ed25519 public key, as listed on an identity page.
+///
+/// It dereferences to its 32 bytes, so it can be passed wherever a `&[u8]` key is expected.
+/// It is serialized as `ed25519-pub:<hex>`.
+#[derive(Clone, Copy)]
+pub struct PublicKey {
+    bytes: [u8; 32],
+    verifying_key: VerifyingKey,
+}
+
+impl PublicKey {
+    /// # Errors
+    /// Returns `Err` if `bytes` is not a valid Ed25519 public key.
+    pub fn from_bytes(bytes: &[u8]) -> Result<PublicKey, WebIdentityError> {
+        let bytes: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| WebIdentityError::InvalidPublicKeyFormat("Wrong key size".into()))?;
+        let verifying_key = VerifyingKey::from_bytes(&bytes).map_err(|_| {
+            WebIdentityError::InvalidPublicKeyFormat("Not a valid Ed25519 public key.".into())
+        })?;
+        Ok(PublicKey {
+            bytes,
+            verifying_key,
+        })
+    }
+
+    /// Parses a key as listed on an identity page, e.g. `ed25519-pub:<hex>`.
+    ///
+    /// # Errors
+    /// Returns `Err` if the prefix is unsupported or the key is invalid.
+    pub fn from_hex_prefixed(value: &str) -> Result<PublicKey, WebIdentityError> {
+        parse_public_key(value)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.bytes
+    }
+
+    pub fn verifying_key(&self) -> &VerifyingKey {
+        &self.verifying_key
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.bytes)
+    }
+
+    /// The key as listed on an identity page: `ed25519-pub:<hex>`.
+    pub fn to_prefixed(&self) -> String {
+        format!("{}{}", PK_PREFIX, self.to_hex())
+    }
+
+    /// The key as a base58btc multibase `Multikey` (`z6Mk...`), as used by DIDs and ActivityPub.
+    pub fn to_multibase(&self) -> String {
+        let mut multikey = ED25519_MULTICODEC.to_vec();
+        multikey.extend_from_slice(&self.bytes);
+        format!("z{}", bs58::encode(multikey).into_string())
+    }
+
+    /// The key's fingerprint, which is the identity's id for its primary key.
+    pub fn id(&self) -> String {
+        identity_id(&self.bytes)
+    }
+}
+
+impl Deref for PublicKey {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl AsRef<[u8]> for PublicKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl From<VerifyingKey> for PublicKey {
+    fn from(verifying_key: VerifyingKey) -> Self {
+        PublicKey {
+            bytes: verifying_key.to_bytes(),
+            verifying_key,
+        }
+    }
+}
+
+impl From<PublicKey> for VerifyingKey {
+    fn from(public_key: PublicKey) -> Self {
+        public_key.verifying_key
+    }
+}
+
+impl PartialEq for PublicKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes == other.bytes
+    }
+}
+
+impl Eq for PublicKey {}
+
+impl Hash for PublicKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.bytes.hash(state);
+    }
+}
+
+impl PartialEq<[u8]> for PublicKey {
+    fn eq(&self, other: &[u8]) -> bool {
+        self.bytes[..] == *other
+    }
+}
+
+impl PartialEq<Vec<u8>> for PublicKey {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        self.bytes[..] == other[..]
+    }
+}
+
+impl fmt::Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PublicKey({})", self.to_prefixed())
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_prefixed())
+    }
+}
+
+impl Serialize for PublicKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_prefixed())
+    }
+}
+
+impl<'de> Deserialize<'de> for PublicKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        PublicKey::from_hex_prefixed(&value).map_err(serde::de::Error::custom)
+    }
+}
@@ -0,0 +1,228 @@
+use super::error::WebIdentityError;
+use super::sign::{key_fingerprint_hint, HeaderProvider};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How many keys a [`MemoryRateLimiter`] tracks by default.
+pub const DEFAULT_RATE_LIMITER_CAPACITY: usize = 100_000;
+
+/// Limits how often each identity can make requests.
+pub trait RateLimiter: Send + Sync {
+    /// Records a request for `key` (usually an identity id).
+    ///
+    /// # Errors
+    /// Returns [`WebIdentityError::RateLimited`] if the request is over the limit.
+    fn check(&self, key: &str) -> Result<(), WebIdentityError>;
+}
+
+/// An in-memory [`RateLimiter`] using the generic cell rate algorithm (GCRA), which behaves
+/// like a token bucket holding `burst` requests and refilling one every `period / burst`.
+///
+/// It tracks at most a fixed number of keys. Once full, keys back at their full burst are
+/// forgotten first, then the ones closest to it, so spraying many identities can't grow it
+/// unbounded.
+#[derive(Debug)]
+pub struct MemoryRateLimiter {
+    emission_interval: Duration,
+    burst_tolerance: Duration,
+    capacity: usize,
+    /// The theoretical arrival time of the next request for each key
+    arrivals: Mutex<HashMap<String, Instant>>,
+}
+
+impl MemoryRateLimiter {
+    /// Allows `burst` requests per `period` for each key, e.g. 30 per minute.
+    pub fn new(burst: u32, period: Duration) -> Self {
+        let emission_interval = period / burst.max(1);
+        MemoryRateLimiter {
+            emission_interval,
+            burst_tolerance: emission_interval * burst.saturating_sub(1),
+            capacity: DEFAULT_RATE_LIMITER_CAPACITY,
+            arrivals: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sets how many keys are tracked ([`DEFAULT_RATE_LIMITER_CAPACITY`] by default).
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    fn make_room(arrivals: &mut HashMap<String, Instant>, now: Instant, capacity: usize) {
+        arrivals.retain(|_, arrival| *arrival > now);
+        if arrivals.len() < capacity {
+            return;
+        }
+
+        // Forget the quarter of keys closest to their full burst at once, so a flood of new
+        // keys doesn't scan the whole map on every request
+        let mut by_arrival: Vec<Instant> = arrivals.values().copied().collect();
+        let index = by_arrival.len() / 4;
+        let (_, cutoff, _) = by_arrival.select_nth_unstable(index);
+        let cutoff = *cutoff;
+        arrivals.retain(|_, arrival| *arrival > cutoff);
+    }
+}
+
+impl RateLimiter for MemoryRateLimiter {
+    fn check(&self, key: &str) -> Result<(), WebIdentityError> {
+        let now = Instant::now();
+        let mut arrivals = self.arrivals.lock().unwrap();
+
+        let arrival = match arrivals.get(key) {
+            Some(arrival) => (*arrival).max(now),
+            None => {
+                if arrivals.len() >= self.capacity {
+                    Self::make_room(&mut arrivals, now, self.capacity);
+                }
+                now
+            }
+        };
+
+        let allowed_at = arrival.checked_sub(self.burst_tolerance).unwrap_or(now);
+        if allowed_at > now {
+            return Err(WebIdentityError::RateLimited {
+                retry_after: allowed_at - now,
+            });
+        }
+
+        arrivals.insert(key.to_string(), arrival + self.emission_interval);
+        Ok(())
+    }
+}
+
+/// Checks a request against `limiter` using only its headers, before resolving the identity or
+/// verifying the signature.
+///
+/// The request is keyed by its `WebIdentity-Key` fingerprint (the identity id) if it has one,
+/// otherwise by its `WebIdentity-Location`. Requests with neither are left to verification to
+/// reject. These headers aren't verified yet, so this only protects the work that follows; a
+/// limit on verified identities should also check [`Identity::id`](crate::Identity::id).
+///
+/// # Errors
+/// Returns [`WebIdentityError::RateLimited`] if the request is over the limit.
+pub fn check_request_rate(
+    limiter: &(impl RateLimiter + ?Sized),
+    headers: &impl HeaderProvider,
+) -> Result<(), WebIdentityError> {
+    let key = key_fingerprint_hint(headers)
+        .map(|fingerprint| fingerprint.to_ascii_lowercase())
+        .or_else(|| {
+            headers
+                .get_header("WebIdentity-Location")
+                .map(|location| format!("location:{}", location))
+        });
+    match key {
+        Some(key) => limiter.check(&key),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sign::SimpleHeaderProvider;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    fn is_limited(result: Result<(), WebIdentityError>) -> bool {
+        matches!(result, Err(WebIdentityError::RateLimited { .. }))
+    }
+
+    #[test]
+    fn allows_a_burst_per_key() {
+        let limiter = MemoryRateLimiter::new(3, Duration::from_secs(60));
+        for _ in 0..3 {
+            limiter.check("alice").unwrap();
+        }
+        match limiter.check("alice") {
+            Err(WebIdentityError::RateLimited { retry_after }) => {
+                assert!(retry_after <= Duration::from_secs(20), "{:?}", retry_after);
+                assert!(retry_after > Duration::from_secs(19), "{:?}", retry_after);
+            }
+            other => panic!("expected a rate limit, got {:?}", other),
+        }
+        limiter.check("bob").unwrap();
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let limiter = MemoryRateLimiter::new(2, Duration::from_secs(2));
+        limiter.check("alice").unwrap();
+        limiter.check("alice").unwrap();
+        assert!(is_limited(limiter.check("alice")));
+
+        thread::sleep(Duration::from_millis(1100));
+        limiter.check("alice").unwrap();
+        assert!(is_limited(limiter.check("alice")));
+    }
+
+    #[test]
+    fn counts_concurrent_requests_exactly() {
+        let limiter = MemoryRateLimiter::new(100, Duration::from_secs(3600));
+        let allowed = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..50 {
+                        if limiter.check("alice").is_ok() {
+                            allowed.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(allowed.into_inner(), 100);
+    }
+
+    #[test]
+    fn tracks_a_bounded_number_of_keys() {
+        let limiter = MemoryRateLimiter::new(5, Duration::from_secs(3600)).with_capacity(100);
+        for i in 0..10_000 {
+            limiter.check(&format!("sprayed-{}", i)).unwrap();
+            assert!(limiter.arrivals.lock().unwrap().len() <= 100);
+        }
+
+        // A key that was just limited is among the last to be forgotten
+        for _ in 0..5 {
+            limiter.check("alice").unwrap();
+        }
+        for i in 0..50 {
+            limiter.check(&format!("more-{}", i)).unwrap();
+        }
+        assert!(is_limited(limiter.check("alice")));
+    }
+
+    #[test]
+    fn keys_requests_by_fingerprint_then_location() {
+        let limiter = MemoryRateLimiter::new(1, Duration::from_secs(3600));
+        let headers = |pairs: &[(&str, &str)]| -> SimpleHeaderProvider {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        };
+
+        let by_key = headers(&[
+            ("WebIdentity-Key", "ABCDEF"),
+            ("WebIdentity-Location", "alice.example.com"),
+        ]);
+        check_request_rate(&limiter, &by_key).unwrap();
+        // The fingerprint is compared case-insensitively, whatever the location
+        let same_key = headers(&[
+            ("WebIdentity-Key", "abcdef"),
+            ("WebIdentity-Location", "other.example.com"),
+        ]);
+        assert!(is_limited(check_request_rate(&limiter, &same_key)));
+
+        let by_location = headers(&[("WebIdentity-Location", "alice.example.com")]);
+        check_request_rate(&limiter, &by_location).unwrap();
+        assert!(is_limited(check_request_rate(&limiter, &by_location)));
+
+        let anonymous = headers(&[]);
+        for _ in 0..3 {
+            check_request_rate(&limiter, &anonymous).unwrap();
+        }
+    }
+}
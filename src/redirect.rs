@@ -0,0 +1,88 @@
+use super::error::WebIdentityError;
+use url::Url;
+
+/// How many redirects are followed by default when fetching an identity page.
+pub const MAX_REDIRECTS: u8 = 5;
+
+/// Which redirects are followed when fetching an identity page.
+///
+/// A redirect from HTTPS to HTTP is never followed. The page is always parsed as the one at
+/// the location that was fetched, wherever it was redirected to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedirectPolicy {
+    /// Follows no redirect, so identities are only read from their location
+    None,
+    /// Follows up to this many redirects, to any host
+    Limited(u8),
+    /// Follows up to this many redirects, as long as they stay on the location's host
+    SameHost(u8),
+}
+
+/// Follows up to [`MAX_REDIRECTS`] redirects on the same host.
+impl Default for RedirectPolicy {
+    fn default() -> Self {
+        RedirectPolicy::SameHost(MAX_REDIRECTS)
+    }
+}
+
+impl RedirectPolicy {
+    /// Checks that the page at `url` may be read from `target`, after `redirects` redirects
+    /// were already followed. Fetchers call it before following each redirect.
+    ///
+    /// # Errors
+    /// Returns [`WebIdentityError::RedirectNotAllowed`] if the policy doesn't allow it.
+    pub fn check(&self, url: &Url, target: &Url, redirects: u8) -> Result<(), WebIdentityError> {
+        let allowed = match *self {
+            RedirectPolicy::None => false,
+            RedirectPolicy::Limited(max) => redirects < max,
+            RedirectPolicy::SameHost(max) => redirects < max && target.host_str() == url.host_str(),
+        };
+        let downgraded = url.scheme() == "https" && target.scheme() != "https";
+        if !allowed || downgraded {
+            return Err(WebIdentityError::RedirectNotAllowed(target.to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_redirects_as_the_policy_allows() {
+        let url = Url::parse("https://alice.example.com/").unwrap();
+        let same_host = Url::parse("https://alice.example.com/identity").unwrap();
+        let other_host = Url::parse("https://mallory.example.net/").unwrap();
+        let downgraded = Url::parse("http://alice.example.com/identity").unwrap();
+
+        let policy = RedirectPolicy::default();
+        assert!(policy.check(&url, &same_host, 0).is_ok());
+        assert!(policy.check(&url, &same_host, MAX_REDIRECTS - 1).is_ok());
+        assert!(policy.check(&url, &same_host, MAX_REDIRECTS).is_err());
+        assert!(policy.check(&url, &other_host, 0).is_err());
+        assert!(policy.check(&url, &downgraded, 0).is_err());
+
+        assert!(RedirectPolicy::None.check(&url, &same_host, 0).is_err());
+        assert!(RedirectPolicy::Limited(2)
+            .check(&url, &other_host, 1)
+            .is_ok());
+        assert!(RedirectPolicy::Limited(2)
+            .check(&url, &other_host, 2)
+            .is_err());
+        assert!(RedirectPolicy::Limited(2)
+            .check(&url, &downgraded, 0)
+            .is_err());
+    }
+
+    #[test]
+    fn upgrades_are_allowed() {
+        let url = Url::parse("http://alice.example.com/").unwrap();
+        let upgraded = Url::parse("https://alice.example.com/").unwrap();
+        RedirectPolicy::default().check(&url, &upgraded, 0).unwrap();
+        assert!(matches!(
+            RedirectPolicy::SameHost(0).check(&url, &upgraded, 0),
+            Err(WebIdentityError::RedirectNotAllowed(target)) if target == "https://alice.example.com/"
+        ));
+    }
+}
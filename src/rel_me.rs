@@ -0,0 +1,117 @@
+use super::identity::{is_rel_me, location_from_url, Identity};
+use lol_html::{element, HtmlRewriter, Settings};
+use std::cell::RefCell;
+use std::rc::Rc;
+use url::Url;
+
+impl Identity {
+    /// Checks that the profile at `profile_url`, whose HTML is `profile_page`, links back to
+    /// this identity, confirming one of its [`Identity::verified_links`] in both directions.
+    ///
+    /// The profile must be listed in `verified_links`, and have a `<link rel="me">` or
+    /// `<a rel="me">` pointing at the identity's location (ignoring the scheme and a trailing
+    /// slash). Fetching the profile is left to the caller.
+    pub fn is_linked_back(&self, profile_url: &Url, profile_page: &str) -> bool {
+        let profile = location_from_url(profile_url);
+        if !self
+            .verified_links
+            .iter()
+            .any(|link| location_from_url(link) == profile)
+        {
+            return false;
+        }
+
+        rel_me_links(profile_url, profile_page)
+            .iter()
+            .any(|link| location_from_url(link) == self.location)
+    }
+}
+
+/// Reads the `rel="me"` links of a page, resolved against `base`.
+fn rel_me_links(base: &Url, page: &str) -> Vec<Url> {
+    let links = Rc::new(RefCell::new(Vec::new()));
+    let handler_links = Rc::clone(&links);
+
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers: vec![element!("a[rel], link[rel]", move |el| {
+                if el.get_attribute("rel").is_some_and(|rel| is_rel_me(&rel)) {
+                    if let Some(href) = el.get_attribute("href") {
+                        handler_links.borrow_mut().push(href);
+                    }
+                }
+                Ok(())
+            })],
+            ..Settings::default()
+        },
+        |_: &[u8]| {},
+    );
+    // Keep whatever links were found before a parse error
+    let _ = rewriter.write(page.as_bytes()).and_then(|_| rewriter.end());
+
+    Rc::try_unwrap(links)
+        .unwrap()
+        .into_inner()
+        .iter()
+        .filter_map(|href| base.join(href.trim()).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestIdentity;
+
+    const PROFILE: &str = "https://social.example/@amy";
+
+    fn amy() -> Identity {
+        let amy = TestIdentity::generate("amy.carroted.org");
+        let links = format!(
+            r#"<link rel="me" href="{}"><a rel="me" href="mailto:amy@carroted.org">Mail</a></head>"#,
+            PROFILE
+        );
+        let page = amy.page.replace("</head>", &links);
+        crate::get_identity(&Url::parse("https://amy.carroted.org").unwrap(), &page).unwrap()
+    }
+
+    #[test]
+    fn confirms_profiles_linking_back() {
+        let amy = amy();
+        // Only web pages can link back
+        assert_eq!(amy.verified_links, [Url::parse(PROFILE).unwrap()]);
+
+        let profile_url = Url::parse(PROFILE).unwrap();
+        for page in [
+            r#"<a rel="me" href="https://amy.carroted.org">Website</a>"#,
+            r#"<link rel="me" href="http://amy.carroted.org/">"#,
+            r#"<a rel="nofollow ME noopener" href="//amy.carroted.org">Website</a>"#,
+        ] {
+            assert!(amy.is_linked_back(&profile_url, page), "{}", page);
+        }
+        // The profile URL may differ in scheme and trailing slash
+        let page = r#"<a rel="me" href="https://amy.carroted.org">Website</a>"#;
+        let profile_url = Url::parse("http://social.example/@amy/").unwrap();
+        assert!(amy.is_linked_back(&profile_url, page));
+    }
+
+    #[test]
+    fn rejects_profiles_that_do_not_link_back() {
+        let amy = amy();
+        let profile_url = Url::parse(PROFILE).unwrap();
+        for page in [
+            "",
+            r#"<a href="https://amy.carroted.org">Website</a>"#,
+            r#"<a rel="meh" href="https://amy.carroted.org">Website</a>"#,
+            r#"<a rel="me" href="https://amy.carroted.org/blog">Blog</a>"#,
+            r#"<a rel="me" href="https://mallory.example">Website</a>"#,
+            r#"<a rel="me">Website</a>"#,
+        ] {
+            assert!(!amy.is_linked_back(&profile_url, page), "{}", page);
+        }
+
+        // A profile the identity doesn't link to can't confirm it
+        let page = r#"<a rel="me" href="https://amy.carroted.org">Website</a>"#;
+        let other = Url::parse("https://social.example/@mallory").unwrap();
+        assert!(!amy.is_linked_back(&other, page));
+    }
+}
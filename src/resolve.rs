@@ -1,13 +1,39 @@
+use super::diff::{FieldChange, IdentityDiff, Severity};
 use super::error::WebIdentityError;
+use super::identity::{get_identity, location_from_url, Identity};
+use super::identity_ref::{strip_scheme, IdentityRef};
+use super::verifier_cache::VerifierCache;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Component, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use url::Url;
 
 /// Resolves a location string into a full HTTPS or HTTP URL.
 ///
-/// It prepends "https://" if no protocol is specified.
+/// It prepends "https://" if no protocol is specified. A `webid:` URI (see [`IdentityRef`]) is
+/// resolved to the location it references.
 ///
 /// # Errors
 /// Returns `Err` if the protocol is not `http` or `https`, or if the URL is invalid.
 pub fn resolve_location_url(location: &str) -> Result<Url, WebIdentityError> {
+    let mut url = parse_location_url(location)?;
+    // `example.com.` is the fully qualified form of `example.com`, keep a single form
+    if let Some(host) = url.host_str().and_then(|host| host.strip_suffix('.')) {
+        let host = host.to_string();
+        url.set_host(Some(&host))?;
+    }
+    Ok(url)
+}
+
+fn parse_location_url(location: &str) -> Result<Url, WebIdentityError> {
+    if strip_scheme(location.trim()).is_some() {
+        let identity_ref = IdentityRef::parse(location)?;
+        return resolve_location_url(identity_ref.location());
+    }
     if location.contains("://") {
         let scheme = location.split("://").next().unwrap_or("");
         if scheme == "http" || scheme == "https" {
@@ -20,3 +46,994 @@ pub fn resolve_location_url(location: &str) -> Result<Url, WebIdentityError> {
         Url::parse(&full_url).map_err(WebIdentityError::from)
     }
 }
+
+/// Why a location did or didn't resolve to an identity, for tools such as directory crawlers
+/// that categorize locations without matching on every error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ResolutionStatus {
+    Resolved,
+    /// The page was retrieved but lists no `identity:public-key`
+    NotAnIdentity,
+    /// The page couldn't be retrieved ([`WebIdentityError::Fetch`], a
+    /// [`WebIdentityError::HttpStatus`] other than `404` and `410`, or
+    /// [`WebIdentityError::Io`])
+    Unreachable,
+    /// There is no page at the location (`404 Not Found`, or a missing file)
+    NotFound,
+    /// The page was removed on purpose (`410 Gone`), e.g. the identity was revoked
+    Gone,
+    /// The page was retrieved but isn't HTML
+    NotHtml,
+    /// The page lists an identity, but it is malformed (e.g. an invalid key or threshold)
+    InvalidIdentity,
+    /// The location isn't a valid or supported URL
+    InvalidLocation,
+    /// Any other failure, e.g. the identity was blocked
+    Other,
+}
+
+impl ResolutionStatus {
+    /// Summarizes the result of resolving an identity.
+    pub fn of<T>(result: &Result<T, WebIdentityError>) -> Self {
+        match result {
+            Ok(_) => ResolutionStatus::Resolved,
+            Err(error) => ResolutionStatus::from_error(error),
+        }
+    }
+
+    pub fn from_error(error: &WebIdentityError) -> Self {
+        match error {
+            WebIdentityError::MissingPublicKey => ResolutionStatus::NotAnIdentity,
+            WebIdentityError::HttpStatus(404) => ResolutionStatus::NotFound,
+            WebIdentityError::HttpStatus(410) => ResolutionStatus::Gone,
+            WebIdentityError::Io(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                ResolutionStatus::NotFound
+            }
+            WebIdentityError::Fetch(_)
+            | WebIdentityError::HttpStatus(_)
+            | WebIdentityError::Io(_) => ResolutionStatus::Unreachable,
+            WebIdentityError::UnexpectedContentType(_) => ResolutionStatus::NotHtml,
+            WebIdentityError::Parse(_)
+            | WebIdentityError::DocumentTooComplex
+            | WebIdentityError::BodyTooLarge(_)
+            | WebIdentityError::InvalidPublicKeyFormat(_)
+            | WebIdentityError::InvalidThreshold(_)
+            | WebIdentityError::InvalidSpecVersion(_)
+            | WebIdentityError::UnsupportedSpecVersion(_)
+            | WebIdentityError::SpecVersionMismatch { .. }
+            | WebIdentityError::MissingDisplayName => ResolutionStatus::InvalidIdentity,
+            WebIdentityError::UrlParse(_)
+            | WebIdentityError::UnsupportedProtocol(_)
+            | WebIdentityError::UnsupportedLocation(_)
+            | WebIdentityError::InvalidIdentityUri(_) => ResolutionStatus::InvalidLocation,
+            _ => ResolutionStatus::Other,
+        }
+    }
+}
+
+/// Resolves a location into a parsed [`Identity`].
+///
+/// Identities are returned behind an [`Arc`] so resolvers can hand out cached identities
+/// without copying them.
+pub trait IdentityResolver {
+    fn resolve_identity(&self, location: &str) -> Result<Arc<Identity>, WebIdentityError>;
+
+    /// Drops any cached copy of the identity for `location`, so the next resolution reads it
+    /// again. Resolvers without a cache don't need to implement it.
+    fn invalidate(&self, _location: &str) {}
+}
+
+/// How a [`FileSystemResolver`] maps a location to a file in its directory.
+#[derive(Debug, Clone, Copy)]
+pub enum FileNaming {
+    /// `amy.carroted.org/blog` is read from `amy.carroted.org_blog.html`
+    Flat,
+    /// `amy.carroted.org/blog` is read from `amy.carroted.org/blog/index.html`
+    Nested,
+    /// The location (as host and path, without a trailing slash) is mapped by the given function
+    Custom(fn(&str) -> PathBuf),
+}
+
+/// Resolves identities from HTML files in a directory instead of the network.
+///
+/// Useful for tests and air-gapped deployments. Parsed identities are cached until
+/// [`FileSystemResolver::invalidate`] or [`FileSystemResolver::clear_cache`] is called.
+#[derive(Debug)]
+pub struct FileSystemResolver {
+    directory: PathBuf,
+    naming: FileNaming,
+    cache: Mutex<HashMap<String, Arc<Identity>>>,
+}
+
+impl FileSystemResolver {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        FileSystemResolver {
+            directory: directory.into(),
+            naming: FileNaming::Flat,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_naming(mut self, naming: FileNaming) -> Self {
+        self.naming = naming;
+        self
+    }
+
+    /// Returns the path the identity for `location` is read from.
+    ///
+    /// # Errors
+    /// Returns `Err` if the location is not a valid URL.
+    pub fn path_for(&self, location: &str) -> Result<PathBuf, WebIdentityError> {
+        let url = resolve_location_url(location)?;
+        let location = location_from_url(&url);
+
+        let relative = match self.naming {
+            FileNaming::Flat => PathBuf::from(format!("{}.html", location.replace('/', "_"))),
+            FileNaming::Nested => {
+                let mut path: PathBuf = location.split('/').collect();
+                path.push("index.html");
+                path
+            }
+            FileNaming::Custom(naming) => naming(&location),
+        };
+
+        // Never read outside of the directory
+        if relative
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            return Err(WebIdentityError::UnsupportedLocation(location));
+        }
+
+        Ok(self.directory.join(relative))
+    }
+
+    pub fn invalidate(&self, location: &str) {
+        if let Ok(url) = resolve_location_url(location) {
+            self.cache.lock().unwrap().remove(&location_from_url(&url));
+        }
+    }
+
+    pub fn clear_cache(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+}
+
+impl IdentityResolver for FileSystemResolver {
+    fn resolve_identity(&self, location: &str) -> Result<Arc<Identity>, WebIdentityError> {
+        let url = resolve_location_url(location)?;
+        let key = location_from_url(&url);
+        if let Some(identity) = self.cache.lock().unwrap().get(&key) {
+            return Ok(Arc::clone(identity));
+        }
+
+        let content = fs::read_to_string(self.path_for(location)?)?;
+        let identity = Arc::new(Identity {
+            fetched_at: Some(SystemTime::now()),
+            ..get_identity(&url, &content)?
+        });
+
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(key, Arc::clone(&identity));
+        Ok(identity)
+    }
+
+    fn invalidate(&self, location: &str) {
+        FileSystemResolver::invalidate(self, location);
+    }
+}
+
+/// How long a [`CachingResolver`] uses an identity before resolving it again, by default.
+pub const DEFAULT_IDENTITY_TTL: Duration = Duration::from_secs(300);
+
+/// Runs the background refreshes of a [`CachingResolver`], so they can go through the
+/// application's executor instead of a thread each.
+///
+/// Refreshes call the inner resolver, which blocks, so a spawner for an async runtime should
+/// run them on its blocking pool, e.g. with `smol::unblock` or `tokio::task::spawn_blocking`.
+/// Closures taking the task are spawners.
+pub trait Spawner: Send + Sync {
+    fn spawn(&self, task: Box<dyn FnOnce() + Send>);
+}
+
+impl<F: Fn(Box<dyn FnOnce() + Send>) + Send + Sync> Spawner for F {
+    fn spawn(&self, task: Box<dyn FnOnce() + Send>) {
+        self(task)
+    }
+}
+
+/// Called by a [`CachingResolver`] with the new identity and what changed when resolving an
+/// identity again replaces a cached one that differs.
+type ChangeHook = Arc<dyn Fn(&Identity, &IdentityDiff) + Send + Sync>;
+
+/// The default [`Spawner`], running each task on a thread of its own.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThreadSpawner;
+
+impl Spawner for ThreadSpawner {
+    fn spawn(&self, task: Box<dyn FnOnce() + Send>) {
+        thread::spawn(task);
+    }
+}
+
+#[derive(Debug)]
+struct CachedIdentity {
+    identity: Arc<Identity>,
+    resolved_at: Instant,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<String, CachedIdentity>,
+    /// Locations with a background refresh in progress
+    refreshing: HashSet<String>,
+}
+
+/// Caches the identities resolved by another resolver, with stale-while-revalidate semantics.
+///
+/// An identity is reused for its TTL. Once expired, it is still returned for the stale window
+/// while it is resolved again in the background, on a thread of its own unless another
+/// [`Spawner`] is set, so verification isn't slowed down by the refresh. If the refresh fails the stale identity is kept until the window ends, after
+/// which the identity is resolved again before returning.
+///
+/// When a refreshed identity no longer lists a key, the key is evicted from
+/// [`VerifierCache::global`], and changes can be followed with
+/// [`CachingResolver::with_change_hook`].
+pub struct CachingResolver<R> {
+    inner: Arc<R>,
+    ttl: Duration,
+    stale_window: Duration,
+    state: Arc<Mutex<CacheState>>,
+    spawner: Arc<dyn Spawner>,
+    on_change: Option<ChangeHook>,
+}
+
+impl<R> CachingResolver<R> {
+    pub fn new(inner: R) -> Self {
+        CachingResolver {
+            inner: Arc::new(inner),
+            ttl: DEFAULT_IDENTITY_TTL,
+            stale_window: Duration::ZERO,
+            state: Arc::new(Mutex::new(CacheState::default())),
+            spawner: Arc::new(ThreadSpawner),
+            on_change: None,
+        }
+    }
+
+    /// Sets how long a resolved identity is used as-is ([`DEFAULT_IDENTITY_TTL`] by default).
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Sets how long after its TTL an identity is still returned while it is refreshed in the
+    /// background (zero by default, which disables serving stale identities).
+    pub fn with_stale_window(mut self, stale_window: Duration) -> Self {
+        self.stale_window = stale_window;
+        self
+    }
+
+    /// Runs background refreshes with `spawner` instead of [`ThreadSpawner`].
+    pub fn with_spawner(mut self, spawner: impl Spawner + 'static) -> Self {
+        self.spawner = Arc::new(spawner);
+        self
+    }
+
+    /// Calls `hook` with the new identity and an [`IdentityDiff`] from the cached one whenever
+    /// an identity resolved again differs from it, e.g. to notify people relying on it of a
+    /// key change.
+    ///
+    /// The hook runs on the thread that resolved the identity, which is a background one for
+    /// refreshes within the stale window.
+    pub fn with_change_hook(
+        mut self,
+        hook: impl Fn(&Identity, &IdentityDiff) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_change = Some(Arc::new(hook));
+        self
+    }
+
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    /// Drops the cached identity for `location`, and its keys from [`VerifierCache::global`].
+    pub fn invalidate(&self, location: &str) {
+        if let Ok(url) = resolve_location_url(location) {
+            let removed = self
+                .state
+                .lock()
+                .unwrap()
+                .entries
+                .remove(&location_from_url(&url));
+            if let Some(removed) = removed {
+                VerifierCache::global().evict_identity(&removed.identity);
+            }
+        }
+    }
+
+    pub fn clear_cache(&self) {
+        let entries = std::mem::take(&mut self.state.lock().unwrap().entries);
+        for cached in entries.values() {
+            VerifierCache::global().evict_identity(&cached.identity);
+        }
+    }
+}
+
+/// Records when an identity from a resolver that doesn't set [`Identity::fetched_at`] was
+/// resolved.
+fn with_fetched_at(identity: Arc<Identity>) -> Arc<Identity> {
+    if identity.fetched_at.is_some() {
+        return identity;
+    }
+    Arc::new(Identity {
+        fetched_at: Some(SystemTime::now()),
+        ..Identity::clone(&identity)
+    })
+}
+
+/// Tells the change hook, if any, how a replaced identity changed.
+fn report_change(on_change: Option<&ChangeHook>, old: &Identity, new: &Identity) {
+    if let Some(on_change) = on_change {
+        let diff = old.diff(new);
+        if !diff.is_empty() {
+            on_change(new, &diff);
+        }
+    }
+}
+
+/// Evicts the keys of a replaced identity that it no longer lists from
+/// [`VerifierCache::global`], so a rotated or revoked key isn't kept around.
+fn evict_rotated_keys(old: &Identity, new: Option<&Identity>) {
+    for key in &old.public_keys {
+        if new.is_none_or(|new| !new.public_keys.contains(key)) {
+            VerifierCache::global().evict(key);
+        }
+    }
+}
+
+impl<R: IdentityResolver + Send + Sync + 'static> CachingResolver<R> {
+    fn refresh_in_background(&self, key: String, location: &str) {
+        let (inner, state) = (Arc::clone(&self.inner), Arc::clone(&self.state));
+        let on_change = self.on_change.clone();
+        let location = location.to_string();
+
+        self.spawner.spawn(Box::new(move || {
+            let result = inner.resolve_identity(&location);
+
+            let mut state = state.lock().unwrap();
+            state.refreshing.remove(&key);
+            // A failed refresh keeps the stale entry, it is dropped once the window ends
+            if let Ok(identity) = result {
+                let identity = with_fetched_at(identity);
+                let replaced = state.entries.insert(
+                    key,
+                    CachedIdentity {
+                        identity: Arc::clone(&identity),
+                        resolved_at: Instant::now(),
+                    },
+                );
+                // The hook may resolve identities itself
+                drop(state);
+                if let Some(replaced) = replaced {
+                    evict_rotated_keys(&replaced.identity, Some(&identity));
+                    report_change(on_change.as_ref(), &replaced.identity, &identity);
+                }
+            }
+        }));
+    }
+}
+
+impl<R: fmt::Debug> fmt::Debug for CachingResolver<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CachingResolver")
+            .field("inner", &self.inner)
+            .field("ttl", &self.ttl)
+            .field("stale_window", &self.stale_window)
+            .field("state", &self.state)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R: IdentityResolver + Send + Sync + 'static> IdentityResolver for CachingResolver<R> {
+    fn resolve_identity(&self, location: &str) -> Result<Arc<Identity>, WebIdentityError> {
+        let url = resolve_location_url(location)?;
+        let key = location_from_url(&url);
+
+        let expired = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(cached) = state.entries.get(&key) {
+                let age = cached.resolved_at.elapsed();
+                if age < self.ttl {
+                    return Ok(Arc::clone(&cached.identity));
+                }
+                if age < self.ttl + self.stale_window {
+                    let identity = Arc::clone(&cached.identity);
+                    if state.refreshing.insert(key.clone()) {
+                        drop(state);
+                        self.refresh_in_background(key, location);
+                    }
+                    return Ok(identity);
+                }
+            }
+            state.entries.remove(&key)
+        };
+
+        let result = self.inner.resolve_identity(location);
+        if let Some(expired) = &expired {
+            evict_rotated_keys(&expired.identity, result.as_deref().ok());
+        }
+        let identity = with_fetched_at(result?);
+        self.state.lock().unwrap().entries.insert(
+            key,
+            CachedIdentity {
+                identity: Arc::clone(&identity),
+                resolved_at: Instant::now(),
+            },
+        );
+        if let Some(expired) = expired {
+            report_change(self.on_change.as_ref(), &expired.identity, &identity);
+        }
+        Ok(identity)
+    }
+
+    fn invalidate(&self, location: &str) {
+        CachingResolver::invalidate(self, location);
+        self.inner.invalidate(location);
+    }
+}
+
+/// Falls back to an identity's mirrors (its `identity:backup-location` tags) when its location
+/// can't be reached.
+///
+/// The mirrors are the ones listed the last time the identity was resolved, so an identity
+/// must have been resolved once before its mirrors can be used. Only unavailability (connection
+/// failures, timeouts and `5xx` responses) falls back to a mirror: an identity page that is
+/// missing, gone or invalid fails as usual. A mirror must list the same keys and threshold as
+/// the identity, otherwise it is rejected with [`WebIdentityError::MirrorKeyMismatch`] so
+/// mirrors can't be used to substitute keys. The identity returned from a mirror keeps the
+/// original location, keys and mirrors, only its profile is read from the mirror.
+#[derive(Debug)]
+pub struct MirrorResolver<R> {
+    inner: R,
+    known: Mutex<HashMap<String, Arc<Identity>>>,
+}
+
+impl<R> MirrorResolver<R> {
+    pub fn new(inner: R) -> Self {
+        MirrorResolver {
+            inner,
+            known: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+}
+
+impl<R: IdentityResolver> MirrorResolver<R> {
+    fn resolve_from_mirrors(
+        &self,
+        known: &Identity,
+        error: WebIdentityError,
+    ) -> Result<Arc<Identity>, WebIdentityError> {
+        let mut error = error;
+        for mirror in &known.backup_locations {
+            match self.inner.resolve_identity(mirror) {
+                Ok(mirrored) if !same_keys(known, &mirrored) => {
+                    // The mirror is expected to be at another location
+                    let mut diff = known.diff(&mirrored);
+                    diff.changes.retain(|change| {
+                        change.severity() == Severity::High
+                            && !matches!(change, FieldChange::LocationChanged { .. })
+                    });
+                    return Err(WebIdentityError::MirrorKeyMismatch {
+                        mirror: mirror.clone(),
+                        diff,
+                    });
+                }
+                Ok(mirrored) => {
+                    return Ok(Arc::new(Identity {
+                        id: known.id.clone(),
+                        public_key: known.public_key,
+                        public_keys: known.public_keys.clone(),
+                        threshold: known.threshold,
+                        location_url: known.location_url.clone(),
+                        location: known.location.clone(),
+                        backup_locations: known.backup_locations.clone(),
+                        ..Identity::clone(&mirrored)
+                    }));
+                }
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+}
+
+impl<R: IdentityResolver> IdentityResolver for MirrorResolver<R> {
+    fn resolve_identity(&self, location: &str) -> Result<Arc<Identity>, WebIdentityError> {
+        let key = location_from_url(&resolve_location_url(location)?);
+
+        match self.inner.resolve_identity(location) {
+            Ok(identity) => {
+                self.known
+                    .lock()
+                    .unwrap()
+                    .insert(key, Arc::clone(&identity));
+                Ok(identity)
+            }
+            Err(error) if is_unavailable(&error) => {
+                let known = self.known.lock().unwrap().get(&key).cloned();
+                match known {
+                    Some(known) => self.resolve_from_mirrors(&known, error),
+                    None => Err(error),
+                }
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    fn invalidate(&self, location: &str) {
+        self.inner.invalidate(location);
+    }
+}
+
+/// Whether a mirror lists the same primary key, set of keys and threshold as the identity.
+fn same_keys(known: &Identity, mirrored: &Identity) -> bool {
+    let keys = |identity: &Identity| -> HashSet<Vec<u8>> {
+        identity
+            .public_keys
+            .iter()
+            .map(|key| key.to_vec())
+            .collect()
+    };
+    mirrored.public_key == known.public_key
+        && mirrored.threshold.unwrap_or(1) == known.threshold.unwrap_or(1)
+        && keys(mirrored) == keys(known)
+}
+
+/// Whether an error means the identity page couldn't be reached, rather than that it doesn't
+/// exist or is invalid.
+fn is_unavailable(error: &WebIdentityError) -> bool {
+    match error {
+        WebIdentityError::Io(e) => e.kind() != std::io::ErrorKind::NotFound,
+        // Connection failures and timeouts
+        WebIdentityError::Fetch(_) => true,
+        WebIdentityError::HttpStatus(status) => *status >= 500,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::RequestDigest;
+    use crate::fetch::FetcherResolver;
+    use crate::sign::{create_signed_headers, verify_request_with_key, VerifyOptions};
+    use crate::testing::{MockFetcher, MockResponse, StaticFetcher, TestIdentity};
+    use crate::PublicKey;
+
+    const MIRROR: &str = "mirror.example.net/alice";
+
+    /// A [`StaticFetcher`] whose locations can be made to fail.
+    struct FlakyResolver {
+        pages: StaticFetcher,
+        failures: Mutex<HashMap<String, fn() -> WebIdentityError>>,
+    }
+
+    impl FlakyResolver {
+        fn fail(&self, location: &str, error: fn() -> WebIdentityError) {
+            self.failures
+                .lock()
+                .unwrap()
+                .insert(location.to_string(), error);
+        }
+    }
+
+    impl IdentityResolver for FlakyResolver {
+        fn resolve_identity(&self, location: &str) -> Result<Arc<Identity>, WebIdentityError> {
+            if let Some(error) = self.failures.lock().unwrap().get(location) {
+                return Err(error());
+            }
+            self.pages.resolve_identity(location)
+        }
+    }
+
+    fn with_mirror(page: &str) -> String {
+        page.replace(
+            "</head>",
+            &format!(
+                "    <meta name=\"identity:backup-location\" content=\"{}\">\n</head>",
+                MIRROR
+            ),
+        )
+    }
+
+    fn mirror_resolver(mirror_page: String) -> MirrorResolver<FlakyResolver> {
+        let alice = TestIdentity::generate("alice.example.com");
+        let resolver = MirrorResolver::new(FlakyResolver {
+            pages: StaticFetcher::new()
+                .with_page("alice.example.com", with_mirror(&alice.page))
+                .with_page(MIRROR, mirror_page),
+            failures: Mutex::new(HashMap::new()),
+        });
+        resolver.resolve_identity("alice.example.com").unwrap();
+        resolver
+    }
+
+    fn mirror_page() -> String {
+        TestIdentity::generate("alice.example.com")
+            .page
+            .replace("Test identity", "Alice (mirrored)")
+    }
+
+    #[test]
+    fn falls_back_to_mirror_when_unavailable() {
+        let resolver = mirror_resolver(mirror_page());
+        resolver.inner().fail("alice.example.com", || {
+            WebIdentityError::Fetch("connection refused".into())
+        });
+
+        let identity = resolver.resolve_identity("alice.example.com").unwrap();
+        assert_eq!(identity.location, "alice.example.com");
+        assert_eq!(identity.display_name, "Alice (mirrored)");
+        assert_eq!(identity.backup_locations, vec![MIRROR.to_string()]);
+
+        resolver
+            .inner()
+            .fail("alice.example.com", || WebIdentityError::HttpStatus(503));
+        assert!(resolver.resolve_identity("alice.example.com").is_ok());
+    }
+
+    #[test]
+    fn missing_page_does_not_fall_back() {
+        let resolver = mirror_resolver(mirror_page());
+        resolver
+            .inner()
+            .fail("alice.example.com", || WebIdentityError::HttpStatus(404));
+        assert!(matches!(
+            resolver.resolve_identity("alice.example.com"),
+            Err(WebIdentityError::HttpStatus(404))
+        ));
+
+        resolver
+            .inner()
+            .fail("alice.example.com", || WebIdentityError::HttpStatus(410));
+        assert!(matches!(
+            resolver.resolve_identity("alice.example.com"),
+            Err(WebIdentityError::HttpStatus(410))
+        ));
+    }
+
+    #[test]
+    fn rejects_mirror_with_extra_key() {
+        let extra = TestIdentity::generate("mallory.example.com");
+        let extra_key = format!(
+            "    <meta name=\"identity:public-key\" content=\"{}\">\n</head>",
+            extra.identity.public_key.to_prefixed()
+        );
+        let resolver = mirror_resolver(mirror_page().replace("</head>", &extra_key));
+        resolver.inner().fail("alice.example.com", || {
+            WebIdentityError::Fetch("timed out".into())
+        });
+
+        assert!(matches!(
+            resolver.resolve_identity("alice.example.com"),
+            Err(WebIdentityError::MirrorKeyMismatch { mirror, diff })
+                if mirror == MIRROR
+                    && diff.changes
+                        == [FieldChange::KeyAdded {
+                            key: hex::encode(extra.identity.public_key)
+                        }]
+        ));
+    }
+
+    #[test]
+    fn rejects_mirror_with_other_threshold() {
+        let bob = TestIdentity::generate("bob.example.com");
+        let second_key = format!(
+            "    <meta name=\"identity:public-key\" content=\"{}\">\n</head>",
+            bob.identity.public_key.to_prefixed()
+        );
+        let threshold = "    <meta name=\"identity:threshold\" content=\"2\">\n</head>";
+        let alice = TestIdentity::generate("alice.example.com");
+        let known = with_mirror(&alice.page.replace("</head>", &second_key));
+        let mirrored = known
+            .replace("</head>", threshold)
+            .replace("alice.example.com", MIRROR);
+        let resolver = MirrorResolver::new(FlakyResolver {
+            pages: StaticFetcher::new()
+                .with_page("alice.example.com", known)
+                .with_page(MIRROR, mirrored),
+            failures: Mutex::new(HashMap::new()),
+        });
+        resolver.resolve_identity("alice.example.com").unwrap();
+        resolver.inner().fail("alice.example.com", || {
+            WebIdentityError::Fetch("timed out".into())
+        });
+
+        assert!(matches!(
+            resolver.resolve_identity("alice.example.com"),
+            Err(WebIdentityError::MirrorKeyMismatch { diff, .. })
+                if diff.changes
+                    == [FieldChange::ThresholdChanged {
+                        old: None,
+                        new: Some(2)
+                    }]
+        ));
+    }
+
+    #[test]
+    fn classifies_resolution_errors() {
+        assert_eq!(
+            ResolutionStatus::from_error(&WebIdentityError::MissingPublicKey),
+            ResolutionStatus::NotAnIdentity
+        );
+        assert_eq!(
+            ResolutionStatus::from_error(&WebIdentityError::HttpStatus(503)),
+            ResolutionStatus::Unreachable
+        );
+        assert_eq!(
+            ResolutionStatus::from_error(&WebIdentityError::Fetch("timed out".into())),
+            ResolutionStatus::Unreachable
+        );
+        assert_eq!(
+            ResolutionStatus::from_error(&WebIdentityError::HttpStatus(404)),
+            ResolutionStatus::NotFound
+        );
+        assert_eq!(
+            ResolutionStatus::from_error(&WebIdentityError::HttpStatus(410)),
+            ResolutionStatus::Gone
+        );
+        assert_eq!(
+            ResolutionStatus::of(
+                &FileSystemResolver::new("/nonexistent").resolve_identity("a.example")
+            ),
+            ResolutionStatus::NotFound
+        );
+        assert_eq!(
+            ResolutionStatus::from_error(&WebIdentityError::UnexpectedContentType(
+                "application/json".into()
+            )),
+            ResolutionStatus::NotHtml
+        );
+    }
+
+    fn caching(fetcher: MockFetcher) -> CachingResolver<FetcherResolver<MockFetcher>> {
+        CachingResolver::new(FetcherResolver::new(fetcher))
+    }
+
+    #[test]
+    fn cache_hits_share_the_identity() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let resolver = caching(MockFetcher::new().with_identity(&alice));
+
+        let first = resolver.resolve_identity("alice.example.com").unwrap();
+        assert!(first.fetched_at.is_some());
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    let hit = resolver
+                        .resolve_identity("https://alice.example.com/")
+                        .unwrap();
+                    assert!(Arc::ptr_eq(&first, &hit));
+                });
+            }
+        });
+        resolver
+            .inner()
+            .fetcher()
+            .assert_fetched("alice.example.com", 1);
+
+        resolver.invalidate("alice.example.com");
+        let refetched = resolver.resolve_identity("alice.example.com").unwrap();
+        assert!(!Arc::ptr_eq(&first, &refetched));
+        assert_eq!(refetched.public_key, first.public_key);
+        resolver
+            .inner()
+            .fetcher()
+            .assert_fetched("alice.example.com", 2);
+    }
+
+    #[test]
+    fn expired_identities_are_fetched_again() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let resolver = caching(MockFetcher::new().with_identity(&alice))
+            .with_ttl(Duration::ZERO)
+            .with_stale_window(Duration::ZERO);
+
+        let first = resolver.resolve_identity("alice.example.com").unwrap();
+        let second = resolver.resolve_identity("alice.example.com").unwrap();
+        assert!(!Arc::ptr_eq(&first, &second));
+        resolver
+            .inner()
+            .fetcher()
+            .assert_fetched("alice.example.com", 2);
+    }
+
+    #[test]
+    fn expired_identities_are_not_kept_when_refetching_fails() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let resolver = caching(MockFetcher::new().with_responses(
+            "alice.example.com",
+            vec![MockResponse::identity(&alice), MockResponse::Status(503)],
+        ))
+        .with_ttl(Duration::ZERO);
+
+        resolver.resolve_identity("alice.example.com").unwrap();
+        assert!(matches!(
+            resolver.resolve_identity("alice.example.com"),
+            Err(WebIdentityError::HttpStatus(503))
+        ));
+    }
+
+    #[test]
+    fn refreshes_stale_identities_with_the_spawner() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let rotated = TestIdentity::generate("alice.example.com#rotated");
+        type Task = Box<dyn FnOnce() + Send>;
+        let tasks: Arc<Mutex<Vec<Task>>> = Arc::default();
+        let queued = Arc::clone(&tasks);
+        let changes: Arc<Mutex<Option<IdentityDiff>>> = Arc::default();
+        let reported = Arc::clone(&changes);
+        let resolver = caching(MockFetcher::new().with_responses(
+            "alice.example.com",
+            vec![
+                MockResponse::identity(&alice),
+                MockResponse::Timeout(Duration::ZERO),
+                MockResponse::Page(rotated.page.replace("#rotated", "")),
+            ],
+        ))
+        .with_ttl(Duration::ZERO)
+        .with_stale_window(Duration::from_secs(60))
+        .with_spawner(move |task| queued.lock().unwrap().push(task))
+        .with_change_hook(move |_, diff| *reported.lock().unwrap() = Some(diff.clone()));
+        let run_queued = || {
+            let task = tasks.lock().unwrap().pop().unwrap();
+            task();
+        };
+
+        let first = resolver.resolve_identity("alice.example.com").unwrap();
+        let stale = resolver.resolve_identity("alice.example.com").unwrap();
+        assert!(Arc::ptr_eq(&first, &stale));
+        // A refresh is already queued
+        resolver.resolve_identity("alice.example.com").unwrap();
+        assert_eq!(tasks.lock().unwrap().len(), 1);
+        resolver
+            .inner()
+            .fetcher()
+            .assert_fetched("alice.example.com", 1);
+
+        // A failed refresh keeps the stale identity
+        run_queued();
+        resolver
+            .inner()
+            .fetcher()
+            .assert_fetched("alice.example.com", 2);
+        let stale = resolver.resolve_identity("alice.example.com").unwrap();
+        assert!(Arc::ptr_eq(&first, &stale));
+
+        run_queued();
+        let refreshed = resolver.resolve_identity("alice.example.com").unwrap();
+        assert_eq!(refreshed.public_key, rotated.identity.public_key);
+        let diff = changes.lock().unwrap().take().unwrap();
+        assert_eq!(diff.severity(), Some(Severity::High));
+        assert!(diff.changes.contains(&FieldChange::PrimaryKeyChanged {
+            old: hex::encode(first.public_key),
+            new: hex::encode(refreshed.public_key),
+        }));
+    }
+
+    #[test]
+    fn reports_changes_to_the_change_hook() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let rotated = TestIdentity::generate("alice.example.com#rotated");
+        let rotated_page = rotated.page.replace("#rotated", "");
+        let extra = TestIdentity::generate("alice.example.com#extra")
+            .identity
+            .public_key;
+        let with_extra = alice.page.replace(
+            "</head>",
+            &format!(
+                "    <meta name=\"identity:public-key\" content=\"{}\">\n</head>",
+                extra.to_prefixed()
+            ),
+        );
+        let changes: Arc<Mutex<Vec<IdentityDiff>>> = Arc::default();
+        let reported = Arc::clone(&changes);
+        let resolver = caching(MockFetcher::new().with_responses(
+            "alice.example.com",
+            vec![
+                MockResponse::identity(&alice),
+                MockResponse::identity(&alice),
+                MockResponse::Page(with_extra),
+                MockResponse::identity(&alice),
+                MockResponse::Page(rotated_page),
+            ],
+        ))
+        .with_ttl(Duration::ZERO)
+        .with_change_hook(move |identity, diff| {
+            assert_eq!(identity.location, "alice.example.com");
+            reported.lock().unwrap().push(diff.clone());
+        });
+
+        for _ in 0..5 {
+            resolver.resolve_identity("alice.example.com").unwrap();
+        }
+        let key = |key: &PublicKey| hex::encode(key);
+        let (old, new) = (&alice.identity.public_key, &rotated.identity.public_key);
+        // Resolving the same page again isn't a change
+        assert_eq!(
+            *changes.lock().unwrap(),
+            [
+                vec![FieldChange::KeyAdded { key: key(&extra) }],
+                vec![FieldChange::KeyRemoved { key: key(&extra) }],
+                vec![
+                    FieldChange::PrimaryKeyChanged {
+                        old: key(old),
+                        new: key(new)
+                    },
+                    FieldChange::KeyRemoved { key: key(old) },
+                    FieldChange::KeyAdded { key: key(new) },
+                ],
+            ]
+            .map(|changes| IdentityDiff { changes })
+        );
+    }
+
+    #[test]
+    fn trailing_dot_is_the_dotless_host() {
+        for (location, expected) in [
+            ("example.com.", "https://example.com/"),
+            ("https://Example.com./amy/", "https://example.com/amy/"),
+            (
+                "https://example.com.:8443/amy",
+                "https://example.com:8443/amy",
+            ),
+            ("http://localhost.", "http://localhost/"),
+        ] {
+            assert_eq!(
+                resolve_location_url(location).unwrap().as_str(),
+                expected,
+                "{}",
+                location
+            );
+        }
+
+        let alice = TestIdentity::generate("alice.example.com.");
+        assert_eq!(alice.identity.location, "alice.example.com");
+        assert_eq!(alice.identity.handle(), "alice.example.com");
+
+        let headers = create_signed_headers(
+            "alice.example.com.",
+            "GET",
+            "example.com.",
+            "/notes",
+            b"",
+            &alice.signing_key,
+        )
+        .unwrap();
+        verify_request_with_key(
+            "GET",
+            "example.com",
+            "/notes",
+            &RequestDigest::of(b""),
+            &headers,
+            &alice.signing_key.verifying_key(),
+            &VerifyOptions::new(Duration::from_secs(300)),
+        )
+        .unwrap();
+
+        let resolver = CachingResolver::new(StaticFetcher::new().with_identity(&alice));
+        let first = resolver.resolve_identity("alice.example.com").unwrap();
+        let second = resolver
+            .resolve_identity(&headers["WebIdentity-Location"])
+            .unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+    }
+}
@@ -0,0 +1,235 @@
+use super::authenticate::{AuthenticatedRequest, Authenticator};
+use super::error::{SignatureError, WebIdentityError};
+use super::sign::HeaderProvider;
+use rocket::data::{self, Data, FromData, ToByteUnit};
+use rocket::http::{HeaderMap, Status};
+use rocket::outcome::Outcome;
+use rocket::tokio::task::spawn_blocking;
+use rocket::Request;
+use std::borrow::Cow;
+
+impl HeaderProvider for HeaderMap<'_> {
+    fn get_header(&self, name: &str) -> Option<&str> {
+        self.get_one(name)
+    }
+
+    fn has_duplicate_header(&self, name: &str) -> bool {
+        self.get(name).nth(1).is_some()
+    }
+
+    fn header_names(&self) -> Option<Vec<&str>> {
+        // The names are borrowed from the map
+        self.iter()
+            .map(|header| match header.name.into_cow() {
+                Cow::Borrowed(name) => Some(name),
+                Cow::Owned(_) => None,
+            })
+            .collect()
+    }
+}
+
+/// Authenticates the request with the [`Authenticator`] managed by the Rocket instance, or
+/// fails with `401 Unauthorized`.
+///
+/// This is a data guard rather than a request guard because the signature covers the body,
+/// which is read up to [`Authenticator::max_body_size`] and handed back in
+/// [`AuthenticatedRequest::body`]. The signed host is the `Host` header. Forwarding headers
+/// are only trusted with [`Authenticator::with_trusted_proxy`].
+///
+/// The identity is resolved on Tokio's blocking thread pool (`spawn_blocking`), so fetching
+/// it doesn't hold up the worker.
+#[rocket::async_trait]
+impl<'r> FromData<'r> for AuthenticatedRequest {
+    type Error = WebIdentityError;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        let Some(authenticator) = request.rocket().state::<Authenticator>() else {
+            return Outcome::Error((
+                Status::InternalServerError,
+                WebIdentityError::MissingAuthenticator,
+            ));
+        };
+
+        let body = match data
+            .open(authenticator.max_body_size().bytes())
+            .into_bytes()
+            .await
+        {
+            Ok(body) if body.is_complete() => body.into_inner(),
+            Ok(_) => {
+                return Outcome::Error((
+                    Status::PayloadTooLarge,
+                    WebIdentityError::BodyTooLarge(authenticator.max_body_size()),
+                ))
+            }
+            Err(e) => {
+                return Outcome::Error((Status::BadRequest, WebIdentityError::Body(e.to_string())))
+            }
+        };
+
+        let headers = OwnedHeaders::from(request.headers());
+        let Some(host) = headers.get_header("Host") else {
+            return Outcome::Error((
+                Status::Unauthorized,
+                SignatureError::MissingHeader("Host".into()).into(),
+            ));
+        };
+        let host = match authenticator.external_host(host, &headers) {
+            Ok(host) => host,
+            Err(e) => return Outcome::Error((Status::Unauthorized, e)),
+        };
+        let path = request.uri().to_string();
+        let method = request.method();
+        let authenticator = authenticator.clone();
+
+        let authenticated = spawn_blocking(move || {
+            authenticator.authenticate(method.as_str(), &host, &path, body, &headers)
+        })
+        .await
+        .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+        match authenticated {
+            Ok(authenticated) => Outcome::Success(authenticated),
+            Err(e) => Outcome::Error((Status::Unauthorized, e)),
+        }
+    }
+}
+
+/// The headers of a request, copied so they can be read on a blocking thread.
+struct OwnedHeaders(Vec<(String, String)>);
+
+impl From<&HeaderMap<'_>> for OwnedHeaders {
+    fn from(headers: &HeaderMap<'_>) -> Self {
+        OwnedHeaders(
+            headers
+                .iter()
+                .map(|header| (header.name().to_string(), header.value().to_string()))
+                .collect(),
+        )
+    }
+}
+
+impl HeaderProvider for OwnedHeaders {
+    fn get_header(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn has_duplicate_header(&self, name: &str) -> bool {
+        self.0
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .nth(1)
+            .is_some()
+    }
+
+    fn header_names(&self) -> Option<Vec<&str>> {
+        Some(self.0.iter().map(|(key, _)| key.as_str()).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolve::IdentityResolver;
+    use crate::sign::VerifyOptions;
+    use crate::testing::{StaticFetcher, TestIdentity};
+    use rocket::http::Header;
+    use rocket::local::asynchronous::Client;
+    use std::time::Duration;
+
+    #[rocket::post("/notes", data = "<request>")]
+    fn notes(request: AuthenticatedRequest) -> String {
+        request.identity.id.clone()
+    }
+
+    async fn client(resolver: impl IdentityResolver + Send + Sync + 'static) -> Client {
+        client_with(Authenticator::new(
+            resolver,
+            VerifyOptions::new(Duration::from_secs(300)),
+        ))
+        .await
+    }
+
+    async fn client_with(authenticator: Authenticator) -> Client {
+        let rocket = rocket::build()
+            .manage(authenticator)
+            .mount("/", rocket::routes![notes]);
+        Client::untracked(rocket).await.unwrap()
+    }
+
+    async fn post_notes(client: &Client, alice: &TestIdentity, signed: bool) -> (Status, String) {
+        let mut request = client
+            .post("/notes")
+            .header(Header::new("Host", "api.example.com"))
+            .body("hello");
+        if signed {
+            let headers = alice.signed_headers_for("POST", "api.example.com", "/notes", b"hello");
+            for (name, value) in headers.into_headers() {
+                request = request.header(Header::new(name, value));
+            }
+        }
+        let response = request.dispatch().await;
+        (
+            response.status(),
+            response.into_string().await.unwrap_or_default(),
+        )
+    }
+
+    #[rocket::async_test]
+    async fn authenticates_on_the_blocking_pool() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let client = client(StaticFetcher::new().with_identity(&alice)).await;
+
+        assert_eq!(
+            post_notes(&client, &alice, true).await,
+            (Status::Ok, alice.identity.id.clone())
+        );
+        assert_eq!(
+            post_notes(&client, &alice, false).await.0,
+            Status::Unauthorized
+        );
+    }
+
+    #[rocket::async_test]
+    async fn trusts_forwarding_headers_only_behind_a_trusted_proxy() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let authenticator = Authenticator::new(
+            StaticFetcher::new().with_identity(&alice),
+            VerifyOptions::new(Duration::from_secs(300)),
+        );
+        let post_forwarded = |client: Client| {
+            let headers = alice.signed_headers_for("POST", "api.example.com", "/notes", b"hello");
+            async move {
+                let mut request = client
+                    .post("/notes")
+                    .header(Header::new("Host", "internal:8000"))
+                    .header(Header::new("X-Forwarded-Host", "api.example.com"))
+                    .body("hello");
+                for (name, value) in headers.into_headers() {
+                    request = request.header(Header::new(name, value));
+                }
+                request.dispatch().await.status()
+            }
+        };
+
+        let client = client_with(authenticator.clone()).await;
+        assert_eq!(post_forwarded(client).await, Status::Unauthorized);
+        let client = client_with(authenticator.with_trusted_proxy(&["api.example.com"])).await;
+        assert_eq!(post_forwarded(client).await, Status::Ok);
+    }
+
+    #[cfg(feature = "reqwest")]
+    #[rocket::async_test]
+    async fn resolves_with_a_runtime_fetcher() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let resolver = crate::FetcherResolver::with_runtime(
+            StaticFetcher::new().with_identity(&alice),
+            rocket::tokio::runtime::Handle::current(),
+        );
+        let client = client(resolver).await;
+
+        assert_eq!(post_notes(&client, &alice, true).await.0, Status::Ok);
+    }
+}
@@ -0,0 +1,258 @@
+use super::error::{SignatureError, WebIdentityError};
+use super::identity::{identity_id, Identity, PK_PREFIX, SPEC_VERSION};
+use super::public_key::PublicKey;
+use super::sign::{strip_hex_prefix, RequestSigner};
+use ed25519_dalek::{Signature, SigningKey, Verifier};
+use rand::rngs::OsRng;
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Proof that an identity moved from one key to another: each key signed the other.
+///
+/// It is listed on the updated page in the `identity:rotation` meta tag as
+/// `ed25519-pub:<old hex>;ed25519-pub:<new hex>;<rotated_at>;<old signature>;<new signature>`,
+/// where both keys sign `WebIdentity-Rotation\n<location>\n<old hex>\n<new hex>\n<rotated_at>`.
+/// A verifier that knew the old key can then trust the new one instead of seeing an
+/// unexplained key change.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RotationProof {
+    pub location: String,
+    pub old_key: PublicKey,
+    pub new_key: PublicKey,
+    /// Seconds since the UNIX epoch
+    pub rotated_at: u64,
+    old_signature: [u8; 64],
+    new_signature: [u8; 64],
+}
+
+impl RotationProof {
+    /// Creates the proof for the identity at `location` moving from `old_signer` to
+    /// `new_signer`, signed by both.
+    pub fn issue(
+        location: &str,
+        old_signer: &impl RequestSigner,
+        new_signer: &impl RequestSigner,
+        rotated_at: u64,
+    ) -> Result<RotationProof, WebIdentityError> {
+        let old_key = PublicKey::from(old_signer.verifying_key());
+        let new_key = PublicKey::from(new_signer.verifying_key());
+        let message = signing_message(location, &old_key, &new_key, rotated_at);
+
+        Ok(RotationProof {
+            location: location.to_string(),
+            old_key,
+            new_key,
+            rotated_at,
+            old_signature: old_signer.sign_message(&message)?,
+            new_signature: new_signer.sign_message(&message)?,
+        })
+    }
+
+    /// Parses the value of an `identity:rotation` meta tag for the identity at `location`.
+    ///
+    /// # Errors
+    /// Returns [`SignatureError::InvalidRotation`] if the value is malformed.
+    pub fn parse(location: &str, value: &str) -> Result<RotationProof, SignatureError> {
+        let invalid = |reason: &str| SignatureError::InvalidRotation(reason.to_string());
+
+        let parts: Vec<&str> = value.trim().split(';').map(str::trim).collect();
+        let [old_key, new_key, rotated_at, old_signature, new_signature] = parts[..] else {
+            return Err(invalid(
+                "Expected two keys, a timestamp and two signatures.",
+            ));
+        };
+
+        let parse_key = |key: &str| {
+            key.strip_prefix(PK_PREFIX)
+                .and_then(|key| hex::decode(strip_hex_prefix(key)).ok())
+                .and_then(|key| PublicKey::from_bytes(&key).ok())
+                .ok_or_else(|| invalid("Invalid key."))
+        };
+        let parse_signature = |signature: &str| {
+            let mut bytes = [0u8; 64];
+            hex::decode_to_slice(strip_hex_prefix(signature), &mut bytes)
+                .map_err(|_| invalid("Invalid signature."))?;
+            Ok(bytes)
+        };
+
+        Ok(RotationProof {
+            location: location.to_string(),
+            old_key: parse_key(old_key)?,
+            new_key: parse_key(new_key)?,
+            rotated_at: rotated_at
+                .parse::<u64>()
+                .map_err(|_| invalid("Invalid timestamp."))?,
+            old_signature: parse_signature(old_signature)?,
+            new_signature: parse_signature(new_signature)?,
+        })
+    }
+
+    /// The value of the `identity:rotation` meta tag.
+    pub fn to_meta_value(&self) -> String {
+        format!(
+            "{};{};{};{};{}",
+            self.old_key.to_prefixed(),
+            self.new_key.to_prefixed(),
+            self.rotated_at,
+            hex::encode(self.old_signature),
+            hex::encode(self.new_signature)
+        )
+    }
+
+    /// Checks both keys signed the rotation.
+    ///
+    /// This only proves the keys agreed; callers should also check `old_key` is the key they
+    /// knew for `location`, and that the page now lists `new_key`.
+    ///
+    /// # Errors
+    /// Returns [`SignatureError::RotationSignatureMismatch`] if either signature is incorrect.
+    pub fn verify(&self) -> Result<(), SignatureError> {
+        let message = signing_message(
+            &self.location,
+            &self.old_key,
+            &self.new_key,
+            self.rotated_at,
+        );
+        for (key, signature) in [
+            (&self.old_key, &self.old_signature),
+            (&self.new_key, &self.new_signature),
+        ] {
+            key.verifying_key()
+                .verify(&message, &Signature::from_bytes(signature))
+                .map_err(|_| SignatureError::RotationSignatureMismatch)?;
+        }
+        Ok(())
+    }
+}
+
+fn signing_message(
+    location: &str,
+    old_key: &PublicKey,
+    new_key: &PublicKey,
+    rotated_at: u64,
+) -> Vec<u8> {
+    format!(
+        "WebIdentity-Rotation\n{}\n{}\n{}\n{}",
+        location,
+        old_key.to_hex(),
+        new_key.to_hex(),
+        rotated_at
+    )
+    .into_bytes()
+}
+
+/// Rotates `identity` away from `old_key`, returning the new key, the updated identity page and
+/// the rotation proof.
+///
+/// The page lists the new key where the old one was, keeps the other keys, profile fields and
+/// `rel="me"` links, and lists the old key as `identity:retired-key` and the proof as
+/// `identity:rotation`. The new key must be stored before the page is published.
+///
+/// # Errors
+/// Returns [`WebIdentityError::KeyNotListed`] if `old_key` isn't listed on the identity.
+pub fn rotate_identity(
+    identity: &Identity,
+    old_key: &SigningKey,
+) -> Result<(SigningKey, String, RotationProof), WebIdentityError> {
+    let old_public_key = PublicKey::from(old_key.verifying_key());
+    if !identity.public_keys.contains(&old_public_key) {
+        return Err(WebIdentityError::KeyNotListed(identity_id(&old_public_key)));
+    }
+
+    let new_key = SigningKey::generate(&mut OsRng);
+    let rotated_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let proof = RotationProof::issue(&identity.location, old_key, &new_key, rotated_at)?;
+
+    let keys: Vec<PublicKey> = identity
+        .public_keys
+        .iter()
+        .map(|key| {
+            if *key == old_public_key {
+                PublicKey::from(new_key.verifying_key())
+            } else {
+                *key
+            }
+        })
+        .collect();
+    let page = rotated_page(identity, &keys, &old_public_key, &proof);
+
+    Ok((new_key, page, proof))
+}
+
+fn rotated_page(
+    identity: &Identity,
+    keys: &[PublicKey],
+    retired_key: &PublicKey,
+    proof: &RotationProof,
+) -> String {
+    let mut meta = vec![("identity:version".to_string(), SPEC_VERSION.to_string())];
+    meta.extend(
+        keys.iter()
+            .map(|key| ("identity:public-key".to_string(), key.to_prefixed())),
+    );
+    meta.push(("identity:retired-key".into(), retired_key.to_prefixed()));
+    meta.push(("identity:rotation".into(), proof.to_meta_value()));
+    if let Some(threshold) = identity.threshold {
+        meta.push(("identity:threshold".into(), threshold.to_string()));
+    }
+    meta.push((
+        "identity:display-name".into(),
+        identity.display_name.clone(),
+    ));
+    if let Some(avatar) = &identity.avatar {
+        meta.push(("identity:avatar".into(), avatar.to_string()));
+    }
+    if let Some(description) = &identity.description {
+        meta.push(("identity:description".into(), description.clone()));
+    }
+    let mut descriptions: Vec<_> = identity.descriptions.iter().collect();
+    descriptions.sort();
+    meta.extend(descriptions.into_iter().map(|(lang, description)| {
+        (
+            format!("identity:description:{}", lang),
+            description.clone(),
+        )
+    }));
+    meta.extend(
+        identity
+            .backup_locations
+            .iter()
+            .map(|backup| ("identity:backup-location".to_string(), backup.clone())),
+    );
+
+    let mut page = String::new();
+    page.push_str("<!DOCTYPE html>\n<html>\n<head>\n");
+    let _ = writeln!(
+        page,
+        "    <title>{}</title>",
+        escape_html(&identity.display_name)
+    );
+    for (name, content) in meta {
+        let _ = writeln!(
+            page,
+            "    <meta name=\"{}\" content=\"{}\">",
+            escape_html(&name),
+            escape_html(&content)
+        );
+    }
+    for link in &identity.verified_links {
+        let _ = writeln!(
+            page,
+            "    <link rel=\"me\" href=\"{}\">",
+            escape_html(link.as_str())
+        );
+    }
+    page.push_str("</head>\n<body></body>\n</html>\n");
+    page
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,321 @@
+use super::error::WebIdentityError;
+use super::keyfile::Keyfile;
+use super::sign::{as_array, create_signed_headers};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use zeroize::Zeroizing;
+
+/// A WebIdentity client: the location of an identity page and the key listed on it.
+#[derive(Debug, Clone)]
+pub struct SigningSession {
+    location: String,
+    signing_key: SigningKey,
+}
+
+impl SigningSession {
+    pub fn new(location: impl Into<String>, signing_key: SigningKey) -> Self {
+        SigningSession {
+            location: location.into(),
+            signing_key,
+        }
+    }
+
+    /// Loads a session from the `WEBIDENTITY_*` environment variables.
+    ///
+    /// See [`ClientConfig::from_env_prefixed`] for the variables that are read.
+    ///
+    /// # Errors
+    /// Returns `Err` naming the variable that is missing or invalid.
+    pub fn from_env() -> Result<SigningSession, WebIdentityError> {
+        ClientConfig::from_env().map(SigningSession::from)
+    }
+
+    pub fn location(&self) -> &str {
+        &self.location
+    }
+
+    pub fn verifying_key(&self) -> VerifyingKey {
+        self.signing_key.verifying_key()
+    }
+
+    /// Creates the `WebIdentity-*` headers for a request signed by this session.
+    pub fn create_signed_headers(
+        &self,
+        http_method: &str,
+        host: &str,
+        path: &str,
+        body: &[u8],
+    ) -> Result<HashMap<String, String>, WebIdentityError> {
+        create_signed_headers(
+            &self.location,
+            http_method,
+            host,
+            path,
+            body,
+            &self.signing_key,
+        )
+    }
+}
+
+impl From<ClientConfig> for SigningSession {
+    fn from(config: ClientConfig) -> Self {
+        SigningSession::new(config.location, config.signing_key)
+    }
+}
+
+/// Signing configuration, typically loaded from environment variables.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub location: String,
+    pub signing_key: SigningKey,
+}
+
+impl ClientConfig {
+    /// Loads the configuration from the `WEBIDENTITY_*` environment variables.
+    ///
+    /// # Errors
+    /// Returns `Err` naming the variable that is missing or invalid.
+    pub fn from_env() -> Result<ClientConfig, WebIdentityError> {
+        Self::from_env_prefixed("WEBIDENTITY")
+    }
+
+    /// Loads the configuration from environment variables starting with `prefix`, which allows
+    /// running multiple identities in one process.
+    ///
+    /// The following variables are read:
+    /// - `<prefix>_LOCATION`: the identity location
+    /// - `<prefix>_SEED_HEX`: the hex-encoded 32 byte Ed25519 seed
+    /// - `<prefix>_KEYFILE` and `<prefix>_KEYFILE_PASSPHRASE_FILE`: the path of a [`Keyfile`]
+    ///   and of a file holding its passphrase
+    ///
+    /// If `<prefix>_SEED_HEX` is set, it takes precedence and the keyfile variables are ignored.
+    /// `<prefix>_LOCATION` is required with a seed, and overrides the keyfile's own location
+    /// when a keyfile is used.
+    ///
+    /// The secret material read from the environment is zeroized once the key is decoded, and is
+    /// never included in error messages.
+    ///
+    /// # Errors
+    /// Returns `Err` naming the variable that is missing or invalid.
+    pub fn from_env_prefixed(prefix: &str) -> Result<ClientConfig, WebIdentityError> {
+        let location_var = format!("{}_LOCATION", prefix);
+        let seed_var = format!("{}_SEED_HEX", prefix);
+        let keyfile_var = format!("{}_KEYFILE", prefix);
+        let passphrase_var = format!("{}_KEYFILE_PASSPHRASE_FILE", prefix);
+
+        let location = read_var(&location_var)?;
+        if location.as_ref().is_some_and(|l| l.trim().is_empty()) {
+            return Err(WebIdentityError::InvalidEnvVar {
+                variable: location_var,
+                reason: "Location is empty.".into(),
+            });
+        }
+        let location = location.map(|l| l.trim().to_string());
+
+        if let Some(seed_hex) = read_var(&seed_var)? {
+            let signing_key = signing_key_from_hex(&seed_var, &seed_hex)?;
+            let location = location.ok_or(WebIdentityError::MissingEnvVar(location_var))?;
+            return Ok(ClientConfig {
+                location,
+                signing_key,
+            });
+        }
+
+        let Some(keyfile_path) = read_var(&keyfile_var)? else {
+            return Err(WebIdentityError::MissingEnvVar(seed_var));
+        };
+        let passphrase_path = read_var(&passphrase_var)?
+            .ok_or(WebIdentityError::MissingEnvVar(passphrase_var.clone()))?;
+
+        let keyfile = fs::read_to_string(keyfile_path.as_str()).map_err(|e| {
+            WebIdentityError::InvalidEnvVar {
+                variable: keyfile_var.clone(),
+                reason: e.to_string(),
+            }
+        })?;
+        let passphrase =
+            Zeroizing::new(fs::read_to_string(passphrase_path.as_str()).map_err(|e| {
+                WebIdentityError::InvalidEnvVar {
+                    variable: passphrase_var,
+                    reason: e.to_string(),
+                }
+            })?);
+        let passphrase = passphrase.trim_end_matches(['\r', '\n']);
+
+        let (signing_key, keyfile_location) =
+            Keyfile::import(&keyfile, passphrase).map_err(|e| WebIdentityError::InvalidEnvVar {
+                variable: keyfile_var,
+                reason: e.to_string(),
+            })?;
+
+        Ok(ClientConfig {
+            location: location.unwrap_or(keyfile_location),
+            signing_key,
+        })
+    }
+}
+
+fn read_var(name: &str) -> Result<Option<Zeroizing<String>>, WebIdentityError> {
+    match env::var(name) {
+        Ok(value) => Ok(Some(Zeroizing::new(value))),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(env::VarError::NotUnicode(_)) => Err(WebIdentityError::InvalidEnvVar {
+            variable: name.to_string(),
+            reason: "Value is not valid unicode.".into(),
+        }),
+    }
+}
+
+fn signing_key_from_hex(variable: &str, seed_hex: &str) -> Result<SigningKey, WebIdentityError> {
+    let invalid = |reason: &str| WebIdentityError::InvalidEnvVar {
+        variable: variable.to_string(),
+        reason: reason.to_string(),
+    };
+
+    let seed =
+        Zeroizing::new(hex::decode(seed_hex.trim()).map_err(|_| invalid("Invalid hex encoding."))?);
+    let seed = as_array::<u8, 32>(&seed).ok_or_else(|| invalid("Seed must be 32 bytes."))?;
+
+    Ok(SigningKey::from_bytes(seed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const SEED_HEX: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+    const PASSPHRASE: &str = "correct horse battery staple";
+
+    /// Sets the variables of `prefix`, which must be unique to the test as they run in
+    /// parallel.
+    fn set_vars(prefix: &str, vars: &[(&str, &str)]) {
+        for (name, value) in vars {
+            env::set_var(format!("{}_{}", prefix, name), value);
+        }
+    }
+
+    fn write_keyfile(prefix: &str, location: &str) -> (PathBuf, PathBuf) {
+        let dir = env::temp_dir().join(format!("webidentity-{}-{}", prefix, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let key = SigningKey::from_bytes(&[4; 32]);
+        let keyfile = dir.join("key.json");
+        fs::write(
+            &keyfile,
+            Keyfile::export(&key, location, PASSPHRASE).unwrap(),
+        )
+        .unwrap();
+        let passphrase = dir.join("passphrase");
+        fs::write(&passphrase, format!("{}\n", PASSPHRASE)).unwrap();
+        (keyfile, passphrase)
+    }
+
+    #[test]
+    fn reads_a_seed() {
+        set_vars(
+            "WI_TEST_SEED",
+            &[("LOCATION", " amy.carroted.org "), ("SEED_HEX", SEED_HEX)],
+        );
+        let config = ClientConfig::from_env_prefixed("WI_TEST_SEED").unwrap();
+        assert_eq!(config.location, "amy.carroted.org");
+        assert_eq!(hex::encode(config.signing_key.to_bytes()), SEED_HEX);
+    }
+
+    #[test]
+    fn prefers_the_seed_over_a_keyfile() {
+        set_vars(
+            "WI_TEST_PRECEDENCE",
+            &[
+                ("LOCATION", "amy.carroted.org"),
+                ("SEED_HEX", SEED_HEX),
+                ("KEYFILE", "/nonexistent/key.json"),
+                ("KEYFILE_PASSPHRASE_FILE", "/nonexistent/passphrase"),
+            ],
+        );
+        let config = ClientConfig::from_env_prefixed("WI_TEST_PRECEDENCE").unwrap();
+        assert_eq!(hex::encode(config.signing_key.to_bytes()), SEED_HEX);
+    }
+
+    #[test]
+    fn reads_a_keyfile_whose_location_can_be_overridden() {
+        let (keyfile, passphrase) = write_keyfile("WI_TEST_KEYFILE", "keyfile.example.com");
+        set_vars(
+            "WI_TEST_KEYFILE",
+            &[
+                ("KEYFILE", keyfile.to_str().unwrap()),
+                ("KEYFILE_PASSPHRASE_FILE", passphrase.to_str().unwrap()),
+            ],
+        );
+        let config = ClientConfig::from_env_prefixed("WI_TEST_KEYFILE").unwrap();
+        assert_eq!(config.location, "keyfile.example.com");
+        assert_eq!(config.signing_key.to_bytes(), [4; 32]);
+
+        set_vars("WI_TEST_KEYFILE", &[("LOCATION", "amy.carroted.org")]);
+        let session =
+            SigningSession::from(ClientConfig::from_env_prefixed("WI_TEST_KEYFILE").unwrap());
+        assert_eq!(session.location(), "amy.carroted.org");
+    }
+
+    #[test]
+    fn names_missing_variables() {
+        assert!(matches!(
+            ClientConfig::from_env_prefixed("WI_TEST_NOTHING"),
+            Err(WebIdentityError::MissingEnvVar(name)) if name == "WI_TEST_NOTHING_SEED_HEX"
+        ));
+
+        set_vars("WI_TEST_NO_LOCATION", &[("SEED_HEX", SEED_HEX)]);
+        assert!(matches!(
+            ClientConfig::from_env_prefixed("WI_TEST_NO_LOCATION"),
+            Err(WebIdentityError::MissingEnvVar(name)) if name == "WI_TEST_NO_LOCATION_LOCATION"
+        ));
+
+        set_vars(
+            "WI_TEST_EMPTY_LOCATION",
+            &[("LOCATION", "  "), ("SEED_HEX", SEED_HEX)],
+        );
+        assert!(matches!(
+            ClientConfig::from_env_prefixed("WI_TEST_EMPTY_LOCATION"),
+            Err(WebIdentityError::InvalidEnvVar { variable, .. })
+                if variable == "WI_TEST_EMPTY_LOCATION_LOCATION"
+        ));
+    }
+
+    #[test]
+    fn never_includes_secrets_in_errors() {
+        // Not valid hex, and 33 bytes, so both checks fail on a secret-looking value
+        for seed in [&SEED_HEX[..63], &format!("{}00", SEED_HEX)[..]] {
+            set_vars(
+                "WI_TEST_SECRET_SEED",
+                &[("LOCATION", "amy.carroted.org"), ("SEED_HEX", seed)],
+            );
+            let error = ClientConfig::from_env_prefixed("WI_TEST_SECRET_SEED").unwrap_err();
+            for text in [error.to_string(), format!("{:?}", error)] {
+                assert!(text.contains("WI_TEST_SECRET_SEED_SEED_HEX"), "{}", text);
+                assert!(!text.contains(&SEED_HEX[..16]), "{}", text);
+            }
+        }
+
+        let (keyfile, _) = write_keyfile("WI_TEST_SECRET_PASSPHRASE", "amy.carroted.org");
+        let dir = keyfile.parent().unwrap();
+        let wrong = dir.join("wrong-passphrase");
+        fs::write(&wrong, "hunter2-not-the-passphrase").unwrap();
+        set_vars(
+            "WI_TEST_SECRET_PASSPHRASE",
+            &[
+                ("KEYFILE", keyfile.to_str().unwrap()),
+                ("KEYFILE_PASSPHRASE_FILE", wrong.to_str().unwrap()),
+            ],
+        );
+        let error = ClientConfig::from_env_prefixed("WI_TEST_SECRET_PASSPHRASE").unwrap_err();
+        for text in [error.to_string(), format!("{:?}", error)] {
+            assert!(
+                text.contains("WI_TEST_SECRET_PASSPHRASE_KEYFILE"),
+                "{}",
+                text
+            );
+            assert!(!text.contains("hunter2"), "{}", text);
+        }
+    }
+}
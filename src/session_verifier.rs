@@ -0,0 +1,166 @@
+use super::authorization::AuthorizationHeaders;
+use super::digest::RequestDigest;
+use super::error::WebIdentityError;
+use super::identity::Identity;
+use super::sign::{identity_keys, HeaderProvider, SignedRequest, VerifiedRequest, VerifyOptions};
+use ed25519_dalek::VerifyingKey;
+
+/// Verifies many requests from the same identity, doing the per-identity work once.
+///
+/// The identity's keys are parsed and fingerprinted when the verifier is created, and each
+/// request's headers are parsed once however many keys are tried. To reject replayed
+/// requests, configure `options` with [`VerifyOptions::with_monotonic_timestamps`].
+#[derive(Debug, Clone)]
+pub struct SessionVerifier {
+    /// The identity's keys with their fingerprints, primary key first
+    keys: Vec<(String, VerifyingKey)>,
+    threshold: Option<u8>,
+    options: VerifyOptions,
+}
+
+impl SessionVerifier {
+    pub fn new(identity: &Identity, options: VerifyOptions) -> Self {
+        SessionVerifier {
+            keys: identity_keys(identity),
+            threshold: identity.threshold,
+            options,
+        }
+    }
+
+    /// Verifies a signed request against the identity's keys.
+    ///
+    /// When the request names its key with `WebIdentity-Key`, only that key is tried.
+    /// Otherwise each key is tried in order, starting with the primary one. Identities with a
+    /// [`threshold`](Identity::threshold) above one need that many keys to have signed.
+    ///
+    /// # Errors
+    /// Returns `Err` if any header is missing, the timestamp is invalid, outside the allowed
+    /// window or replayed, no key produced the signature, or too few keys did.
+    pub fn verify(
+        &self,
+        http_method: &str,
+        host: &str,
+        path: &str,
+        body_digest: &RequestDigest,
+        headers: &impl HeaderProvider,
+    ) -> Result<VerifiedRequest, WebIdentityError> {
+        let headers = AuthorizationHeaders::new(headers)?;
+        let request = SignedRequest::parse(
+            http_method,
+            host,
+            path,
+            body_digest,
+            &headers,
+            &self.options,
+        )?;
+
+        request.verify_for_keys(&self.keys, self.threshold, &self.options)
+    }
+}
+
+/// A request to verify with [`verify_batch_for_identity`].
+#[derive(Debug, Clone, Copy)]
+pub struct BatchRequest<'a, H> {
+    pub http_method: &'a str,
+    pub host: &'a str,
+    pub path: &'a str,
+    pub body_digest: RequestDigest,
+    pub headers: &'a H,
+}
+
+/// Verifies a batch of requests from the same identity, such as an event stream from a single
+/// signer, parsing the identity's keys once for the whole batch (see [`SessionVerifier`]).
+///
+/// The results are in the same order as `requests`, and a request that fails only affects its
+/// own entry.
+pub fn verify_batch_for_identity<'a, H: HeaderProvider + 'a>(
+    identity: &Identity,
+    requests: impl IntoIterator<Item = BatchRequest<'a, H>>,
+    options: &VerifyOptions,
+) -> Vec<Result<VerifiedRequest, WebIdentityError>> {
+    let verifier = SessionVerifier::new(identity, options.clone());
+    requests
+        .into_iter()
+        .map(|request| {
+            verifier.verify(
+                request.http_method,
+                request.host,
+                request.path,
+                &request.body_digest,
+                request.headers,
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{SharedTestIdentity, TestIdentity};
+    use crate::MemoryTimestampStore;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const HOST: &str = "api.example.com";
+    const BODY: &[u8] = b"event";
+
+    fn options() -> VerifyOptions {
+        VerifyOptions::new(Duration::from_secs(300))
+    }
+
+    #[test]
+    fn verifies_requests_from_any_key() {
+        let identity = TestIdentity::generate("alice.example.com");
+        let verifier = SessionVerifier::new(&identity.identity, options());
+        let headers = identity.signed_headers_for("POST", HOST, "/events", BODY);
+
+        let request = verifier
+            .verify(
+                "POST",
+                HOST,
+                "/events",
+                &RequestDigest::of(BODY),
+                headers.headers(),
+            )
+            .unwrap();
+        assert_eq!(request.location, "alice.example.com");
+        assert!(!request.first_seen);
+    }
+
+    #[test]
+    fn batch_enforces_threshold_per_request() {
+        let identity = SharedTestIdentity::generate("team.example.com", 2, 2);
+        let single = identity.signed_headers_for(&[0], "POST", HOST, "/a", BODY);
+        let both = identity.signed_headers_for(&[0, 1], "POST", HOST, "/b", BODY);
+        let requests = [("/a", &single), ("/b", &both)].map(|(path, headers)| BatchRequest {
+            http_method: "POST",
+            host: HOST,
+            path,
+            body_digest: RequestDigest::of(BODY),
+            headers,
+        });
+
+        let results = verify_batch_for_identity(&identity.identity, requests, &options());
+        assert!(results[0].is_err());
+        assert!(results[1].is_ok());
+    }
+
+    #[test]
+    fn rejects_replayed_requests() {
+        let identity = TestIdentity::generate("alice.example.com");
+        let options = options().with_monotonic_timestamps(Arc::new(MemoryTimestampStore::new(
+            Duration::from_secs(600),
+        )));
+        let verifier = SessionVerifier::new(&identity.identity, options);
+        let headers = identity.signed_headers_for("POST", HOST, "/events", BODY);
+        let digest = RequestDigest::of(BODY);
+
+        let first = verifier
+            .verify("POST", HOST, "/events", &digest, headers.headers())
+            .unwrap();
+        assert!(first.first_seen);
+        assert!(verifier
+            .verify("POST", HOST, "/events", &digest, headers.headers())
+            .is_err());
+    }
+}
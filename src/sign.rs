@@ -1,11 +1,79 @@
+use super::algorithm::SignatureAlgorithm;
+use super::authorization::{to_authorization, AuthorizationHeaders};
+use super::delegation::Delegation;
+use super::digest::{DigestAlgorithm, RequestDigest};
 use super::error::{SignatureError, WebIdentityError};
+use super::identity::{identity_id, location_from_url, strip_www, Identity};
+use super::timestamp_store::TimestampStore;
+use base64::prelude::{Engine, BASE64_STANDARD};
 use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
-use sha2::{Digest, Sha256};
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::net::Ipv6Addr;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use url::Url;
 
 pub trait HeaderProvider {
     fn get_header(&self, name: &str) -> Option<&str>;
+
+    /// Returns `true` if the header appeared more than once in the request.
+    ///
+    /// Providers that can't see repeated headers can keep the default, which returns `false`.
+    fn has_duplicate_header(&self, _name: &str) -> bool {
+        false
+    }
+
+    /// Lists the names of every header in the request, for
+    /// [`VerifyOptions::with_full_header_coverage`] to check none was added.
+    ///
+    /// Providers that can't list their headers can keep the default, which returns `None`.
+    fn header_names(&self) -> Option<Vec<&str>> {
+        None
+    }
+
+    /// Gets a trailer field, sent after the body, for requests signed with
+    /// [`SignOptions::with_trailers`].
+    ///
+    /// Trailers only exist once the whole body was received, so they can only be checked then.
+    /// Providers that don't see trailers can keep the default, which returns `None`, and be
+    /// combined with them through [`WithTrailers`].
+    fn get_trailer(&self, _name: &str) -> Option<&str> {
+        None
+    }
+}
+
+/// The headers of a request together with its trailers, once the body was received, for
+/// verifying requests signed with [`SignOptions::with_trailers`].
+#[derive(Debug, Clone, Copy)]
+pub struct WithTrailers<'a, H, T> {
+    headers: &'a H,
+    trailers: &'a T,
+}
+
+impl<'a, H: HeaderProvider, T: HeaderProvider> WithTrailers<'a, H, T> {
+    pub fn new(headers: &'a H, trailers: &'a T) -> Self {
+        WithTrailers { headers, trailers }
+    }
+}
+
+impl<H: HeaderProvider, T: HeaderProvider> HeaderProvider for WithTrailers<'_, H, T> {
+    fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers.get_header(name)
+    }
+
+    fn has_duplicate_header(&self, name: &str) -> bool {
+        self.headers.has_duplicate_header(name)
+    }
+
+    fn header_names(&self) -> Option<Vec<&str>> {
+        self.headers.header_names()
+    }
+
+    fn get_trailer(&self, name: &str) -> Option<&str> {
+        self.trailers.get_header(name)
+    }
 }
 
 /// A simple HashMap implementation of `HeaderProvider`
@@ -14,10 +82,492 @@ impl HeaderProvider for SimpleHeaderProvider {
     fn get_header(&self, name: &str) -> Option<&str> {
         self.get(name).map(|s| s.as_str())
     }
+
+    fn header_names(&self) -> Option<Vec<&str>> {
+        Some(self.keys().map(String::as_str).collect())
+    }
+}
+
+/// A HashMap implementation of `HeaderProvider` that keeps every value of repeated headers
+pub type MultiHeaderProvider = HashMap<String, Vec<String>>;
+impl HeaderProvider for MultiHeaderProvider {
+    fn get_header(&self, name: &str) -> Option<&str> {
+        self.get(name).and_then(|v| v.first()).map(|s| s.as_str())
+    }
+
+    fn has_duplicate_header(&self, name: &str) -> bool {
+        self.get(name).is_some_and(|v| v.len() > 1)
+    }
+
+    fn header_names(&self) -> Option<Vec<&str>> {
+        Some(self.keys().map(String::as_str).collect())
+    }
+}
+
+/// Signs requests with an Ed25519 key.
+///
+/// This is implemented for [`SigningKey`], and can be implemented outside this crate for keys that
+/// never leave hardware (security keys, HSMs, OS keystores). Implementations must produce plain
+/// Ed25519 signatures over the given message that verify against [`RequestSigner::verifying_key`],
+/// and should return [`WebIdentityError::Signer`] when the backend can't do that, e.g. because the
+/// device doesn't support raw Ed25519 or the user declined to enter a PIN.
+pub trait RequestSigner {
+    fn verifying_key(&self) -> VerifyingKey;
+
+    fn sign_message(&self, message: &[u8]) -> Result<[u8; 64], WebIdentityError>;
+}
+
+impl RequestSigner for SigningKey {
+    fn verifying_key(&self) -> VerifyingKey {
+        SigningKey::verifying_key(self)
+    }
+
+    fn sign_message(&self, message: &[u8]) -> Result<[u8; 64], WebIdentityError> {
+        Ok(self.sign(message).to_bytes())
+    }
+}
+
+/// Signs requests with an Ed25519 key that is only reachable asynchronously, e.g. a remote KMS
+/// or a device behind an async driver.
+///
+/// Every [`RequestSigner`] is also an `AsyncRequestSigner`, signing immediately. The same
+/// requirements apply: plain Ed25519 signatures that verify against
+/// [`AsyncRequestSigner::verifying_key`], and [`WebIdentityError::Signer`] when that isn't
+/// possible.
+#[cfg(feature = "async")]
+pub trait AsyncRequestSigner {
+    fn verifying_key(&self) -> VerifyingKey;
+
+    fn sign_message_async(
+        &self,
+        message: &[u8],
+    ) -> impl std::future::Future<Output = Result<[u8; 64], WebIdentityError>> + Send;
+}
+
+#[cfg(feature = "async")]
+impl<T: RequestSigner + Sync> AsyncRequestSigner for T {
+    fn verifying_key(&self) -> VerifyingKey {
+        RequestSigner::verifying_key(self)
+    }
+
+    fn sign_message_async(
+        &self,
+        message: &[u8],
+    ) -> impl std::future::Future<Output = Result<[u8; 64], WebIdentityError>> + Send {
+        std::future::ready(self.sign_message(message))
+    }
+}
+
+/// Options controlling how a signed request is verified.
+#[derive(Debug, Clone)]
+pub struct VerifyOptions {
+    max_age: Duration,
+    max_skew: Option<Duration>,
+    server_now: Option<u64>,
+    uncertainty: Duration,
+    digest_algorithm: DigestAlgorithm,
+    expected_host: Option<String>,
+    expected_path: Option<String>,
+    timestamp_store: Option<Arc<dyn TimestampStore>>,
+    allowed_methods: Option<Vec<String>>,
+    location_from_host: bool,
+    unbound_body: bool,
+    full_header_coverage: bool,
+    max_lifetime: Option<Duration>,
+    www_equivalence: bool,
+}
+
+impl VerifyOptions {
+    /// Accepts requests signed at most `max_age` ago, with no limit on timestamps in the future.
+    pub fn new(max_age: Duration) -> Self {
+        VerifyOptions {
+            max_age,
+            max_skew: None,
+            server_now: None,
+            uncertainty: Duration::ZERO,
+            digest_algorithm: DigestAlgorithm::default(),
+            expected_host: None,
+            expected_path: None,
+            timestamp_store: None,
+            allowed_methods: None,
+            location_from_host: false,
+            unbound_body: false,
+            full_header_coverage: false,
+            max_lifetime: None,
+            www_equivalence: false,
+        }
+    }
+
+    /// Rejects requests with a timestamp more than `max_skew` ahead of the server's time.
+    pub fn with_max_skew(mut self, max_skew: Duration) -> Self {
+        self.max_skew = Some(max_skew);
+        self
+    }
+
+    /// Uses `server_now` (seconds since the UNIX epoch) as the current time instead of
+    /// `SystemTime::now()`, for servers with their own authoritative time source.
+    pub fn with_server_now(mut self, server_now: u64) -> Self {
+        self.server_now = Some(server_now);
+        self
+    }
+
+    /// Widens both the age and skew windows by how far the server's own clock may be off.
+    pub fn with_uncertainty(mut self, uncertainty: Duration) -> Self {
+        self.uncertainty = uncertainty;
+        self
+    }
+
+    /// Sets the algorithm body digests must have been computed with.
+    pub fn with_digest_algorithm(mut self, algorithm: DigestAlgorithm) -> Self {
+        self.digest_algorithm = algorithm;
+        self
+    }
+
+    /// Rejects requests whose host isn't `host`, the name the server actually serves.
+    ///
+    /// A valid signature only proves the client signed the host and path it was verified with.
+    /// When those are taken from the request itself (e.g. the `Host` header), this makes sure
+    /// the request was also meant for this server, failing with
+    /// [`SignatureError::RequestMismatch`] otherwise.
+    pub fn with_expected_host(mut self, host: impl Into<String>) -> Self {
+        self.expected_host = Some(host.into());
+        self
+    }
+
+    /// Rejects requests whose path isn't `path` once canonicalized, e.g. the path of the route
+    /// that is actually being served, failing with [`SignatureError::RequestMismatch`].
+    pub fn with_expected_path(mut self, path: impl Into<String>) -> Self {
+        self.expected_path = Some(path.into());
+        self
+    }
+
+    /// Rejects requests whose timestamp isn't newer than the last one accepted for the same key
+    /// (the identity id for its primary key), a replay defense that needs no nonce from the
+    /// client.
+    ///
+    /// Timestamps are whole seconds, so only the first of several requests signed with a key
+    /// within the same second is accepted, and the others are rejected as replays even when
+    /// legitimate. Accepting such bursts would take millisecond timestamps, which
+    /// `WebIdentity-Timestamp` doesn't carry, so this suits endpoints a client calls at most
+    /// once a second.
+    pub fn with_monotonic_timestamps(mut self, store: Arc<dyn TimestampStore>) -> Self {
+        self.timestamp_store = Some(store);
+        self
+    }
+
+    /// Rejects requests whose method isn't one of `methods` (compared case-insensitively),
+    /// before the signature is checked.
+    pub fn with_allowed_methods(mut self, methods: &[&str]) -> Self {
+        self.allowed_methods = Some(methods.iter().map(|m| m.to_uppercase()).collect());
+        self
+    }
+
+    /// Uses the location of the identity at the root of the request's host (`example.com` for
+    /// `example.com:8443`) when the request has no `WebIdentity-Location` header, for clients
+    /// signing with [`SignOptions::with_implicit_location`].
+    ///
+    /// The host is chosen by the client, so this lets any request name the identity of
+    /// whichever host it claims to be for: the signature still has to match that identity's
+    /// keys, but the server resolves identities for hosts it doesn't serve. Use it together with
+    /// [`VerifyOptions::with_expected_host`], so only the server's own identity is accepted
+    /// this way.
+    pub fn with_location_from_host(mut self) -> Self {
+        self.location_from_host = true;
+        self
+    }
+
+    /// Accepts requests signed with [`SignOptions::without_body_binding`], whose signature
+    /// doesn't cover the body. They are rejected with [`SignatureError::UnboundBody`] otherwise.
+    ///
+    /// The server is then responsible for the body's integrity, e.g. by checking it against a
+    /// signed `Content-Digest` header with [`verify_content_digest`] once it was read.
+    pub fn with_unbound_body(mut self) -> Self {
+        self.unbound_body = true;
+        self
+    }
+
+    /// Requires requests to be signed with [`SignOptions::with_all_headers`], and rejects them
+    /// with [`SignatureError::UncoveredHeader`] if a header that isn't covered was added.
+    ///
+    /// The headers listed in `WebIdentity-Headers` are always checked. This also makes sure no
+    /// other header was added, so the header provider must be able to list its headers (see
+    /// [`HeaderProvider::header_names`]). Hop-by-hop headers and the headers proxies add, such
+    /// as `Via` and `X-Forwarded-For`, are never covered.
+    pub fn with_full_header_coverage(mut self) -> Self {
+        self.full_header_coverage = true;
+        self
+    }
+
+    /// Sets the longest a request signed with [`SignOptions::with_expiry`] is accepted after
+    /// it was created, however late its expiry (`max_age` by default).
+    ///
+    /// Such requests are accepted from their `WebIdentity-Timestamp` until their
+    /// `WebIdentity-Expires` time or this maximum, whichever comes first, instead of for
+    /// `max_age`: the signer can shorten the window, but never extend it past the server's
+    /// limit. Timestamps ahead of the server's time are only tolerated within `max_skew`.
+    pub fn with_max_lifetime(mut self, max_lifetime: Duration) -> Self {
+        self.max_lifetime = Some(max_lifetime);
+        self
+    }
+
+    /// Treats a host on `www.` the same as the apex domain (`www.example.com` and
+    /// `example.com`), see [`strip_www`]. This applies to:
+    /// - the host a request was signed for, when checked against
+    ///   [`VerifyOptions::with_expected_host`], so a server answering on both names accepts
+    ///   requests signed for either;
+    /// - the location a signed URL names, when checked against the identity's `location` in
+    ///   [`verify_url_for_identity`](crate::verify_url_for_identity).
+    ///
+    /// Tokens are always checked against the exact location, as their verification takes no
+    /// options.
+    ///
+    /// Off by default: this is a heuristic, as nothing guarantees both hosts belong to the
+    /// same person, and only applies to the `www` label, never to other subdomains.
+    pub fn with_www_equivalence(mut self) -> Self {
+        self.www_equivalence = true;
+        self
+    }
+
+    /// Whether a signed location (or host) is the expected one.
+    pub(crate) fn location_matches(&self, signed: &str, location: &str) -> bool {
+        signed == location || (self.www_equivalence && strip_www(signed) == strip_www(location))
+    }
+
+    /// The location a request claims, from its `WebIdentity-Location` header or, if allowed,
+    /// its host.
+    pub(crate) fn location<'a>(
+        &self,
+        headers: &'a impl HeaderProvider,
+        host: &str,
+    ) -> Result<Cow<'a, str>, SignatureError> {
+        match optional_header(headers, "WebIdentity-Location")? {
+            Some(location) => Ok(Cow::Borrowed(location)),
+            None if self.location_from_host => {
+                Url::parse(&format!("https://{}/", canonical_host(host)))
+                    .map(|url| Cow::Owned(location_from_url(&url)))
+                    .map_err(|_| SignatureError::MissingHeader("WebIdentity-Location".into()))
+            }
+            None => Err(SignatureError::MissingHeader("WebIdentity-Location".into())),
+        }
+    }
+
+    pub(crate) fn check_method(&self, http_method: &str) -> Result<(), SignatureError> {
+        match &self.allowed_methods {
+            Some(allowed) if !allowed.iter().any(|m| m.eq_ignore_ascii_case(http_method)) => {
+                Err(SignatureError::MethodNotAllowed(http_method.to_uppercase()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn check_target(&self, host: &str, path: &str) -> Result<(), SignatureError> {
+        if let Some(expected) = &self.expected_host {
+            let expected = canonical_host(expected).to_ascii_lowercase();
+            let host = canonical_host(host).to_ascii_lowercase();
+            if !self.location_matches(&host, &expected) {
+                return Err(SignatureError::RequestMismatch("host".into()));
+            }
+        }
+        if let Some(expected) = &self.expected_path {
+            if canonical_path(expected) != canonical_path(path) {
+                return Err(SignatureError::RequestMismatch("path".into()));
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn now(&self) -> u64 {
+        self.server_now.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        })
+    }
+
+    /// The oldest a request can be to be accepted, including the clock uncertainty.
+    pub(crate) fn accepted_age(&self) -> Duration {
+        self.max_age + self.uncertainty
+    }
+
+    /// Records the timestamp of a verified request in the timestamp store, if any, returning
+    /// whether it was recorded.
+    pub(crate) fn check_monotonic(
+        &self,
+        key_id: &str,
+        timestamp: u64,
+    ) -> Result<bool, SignatureError> {
+        match &self.timestamp_store {
+            Some(store) => store
+                .check_and_update(key_id, timestamp)
+                .map(|()| true)
+                .map_err(|stale| SignatureError::TimestampReplayed {
+                    last_accepted: stale.last_accepted,
+                }),
+            None => Ok(false),
+        }
+    }
+
+    /// Checks that a request signed at `timestamp` is within the accepted window, given its
+    /// signed `WebIdentity-Expires` value if any.
+    pub(crate) fn check_window(
+        &self,
+        timestamp: u64,
+        expires: Option<&str>,
+    ) -> Result<(), SignatureError> {
+        match expires {
+            Some(expires) => {
+                let expires = expires
+                    .parse::<u64>()
+                    .map_err(|_| SignatureError::InvalidExpiry(expires.to_string()))?;
+                self.check_validity(timestamp, expires)
+            }
+            None => self.check_timestamp(timestamp),
+        }
+    }
+
+    /// Checks the signed extensions describing the body against the body that was received.
+    pub(crate) fn check_body(
+        &self,
+        extensions: &[(&str, &str)],
+        body_digest: &RequestDigest,
+        headers: &impl HeaderProvider,
+    ) -> Result<(), WebIdentityError> {
+        let unbound = match extension(extensions, "WebIdentity-Body") {
+            Some(UNBOUND_BODY) if self.unbound_body => true,
+            Some(UNBOUND_BODY) => return Err(SignatureError::UnboundBody.into()),
+            Some(_) => {
+                return Err(SignatureError::UnsupportedDigest("WebIdentity-Body".into()).into())
+            }
+            None => false,
+        };
+        if !unbound && body_digest.algorithm() != self.digest_algorithm {
+            return Err(SignatureError::DigestAlgorithmMismatch.into());
+        }
+        // A clear error for bodies dropped or cut in transit, rather than a signature mismatch
+        if let Some(length) = extension(extensions, "WebIdentity-Body-Length").filter(|_| !unbound)
+        {
+            let length = length
+                .parse::<u64>()
+                .map_err(|_| SignatureError::RequestMismatch("body".into()))?;
+            let matches = match body_digest.body_length() {
+                Some(received) => received == length,
+                None => body_digest.is_empty_body() == (length == 0),
+            };
+            if !matches {
+                return Err(SignatureError::RequestMismatch("body".into()).into());
+            }
+        }
+        // The signed Content-Digest must describe the body that was received, which is checked
+        // later for unbound bodies
+        if extension(extensions, "Content-Digest").is_some() && !unbound {
+            verify_content_digest(headers, body_digest)?;
+        }
+        Ok(())
+    }
+
+    /// Checks that no header was added to a request, if full header coverage is required.
+    pub(crate) fn check_coverage(
+        &self,
+        headers: &impl HeaderProvider,
+        extensions: &[(&str, &str)],
+    ) -> Result<(), SignatureError> {
+        if !self.full_header_coverage {
+            return Ok(());
+        }
+        check_header_coverage(headers, extension(extensions, "WebIdentity-Headers"))
+    }
+
+    /// Parses a signed `WebIdentity-Delegation` value, rejecting expired delegations.
+    pub(crate) fn delegation(
+        &self,
+        value: Option<&str>,
+    ) -> Result<Option<Delegation>, WebIdentityError> {
+        let Some(value) = value else {
+            return Ok(None);
+        };
+        let delegation = Delegation::parse(value)?;
+        self.check_delegation(&delegation)?;
+        Ok(Some(delegation))
+    }
+
+    pub(crate) fn check_delegation(&self, delegation: &Delegation) -> Result<(), SignatureError> {
+        if self.now() > delegation.expires_at {
+            return Err(SignatureError::DelegationExpired);
+        }
+        Ok(())
+    }
+
+    /// Checks the window of a request signed with [`SignOptions::with_expiry`], which replaces
+    /// the `max_age` check.
+    fn check_validity(&self, created: u64, expires: u64) -> Result<(), SignatureError> {
+        let now = self.now();
+        let uncertainty = self.uncertainty.as_secs();
+
+        let max_skew = self
+            .max_skew
+            .unwrap_or_default()
+            .as_secs()
+            .saturating_add(uncertainty);
+        let ahead_by = created.saturating_sub(now);
+        if ahead_by > max_skew {
+            return Err(SignatureError::NotYetValid { ahead_by, max_skew });
+        }
+        if now > expires.saturating_add(uncertainty) {
+            return Err(SignatureError::RequestExpired {
+                expired_for: now - expires,
+            });
+        }
+        let max_lifetime = self
+            .max_lifetime
+            .unwrap_or(self.max_age)
+            .as_secs()
+            .saturating_add(uncertainty);
+        let lifetime = now.saturating_sub(created);
+        if lifetime > max_lifetime {
+            return Err(SignatureError::LifetimeExceeded {
+                lifetime,
+                max_lifetime,
+            });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_timestamp(&self, timestamp: u64) -> Result<(), SignatureError> {
+        let now = self.now();
+
+        let max_age = self.max_age.saturating_add(self.uncertainty).as_secs();
+        let age = now.saturating_sub(timestamp);
+        if age > max_age {
+            return Err(SignatureError::TimestampExpired { age, max_age });
+        }
+        if let Some(max_skew) = self.max_skew {
+            let max_skew = max_skew.saturating_add(self.uncertainty).as_secs();
+            let ahead_by = timestamp.saturating_sub(now);
+            if ahead_by > max_skew {
+                return Err(SignatureError::TimestampInFuture { ahead_by, max_skew });
+            }
+        }
+
+        Ok(())
+    }
 }
 
 /// Verifies a signed request against a public key.
 ///
+/// The signature is checked over the given host and path, so a valid signature means the
+/// client signed a request for them, not necessarily for this server. If they come from the
+/// request itself, use [`VerifyOptions::with_expected_host`] and
+/// [`VerifyOptions::with_expected_path`] to also check the request was meant for where it
+/// arrived.
+///
+/// If the request has a `WebIdentity-Delegation` header (see [`Delegation`]), the delegation
+/// must be signed by the public key and the request by the delegated subkey.
+///
+/// Requests without a `WebIdentity-Signature` header can send the `WebIdentity-*` headers as a
+/// single `Authorization: WebIdentity ...` header instead (see
+/// [`SignOptions::with_authorization_header`]).
+///
 /// # Errors
 /// Returns `Err` if any header is missing, the timestamp is invalid/expired,
 /// or the signature is incorrect.
@@ -30,42 +580,759 @@ pub fn verify_request(
     public_key_bytes: &[u8],
     max_age: Duration,
 ) -> Result<(), WebIdentityError> {
-    // Get headers
-    let location = headers
-        .get_header("WebIdentity-Location")
-        .ok_or_else(|| SignatureError::MissingHeader("WebIdentity-Location".to_string()))?;
-    let timestamp_str = headers
+    verify_request_with_options(
+        http_method,
+        host,
+        path,
+        body,
+        headers,
+        public_key_bytes,
+        &VerifyOptions::new(max_age),
+    )
+}
+
+/// Verifies a signed request against a public key, as configured by `options`.
+///
+/// # Errors
+/// Returns `Err` if any header is missing, the timestamp is invalid or outside the
+/// allowed window, or the signature is incorrect.
+pub fn verify_request_with_options(
+    http_method: &str,
+    host: &str,
+    path: &str,
+    body: &[u8],
+    headers: &impl HeaderProvider,
+    public_key_bytes: &[u8],
+    options: &VerifyOptions,
+) -> Result<(), WebIdentityError> {
+    verify_request_prehashed(
+        http_method,
+        host,
+        path,
+        &body_digest(body, headers)?,
+        headers,
+        public_key_bytes,
+        options,
+    )
+}
+
+/// Hashes `body` as the request's `WebIdentity-Canonicalization` header says it was signed.
+pub(crate) fn body_digest(
+    body: &[u8],
+    headers: &impl HeaderProvider,
+) -> Result<RequestDigest, WebIdentityError> {
+    let canonicalization = AuthorizationHeaders::new(headers)?
+        .get_header("WebIdentity-Canonicalization")
+        .map(str::to_string);
+    match canonicalization {
+        Some(value) if value.eq_ignore_ascii_case(JCS_CANONICALIZATION) => {
+            Ok(RequestDigest::of_json(body)?)
+        }
+        Some(_) => {
+            Err(SignatureError::UnsupportedDigest("WebIdentity-Canonicalization".into()).into())
+        }
+        None => Ok(RequestDigest::of(body)),
+    }
+}
+
+/// Verifies a signed request when only the digest of the body is available.
+///
+/// This is useful when middleware has already consumed the body, or when trying several keys
+/// without hashing the body again each time. If the digest came from a `Content-Digest` or
+/// `Digest` header, it can be cross-checked with [`verify_content_digest`].
+///
+/// # Errors
+/// Returns `Err` if any header is missing, the timestamp is invalid or outside the
+/// allowed window, or the signature is incorrect.
+pub fn verify_request_prehashed(
+    http_method: &str,
+    host: &str,
+    path: &str,
+    body_digest: &RequestDigest,
+    headers: &impl HeaderProvider,
+    public_key_bytes: &[u8],
+    options: &VerifyOptions,
+) -> Result<(), WebIdentityError> {
+    let verifying_key = parse_verifying_key(public_key_bytes)?;
+    verify_request_with_key(
+        http_method,
+        host,
+        path,
+        body_digest,
+        headers,
+        &verifying_key,
+        options,
+    )
+}
+
+/// Verifies a request signed with [`SignOptions::without_body_binding`] before its body is
+/// read, so forged uploads can be rejected early.
+///
+/// `options` must accept unbound bodies (see [`VerifyOptions::with_unbound_body`]). If the
+/// request signed a `Content-Digest` header, the body should be checked against it with
+/// [`verify_content_digest`] once it was read.
+///
+/// # Errors
+/// Returns `Err` if the request signs its body, any header is missing, the timestamp is
+/// invalid or outside the allowed window, or the signature is incorrect.
+pub fn verify_request_headers(
+    http_method: &str,
+    host: &str,
+    path: &str,
+    headers: &impl HeaderProvider,
+    public_key_bytes: &[u8],
+    options: &VerifyOptions,
+) -> Result<(), WebIdentityError> {
+    // Any digest would do for an unbound body, but one that does sign its body must not be
+    // accepted before the body is hashed
+    let signed_headers = AuthorizationHeaders::new(headers)?;
+    if optional_header(&signed_headers, "WebIdentity-Body")? != Some(UNBOUND_BODY) {
+        return Err(SignatureError::MissingHeader("WebIdentity-Body".into()).into());
+    }
+    verify_request_prehashed(
+        http_method,
+        host,
+        path,
+        &RequestDigest::of(&[]),
+        headers,
+        public_key_bytes,
+        options,
+    )
+}
+
+/// Verifies a signed request against an already parsed key.
+///
+/// Servers that verify many requests from the same identity can parse its key once (e.g. with
+/// [`Identity::verifying_key`]) and keep it, instead of decoding it again on every request.
+/// The body is passed as a [`RequestDigest`] so it can be shared between attempts.
+///
+/// # Errors
+/// Returns `Err` if any header is missing, the timestamp is invalid or outside the
+/// allowed window, or the signature is incorrect.
+pub fn verify_request_with_key(
+    http_method: &str,
+    host: &str,
+    path: &str,
+    body_digest: &RequestDigest,
+    headers: &impl HeaderProvider,
+    verifying_key: &VerifyingKey,
+    options: &VerifyOptions,
+) -> Result<(), WebIdentityError> {
+    verify_and_record(
+        http_method,
+        host,
+        path,
+        body_digest,
+        headers,
+        verifying_key,
+        options,
+    )
+    .map(|_| ())
+}
+
+/// Verifies a signed request against a key, returning whether the timestamp store recorded it.
+fn verify_and_record(
+    http_method: &str,
+    host: &str,
+    path: &str,
+    body_digest: &RequestDigest,
+    headers: &impl HeaderProvider,
+    verifying_key: &VerifyingKey,
+    options: &VerifyOptions,
+) -> Result<bool, WebIdentityError> {
+    let headers = AuthorizationHeaders::new(headers)?;
+    let request = SignedRequest::parse(http_method, host, path, body_digest, &headers, options)?;
+    request.verify(verifying_key)?;
+    let recorded =
+        options.check_monotonic(&identity_id(verifying_key.as_bytes()), request.timestamp)?;
+    Ok(recorded)
+}
+
+/// The `WebIdentity-*` header values of a request that passed verification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifiedRequest {
+    pub location: String,
+    /// Seconds since the UNIX epoch
+    pub timestamp: u64,
+    /// How long ago the request was signed, zero if its timestamp is in the future
+    pub age: Duration,
+    /// The oldest a request could be to be accepted, including the clock uncertainty
+    pub max_age: Duration,
+    /// Whether the timestamp store (see [`VerifyOptions::with_monotonic_timestamps`]) recorded
+    /// this request as new, so a server can process each request exactly once. Always `false`
+    /// without a store, since replays can't be told apart then.
+    pub first_seen: bool,
+}
+
+impl VerifiedRequest {
+    /// How much of `max_age` the request has used up, from 0.0 (just signed) to 1.0 (about
+    /// to be rejected).
+    ///
+    /// Handlers can use it to accept older signatures for reads, but require a fresher one
+    /// for sensitive actions.
+    pub fn age_fraction(&self) -> f64 {
+        if self.max_age.is_zero() {
+            return 1.0;
+        }
+        (self.age.as_secs_f64() / self.max_age.as_secs_f64()).min(1.0)
+    }
+}
+
+/// A failed verification, with whatever header values could still be read, so the attempt
+/// can be logged with the identity it claimed to come from.
+///
+/// The values are unverified and must only be used for logging or diagnostics.
+#[derive(Error, Debug)]
+#[error("{error}")]
+pub struct VerificationFailure {
+    #[source]
+    pub error: WebIdentityError,
+    pub location: Option<String>,
+    /// Seconds since the UNIX epoch
+    pub timestamp: Option<u64>,
+}
+
+impl From<VerificationFailure> for WebIdentityError {
+    fn from(failure: VerificationFailure) -> Self {
+        failure.error
+    }
+}
+
+/// Like [`verify_request_with_key`], but returns the request's location, timestamp and age,
+/// and keeps the location and timestamp (when they could be read) if verification fails.
+///
+/// # Errors
+/// Returns `Err` if any header is missing, the timestamp is invalid or outside the
+/// allowed window, or the signature is incorrect.
+pub fn verify_request_with_context(
+    http_method: &str,
+    host: &str,
+    path: &str,
+    body_digest: &RequestDigest,
+    headers: &impl HeaderProvider,
+    verifying_key: &VerifyingKey,
+    options: &VerifyOptions,
+) -> Result<VerifiedRequest, VerificationFailure> {
+    let context = AuthorizationHeaders::new(headers).map_err(|e| VerificationFailure {
+        error: e.into(),
+        location: None,
+        timestamp: None,
+    })?;
+    let location = options.location(&context, host).ok();
+    let timestamp = context
         .get_header("WebIdentity-Timestamp")
-        .ok_or_else(|| SignatureError::MissingHeader("WebIdentity-Timestamp".to_string()))?;
-    let signature_hex = headers
-        .get_header("WebIdentity-Signature")
-        .ok_or_else(|| SignatureError::MissingHeader("WebIdentity-Signature".to_string()))?;
+        .and_then(|timestamp| timestamp.parse::<u64>().ok());
 
-    let timestamp = timestamp_str
-        .parse::<u64>()
-        .map_err(|_| SignatureError::InvalidTimestamp(timestamp_str.to_string()))?;
+    let result = verify_and_record(
+        http_method,
+        host,
+        path,
+        body_digest,
+        headers,
+        verifying_key,
+        options,
+    );
+    match (result, location, timestamp) {
+        (Ok(first_seen), Some(location), Some(timestamp)) => Ok(VerifiedRequest {
+            location: location.into_owned(),
+            timestamp,
+            age: Duration::from_secs(options.now().saturating_sub(timestamp)),
+            max_age: options.accepted_age(),
+            first_seen,
+        }),
+        (result, location, timestamp) => Err(VerificationFailure {
+            // Verification can't succeed without both headers
+            error: result
+                .err()
+                .unwrap_or(SignatureError::SignatureMismatch.into()),
+            location: location.map(Cow::into_owned),
+            timestamp,
+        }),
+    }
+}
 
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
+/// Verifies a request that must be signed by several of an identity's keys.
+///
+/// The `WebIdentity-Signature` header holds a comma-separated list of signatures over the same
+/// canonical string, one per signing member. Each signature is matched against the keys listed
+/// on the identity, and the request is accepted once at least `identity.threshold` distinct keys
+/// (or one, if the identity doesn't declare a threshold) produced a valid signature. Repeated
+/// signatures from the same key only count once.
+///
+/// # Errors
+/// Returns `Err` if any header is missing, the timestamp is invalid or outside the allowed
+/// window, or [`SignatureError::ThresholdNotMet`] with how many distinct keys signed.
+pub fn verify_request_threshold(
+    http_method: &str,
+    host: &str,
+    path: &str,
+    body_digest: &RequestDigest,
+    headers: &impl HeaderProvider,
+    identity: &Identity,
+    options: &VerifyOptions,
+) -> Result<(), WebIdentityError> {
+    let headers = AuthorizationHeaders::new(headers)?;
+    let request = SignedRequest::parse(http_method, host, path, body_digest, &headers, options)?;
+    let keys = identity_keys(identity);
+    request.check_listed_fingerprint(&keys)?;
 
-    if now.saturating_sub(timestamp) > max_age.as_secs() {
-        return Err(SignatureError::TimestampExpired.into());
+    let valid = request.count_signers(&keys);
+    let required = identity.threshold.unwrap_or(1) as usize;
+    if valid >= required {
+        options.check_monotonic(&identity.id, request.timestamp)?;
+        Ok(())
+    } else {
+        Err(SignatureError::ThresholdNotMet { valid, required }.into())
     }
+}
 
-    let body_hash = hash_body(body);
-    let canonical_string =
-        build_canonical_string(http_method, host, path, &body_hash, location, timestamp_str);
+/// The fingerprints and parsed keys of an identity, primary key first.
+pub(crate) fn identity_keys(identity: &Identity) -> Vec<(String, VerifyingKey)> {
+    identity
+        .public_keys
+        .iter()
+        .map(|key| (identity_id(key), *key.verifying_key()))
+        .collect()
+}
 
-    let signature_bytes =
-        hex::decode(signature_hex).map_err(|_| SignatureError::SignatureMismatch)?;
+/// Verifies a single signature over `message` by any key listed on `identity`, for formats
+/// that carry one signature, like challenges, tokens and signed URLs.
+///
+/// One signature has one signer, so it never meets a [`threshold`](Identity::threshold) above
+/// one: such identities fail with [`SignatureError::ThresholdNotMet`].
+pub(crate) fn verify_identity_signature(
+    identity: &Identity,
+    message: &[u8],
+    signature: &[u8],
+) -> Result<(), WebIdentityError> {
+    let valid = identity_keys(identity)
+        .iter()
+        .filter(|(_, key)| verify_with_key(key, message, signature).is_ok())
+        .count();
+    let required = identity.threshold.unwrap_or(1) as usize;
+    match valid {
+        _ if required > 1 && valid < required => {
+            Err(SignatureError::ThresholdNotMet { valid, required }.into())
+        }
+        0 => Err(SignatureError::SignatureMismatch.into()),
+        _ => Ok(()),
+    }
+}
 
-    verify_signature(
-        public_key_bytes,
-        canonical_string.as_bytes(),
-        &signature_bytes,
-    )
+/// The `WebIdentity-*` headers of a request, checked for freshness, and the canonical string
+/// its signature should cover.
+pub(crate) struct SignedRequest<'a> {
+    pub(crate) location: Cow<'a, str>,
+    pub(crate) signature: &'a str,
+    pub(crate) timestamp: u64,
+    pub(crate) key_fingerprint: Option<&'a str>,
+    pub(crate) delegation: Option<Delegation>,
+    pub(crate) canonical_string: String,
+}
+
+impl<'a> SignedRequest<'a> {
+    pub(crate) fn parse(
+        http_method: &str,
+        host: &str,
+        path: &str,
+        body_digest: &RequestDigest,
+        headers: &'a impl HeaderProvider,
+        options: &VerifyOptions,
+    ) -> Result<Self, WebIdentityError> {
+        options.check_method(http_method)?;
+        options.check_target(host, path)?;
+
+        // Get headers
+        let location = options.location(headers, host)?;
+        let timestamp_str = required_header(headers, "WebIdentity-Timestamp")?;
+        let signature = required_header(headers, "WebIdentity-Signature")?;
+
+        let timestamp = timestamp_str
+            .parse::<u64>()
+            .map_err(|_| SignatureError::InvalidTimestamp(timestamp_str.to_string()))?;
+
+        let extensions = signed_extensions(headers)?;
+        options.check_window(timestamp, extension(&extensions, "WebIdentity-Expires"))?;
+        check_algorithm(&extensions)?;
+        options.check_body(&extensions, body_digest, headers)?;
+        options.check_coverage(headers, &extensions)?;
+        let key_fingerprint = extension(&extensions, "WebIdentity-Key");
+        let delegation = options.delegation(extension(&extensions, "WebIdentity-Delegation"))?;
+
+        let canonical_string = build_canonical_string(
+            http_method,
+            host,
+            path,
+            body_digest.as_bytes(),
+            &location,
+            timestamp_str,
+            &extensions,
+        );
+
+        Ok(SignedRequest {
+            location,
+            signature,
+            timestamp,
+            key_fingerprint,
+            delegation,
+            canonical_string,
+        })
+    }
+
+    /// Verifies the request against an identity's `keys` (fingerprints and keys, primary key
+    /// first) and records its timestamp.
+    ///
+    /// Without a `threshold` above one, a signature from a single key is enough: the key named
+    /// by `WebIdentity-Key`, or else the first key that verifies. With one, the request must be
+    /// signed by that many distinct keys as for [`verify_request_threshold`], so no single
+    /// member of a shared identity can sign for it.
+    pub(crate) fn verify_for_keys(
+        &self,
+        keys: &[(String, VerifyingKey)],
+        threshold: Option<u8>,
+        options: &VerifyOptions,
+    ) -> Result<VerifiedRequest, WebIdentityError> {
+        let required = threshold.unwrap_or(1) as usize;
+        let key_id = if required > 1 {
+            self.check_listed_fingerprint(keys)?;
+            let valid = self.count_signers(keys);
+            if valid < required {
+                return Err(SignatureError::ThresholdNotMet { valid, required }.into());
+            }
+            // Recorded under the identity's id, like `verify_request_threshold`
+            keys[0].0.as_str()
+        } else {
+            match self.key_fingerprint {
+                Some(fingerprint) => {
+                    let (key_id, key) = keys
+                        .iter()
+                        .find(|(key_id, _)| fingerprint.eq_ignore_ascii_case(key_id))
+                        .ok_or(SignatureError::KeyFingerprintMismatch)?;
+                    self.verify(key)?;
+                    key_id.as_str()
+                }
+                None => keys
+                    .iter()
+                    .find(|(_, key)| self.verify(key).is_ok())
+                    .map(|(key_id, _)| key_id.as_str())
+                    .ok_or(SignatureError::SignatureMismatch)?,
+            }
+        };
+        let first_seen = options.check_monotonic(key_id, self.timestamp)?;
+
+        Ok(VerifiedRequest {
+            location: self.location.to_string(),
+            timestamp: self.timestamp,
+            age: Duration::from_secs(options.now().saturating_sub(self.timestamp)),
+            max_age: options.accepted_age(),
+            first_seen,
+        })
+    }
+
+    /// Checks that the key named by `WebIdentity-Key`, if any, is one of `keys`.
+    fn check_listed_fingerprint(
+        &self,
+        keys: &[(String, VerifyingKey)],
+    ) -> Result<(), SignatureError> {
+        match self.key_fingerprint {
+            Some(fingerprint)
+                if !keys
+                    .iter()
+                    .any(|(key_id, _)| fingerprint.eq_ignore_ascii_case(key_id)) =>
+            {
+                Err(SignatureError::KeyFingerprintMismatch)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts the distinct `keys` with a signature in the comma-separated
+    /// `WebIdentity-Signature` list. Repeated signatures from the same key only count once.
+    fn count_signers(&self, keys: &[(String, VerifyingKey)]) -> usize {
+        let mut signed_by = vec![false; keys.len()];
+        for signature in self.signature.split(',') {
+            let Ok(signature) = hex::decode(strip_hex_prefix(signature.trim())) else {
+                continue;
+            };
+            for (i, (_, key)) in keys.iter().enumerate() {
+                if !signed_by[i]
+                    && verify_with_key(key, self.canonical_string.as_bytes(), &signature).is_ok()
+                {
+                    signed_by[i] = true;
+                    break;
+                }
+            }
+        }
+        signed_by.iter().filter(|signed| **signed).count()
+    }
+
+    /// Verifies the signature with `verifying_key`, or with the delegated subkey once the
+    /// delegation is verified with `verifying_key`.
+    pub(crate) fn verify(&self, verifying_key: &VerifyingKey) -> Result<(), WebIdentityError> {
+        if let Some(fingerprint) = self.key_fingerprint {
+            if !fingerprint.eq_ignore_ascii_case(&identity_id(verifying_key.as_bytes())) {
+                return Err(SignatureError::KeyFingerprintMismatch.into());
+            }
+        }
+
+        let verifying_key = match &self.delegation {
+            Some(delegation) => {
+                // The expiry was already checked against the configured time when parsing
+                delegation.verify_signed_by(&self.location, verifying_key)?;
+                &delegation.subkey
+            }
+            None => verifying_key,
+        };
+
+        let mut signature_bytes = [0u8; 64];
+        hex::decode_to_slice(strip_hex_prefix(self.signature), &mut signature_bytes)
+            .map_err(|_| SignatureError::SignatureMismatch)?;
+
+        verify_with_key(
+            verifying_key,
+            self.canonical_string.as_bytes(),
+            &signature_bytes,
+        )
+    }
+}
+
+/// Optional headers covered by the signature, in the order they appear in the canonical string.
+pub(crate) const EXTENSION_HEADERS: &[&str] = &[
+    "WebIdentity-Algorithm",
+    "WebIdentity-Key",
+    "WebIdentity-Delegation",
+    "WebIdentity-Digest",
+    "WebIdentity-Body-Length",
+    "WebIdentity-Canonicalization",
+    "WebIdentity-Body",
+    "WebIdentity-Headers",
+    "WebIdentity-Expires",
+    "WebIdentity-Trailers",
+];
+
+/// Headers that are never covered by [`SignOptions::with_all_headers`]: hop-by-hop headers,
+/// headers proxies add or rewrite, and headers the canonical string already covers. The
+/// `WebIdentity-*` headers and those named in the `Connection` header aren't covered either.
+const UNCOVERED_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "host",
+    "content-length",
+    "authorization",
+    "content-digest",
+    "via",
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-host",
+    "x-forwarded-proto",
+    "x-real-ip",
+];
+
+/// Whether a header is covered by [`SignOptions::with_all_headers`], given the request's
+/// `Connection` header.
+fn is_coverable(name: &str, connection: Option<&str>) -> bool {
+    let is_webidentity = name
+        .get(..12)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("WebIdentity-"));
+    let is_hop_by_hop = connection.is_some_and(|connection| {
+        connection
+            .split(',')
+            .any(|header| header.trim().eq_ignore_ascii_case(name))
+    });
+    !is_webidentity
+        && !is_hop_by_hop
+        && !UNCOVERED_HEADERS
+            .iter()
+            .any(|header| header.eq_ignore_ascii_case(name))
+}
+
+/// Checks that every coverable header of the request is listed in its `WebIdentity-Headers`.
+fn check_header_coverage(
+    headers: &impl HeaderProvider,
+    covered: Option<&str>,
+) -> Result<(), SignatureError> {
+    let covered =
+        covered.ok_or_else(|| SignatureError::MissingHeader("WebIdentity-Headers".into()))?;
+    let names = headers
+        .header_names()
+        .ok_or(SignatureError::HeaderListUnavailable)?;
+    let connection = headers.get_header("Connection");
+    match names.into_iter().find(|name| {
+        is_coverable(name, connection)
+            && !covered
+                .split_ascii_whitespace()
+                .any(|header| header.eq_ignore_ascii_case(name))
+    }) {
+        Some(name) => Err(SignatureError::UncoveredHeader(name.to_string())),
+        None => Ok(()),
+    }
+}
+
+/// The `WebIdentity-Canonicalization` value of requests whose body line is the digest of the
+/// JSON body canonicalized with RFC 8785.
+const JCS_CANONICALIZATION: &str = "jcs";
+
+/// The `WebIdentity-Body` value, and body line, of requests whose signature doesn't cover the
+/// body.
+const UNBOUND_BODY: &str = "unbound";
+
+/// The `WebIdentity-Digest` value of requests that also sign their `Content-Digest` header.
+const CONTENT_DIGEST_MODE: &str = "content-digest";
+
+/// The value of a signed extension header.
+pub(crate) fn extension<'h>(extensions: &[(&'h str, &'h str)], name: &str) -> Option<&'h str> {
+    extensions
+        .iter()
+        .find(|(header, _)| *header == name)
+        .map(|(_, value)| *value)
+}
+
+/// Requests without the `WebIdentity-Algorithm` header predate it, and are Ed25519 like every
+/// key this library verifies with.
+pub(crate) fn check_algorithm(extensions: &[(&str, &str)]) -> Result<(), SignatureError> {
+    if let Some(algorithm) = extension(extensions, "WebIdentity-Algorithm") {
+        algorithm.parse::<SignatureAlgorithm>()?;
+    }
+    Ok(())
+}
+
+/// Gets the optional signed headers present in the request, as canonical string extensions.
+///
+/// With `WebIdentity-Digest: content-digest`, the request's `Content-Digest` header is covered
+/// too, followed by the headers listed in `WebIdentity-Headers` (see
+/// [`SignOptions::with_all_headers`]) and the trailers listed in `WebIdentity-Trailers` (see
+/// [`SignOptions::with_trailers`]), which are covered last.
+pub(crate) fn signed_extensions(
+    headers: &impl HeaderProvider,
+) -> Result<Vec<(&str, &str)>, SignatureError> {
+    let mut extensions = Vec::new();
+    for name in EXTENSION_HEADERS {
+        if let Some(value) = optional_header(headers, name)? {
+            extensions.push((*name, value));
+        }
+    }
+    if let Some(mode) = optional_header(headers, "WebIdentity-Digest")? {
+        if !mode.eq_ignore_ascii_case(CONTENT_DIGEST_MODE) {
+            return Err(SignatureError::UnsupportedDigest(
+                "WebIdentity-Digest".to_string(),
+            ));
+        }
+        extensions.push((
+            "Content-Digest",
+            required_header(headers, "Content-Digest")?,
+        ));
+    }
+    if let Some(covered) = optional_header(headers, "WebIdentity-Headers")? {
+        for name in covered.split_ascii_whitespace() {
+            extensions.push((name, required_header(headers, name)?.trim()));
+        }
+    }
+    // Trailers are only received after the body, so they come last
+    if let Some(trailers) = optional_header(headers, "WebIdentity-Trailers")? {
+        for name in trailers.split_ascii_whitespace() {
+            let value = headers
+                .get_trailer(name)
+                .ok_or_else(|| SignatureError::MissingTrailer(name.to_string()))?;
+            extensions.push((name, value.trim()));
+        }
+    }
+    Ok(extensions)
+}
+
+/// Gets a security-critical header, rejecting it if it is missing or was sent more than once.
+pub(crate) fn required_header<'a>(
+    headers: &'a impl HeaderProvider,
+    name: &str,
+) -> Result<&'a str, SignatureError> {
+    optional_header(headers, name)?.ok_or_else(|| SignatureError::MissingHeader(name.to_string()))
+}
+
+/// Gets an optional security-critical header, rejecting it if it was sent more than once.
+fn optional_header<'a>(
+    headers: &'a impl HeaderProvider,
+    name: &str,
+) -> Result<Option<&'a str>, SignatureError> {
+    if headers.has_duplicate_header(name) {
+        return Err(SignatureError::DuplicateHeader(name.to_string()));
+    }
+    Ok(headers.get_header(name))
+}
+
+/// Returns the key fingerprint a client asserted in its `WebIdentity-Key` header.
+///
+/// This is only a lookup hint: it lets a server find a key it has already pinned or cached
+/// without fetching the identity page first. The page (or the pin store) stays the source of
+/// truth, and verification fails with [`SignatureError::KeyFingerprintMismatch`] if the header
+/// doesn't match the key the request is verified against.
+pub fn key_fingerprint_hint(headers: &impl HeaderProvider) -> Option<&str> {
+    headers.get_header("WebIdentity-Key")
+}
+
+/// Checks a body digest against the request's `Content-Digest` (RFC 9530) header,
+/// falling back to the legacy `Digest` (RFC 3230) header.
+///
+/// # Errors
+/// Returns `Err` if neither header is present, no SHA-256 entry is found in it,
+/// or the digest does not match.
+pub fn verify_content_digest(
+    headers: &impl HeaderProvider,
+    body_digest: &RequestDigest,
+) -> Result<(), WebIdentityError> {
+    let expected = content_digest_sha256(headers)?;
+    if body_digest.algorithm() == DigestAlgorithm::Sha256 && expected == body_digest.as_bytes() {
+        Ok(())
+    } else {
+        Err(SignatureError::DigestMismatch.into())
+    }
+}
+
+/// Reads the SHA-256 digest from the request's `Content-Digest` or `Digest` header.
+pub(crate) fn content_digest_sha256(
+    headers: &impl HeaderProvider,
+) -> Result<Vec<u8>, SignatureError> {
+    let (header, expected) = if let Some(value) = headers.get_header("Content-Digest") {
+        // sha-256=:<base64>:, possibly alongside other algorithms
+        let digest = value.split(',').find_map(|entry| {
+            let (algorithm, digest) = entry.trim().split_once('=')?;
+            if algorithm.trim().eq_ignore_ascii_case("sha-256") {
+                digest.trim().strip_prefix(':')?.strip_suffix(':')
+            } else {
+                None
+            }
+        });
+        ("Content-Digest", digest)
+    } else if let Some(value) = headers.get_header("Digest") {
+        // SHA-256=<base64>, possibly alongside other algorithms
+        let digest = value.split(',').find_map(|entry| {
+            let (algorithm, digest) = entry.trim().split_once('=')?;
+            algorithm
+                .trim()
+                .eq_ignore_ascii_case("sha-256")
+                .then(|| digest.trim())
+        });
+        ("Digest", digest)
+    } else {
+        return Err(SignatureError::MissingHeader("Content-Digest".to_string()));
+    };
+
+    let expected = expected.ok_or_else(|| SignatureError::UnsupportedDigest(header.to_string()))?;
+    BASE64_STANDARD
+        .decode(expected)
+        .map_err(|_| SignatureError::DigestMismatch)
+}
+
+/// Strips the optional `0x` prefix used by Ethereum-adjacent tooling from a hex string.
+pub(crate) fn strip_hex_prefix(hex: &str) -> &str {
+    hex.strip_prefix("0x")
+        .or_else(|| hex.strip_prefix("0X"))
+        .unwrap_or(hex)
 }
 
 // This is taken from rust std, since it is still unstable library feature, but is useful here
@@ -81,34 +1348,436 @@ pub(crate) fn as_array<T, const N: usize>(vec: &[T]) -> Option<&[T; N]> {
     }
 }
 
-/// Creates the three `WebIdentity-*` headers for making a signed request.
+/// Options controlling which headers are created for a signed request.
+#[derive(Debug, Clone, Default)]
+pub struct SignOptions {
+    key_fingerprint: bool,
+    delegation: Option<String>,
+    authorization_header: bool,
+    content_digest: bool,
+    body_length: bool,
+    json_canonicalization: bool,
+    implicit_location: bool,
+    unbound_body: bool,
+    covered_headers: Option<Vec<(String, String)>>,
+    expiry: Option<Duration>,
+    trailers: Option<Vec<(String, String)>>,
+}
+
+impl SignOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a signed `WebIdentity-Key` header with the signer's key fingerprint (its identity id),
+    /// so servers that already know the key can verify without fetching the identity page.
+    pub fn with_key_fingerprint(mut self) -> Self {
+        self.key_fingerprint = true;
+        self
+    }
+
+    /// Adds a signed `WebIdentity-Delegation` header, for requests signed by a subkey the
+    /// identity delegated to (see [`Delegation`]).
+    ///
+    /// The signer doesn't hold the identity's key, so [`SignOptions::with_key_fingerprint`] has
+    /// no effect with a delegation.
+    pub fn with_delegation(mut self, delegation: &Delegation) -> Self {
+        self.delegation = Some(delegation.to_header_value());
+        self
+    }
+
+    /// Encodes the signature as a single `Authorization: WebIdentity ...` header instead of
+    /// the `WebIdentity-*` headers, for clients and proxies that can't send custom headers.
+    ///
+    /// The signature is the same, and verification accepts either encoding.
+    pub fn with_authorization_header(mut self) -> Self {
+        self.authorization_header = true;
+        self
+    }
+
+    /// Adds a `Content-Digest` header (RFC 9530) with the body's SHA-256 digest, and signs it
+    /// along with a `WebIdentity-Digest: content-digest` header.
+    ///
+    /// Servers whose middleware already checks `Content-Digest` can then verify with
+    /// [`RequestDigest::from_content_digest`] instead of hashing the body again. Verification
+    /// still checks the header matches the digest it is given.
+    pub fn with_content_digest(mut self) -> Self {
+        self.content_digest = true;
+        self
+    }
+
+    /// Adds a signed `WebIdentity-Body-Length` header, so a body dropped or cut in transit is
+    /// reported as [`SignatureError::RequestMismatch`] instead of a signature mismatch.
+    pub fn with_body_length(mut self) -> Self {
+        self.body_length = true;
+        self
+    }
+
+    /// Hashes the body as JSON canonicalized with RFC 8785 (JCS) rather than byte for byte, and
+    /// signs a `WebIdentity-Canonicalization: jcs` header so verification does the same.
+    ///
+    /// The signature then survives middleware that reorders keys or changes whitespace. Signing
+    /// fails if the body isn't JSON. The `Content-Digest` header describes the exact bytes, so
+    /// [`SignOptions::with_content_digest`] has no effect with canonicalization.
+    pub fn with_json_canonicalization(mut self) -> Self {
+        self.json_canonicalization = true;
+        self
+    }
+
+    /// Leaves out the `WebIdentity-Location` header, for an identity at the root of the host
+    /// the request is sent to. The location is still signed, so it must be the one servers
+    /// derive from the host with [`VerifyOptions::with_location_from_host`] (`example.com` for
+    /// `example.com:8443`).
+    pub fn with_implicit_location(mut self) -> Self {
+        self.implicit_location = true;
+        self
+    }
+
+    /// Signs the request without its body, so a server can check the signature before a
+    /// large upload is received (see [`verify_request_headers`]).
+    ///
+    /// The canonical string's body line is `unbound` and a signed `WebIdentity-Body: unbound`
+    /// header declares it, so a signature can't be passed off as covering a body. Servers must
+    /// opt in with [`VerifyOptions::with_unbound_body`]. Combine it with
+    /// [`SignOptions::with_content_digest`] so the server can still check the body once read.
+    /// [`SignOptions::with_body_length`] and [`SignOptions::with_json_canonicalization`] have no
+    /// effect.
+    pub fn without_body_binding(mut self) -> Self {
+        self.unbound_body = true;
+        self
+    }
+
+    /// Adds a signed `WebIdentity-Expires` header, `expiry` after the request's
+    /// `WebIdentity-Timestamp` (its creation time), after which servers reject it.
+    ///
+    /// Servers still cap how long after its creation a request is accepted, see
+    /// [`VerifyOptions::with_max_lifetime`].
+    pub fn with_expiry(mut self, expiry: Duration) -> Self {
+        self.expiry = Some(expiry);
+        self
+    }
+
+    /// Signs every one of the request's other `headers`, and lists them in a signed
+    /// `WebIdentity-Headers` header, so none can be modified or removed in transit. Servers
+    /// can also reject added headers with [`VerifyOptions::with_full_header_coverage`].
+    ///
+    /// Hop-by-hop headers (`Connection`, `Keep-Alive`, `Transfer-Encoding`, those named in
+    /// `Connection`...), headers proxies add or rewrite (`Via`, `Forwarded`, `X-Forwarded-*`,
+    /// `X-Real-IP`, `Content-Length`), and headers the signature already covers (`Host`,
+    /// `Authorization`, `Content-Digest` and `WebIdentity-*`) are left out. Values are signed
+    /// without surrounding whitespace, and only the first value of a repeated header is
+    /// signed; servers reject repeated covered headers.
+    pub fn with_all_headers<'h>(
+        mut self,
+        headers: impl IntoIterator<Item = (&'h str, &'h str)>,
+    ) -> Self {
+        let headers: Vec<(&str, &str)> = headers.into_iter().collect();
+        let connection = headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Connection"))
+            .map(|(_, value)| *value);
+
+        let mut covered: Vec<(String, String)> = Vec::new();
+        for (name, value) in headers {
+            if is_coverable(name, connection)
+                && !covered.iter().any(|(c, _)| c.eq_ignore_ascii_case(name))
+            {
+                covered.push((name.to_string(), value.trim().to_string()));
+            }
+        }
+        covered.sort_by_key(|(name, _)| name.to_ascii_lowercase());
+        self.covered_headers = Some(covered);
+        self
+    }
+
+    /// Signs the request's `trailers`, the fields sent after the body (e.g. by gRPC over
+    /// HTTP/2), and lists them in a signed `WebIdentity-Trailers` header.
+    ///
+    /// Their values must be known when signing, as the signature is sent in the headers, and
+    /// they aren't part of the returned headers: they must be sent as trailers with these
+    /// values. Trailers are only meaningful once the whole body was received, so servers can
+    /// only verify such requests then, passing the trailers with [`WithTrailers`];
+    /// [`verify_request_headers`] fails with [`SignatureError::MissingTrailer`]. Values are
+    /// signed without surrounding whitespace, and a repeated trailer is only signed once.
+    pub fn with_trailers<'t>(
+        mut self,
+        trailers: impl IntoIterator<Item = (&'t str, &'t str)>,
+    ) -> Self {
+        let mut signed: Vec<(String, String)> = Vec::new();
+        for (name, value) in trailers {
+            if !signed.iter().any(|(s, _)| s.eq_ignore_ascii_case(name)) {
+                signed.push((name.to_string(), value.trim().to_string()));
+            }
+        }
+        signed.sort_by_key(|(name, _)| name.to_ascii_lowercase());
+        self.trailers = Some(signed);
+        self
+    }
+}
+
+/// Creates the `WebIdentity-*` headers for making a signed request.
+///
+/// `signer` is usually a [`SigningKey`], but can be any [`RequestSigner`].
 pub fn create_signed_headers(
     location: &str,
     http_method: &str,
     host: &str,
     path: &str,
     body: &[u8],
-    signing_key: &SigningKey,
+    signer: &impl RequestSigner,
+) -> Result<HashMap<String, String>, WebIdentityError> {
+    create_signed_headers_with_options(
+        location,
+        http_method,
+        host,
+        path,
+        body,
+        signer,
+        &SignOptions::default(),
+    )
+}
+
+/// Creates the `WebIdentity-*` headers for making a signed request, as configured by `options`.
+pub fn create_signed_headers_with_options(
+    location: &str,
+    http_method: &str,
+    host: &str,
+    path: &str,
+    body: &[u8],
+    signer: &impl RequestSigner,
+    options: &SignOptions,
 ) -> Result<HashMap<String, String>, WebIdentityError> {
-    let timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs()
-        .to_string();
-    let body_hash = hash_body(body);
+    let unsigned = UnsignedHeaders::new(
+        location,
+        http_method,
+        host,
+        path,
+        body,
+        &signer.verifying_key(),
+        options,
+    )?;
+    let signature = signer.sign_message(unsigned.canonical_string.as_bytes())?;
+    Ok(unsigned.sign(signature))
+}
+
+/// Creates the `WebIdentity-*` headers for making a signed request with a signer that signs
+/// asynchronously, as configured by `options`.
+#[cfg(feature = "async")]
+pub async fn create_signed_headers_async(
+    location: &str,
+    http_method: &str,
+    host: &str,
+    path: &str,
+    body: &[u8],
+    signer: &impl AsyncRequestSigner,
+    options: &SignOptions,
+) -> Result<HashMap<String, String>, WebIdentityError> {
+    let unsigned = UnsignedHeaders::new(
+        location,
+        http_method,
+        host,
+        path,
+        body,
+        &signer.verifying_key(),
+        options,
+    )?;
+    let signature = signer
+        .sign_message_async(unsigned.canonical_string.as_bytes())
+        .await?;
+    Ok(unsigned.sign(signature))
+}
+
+/// The headers of a request that is about to be signed, and the canonical string to sign.
+struct UnsignedHeaders {
+    canonical_string: String,
+    headers: HashMap<String, String>,
+    authorization_header: bool,
+}
+
+impl UnsignedHeaders {
+    fn new(
+        location: &str,
+        http_method: &str,
+        host: &str,
+        path: &str,
+        body: &[u8],
+        verifying_key: &VerifyingKey,
+        options: &SignOptions,
+    ) -> Result<UnsignedHeaders, WebIdentityError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let timestamp = now.to_string();
+        // An unbound body isn't hashed for the canonical string, so it isn't canonicalized either
+        let json_canonicalization = options.json_canonicalization && !options.unbound_body;
+        let body_digest = if json_canonicalization {
+            RequestDigest::of_json(body)?
+        } else {
+            RequestDigest::of(body)
+        };
+        let content_digest_mode = options.content_digest && !json_canonicalization;
+
+        let fingerprint = identity_id(verifying_key.as_bytes());
+        let mut extensions = vec![(
+            "WebIdentity-Algorithm",
+            SignatureAlgorithm::Ed25519.as_str(),
+        )];
+        if options.key_fingerprint && options.delegation.is_none() {
+            extensions.push(("WebIdentity-Key", fingerprint.as_str()));
+        }
+        if let Some(delegation) = &options.delegation {
+            extensions.push(("WebIdentity-Delegation", delegation.as_str()));
+        }
+        let content_digest = format!(
+            "sha-256=:{}:",
+            BASE64_STANDARD.encode(body_digest.as_bytes())
+        );
+        // The length of what was hashed, which is what verification compares
+        let body_length = body_digest.body_length().unwrap_or_default().to_string();
+        if content_digest_mode {
+            extensions.push(("WebIdentity-Digest", CONTENT_DIGEST_MODE));
+        }
+        if options.body_length && !options.unbound_body {
+            extensions.push(("WebIdentity-Body-Length", body_length.as_str()));
+        }
+        if json_canonicalization {
+            extensions.push(("WebIdentity-Canonicalization", JCS_CANONICALIZATION));
+        }
+        if options.unbound_body {
+            extensions.push(("WebIdentity-Body", UNBOUND_BODY));
+        }
+        let covered_names = options.covered_headers.as_ref().map(|covered| {
+            covered
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>()
+                .join(" ")
+        });
+        if let Some(covered_names) = &covered_names {
+            extensions.push(("WebIdentity-Headers", covered_names.as_str()));
+        }
+        let expires = options
+            .expiry
+            .map(|expiry| now.saturating_add(expiry.as_secs()).to_string());
+        if let Some(expires) = &expires {
+            extensions.push(("WebIdentity-Expires", expires.as_str()));
+        }
+        let trailer_names = options.trailers.as_ref().map(|trailers| {
+            trailers
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>()
+                .join(" ")
+        });
+        if let Some(trailer_names) = &trailer_names {
+            extensions.push(("WebIdentity-Trailers", trailer_names.as_str()));
+        }
+        // Covered last, see `signed_extensions`
+        if content_digest_mode {
+            extensions.push(("Content-Digest", content_digest.as_str()));
+        }
+        for (name, value) in options.covered_headers.iter().flatten() {
+            extensions.push((name.as_str(), value.as_str()));
+        }
+        for (name, value) in options.trailers.iter().flatten() {
+            extensions.push((name.as_str(), value.as_str()));
+        }
+
+        let canonical_string = build_canonical_string(
+            http_method,
+            host,
+            path,
+            body_digest.as_bytes(),
+            location,
+            &timestamp,
+            &extensions,
+        );
+
+        // The covered headers are already part of the request, the trailers follow the body
+        let covered_count = options.covered_headers.as_ref().map_or(0, Vec::len)
+            + options.trailers.as_ref().map_or(0, Vec::len);
+        let mut headers = HashMap::new();
+        for (name, value) in &extensions[..extensions.len() - covered_count] {
+            headers.insert(name.to_string(), value.to_string());
+        }
+        if !options.implicit_location {
+            headers.insert("WebIdentity-Location".to_string(), location.to_string());
+        }
+        headers.insert("WebIdentity-Timestamp".to_string(), timestamp);
+
+        Ok(UnsignedHeaders {
+            canonical_string,
+            headers,
+            authorization_header: options.authorization_header,
+        })
+    }
+
+    /// Adds the signature, folding the headers into `Authorization` if configured to.
+    fn sign(self, signature: [u8; 64]) -> HashMap<String, String> {
+        let mut headers = self.headers;
+        headers.insert("WebIdentity-Signature".to_string(), hex::encode(signature));
 
-    let canonical_string =
-        build_canonical_string(http_method, host, path, &body_hash, location, &timestamp);
+        if self.authorization_header {
+            let mut authorization =
+                HashMap::from([("Authorization".to_string(), to_authorization(&headers))]);
+            // Content-Digest is a standard header, it isn't folded into Authorization
+            if let Some(content_digest) = headers.remove("Content-Digest") {
+                authorization.insert("Content-Digest".to_string(), content_digest);
+            }
+            return authorization;
+        }
+        headers
+    }
+}
+
+/// Adds another member's signature to headers created by [`create_signed_headers`], for
+/// identities that require several keys to sign (see [`verify_request_threshold`]).
+///
+/// The body is hashed as the headers declare, canonicalized with
+/// [`SignOptions::with_json_canonicalization`] or left out with
+/// [`SignOptions::without_body_binding`].
+///
+/// # Errors
+/// Returns `Err` if `headers` is missing the `WebIdentity-*` headers, or signing fails.
+pub fn add_cosignature(
+    headers: &mut HashMap<String, String>,
+    http_method: &str,
+    host: &str,
+    path: &str,
+    body: &[u8],
+    signer: &impl RequestSigner,
+) -> Result<(), WebIdentityError> {
+    let location = required_header(headers, "WebIdentity-Location")?;
+    let timestamp = required_header(headers, "WebIdentity-Timestamp")?;
+    let extensions = signed_extensions(headers)?;
+    // Hashed as the first signer did, an unbound body isn't covered at all
+    let body_digest = if extension(&extensions, "WebIdentity-Body") == Some(UNBOUND_BODY) {
+        RequestDigest::of(&[])
+    } else {
+        body_digest(body, headers)?
+    };
 
-    let signature = signing_key.sign(canonical_string.as_bytes());
-    let signature_hex = hex::encode(signature.to_bytes());
+    let canonical_string = build_canonical_string(
+        http_method,
+        host,
+        path,
+        body_digest.as_bytes(),
+        location,
+        timestamp,
+        &extensions,
+    );
+    let signature = hex::encode(signer.sign_message(canonical_string.as_bytes())?);
 
-    let mut headers = HashMap::new();
-    headers.insert("WebIdentity-Location".to_string(), location.to_string());
-    headers.insert("WebIdentity-Timestamp".to_string(), timestamp);
-    headers.insert("WebIdentity-Signature".to_string(), signature_hex);
+    let signatures = headers
+        .entry("WebIdentity-Signature".to_string())
+        .or_default();
+    if !signatures.is_empty() {
+        signatures.push(',');
+    }
+    signatures.push_str(&signature);
 
-    Ok(headers)
+    Ok(())
 }
 
 /// Helper function to sign with `ed25519-dalek`
@@ -126,13 +1795,23 @@ pub fn verify_signature(
     original_bytes: &[u8],
     signature: &[u8],
 ) -> Result<(), WebIdentityError> {
-    let public_key = VerifyingKey::from_bytes(
+    verify_with_key(&parse_verifying_key(public_key)?, original_bytes, signature)
+}
+
+pub(crate) fn parse_verifying_key(public_key: &[u8]) -> Result<VerifyingKey, SignatureError> {
+    VerifyingKey::from_bytes(
         as_array::<u8, 32>(public_key).ok_or(SignatureError::SignatureMismatch)?,
     )
-    .map_err(|_| SignatureError::SignatureMismatch)?;
+    .map_err(|_| SignatureError::SignatureMismatch)
+}
 
+fn verify_with_key(
+    public_key: &VerifyingKey,
+    original_bytes: &[u8],
+    signature: &[u8],
+) -> Result<(), WebIdentityError> {
     let signature_bytes = as_array::<u8, 64>(signature).ok_or(SignatureError::SignatureMismatch)?;
-    let signature = Signature::from_bytes(&signature_bytes);
+    let signature = Signature::from_bytes(signature_bytes);
 
     if public_key.verify(original_bytes, &signature).is_ok() {
         Ok(())
@@ -141,33 +1820,671 @@ pub fn verify_signature(
     }
 }
 
-fn hash_body(body: &[u8]) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(body);
-    hex::encode(hasher.finalize())
-}
-
-fn build_canonical_string(
+pub(crate) fn build_canonical_string(
     method: &str,
     host: &str,
     path: &str,
-    body_hash: &str,
+    body_sha256: &[u8; 32],
     location: &str,
     timestamp: &str,
+    extensions: &[(&str, &str)],
 ) -> String {
-    let clean_path = if path != "/" {
+    let mut canonical = String::new();
+    write_canonical_string(
+        &mut canonical,
+        method,
+        host,
+        path,
+        body_sha256,
+        location,
+        timestamp,
+        extensions,
+    );
+    canonical
+}
+
+/// The host as covered by the canonical string, without the trailing dot of a fully qualified
+/// name (`example.com.` and `example.com` are the same host).
+///
+/// IPv6 literals are bracketed and written in their compressed form (RFC 5952), so
+/// `2001:DB8:0::1`, `[2001:db8::0:1]` and `[2001:db8::1]` are the same host. A port can only
+/// follow a bracketed literal.
+pub(crate) fn canonical_host(host: &str) -> Cow<'_, str> {
+    if let Some(literal) = host.strip_prefix('[') {
+        return match literal.split_once(']') {
+            Some((address, port)) => match address.parse::<Ipv6Addr>() {
+                Ok(address) => Cow::Owned(format!("[{}]{}", address, port)),
+                Err(_) => Cow::Borrowed(host),
+            },
+            None => Cow::Borrowed(host),
+        };
+    }
+    if let Ok(address) = host.parse::<Ipv6Addr>() {
+        return Cow::Owned(format!("[{}]", address));
+    }
+
+    if let Some(host) = host.strip_suffix('.') {
+        return Cow::Borrowed(host);
+    }
+    match host.rsplit_once(':') {
+        Some((name, port)) => match name.strip_suffix('.') {
+            Some(name) => Cow::Owned(format!("{}:{}", name, port)),
+            None => Cow::Borrowed(host),
+        },
+        None => Cow::Borrowed(host),
+    }
+}
+
+/// The path as covered by the canonical string, without a trailing slash.
+fn canonical_path(path: &str) -> &str {
+    if path != "/" {
         path.trim_end_matches('/')
     } else {
         path
-    };
+    }
+}
 
-    format!(
-        "{}\n{}\n{}\n{}\n{}\n{}",
-        method.to_uppercase(),
-        host,
-        clean_path,
-        body_hash,
-        location,
-        timestamp
-    )
+/// Writes the canonical string of a request into `buf`, clearing it first.
+///
+/// This is what signing and verification sign and check, for callers that hash or verify the
+/// canonical string themselves: reusing one buffer across requests avoids allocating a string
+/// per request on hot paths. `extensions` are the signed optional headers as `(name, value)`
+/// pairs, in the order they are covered (see the [`conformance`](crate::conformance) vectors).
+#[allow(clippy::too_many_arguments)]
+pub fn write_canonical_string(
+    buf: &mut String,
+    method: &str,
+    host: &str,
+    path: &str,
+    body_sha256: &[u8; 32],
+    location: &str,
+    timestamp: &str,
+    extensions: &[(&str, &str)],
+) {
+    let host = canonical_host(host);
+    let clean_path = canonical_path(path);
+
+    let mut body_hash = [0u8; 64];
+    hex::encode_to_slice(body_sha256, &mut body_hash).unwrap();
+    // Hex is always ASCII
+    let mut body_hash = std::str::from_utf8(&body_hash).unwrap();
+    // Requests that don't sign their body say so in the body line, not just in a header
+    if extensions.contains(&("WebIdentity-Body", UNBOUND_BODY)) {
+        body_hash = UNBOUND_BODY;
+    }
+
+    buf.clear();
+    buf.reserve(
+        method.len()
+            + host.len()
+            + clean_path.len()
+            + body_hash.len()
+            + location.len()
+            + timestamp.len()
+            + 5,
+    );
+
+    // Same as `method.to_uppercase()`, without the intermediate string
+    buf.extend(method.chars().flat_map(char::to_uppercase));
+    for part in [host.as_ref(), clean_path, body_hash, location, timestamp] {
+        buf.push('\n');
+        buf.push_str(part);
+    }
+
+    // Optional headers are covered as extra `name:value` lines
+    for (name, value) in extensions {
+        buf.push('\n');
+        buf.extend(name.chars().map(|c| c.to_ascii_lowercase()));
+        buf.push(':');
+        buf.push_str(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::SharedTestIdentity;
+
+    const HOST: &str = "api.example.com";
+    const PATH: &str = "/notes";
+
+    fn cosigned(
+        identity: &SharedTestIdentity,
+        body: &[u8],
+        options: &SignOptions,
+    ) -> HashMap<String, String> {
+        let mut headers = create_signed_headers_with_options(
+            &identity.location,
+            "POST",
+            HOST,
+            PATH,
+            body,
+            &identity.signing_keys[0],
+            options,
+        )
+        .unwrap();
+        add_cosignature(
+            &mut headers,
+            "POST",
+            HOST,
+            PATH,
+            body,
+            &identity.signing_keys[1],
+        )
+        .unwrap();
+        headers
+    }
+
+    #[test]
+    fn thresholds_count_distinct_listed_signers() {
+        // Members of the identity, its threshold, who signs, and the signers counted if too few
+        let cases: &[(usize, u8, &[usize], Option<usize>)] = &[
+            (3, 1, &[2], None),
+            (3, 2, &[0], Some(1)),
+            (3, 2, &[2], Some(1)),
+            (3, 2, &[1, 0], None),
+            (3, 2, &[0, 2], None),
+            (3, 2, &[1, 1], Some(1)),
+            (3, 3, &[0, 1], Some(2)),
+            (3, 3, &[2, 0, 1], None),
+        ];
+        let options = VerifyOptions::new(Duration::from_secs(300));
+        let digest = RequestDigest::of(b"hello");
+
+        for &(members, threshold, signers, too_few) in cases {
+            let identity = SharedTestIdentity::generate("team.example.com", members, threshold);
+            let headers = identity.signed_headers_for(signers, "POST", HOST, PATH, b"hello");
+            let headers = AuthorizationHeaders::new(&headers).unwrap();
+            let request =
+                SignedRequest::parse("POST", HOST, PATH, &digest, &headers, &options).unwrap();
+
+            let result = request.verify_for_keys(
+                &identity_keys(&identity.identity),
+                identity.identity.threshold,
+                &options,
+            );
+            match too_few {
+                None => assert!(
+                    result.is_ok(),
+                    "{:?} of {}: {:?}",
+                    signers,
+                    threshold,
+                    result
+                ),
+                Some(expected) => assert!(
+                    matches!(
+                        result,
+                        Err(WebIdentityError::Signature(SignatureError::ThresholdNotMet {
+                            valid,
+                            required
+                        })) if valid == expected && required == threshold as usize
+                    ),
+                    "{:?} of {}: {:?}",
+                    signers,
+                    threshold,
+                    result
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn cosigns_json_canonicalized_body() {
+        let identity = SharedTestIdentity::generate("team.example.com", 2, 2);
+        let body = br#"{ "b": 1, "a": [true, null] }"#;
+        let headers = cosigned(
+            &identity,
+            body,
+            &SignOptions::new().with_json_canonicalization(),
+        );
+        assert_eq!(
+            headers["WebIdentity-Canonicalization"],
+            JCS_CANONICALIZATION
+        );
+
+        // The body is reformatted in transit, its canonical form is unchanged
+        let received = br#"{"a":[true,null],"b":1}"#;
+        verify_request_threshold(
+            "POST",
+            HOST,
+            PATH,
+            &body_digest(received, &headers).unwrap(),
+            &headers,
+            &identity.identity,
+            &VerifyOptions::new(Duration::from_secs(300)),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn cosigns_unbound_body() {
+        let identity = SharedTestIdentity::generate("team.example.com", 2, 2);
+        let headers = cosigned(
+            &identity,
+            b"a large upload",
+            &SignOptions::new().without_body_binding(),
+        );
+
+        verify_request_threshold(
+            "POST",
+            HOST,
+            PATH,
+            &RequestDigest::of(b"not read yet"),
+            &headers,
+            &identity.identity,
+            &VerifyOptions::new(Duration::from_secs(300)).with_unbound_body(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn rejects_cosignature_over_other_body() {
+        let identity = SharedTestIdentity::generate("team.example.com", 2, 2);
+        let mut headers = create_signed_headers(
+            &identity.location,
+            "POST",
+            HOST,
+            PATH,
+            b"original",
+            &identity.signing_keys[0],
+        )
+        .unwrap();
+        add_cosignature(
+            &mut headers,
+            "POST",
+            HOST,
+            PATH,
+            b"tampered",
+            &identity.signing_keys[1],
+        )
+        .unwrap();
+
+        assert!(matches!(
+            verify_request_threshold(
+                "POST",
+                HOST,
+                PATH,
+                &RequestDigest::of(b"original"),
+                &headers,
+                &identity.identity,
+                &VerifyOptions::new(Duration::from_secs(300)),
+            ),
+            Err(WebIdentityError::Signature(
+                SignatureError::ThresholdNotMet {
+                    valid: 1,
+                    required: 2
+                }
+            ))
+        ));
+    }
+
+    #[test]
+    fn long_expiries_and_lifetimes_saturate() {
+        let identity = SharedTestIdentity::generate("team.example.com", 2, 2);
+        let headers = cosigned(
+            &identity,
+            b"hello",
+            &SignOptions::new().with_expiry(Duration::MAX),
+        );
+        assert_eq!(headers["WebIdentity-Expires"], u64::MAX.to_string());
+
+        let options = VerifyOptions::new(Duration::from_secs(300))
+            .with_max_lifetime(Duration::MAX)
+            .with_uncertainty(Duration::from_secs(5));
+        verify_request_threshold(
+            "POST",
+            HOST,
+            PATH,
+            &body_digest(b"hello", &headers).unwrap(),
+            &headers,
+            &identity.identity,
+            &options,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn long_timestamp_windows_saturate() {
+        let options = VerifyOptions::new(Duration::MAX)
+            .with_max_skew(Duration::MAX)
+            .with_uncertainty(Duration::from_secs(5));
+        options.check_timestamp(0).unwrap();
+        options.check_timestamp(u64::MAX).unwrap();
+    }
+
+    #[test]
+    fn www_equivalence_applies_to_expected_host() {
+        let options =
+            VerifyOptions::new(Duration::from_secs(300)).with_expected_host("example.com");
+        assert!(options.check_target("example.com.", PATH).is_ok());
+        assert!(matches!(
+            options.check_target("www.example.com", PATH),
+            Err(SignatureError::RequestMismatch(_))
+        ));
+
+        let options = options.with_www_equivalence();
+        assert!(options.check_target("WWW.Example.com", PATH).is_ok());
+        assert!(options.check_target("www.example.com.", PATH).is_ok());
+        assert!(options.check_target("api.example.com", PATH).is_err());
+        assert!(options.check_target("www.example.org", PATH).is_err());
+    }
+
+    /// The canonical string as it was built before it was written into a reusable buffer.
+    fn reference_canonical_string(
+        method: &str,
+        host: &str,
+        path: &str,
+        body_hash: &str,
+        location: &str,
+        timestamp: &str,
+        extensions: &[(&str, &str)],
+    ) -> String {
+        let clean_path = if path != "/" {
+            path.trim_end_matches('/')
+        } else {
+            path
+        };
+        let body_hash = if extensions.contains(&("WebIdentity-Body", UNBOUND_BODY)) {
+            UNBOUND_BODY
+        } else {
+            body_hash
+        };
+
+        let mut canonical = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method.to_uppercase(),
+            canonical_host(host),
+            clean_path,
+            body_hash,
+            location,
+            timestamp
+        );
+        for (name, value) in extensions {
+            canonical.push_str(&format!("\n{}:{}", name.to_lowercase(), value));
+        }
+        canonical
+    }
+
+    #[test]
+    fn canonical_string_matches_reference() {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let methods = ["GET", "post", "Delete", "pAtCh", "ünïcode"];
+        let targets = [
+            ("example.com", "/"),
+            ("example.com.", "/v1/messages///"),
+            ("Example.COM:8443", "/search?q=a+b&page=2"),
+            ("[2001:db8::1]:443", "/users/amélie/✨"),
+            ("api.example.com", ""),
+        ];
+        let bodies: [&[u8]; 3] = [b"", b"hello", &[0, 159, 146, 150, 255]];
+        let extensions: [&[(&str, &str)]; 4] = [
+            &[],
+            &[("WebIdentity-Algorithm", "ed25519")],
+            &[
+                ("WebIdentity-Body", UNBOUND_BODY),
+                ("Content-Digest", "sha-256=:AA==:"),
+            ],
+            &[
+                ("WebIdentity-Headers", "content-type"),
+                ("content-type", "application/json"),
+            ],
+        ];
+
+        let mut buf = String::from("left over from the previous request");
+        for method in methods {
+            for (host, path) in targets {
+                for body in bodies {
+                    for extensions in extensions {
+                        let body_sha256 = *RequestDigest::of(body).as_bytes();
+                        write_canonical_string(
+                            &mut buf,
+                            method,
+                            host,
+                            path,
+                            &body_sha256,
+                            "amy.carroted.org",
+                            "1700000000",
+                            extensions,
+                        );
+                        let expected = reference_canonical_string(
+                            method,
+                            host,
+                            path,
+                            &hex::encode(body_sha256),
+                            "amy.carroted.org",
+                            "1700000000",
+                            extensions,
+                        );
+                        assert_eq!(buf, expected);
+                        assert_eq!(
+                            signing_key.sign(buf.as_bytes()),
+                            signing_key.sign(expected.as_bytes())
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn signs_with_async_signers() {
+        /// A signer that only answers asynchronously, like a remote KMS
+        struct RemoteSigner(SigningKey);
+
+        impl AsyncRequestSigner for RemoteSigner {
+            fn verifying_key(&self) -> VerifyingKey {
+                self.0.verifying_key()
+            }
+
+            fn sign_message_async(
+                &self,
+                message: &[u8],
+            ) -> impl std::future::Future<Output = Result<[u8; 64], WebIdentityError>> + Send
+            {
+                let signature = self.0.sign(message).to_bytes();
+                async move {
+                    tokio::task::yield_now().await;
+                    Ok(signature)
+                }
+            }
+        }
+
+        let key = SigningKey::from_bytes(&[3; 32]);
+        let options = SignOptions::default().with_content_digest();
+        for headers in [
+            create_signed_headers_async(
+                "amy.carroted.org",
+                "POST",
+                HOST,
+                PATH,
+                b"hello",
+                &RemoteSigner(key.clone()),
+                &options,
+            )
+            .await
+            .unwrap(),
+            // Every RequestSigner signs asynchronously too
+            create_signed_headers_async(
+                "amy.carroted.org",
+                "POST",
+                HOST,
+                PATH,
+                b"hello",
+                &key,
+                &options,
+            )
+            .await
+            .unwrap(),
+        ] {
+            verify_request_with_key(
+                "POST",
+                HOST,
+                PATH,
+                &RequestDigest::of(b"hello"),
+                &headers,
+                &key.verifying_key(),
+                &VerifyOptions::new(Duration::from_secs(300)),
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn signs_trailers_after_the_covered_headers() {
+        let key = SigningKey::from_bytes(&[5; 32]);
+        let options = SignOptions::default()
+            .with_all_headers([("Content-Type", "application/grpc")])
+            .with_trailers([
+                ("X-Upload-CRC32C", " yZRlqg== "),
+                ("x-upload-crc32c", "dup"),
+            ]);
+        let mut headers = create_signed_headers_with_options(
+            "amy.carroted.org",
+            "POST",
+            HOST,
+            PATH,
+            b"hello",
+            &key,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(headers["WebIdentity-Trailers"], "X-Upload-CRC32C");
+        assert!(!headers.contains_key("X-Upload-CRC32C"));
+        headers.insert("Content-Type".to_string(), "application/grpc".to_string());
+
+        let check = |trailers: &SimpleHeaderProvider| {
+            verify_request_with_key(
+                "POST",
+                HOST,
+                PATH,
+                &RequestDigest::of(b"hello"),
+                &WithTrailers::new(&headers, trailers),
+                &key.verifying_key(),
+                &VerifyOptions::new(Duration::from_secs(300)),
+            )
+        };
+        let trailers = HashMap::from([("X-Upload-CRC32C".to_string(), "yZRlqg==".to_string())]);
+        check(&trailers).unwrap();
+
+        let with_trailers = WithTrailers::new(&headers, &trailers);
+        let request = SignedRequest::parse(
+            "POST",
+            HOST,
+            PATH,
+            &RequestDigest::of(b"hello"),
+            &with_trailers,
+            &VerifyOptions::new(Duration::from_secs(300)),
+        )
+        .unwrap();
+        assert!(request
+            .canonical_string
+            .ends_with("\ncontent-type:application/grpc\nx-upload-crc32c:yZRlqg=="));
+
+        assert!(matches!(
+            check(&HashMap::new()),
+            Err(WebIdentityError::Signature(SignatureError::MissingTrailer(name)))
+                if name == "X-Upload-CRC32C"
+        ));
+        let tampered = HashMap::from([("X-Upload-CRC32C".to_string(), "AAAAAA==".to_string())]);
+        assert!(check(&tampered).is_err());
+    }
+
+    #[test]
+    fn signs_trailers_in_the_authorization_header() {
+        let key = SigningKey::from_bytes(&[5; 32]);
+        let options = SignOptions::default()
+            .with_authorization_header()
+            .with_trailers([("x-upload-crc32c", "yZRlqg==")]);
+        let headers = create_signed_headers_with_options(
+            "amy.carroted.org",
+            "PUT",
+            HOST,
+            PATH,
+            b"hello",
+            &key,
+            &options,
+        )
+        .unwrap();
+        assert!(headers["Authorization"].contains(r#"trailers="x-upload-crc32c""#));
+
+        let trailers = HashMap::from([("x-upload-crc32c".to_string(), "yZRlqg==".to_string())]);
+        verify_request_with_key(
+            "PUT",
+            HOST,
+            PATH,
+            &RequestDigest::of(b"hello"),
+            &WithTrailers::new(&headers, &trailers),
+            &key.verifying_key(),
+            &VerifyOptions::new(Duration::from_secs(300)),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn canonicalizes_ipv6_literals() {
+        for host in [
+            "2001:db8::1",
+            "[2001:db8::1]",
+            "[2001:DB8::1]",
+            "[2001:db8:0:0:0:0:0:1]",
+            "2001:0db8:0000::0001",
+        ] {
+            assert_eq!(canonical_host(host), "[2001:db8::1]", "{}", host);
+        }
+        assert_eq!(canonical_host("[2001:db8:0::1]:8443"), "[2001:db8::1]:8443");
+        assert_eq!(canonical_host("[::ffff:192.0.2.1]"), "[::ffff:192.0.2.1]");
+
+        // Anything else is left as it is
+        for host in [
+            "192.0.2.1",
+            "192.0.2.1:8443",
+            "[not-an-address]",
+            "[2001:db8::1",
+        ] {
+            assert_eq!(canonical_host(host), host);
+        }
+    }
+
+    #[test]
+    fn verifies_ipv6_hosts_in_any_representation() {
+        let key = SigningKey::from_bytes(&[6; 32]);
+        let headers =
+            create_signed_headers("amy.carroted.org", "GET", "2001:db8::1", PATH, b"", &key)
+                .unwrap();
+
+        for host in ["[2001:db8::1]", "[2001:DB8:0:0::1]", "2001:0db8::0001"] {
+            verify_request_with_key(
+                "GET",
+                host,
+                PATH,
+                &RequestDigest::of(b""),
+                &headers,
+                &key.verifying_key(),
+                &VerifyOptions::new(Duration::from_secs(300)).with_expected_host("[2001:db8:0::1]"),
+            )
+            .unwrap_or_else(|e| panic!("{}: {}", host, e));
+        }
+
+        assert!(matches!(
+            verify_request_with_key(
+                "GET",
+                "[2001:db8::2]",
+                PATH,
+                &RequestDigest::of(b""),
+                &headers,
+                &key.verifying_key(),
+                &VerifyOptions::new(Duration::from_secs(300)),
+            ),
+            Err(WebIdentityError::Signature(
+                SignatureError::SignatureMismatch
+            ))
+        ));
+        assert!(matches!(
+            VerifyOptions::new(Duration::from_secs(300))
+                .with_expected_host("[2001:db8::1]")
+                .check_target("[2001:db8::1]:8443", PATH),
+            Err(SignatureError::RequestMismatch(field)) if field == "host"
+        ));
+    }
 }
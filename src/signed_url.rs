@@ -0,0 +1,340 @@
+use super::error::{SignatureError, WebIdentityError};
+use super::identity::{location_from_url, Identity};
+use super::resolve::{resolve_location_url, IdentityResolver};
+use super::sign::{verify_identity_signature, verify_signature, RequestSigner, VerifyOptions};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::{form_urlencoded, Url};
+
+/// The query parameters added by [`sign_url`], the signature last.
+const LOCATION_PARAM: &str = "wi_loc";
+const TIMESTAMP_PARAM: &str = "wi_ts";
+const EXPIRES_PARAM: &str = "wi_exp";
+const SIGNATURE_PARAM: &str = "wi_sig";
+
+/// Signs a URL as the identity at `location`, so it can be shared as a link that carries its
+/// own authentication (e.g. a download link), valid for `expiry`.
+///
+/// This is [`create_signed_url`] for a `GET` link expiring `expiry` from now.
+///
+/// # Errors
+/// Returns `Err` if the URL already has `wi_*` parameters or the signer fails.
+pub fn sign_url(
+    url: &Url,
+    location: &str,
+    expiry: Duration,
+    signer: &impl RequestSigner,
+) -> Result<Url, WebIdentityError> {
+    let expires_at = now().saturating_add(expiry.as_secs());
+    create_signed_url(location, "GET", url, expires_at, signer)
+}
+
+/// Signs a URL for `http_method` as the identity at `location`, for links opened where headers
+/// can't be set, like email verification links. The link is valid until `expires_at` (seconds
+/// since the UNIX epoch).
+///
+/// The `wi_loc`, `wi_ts` (seconds since the UNIX epoch) and `wi_exp` parameters are added to
+/// the query, then `wi_sig`: the hex-encoded signature over
+/// `WebIdentity-URL\n<METHOD>\n<origin><path>\n<query>`, where the query is every other
+/// parameter sorted by name and value, so `wi_sig` is never signed and reordering the
+/// parameters doesn't break the signature. The fragment isn't signed.
+///
+/// # Errors
+/// Returns `Err` if the URL already has `wi_*` parameters or the signer fails.
+pub fn create_signed_url(
+    location: &str,
+    http_method: &str,
+    base_url: &Url,
+    expires_at: u64,
+    signer: &impl RequestSigner,
+) -> Result<Url, WebIdentityError> {
+    if base_url
+        .query_pairs()
+        .any(|(name, _)| is_signed_url_param(&name))
+    {
+        return Err(SignatureError::InvalidSignedUrl("The URL is already signed.".into()).into());
+    }
+
+    let mut signed = base_url.clone();
+    signed
+        .query_pairs_mut()
+        .append_pair(LOCATION_PARAM, location)
+        .append_pair(TIMESTAMP_PARAM, &now().to_string())
+        .append_pair(EXPIRES_PARAM, &expires_at.to_string());
+
+    let signature = signer.sign_message(&signing_message(http_method, &signed))?;
+    signed
+        .query_pairs_mut()
+        .append_pair(SIGNATURE_PARAM, &hex::encode(signature));
+    Ok(signed)
+}
+
+/// The parameters of a URL that passed [`verify_signed_url`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifiedUrl {
+    pub location: String,
+    /// Seconds since the UNIX epoch
+    pub timestamp: u64,
+    /// Seconds since the UNIX epoch
+    pub expires_at: u64,
+}
+
+/// Verifies a URL signed with [`create_signed_url`] for `http_method` against a public key,
+/// at `now` (seconds since the UNIX epoch).
+///
+/// # Errors
+/// Returns `Err` if the `wi_*` parameters are missing or malformed, the link expired or was
+/// signed for another method, or the signature is incorrect.
+pub fn verify_signed_url(
+    received: &Url,
+    http_method: &str,
+    public_key: &[u8],
+    now: u64,
+) -> Result<VerifiedUrl, WebIdentityError> {
+    let params = SignedUrlParams::parse(received)?;
+    if now > params.expires_at {
+        return Err(SignatureError::UrlExpired.into());
+    }
+
+    verify_signature(
+        public_key,
+        &signing_message(http_method, received),
+        &params.signature_bytes()?,
+    )?;
+
+    Ok(VerifiedUrl {
+        location: params.location,
+        timestamp: params.timestamp,
+        expires_at: params.expires_at,
+    })
+}
+
+/// Verifies a `GET` link signed with [`sign_url`], resolving the identity that signed it with
+/// `resolver`.
+///
+/// Only the expected host and path of `options` and its clock are used: the link is accepted
+/// until `wi_exp`, whatever its age.
+///
+/// # Errors
+/// Returns `Err` if the `wi_*` parameters are missing or malformed, the link expired or was
+/// signed for another host or path, its identity can't be resolved, or the signature is
+/// incorrect.
+pub fn verify_url(
+    url: &Url,
+    resolver: &impl IdentityResolver,
+    options: &VerifyOptions,
+) -> Result<Arc<Identity>, WebIdentityError> {
+    let params = SignedUrlParams::parse(url)?;
+    let identity = resolver.resolve_identity(&params.location)?;
+    verify_url_for_identity(url, &identity, options)?;
+    Ok(identity)
+}
+
+/// Verifies a `GET` link signed with [`sign_url`] against an identity that was already
+/// resolved.
+///
+/// The link may be signed by any key listed on the identity. It has a single signer, so it
+/// can't authenticate an identity whose threshold is above one.
+///
+/// # Errors
+/// Returns `Err` if the `wi_*` parameters are missing or malformed, the link expired or was
+/// signed for another host or path or by another identity, the signature is incorrect, or the
+/// identity requires several signers.
+pub fn verify_url_for_identity(
+    url: &Url,
+    identity: &Identity,
+    options: &VerifyOptions,
+) -> Result<(), WebIdentityError> {
+    let params = SignedUrlParams::parse(url)?;
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or(""), port),
+        None => url.host_str().unwrap_or("").to_string(),
+    };
+    options.check_target(&host, url.path())?;
+
+    let location = location_from_url(&resolve_location_url(&params.location)?);
+    if !options.location_matches(&location, &identity.location) {
+        return Err(SignatureError::SignatureMismatch.into());
+    }
+
+    if options.now() > params.expires_at {
+        return Err(SignatureError::UrlExpired.into());
+    }
+    verify_identity_signature(
+        identity,
+        &signing_message("GET", url),
+        &params.signature_bytes()?,
+    )
+}
+
+/// The `wi_*` parameters of a signed URL, each required exactly once.
+struct SignedUrlParams {
+    location: String,
+    timestamp: u64,
+    expires_at: u64,
+    signature: String,
+}
+
+impl SignedUrlParams {
+    fn parse(url: &Url) -> Result<Self, SignatureError> {
+        let param = |name: &str| {
+            let mut values = url.query_pairs().filter(|(key, _)| key == name);
+            match (values.next(), values.next()) {
+                (Some((_, value)), None) => Ok(value.into_owned()),
+                (None, _) => Err(SignatureError::InvalidSignedUrl(format!(
+                    "Missing '{}' parameter.",
+                    name
+                ))),
+                (Some(_), Some(_)) => Err(SignatureError::InvalidSignedUrl(format!(
+                    "The '{}' parameter is repeated.",
+                    name
+                ))),
+            }
+        };
+
+        let location = param(LOCATION_PARAM)?;
+        let invalid_time =
+            |name: &str| SignatureError::InvalidSignedUrl(format!("Invalid '{}'.", name));
+        let timestamp = param(TIMESTAMP_PARAM)?
+            .parse::<u64>()
+            .map_err(|_| invalid_time(TIMESTAMP_PARAM))?;
+        let expires_at = param(EXPIRES_PARAM)?
+            .parse::<u64>()
+            .map_err(|_| invalid_time(EXPIRES_PARAM))?;
+        let signature = param(SIGNATURE_PARAM)?;
+
+        Ok(SignedUrlParams {
+            location,
+            timestamp,
+            expires_at,
+            signature,
+        })
+    }
+
+    fn signature_bytes(&self) -> Result<Vec<u8>, SignatureError> {
+        hex::decode(&self.signature)
+            .map_err(|_| SignatureError::InvalidSignedUrl("Invalid signature encoding.".into()))
+    }
+}
+
+fn is_signed_url_param(name: &str) -> bool {
+    [
+        LOCATION_PARAM,
+        TIMESTAMP_PARAM,
+        EXPIRES_PARAM,
+        SIGNATURE_PARAM,
+    ]
+    .contains(&name)
+}
+
+/// The message signed for a URL: the method, its origin and path, and its query without the
+/// signature, sorted so reordering the parameters doesn't break the signature.
+fn signing_message(http_method: &str, url: &Url) -> Vec<u8> {
+    let mut pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(name, _)| name != SIGNATURE_PARAM)
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+    pairs.sort();
+    let query = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(pairs)
+        .finish();
+
+    format!(
+        "WebIdentity-URL\n{}\n{}{}\n{}",
+        http_method.to_uppercase(),
+        url.origin().ascii_serialization(),
+        url.path(),
+        query
+    )
+    .into_bytes()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{SharedTestIdentity, TestIdentity};
+
+    #[test]
+    fn www_equivalence_applies_to_signed_location() {
+        let identity = TestIdentity::generate("example.com/me");
+        let url = Url::parse("https://files.example.net/report.pdf").unwrap();
+        let signed = sign_url(
+            &url,
+            "www.example.com/me",
+            Duration::from_secs(60),
+            &identity.signing_key,
+        )
+        .unwrap();
+
+        let options = VerifyOptions::new(Duration::from_secs(300));
+        assert!(verify_url_for_identity(&signed, &identity.identity, &options).is_err());
+        let options = options.with_www_equivalence();
+        verify_url_for_identity(&signed, &identity.identity, &options).unwrap();
+
+        let other = sign_url(
+            &url,
+            "blog.example.com/me",
+            Duration::from_secs(60),
+            &identity.signing_key,
+        )
+        .unwrap();
+        assert!(verify_url_for_identity(&other, &identity.identity, &options).is_err());
+    }
+
+    #[test]
+    fn one_signer_does_not_meet_a_threshold() {
+        let url = Url::parse("https://files.example.net/report.pdf").unwrap();
+        let options = VerifyOptions::new(Duration::from_secs(300));
+        let team = SharedTestIdentity::generate("team.example.com", 2, 2);
+        let signed = sign_url(
+            &url,
+            &team.location,
+            Duration::from_secs(60),
+            &team.signing_keys[0],
+        )
+        .unwrap();
+        assert!(matches!(
+            verify_url_for_identity(&signed, &team.identity, &options),
+            Err(WebIdentityError::Signature(
+                SignatureError::ThresholdNotMet {
+                    valid: 1,
+                    required: 2
+                }
+            ))
+        ));
+
+        let pair = SharedTestIdentity::generate("pair.example.com", 2, 1);
+        let signed = sign_url(
+            &url,
+            &pair.location,
+            Duration::from_secs(60),
+            &pair.signing_keys[1],
+        )
+        .unwrap();
+        verify_url_for_identity(&signed, &pair.identity, &options).unwrap();
+    }
+
+    #[test]
+    fn long_expiries_saturate() {
+        let identity = TestIdentity::generate("example.com/me");
+        let url = Url::parse("https://files.example.net/report.pdf").unwrap();
+        let signed =
+            sign_url(&url, "example.com/me", Duration::MAX, &identity.signing_key).unwrap();
+
+        let expires = signed
+            .query_pairs()
+            .find(|(name, _)| name == EXPIRES_PARAM)
+            .map(|(_, value)| value.into_owned());
+        assert_eq!(expires, Some(u64::MAX.to_string()));
+        let options = VerifyOptions::new(Duration::from_secs(300));
+        verify_url_for_identity(&signed, &identity.identity, &options).unwrap();
+    }
+}
@@ -0,0 +1,308 @@
+//! [`Arbitrary`] implementations and generators for property testing code that uses
+//! WebIdentity.
+//!
+//! [`SignedRequestCase`] always verifies, and [`TamperedRequestCase`] never does, so both can
+//! be fed to the code under test without writing custom generators. [`SignedHeaders`] is just
+//! the headers of a signed request.
+
+use super::digest::RequestDigest;
+use super::identity::{identity_id, Identity, IdentitySource};
+use super::public_key::PublicKey;
+use super::sign::{create_signed_headers, HeaderProvider, SimpleHeaderProvider, VerifyOptions};
+use arbitrary::{Arbitrary, Result, Unstructured};
+use ed25519_dalek::SigningKey;
+use std::collections::HashMap;
+use std::time::Duration;
+use url::Url;
+
+const METHODS: &[&str] = &["GET", "POST", "PUT", "PATCH", "DELETE"];
+
+impl<'a> Arbitrary<'a> for Identity {
+    /// An identity with a single valid key, whose id and location are consistent with its key
+    /// and URL.
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let signing_key = SigningKey::from_bytes(&u.arbitrary()?);
+        let public_key = PublicKey::from(signing_key.verifying_key());
+        let location = location(u)?;
+        let location_url = Url::parse(&format!("https://{}", location)).unwrap();
+
+        Ok(Identity {
+            id: identity_id(&public_key),
+            public_key,
+            public_keys: vec![public_key],
+            threshold: None,
+            display_name: u.arbitrary()?,
+            avatar: if u.arbitrary()? {
+                Some(location_url.join("/avatar.png").unwrap())
+            } else {
+                None
+            },
+            description: u.arbitrary()?,
+            descriptions: HashMap::new(),
+            location_url,
+            location,
+            backup_locations: Vec::new(),
+            spec_version: None,
+            verified_links: Vec::new(),
+            source: IdentitySource::Page,
+            fetched_at: None,
+            certificate_fingerprint: None,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for VerifyOptions {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let mut options = VerifyOptions::new(Duration::from_secs(u.int_in_range(1..=3600)?));
+        if u.arbitrary()? {
+            options = options.with_max_skew(Duration::from_secs(u.int_in_range(0..=600)?));
+        }
+        Ok(options.with_uncertainty(Duration::from_secs(u.int_in_range(0..=60)?)))
+    }
+}
+
+/// A request signed by a key, that verifies against it by construction.
+#[derive(Debug, Clone)]
+pub struct SignedRequestCase {
+    pub http_method: String,
+    pub host: String,
+    pub path: String,
+    pub body: Vec<u8>,
+    pub location: String,
+    pub signing_key: SigningKey,
+    pub headers: SimpleHeaderProvider,
+}
+
+impl SignedRequestCase {
+    pub fn body_digest(&self) -> RequestDigest {
+        RequestDigest::of(&self.body)
+    }
+
+    /// Options accepting the request, which was signed just now.
+    pub fn verify_options(&self) -> VerifyOptions {
+        VerifyOptions::new(Duration::from_secs(300))
+    }
+}
+
+impl<'a> Arbitrary<'a> for SignedRequestCase {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        signed_request(u)
+    }
+}
+
+/// Generates a request that verifies.
+pub fn signed_request(u: &mut Unstructured<'_>) -> Result<SignedRequestCase> {
+    let http_method = u.choose(METHODS)?.to_string();
+    let host = location(u)?;
+    let path = path(u)?;
+    let body: Vec<u8> = u.arbitrary()?;
+    let location = location(u)?;
+    let signing_key = SigningKey::from_bytes(&u.arbitrary()?);
+
+    let headers = create_signed_headers(&location, &http_method, &host, &path, &body, &signing_key)
+        .expect("Signing with a SigningKey can't fail")
+        .into_iter()
+        .collect();
+
+    Ok(SignedRequestCase {
+        http_method,
+        host,
+        path,
+        body,
+        location,
+        signing_key,
+        headers,
+    })
+}
+
+/// The headers of a request signed by an arbitrary key, for code that only reads headers, such
+/// as rate limiting or logging. Use [`SignedRequestCase`] to also get the parts they verify
+/// against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedHeaders(pub SimpleHeaderProvider);
+
+impl<'a> Arbitrary<'a> for SignedHeaders {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(SignedHeaders(signed_request(u)?.headers))
+    }
+}
+
+impl HeaderProvider for SignedHeaders {
+    fn get_header(&self, name: &str) -> Option<&str> {
+        self.0.get_header(name)
+    }
+
+    fn header_names(&self) -> Option<Vec<&str>> {
+        self.0.header_names()
+    }
+}
+
+/// The single part of a request that was changed after signing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Arbitrary)]
+pub enum Tamper {
+    Method,
+    Host,
+    Path,
+    Body,
+    Location,
+    Timestamp,
+    Signature,
+}
+
+/// A signed request with one part changed after signing, that must fail verification.
+#[derive(Debug, Clone)]
+pub struct TamperedRequestCase {
+    pub request: SignedRequestCase,
+    pub tamper: Tamper,
+}
+
+impl<'a> Arbitrary<'a> for TamperedRequestCase {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        tampered_request(u)
+    }
+}
+
+/// Generates a request that must fail verification.
+pub fn tampered_request(u: &mut Unstructured<'_>) -> Result<TamperedRequestCase> {
+    let mut request = signed_request(u)?;
+    let tamper: Tamper = u.arbitrary()?;
+
+    match tamper {
+        Tamper::Method => {
+            let methods: Vec<_> = METHODS
+                .iter()
+                .filter(|m| **m != request.http_method)
+                .collect();
+            request.http_method = u.choose(&methods)?.to_string();
+        }
+        Tamper::Host => request.host.push('x'),
+        Tamper::Path => request.path.push('x'),
+        Tamper::Body => request.body.push(u.arbitrary()?),
+        Tamper::Location => {
+            request
+                .headers
+                .get_mut("WebIdentity-Location")
+                .unwrap()
+                .push('x');
+        }
+        Tamper::Timestamp => {
+            let timestamp = request.headers.get_mut("WebIdentity-Timestamp").unwrap();
+            *timestamp = (timestamp.parse::<u64>().unwrap() - 1).to_string();
+        }
+        Tamper::Signature => {
+            let signature = request.headers.get_mut("WebIdentity-Signature").unwrap();
+            let mut bytes = hex::decode(&*signature).unwrap();
+            let i = u.choose_index(bytes.len())?;
+            bytes[i] ^= 1 << u.int_in_range(0..=7)?;
+            *signature = hex::encode(bytes);
+        }
+    }
+
+    Ok(TamperedRequestCase { request, tamper })
+}
+
+/// A host with one to three lowercase labels.
+fn location(u: &mut Unstructured<'_>) -> Result<String> {
+    let labels = u.int_in_range(1..=3)?;
+    let mut host = Vec::with_capacity(labels + 1);
+    for _ in 0..labels {
+        host.push(label(u)?);
+    }
+    host.push(u.choose(&["com", "org", "net"])?.to_string());
+    Ok(host.join("."))
+}
+
+/// An absolute path with up to four segments.
+fn path(u: &mut Unstructured<'_>) -> Result<String> {
+    let segments = u.int_in_range(0..=4)?;
+    let mut path = String::new();
+    for _ in 0..segments {
+        path.push('/');
+        path.push_str(&label(u)?);
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    Ok(path)
+}
+
+fn label(u: &mut Unstructured<'_>) -> Result<String> {
+    const CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+    let len = u.int_in_range(1..=12)?;
+    (0..len)
+        .map(|_| u.choose(CHARS).map(|c| *c as char))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sign::verify_request_with_key;
+    use rand::RngCore;
+    use std::collections::HashSet;
+
+    /// Generates `count` values from random bytes.
+    fn generate<T: for<'a> Arbitrary<'a>>(count: usize) -> Vec<T> {
+        let mut bytes = vec![0u8; 4096];
+        (0..count)
+            .map(|_| {
+                rand::thread_rng().fill_bytes(&mut bytes);
+                T::arbitrary(&mut Unstructured::new(&bytes)).unwrap()
+            })
+            .collect()
+    }
+
+    fn verify(request: &SignedRequestCase) -> std::result::Result<(), crate::WebIdentityError> {
+        verify_request_with_key(
+            &request.http_method,
+            &request.host,
+            &request.path,
+            &request.body_digest(),
+            &request.headers,
+            &request.signing_key.verifying_key(),
+            &request.verify_options(),
+        )
+    }
+
+    #[test]
+    fn signed_requests_verify() {
+        for request in generate::<SignedRequestCase>(64) {
+            assert!(verify(&request).is_ok(), "{:?}", request);
+        }
+    }
+
+    #[test]
+    fn any_tampered_field_fails_verification() {
+        let mut tampered = HashSet::new();
+        for case in generate::<TamperedRequestCase>(128) {
+            assert!(verify(&case.request).is_err(), "{:?}", case);
+            tampered.insert(case.tamper);
+        }
+        assert_eq!(tampered.len(), 7, "{:?}", tampered);
+    }
+
+    #[test]
+    fn identities_are_consistent_with_their_key() {
+        for identity in generate::<Identity>(64) {
+            assert_eq!(identity.id, identity_id(&identity.public_key));
+            assert_eq!(identity.public_keys, vec![identity.public_key]);
+            assert_eq!(
+                identity.location_url.host_str(),
+                Some(identity.location.as_str())
+            );
+        }
+    }
+
+    #[test]
+    fn signed_headers_carry_a_signature() {
+        for headers in generate::<SignedHeaders>(64) {
+            for name in [
+                "WebIdentity-Location",
+                "WebIdentity-Timestamp",
+                "WebIdentity-Signature",
+            ] {
+                assert!(headers.get_header(name).is_some(), "{:?}", headers);
+            }
+        }
+    }
+}
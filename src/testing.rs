@@ -0,0 +1,1075 @@
+//! Fixtures for testing code that uses WebIdentity: deterministic identities, signed
+//! headers that can be tampered with, a resolver serving pages from memory, a scriptable
+//! fetcher, and a local HTTP server for identity pages.
+
+use super::digest::RequestDigest;
+use super::error::WebIdentityError;
+use super::fetch::{FetchedPage, IdentityFetcher};
+use super::identity::{get_identity, location_from_url, Identity, PK_PREFIX, SPEC_VERSION};
+use super::redirect::RedirectPolicy;
+use super::resolve::{resolve_location_url, IdentityResolver};
+use super::sign::signed_extensions;
+use super::sign::SimpleHeaderProvider;
+use super::sign::{add_cosignature, build_canonical_string, create_signed_headers};
+use ed25519_dalek::{Signer, SigningKey};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use url::Url;
+
+/// An identity with a known key, for tests.
+#[derive(Debug, Clone)]
+pub struct TestIdentity {
+    pub location: String,
+    pub signing_key: SigningKey,
+    /// The HTML of the identity page
+    pub page: String,
+    pub identity: Identity,
+}
+
+impl TestIdentity {
+    /// Creates the identity for `location`.
+    ///
+    /// The key is derived from the location, so the same location always gives the same
+    /// identity. It must never be used outside of tests.
+    pub fn generate(location: &str) -> TestIdentity {
+        let seed: [u8; 32] = Sha256::digest(format!("webidentity-test:{}", location)).into();
+        let signing_key = SigningKey::from_bytes(&seed);
+
+        let page = format!(
+            r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>{location}</title>
+    <meta name="identity:version" content="{version}">
+    <meta name="identity:public-key" content="{prefix}{key}">
+    <meta name="identity:display-name" content="Test identity">
+    <meta name="identity:description" content="An identity for tests.">
+</head>
+<body></body>
+</html>
+"#,
+            location = location,
+            version = SPEC_VERSION,
+            prefix = PK_PREFIX,
+            key = hex::encode(signing_key.verifying_key().as_bytes()),
+        );
+
+        let url = resolve_location_url(location).expect("Invalid test location");
+        let identity = get_identity(&url, &page).expect("Invalid test identity page");
+
+        TestIdentity {
+            location: location.to_string(),
+            signing_key,
+            page,
+            identity,
+        }
+    }
+
+    /// Signs a request, returning its headers.
+    pub fn signed_headers_for(
+        &self,
+        http_method: &str,
+        host: &str,
+        path: &str,
+        body: &[u8],
+    ) -> TestHeaders {
+        let headers = create_signed_headers(
+            &self.location,
+            http_method,
+            host,
+            path,
+            body,
+            &self.signing_key,
+        )
+        .expect("Signing with a SigningKey can't fail");
+
+        TestHeaders {
+            signing_key: self.signing_key.clone(),
+            http_method: http_method.to_string(),
+            host: host.to_string(),
+            path: path.to_string(),
+            body_digest: RequestDigest::of(body),
+            headers: headers.into_iter().collect(),
+        }
+    }
+}
+
+/// An identity shared by several members, each with their own key, that requires
+/// `threshold` of them to sign requests.
+#[derive(Debug, Clone)]
+pub struct SharedTestIdentity {
+    pub location: String,
+    /// The members' keys, in the order they are listed on the page
+    pub signing_keys: Vec<SigningKey>,
+    /// The HTML of the identity page
+    pub page: String,
+    pub identity: Identity,
+}
+
+impl SharedTestIdentity {
+    /// Creates the identity for `location` with `members` keys, derived from the location like
+    /// those of [`TestIdentity::generate`].
+    pub fn generate(location: &str, members: usize, threshold: u8) -> SharedTestIdentity {
+        let signing_keys: Vec<SigningKey> = (0..members)
+            .map(|member| {
+                let seed: [u8; 32] =
+                    Sha256::digest(format!("webidentity-test:{}#{}", location, member)).into();
+                SigningKey::from_bytes(&seed)
+            })
+            .collect();
+
+        let keys: String = signing_keys
+            .iter()
+            .map(|key| {
+                format!(
+                    "    <meta name=\"identity:public-key\" content=\"{}{}\">\n",
+                    PK_PREFIX,
+                    hex::encode(key.verifying_key().as_bytes())
+                )
+            })
+            .collect();
+        let page = format!(
+            r#"<!DOCTYPE html>
+<html>
+<head>
+    <title>{location}</title>
+    <meta name="identity:version" content="{version}">
+{keys}    <meta name="identity:threshold" content="{threshold}">
+    <meta name="identity:display-name" content="Shared test identity">
+</head>
+<body></body>
+</html>
+"#,
+            location = location,
+            version = SPEC_VERSION,
+            keys = keys,
+            threshold = threshold,
+        );
+
+        let url = resolve_location_url(location).expect("Invalid test location");
+        let identity = get_identity(&url, &page).expect("Invalid test identity page");
+
+        SharedTestIdentity {
+            location: location.to_string(),
+            signing_keys,
+            page,
+            identity,
+        }
+    }
+
+    /// Signs a request with the keys of `members`, returning its headers.
+    pub fn signed_headers_for(
+        &self,
+        members: &[usize],
+        http_method: &str,
+        host: &str,
+        path: &str,
+        body: &[u8],
+    ) -> SimpleHeaderProvider {
+        let (first, others) = members.split_first().expect("No member signs");
+        let mut headers = create_signed_headers(
+            &self.location,
+            http_method,
+            host,
+            path,
+            body,
+            &self.signing_keys[*first],
+        )
+        .expect("Signing with a SigningKey can't fail");
+        for member in others {
+            add_cosignature(
+                &mut headers,
+                http_method,
+                host,
+                path,
+                body,
+                &self.signing_keys[*member],
+            )
+            .expect("The headers were just signed");
+        }
+        headers
+    }
+}
+
+/// The signed headers of a test request, with helpers to break them.
+#[derive(Debug, Clone)]
+pub struct TestHeaders {
+    signing_key: SigningKey,
+    http_method: String,
+    host: String,
+    path: String,
+    body_digest: RequestDigest,
+    headers: SimpleHeaderProvider,
+}
+
+impl TestHeaders {
+    pub fn headers(&self) -> &SimpleHeaderProvider {
+        &self.headers
+    }
+
+    pub fn into_headers(self) -> SimpleHeaderProvider {
+        self.headers
+    }
+
+    /// Re-signs the request as if it was made a day ago, so only its timestamp is invalid.
+    pub fn with_expired_timestamp(self) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        self.with_timestamp(now - 24 * 60 * 60)
+    }
+
+    /// Re-signs the request with `timestamp` (seconds since the UNIX epoch).
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        let timestamp = timestamp.to_string();
+        let location = &self.headers["WebIdentity-Location"];
+        let extensions = signed_extensions(&self.headers).expect("No header is repeated");
+        let canonical_string = build_canonical_string(
+            &self.http_method,
+            &self.host,
+            &self.path,
+            self.body_digest.as_bytes(),
+            location,
+            &timestamp,
+            &extensions,
+        );
+        let signature = self.signing_key.sign(canonical_string.as_bytes());
+
+        self.headers
+            .insert("WebIdentity-Timestamp".into(), timestamp);
+        self.headers.insert(
+            "WebIdentity-Signature".into(),
+            hex::encode(signature.to_bytes()),
+        );
+        self
+    }
+
+    /// Flips a bit of the signature, so it no longer matches the request.
+    pub fn with_bad_signature(mut self) -> Self {
+        let signature = &self.headers["WebIdentity-Signature"];
+        let mut bytes = hex::decode(signature).unwrap();
+        bytes[0] ^= 1;
+        self.headers
+            .insert("WebIdentity-Signature".into(), hex::encode(bytes));
+        self
+    }
+
+    pub fn with_missing_header(mut self, name: &str) -> Self {
+        self.headers.remove(name);
+        self
+    }
+
+    /// Sets a header to `value`, without re-signing the request.
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name.to_string(), value.to_string());
+        self
+    }
+}
+
+/// Resolves identities from pages held in memory, counting how often each location is
+/// resolved. It also serves the pages as an [`IdentityFetcher`].
+#[derive(Debug, Default)]
+pub struct StaticFetcher {
+    /// The pages served for each location, in order, the last one is served from then on
+    pages: HashMap<String, Vec<String>>,
+    fetches: Mutex<HashMap<String, usize>>,
+}
+
+impl StaticFetcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serves `page` for `location`.
+    pub fn with_page(self, location: &str, page: impl Into<String>) -> Self {
+        self.with_pages(location, vec![page.into()])
+    }
+
+    /// Serves each of `pages` in turn for `location`, then keeps serving the last one, e.g.
+    /// to test what happens when an identity changes its key.
+    pub fn with_pages(mut self, location: &str, pages: Vec<String>) -> Self {
+        self.pages.insert(normalize(location), pages);
+        self
+    }
+
+    /// Serves the page of `identity`.
+    pub fn with_identity(self, identity: &TestIdentity) -> Self {
+        self.with_page(&identity.location, identity.page.clone())
+    }
+
+    /// Serves the page of a shared `identity`.
+    pub fn with_shared_identity(self, identity: &SharedTestIdentity) -> Self {
+        self.with_page(&identity.location, identity.page.clone())
+    }
+
+    /// How many times `location` was resolved, including failed attempts.
+    pub fn fetch_count(&self, location: &str) -> usize {
+        let fetches = self.fetches.lock().unwrap();
+        fetches.get(&normalize(location)).copied().unwrap_or(0)
+    }
+}
+
+impl StaticFetcher {
+    /// Serves the next page for `url`, counting the fetch.
+    fn page(&self, url: &Url) -> Option<&str> {
+        let key = location_from_url(url);
+
+        let fetch = {
+            let mut fetches = self.fetches.lock().unwrap();
+            let count = fetches.entry(key.clone()).or_default();
+            *count += 1;
+            *count - 1
+        };
+
+        self.pages
+            .get(&key)
+            .and_then(|pages| pages.get(fetch).or(pages.last()))
+            .map(String::as_str)
+    }
+}
+
+impl IdentityResolver for StaticFetcher {
+    fn resolve_identity(&self, location: &str) -> Result<Arc<Identity>, WebIdentityError> {
+        let url = resolve_location_url(location)?;
+        let page = self
+            .page(&url)
+            .ok_or_else(|| WebIdentityError::UnsupportedLocation(location.to_string()))?;
+        Ok(Arc::new(Identity {
+            fetched_at: Some(SystemTime::now()),
+            ..get_identity(&url, page)?
+        }))
+    }
+}
+
+/// Serves the pages as HTML, so code written against an [`IdentityFetcher`] can be tested
+/// without a network.
+impl IdentityFetcher for StaticFetcher {
+    async fn fetch(&self, url: &Url) -> Result<FetchedPage, WebIdentityError> {
+        Ok(FetchedPage {
+            content_type: Some("text/html; charset=utf-8".into()),
+            peer_certificate: None,
+            body: self
+                .page(url)
+                .ok_or_else(|| WebIdentityError::UnsupportedLocation(url.to_string()))?
+                .as_bytes()
+                .to_vec(),
+        })
+    }
+}
+
+/// A response scripted for a [`MockFetcher`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MockResponse {
+    /// A `200 OK` HTML page
+    Page(String),
+    /// A redirect to a location, resolved against the URL being fetched and followed as the
+    /// fetcher's [`RedirectPolicy`] allows
+    Redirect(String),
+    /// An error status, failing with [`WebIdentityError::HttpStatus`]
+    Status(u16),
+    /// A `304 Not Modified` with the validators a conditional request would have matched.
+    /// Fetchers don't send conditional requests, so like any unexpected status it fails with
+    /// [`WebIdentityError::HttpStatus`]
+    NotModified { etag: String },
+    /// No response, failing with [`WebIdentityError::Fetch`] after the duration, like a
+    /// client timeout
+    Timeout(Duration),
+    /// The response, after the duration
+    Delayed(Duration, Box<MockResponse>),
+}
+
+impl MockResponse {
+    /// The page of `identity`.
+    pub fn identity(identity: &TestIdentity) -> Self {
+        MockResponse::Page(identity.page.clone())
+    }
+
+    pub fn after(self, delay: Duration) -> Self {
+        MockResponse::Delayed(delay, Box::new(self))
+    }
+}
+
+/// An [`IdentityFetcher`] replaying scripted responses, so resolvers, caches and framework
+/// integrations can be tested without a server.
+///
+/// Each location answers its responses in turn, then keeps answering the last one, e.g. a
+/// page and then a page with another key. Locations without responses answer `404 Not Found`.
+/// Fetches are counted for each URL requested, including the ones redirected to.
+///
+/// Delays complete on a thread of their own, so it works on any executor or behind a
+/// [`FetcherResolver`](crate::FetcherResolver) without one.
+#[derive(Debug, Default)]
+pub struct MockFetcher {
+    responses: HashMap<String, Vec<MockResponse>>,
+    redirects: RedirectPolicy,
+    fetches: Mutex<HashMap<String, usize>>,
+}
+
+impl MockFetcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answers `response` for `location`.
+    pub fn with_response(self, location: &str, response: MockResponse) -> Self {
+        self.with_responses(location, vec![response])
+    }
+
+    /// Answers each of `responses` in turn for `location`, then keeps answering the last one.
+    pub fn with_responses(mut self, location: &str, responses: Vec<MockResponse>) -> Self {
+        self.responses.insert(normalize(location), responses);
+        self
+    }
+
+    /// Serves the page of `identity`.
+    pub fn with_identity(self, identity: &TestIdentity) -> Self {
+        self.with_response(&identity.location, MockResponse::identity(identity))
+    }
+
+    /// Follows redirects as `policy` allows, instead of [`RedirectPolicy::default`].
+    pub fn with_redirect_policy(mut self, policy: RedirectPolicy) -> Self {
+        self.redirects = policy;
+        self
+    }
+
+    /// How many times `location` was fetched.
+    pub fn fetch_count(&self, location: &str) -> usize {
+        let fetches = self.fetches.lock().unwrap();
+        fetches.get(&normalize(location)).copied().unwrap_or(0)
+    }
+
+    /// Panics unless `location` was fetched `expected` times.
+    #[track_caller]
+    pub fn assert_fetched(&self, location: &str, expected: usize) {
+        let count = self.fetch_count(location);
+        assert_eq!(
+            count, expected,
+            "{} was fetched {} times, expected {}",
+            location, count, expected
+        );
+    }
+
+    /// The next response for `url`, counting the fetch.
+    fn response(&self, url: &Url) -> MockResponse {
+        let key = location_from_url(url);
+        let fetch = {
+            let mut fetches = self.fetches.lock().unwrap();
+            let count = fetches.entry(key.clone()).or_default();
+            *count += 1;
+            *count - 1
+        };
+        self.responses
+            .get(&key)
+            .and_then(|responses| responses.get(fetch).or(responses.last()))
+            .cloned()
+            .unwrap_or(MockResponse::Status(404))
+    }
+}
+
+impl IdentityFetcher for MockFetcher {
+    async fn fetch(&self, url: &Url) -> Result<FetchedPage, WebIdentityError> {
+        let mut current = url.clone();
+        let mut redirects = 0;
+        loop {
+            let mut response = self.response(&current);
+            while let MockResponse::Delayed(delay, delayed) = response {
+                Sleep::new(delay).await;
+                response = *delayed;
+            }
+            match response {
+                MockResponse::Page(page) => {
+                    return Ok(FetchedPage {
+                        content_type: Some("text/html; charset=utf-8".into()),
+                        body: page.into_bytes(),
+                        peer_certificate: None,
+                    })
+                }
+                MockResponse::Redirect(location) => {
+                    let target = current.join(&location)?;
+                    self.redirects.check(url, &target, redirects)?;
+                    current = target;
+                    redirects += 1;
+                }
+                MockResponse::Status(status) => return Err(WebIdentityError::HttpStatus(status)),
+                MockResponse::NotModified { .. } => return Err(WebIdentityError::HttpStatus(304)),
+                MockResponse::Timeout(after) => {
+                    Sleep::new(after).await;
+                    return Err(WebIdentityError::Fetch("The request timed out.".into()));
+                }
+                MockResponse::Delayed(..) => unreachable!("Delays are waited for above"),
+            }
+        }
+    }
+}
+
+/// A future completing once a deadline passed, woken by a thread sleeping until then.
+struct Sleep {
+    deadline: Instant,
+    waker: Option<Arc<Mutex<Waker>>>,
+}
+
+impl Sleep {
+    fn new(delay: Duration) -> Self {
+        Sleep {
+            deadline: Instant::now() + delay,
+            waker: None,
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+        match &self.waker {
+            Some(waker) => waker.lock().unwrap().clone_from(cx.waker()),
+            None => {
+                let waker = Arc::new(Mutex::new(cx.waker().clone()));
+                let deadline = self.deadline;
+                let wake = Arc::clone(&waker);
+                thread::spawn(move || {
+                    thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                    wake.lock().unwrap().wake_by_ref();
+                });
+                self.waker = Some(waker);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+fn normalize(location: &str) -> String {
+    let url = resolve_location_url(location).expect("Invalid test location");
+    location_from_url(&url)
+}
+
+/// A response served by [`serve_identity`].
+#[derive(Debug, Clone)]
+pub struct ServedPage {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    /// How long to wait before responding
+    pub latency: Duration,
+}
+
+impl ServedPage {
+    /// A `200 OK` HTML page.
+    pub fn html(body: impl Into<String>) -> Self {
+        ServedPage {
+            status: 200,
+            headers: vec![("Content-Type".into(), "text/html; charset=utf-8".into())],
+            body: body.into(),
+            latency: Duration::ZERO,
+        }
+    }
+
+    /// A `302 Found` redirect to `location`.
+    pub fn redirect(location: impl Into<String>) -> Self {
+        ServedPage {
+            status: 302,
+            headers: vec![("Location".into(), location.into())],
+            body: String::new(),
+            latency: Duration::ZERO,
+        }
+    }
+
+    pub fn with_status(mut self, status: u16) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Adds a response header, e.g. `ETag` or `Cache-Control`.
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+}
+
+/// A request received by [`serve_identity`].
+#[derive(Debug, Clone)]
+pub struct ReceivedRequest {
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl ReceivedRequest {
+    /// The value of the header `name`, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// A running server from [`serve_identity`], stopped when dropped.
+#[derive(Debug)]
+pub struct ServerHandle {
+    base_url: String,
+    received: Arc<Mutex<Vec<ReceivedRequest>>>,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    /// The URL of the server, like `http://127.0.0.1:41234`, without a trailing slash.
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// The location of the page served at `path`, e.g. for signing requests.
+    pub fn location(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    /// The requests received so far, in order.
+    pub fn requests(&self) -> Vec<ReceivedRequest> {
+        self.received.lock().unwrap().clone()
+    }
+
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Serves `pages`, keyed by request path, over plain HTTP on an ephemeral local port.
+///
+/// Paths without a page get a `404 Not Found`. Each connection is answered and closed, which
+/// is all an identity fetch needs.
+///
+/// # Errors
+/// Returns `Err` if no local port can be bound.
+pub fn serve_identity(
+    pages: HashMap<String, ServedPage>,
+) -> Result<ServerHandle, WebIdentityError> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    listener.set_nonblocking(true)?;
+    let base_url = format!("http://{}", listener.local_addr()?);
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let stop = Arc::clone(&shutdown);
+    let log = Arc::clone(&received);
+    let thread = thread::spawn(move || {
+        let pages = Arc::new(pages);
+        while !stop.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    let pages = Arc::clone(&pages);
+                    let log = Arc::clone(&log);
+                    thread::spawn(move || {
+                        let _ = respond(stream, &pages, &log);
+                    });
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(5));
+                }
+                Err(_) => break,
+            }
+        }
+    });
+
+    Ok(ServerHandle {
+        base_url,
+        received,
+        shutdown,
+        thread: Some(thread),
+    })
+}
+
+fn respond(
+    mut stream: TcpStream,
+    pages: &HashMap<String, ServedPage>,
+    log: &Mutex<Vec<ReceivedRequest>>,
+) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut headers = Vec::new();
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+        line.clear();
+    }
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+    log.lock().unwrap().push(ReceivedRequest {
+        path: path.to_string(),
+        headers,
+    });
+    let not_found = ServedPage::html("Not found").with_status(404);
+    let page = pages.get(path).unwrap_or(&not_found);
+
+    thread::sleep(page.latency);
+    let mut response = format!(
+        "HTTP/1.1 {} Test\r\nContent-Length: {}\r\nConnection: close\r\n",
+        page.status,
+        page.body.len()
+    );
+    for (name, value) in &page.headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str("\r\n");
+    response.push_str(&page.body);
+    stream.write_all(response.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::SignatureError;
+    use crate::fetch::FetcherResolver;
+    use crate::sign::{verify_request_threshold, verify_request_with_key, VerifyOptions};
+    use std::io::Read;
+
+    const HOST: &str = "api.example.com";
+
+    fn verify(identity: &TestIdentity, headers: &TestHeaders) -> Result<(), WebIdentityError> {
+        verify_request_with_key(
+            "POST",
+            HOST,
+            "/notes",
+            &RequestDigest::of(b"hello"),
+            headers.headers(),
+            &identity.signing_key.verifying_key(),
+            &VerifyOptions::new(Duration::from_secs(300)),
+        )
+    }
+
+    #[test]
+    fn generates_deterministic_identities() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let again = TestIdentity::generate("alice.example.com");
+        let bob = TestIdentity::generate("bob.example.com");
+
+        assert_eq!(alice.signing_key.to_bytes(), again.signing_key.to_bytes());
+        assert_ne!(alice.signing_key.to_bytes(), bob.signing_key.to_bytes());
+        assert_eq!(
+            alice.identity.public_key.verifying_key(),
+            &alice.signing_key.verifying_key()
+        );
+        assert_eq!(alice.identity.spec_version, Some(SPEC_VERSION));
+    }
+
+    #[test]
+    fn signed_headers_verify_until_tampered_with() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let headers = || alice.signed_headers_for("POST", HOST, "/notes", b"hello");
+        verify(&alice, &headers()).unwrap();
+
+        assert!(matches!(
+            verify(&alice, &headers().with_expired_timestamp()),
+            Err(WebIdentityError::Signature(
+                SignatureError::TimestampExpired { .. }
+            ))
+        ));
+        assert!(matches!(
+            verify(&alice, &headers().with_bad_signature()),
+            Err(WebIdentityError::Signature(
+                SignatureError::SignatureMismatch
+            ))
+        ));
+        assert!(matches!(
+            verify(&alice, &headers().with_missing_header("WebIdentity-Timestamp")),
+            Err(WebIdentityError::Signature(SignatureError::MissingHeader(name)))
+                if name == "WebIdentity-Timestamp"
+        ));
+        assert!(verify(
+            &alice,
+            &headers().with_header("WebIdentity-Location", "mallory.example.com")
+        )
+        .is_err());
+
+        // Re-signing with a fresh timestamp is valid again
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        verify(
+            &alice,
+            &headers().with_expired_timestamp().with_timestamp(now),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn shared_identities_need_the_threshold() {
+        let team = SharedTestIdentity::generate("team.example.com", 3, 2);
+        assert_eq!(team.identity.public_keys.len(), 3);
+        assert_eq!(team.identity.threshold, Some(2));
+
+        let verify = |members: &[usize]| {
+            verify_request_threshold(
+                "POST",
+                HOST,
+                "/notes",
+                &RequestDigest::of(b"hello"),
+                &team.signed_headers_for(members, "POST", HOST, "/notes", b"hello"),
+                &team.identity,
+                &VerifyOptions::new(Duration::from_secs(300)),
+            )
+        };
+        verify(&[0, 2]).unwrap();
+        assert!(verify(&[1]).is_err());
+    }
+
+    #[test]
+    fn static_fetcher_serves_pages_in_turn() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let rotated = TestIdentity::generate("alice.example.com/rotated");
+        let fetcher = StaticFetcher::new()
+            .with_pages("alice.example.com", vec![alice.page.clone(), rotated.page]);
+
+        let keys: Vec<_> = (0..3)
+            .map(|_| {
+                fetcher
+                    .resolve_identity("https://alice.example.com/")
+                    .unwrap()
+                    .public_key
+            })
+            .collect();
+        assert_eq!(keys[0], alice.identity.public_key);
+        assert_eq!(keys[1], rotated.identity.public_key);
+        assert_eq!(keys[2], rotated.identity.public_key);
+        assert_eq!(fetcher.fetch_count("alice.example.com"), 3);
+
+        assert!(matches!(
+            fetcher.resolve_identity("bob.example.com"),
+            Err(WebIdentityError::UnsupportedLocation(_))
+        ));
+        assert_eq!(fetcher.fetch_count("bob.example.com"), 1);
+    }
+
+    #[test]
+    fn static_fetcher_is_an_identity_fetcher() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let resolver = FetcherResolver::new(StaticFetcher::new().with_identity(&alice));
+        let identity = resolver.resolve_identity("alice.example.com").unwrap();
+        assert_eq!(identity.public_key, alice.identity.public_key);
+        assert_eq!(resolver.fetcher().fetch_count("alice.example.com"), 1);
+    }
+
+    #[test]
+    fn serves_pages_over_http() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let server = serve_identity(HashMap::from([
+            (
+                "/".to_string(),
+                ServedPage::html(alice.page.clone()).with_header("ETag", "\"v1\""),
+            ),
+            ("/old".to_string(), ServedPage::redirect("/")),
+        ]))
+        .unwrap();
+
+        let get = |path: &str| {
+            let address = server.base_url().trim_start_matches("http://");
+            let mut stream = TcpStream::connect(address).unwrap();
+            write!(
+                stream,
+                "GET {} HTTP/1.1\r\nHost: {}\r\nX-Test: {}\r\n\r\n",
+                path, address, path
+            )
+            .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let page = get("/");
+        assert!(page.starts_with("HTTP/1.1 200"));
+        assert!(page.contains("ETag: \"v1\""));
+        assert!(page.ends_with(&alice.page));
+        assert!(get("/old").contains("Location: /\r\n"));
+        assert!(get("/missing").starts_with("HTTP/1.1 404"));
+
+        let requests = server.requests();
+        let paths: Vec<&str> = requests.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, ["/", "/old", "/missing"]);
+        assert_eq!(requests[1].header("x-test"), Some("/old"));
+        assert_eq!(server.location("/me"), format!("{}/me", server.base_url()));
+        server.shutdown();
+    }
+
+    #[test]
+    fn mock_fetcher_follows_redirect_chains() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let fetcher = MockFetcher::new()
+            .with_response("alice.example.com", MockResponse::Redirect("/old".into()))
+            .with_response(
+                "alice.example.com/old",
+                MockResponse::Redirect("https://alice.example.com/alice".into()),
+            )
+            .with_response("alice.example.com/alice", MockResponse::identity(&alice));
+        let resolver = FetcherResolver::new(fetcher);
+
+        let identity = resolver.resolve_identity("alice.example.com").unwrap();
+        assert_eq!(identity.id, alice.identity.id);
+        for location in [
+            "alice.example.com",
+            "alice.example.com/old",
+            "alice.example.com/alice",
+        ] {
+            resolver.fetcher().assert_fetched(location, 1);
+        }
+
+        let fetcher = MockFetcher::new()
+            .with_response(
+                "alice.example.com",
+                MockResponse::Redirect("https://mallory.example.net/".into()),
+            )
+            .with_identity(&TestIdentity::generate("mallory.example.net"));
+        assert!(matches!(
+            FetcherResolver::new(fetcher).resolve_identity("alice.example.com"),
+            Err(WebIdentityError::RedirectNotAllowed(target)) if target == "https://mallory.example.net/"
+        ));
+    }
+
+    #[test]
+    fn mock_fetcher_fails_like_a_server() {
+        let resolver = FetcherResolver::new(
+            MockFetcher::new()
+                .with_response(
+                    "cached.example.com",
+                    MockResponse::NotModified {
+                        etag: "\"v1\"".into(),
+                    },
+                )
+                .with_response("down.example.com", MockResponse::Status(503)),
+        );
+
+        for (location, status) in [
+            ("missing.example.com", 404),
+            ("cached.example.com", 304),
+            ("down.example.com", 503),
+        ] {
+            assert!(matches!(
+                resolver.resolve_identity(location),
+                Err(WebIdentityError::HttpStatus(s)) if s == status
+            ));
+        }
+    }
+
+    #[test]
+    fn mock_fetcher_delays_responses() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let resolver = FetcherResolver::new(
+            MockFetcher::new()
+                .with_response(
+                    "alice.example.com",
+                    MockResponse::identity(&alice).after(Duration::from_millis(50)),
+                )
+                .with_response(
+                    "slow.example.com",
+                    MockResponse::Timeout(Duration::from_millis(50)),
+                ),
+        );
+
+        let started = Instant::now();
+        assert_eq!(
+            resolver.resolve_identity("alice.example.com").unwrap().id,
+            alice.identity.id
+        );
+        assert!(matches!(
+            resolver.resolve_identity("slow.example.com"),
+            Err(WebIdentityError::Fetch(_))
+        ));
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn mock_fetcher_times_out_on_tokio() {
+        let fetcher = MockFetcher::new().with_response(
+            "slow.example.com",
+            MockResponse::Timeout(Duration::from_secs(60)),
+        );
+        let fetch = crate::fetch::fetch_identity_with(&fetcher, "slow.example.com");
+        assert!(tokio::time::timeout(Duration::from_millis(50), fetch)
+            .await
+            .is_err());
+    }
+
+    /// The whole flow: a client signs a request with the key of an identity served over HTTP,
+    /// and the server fetches the identity to verify it.
+    #[cfg(feature = "reqwest")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn verifies_requests_from_identities_served_over_http() {
+        use crate::authenticate::Authenticator;
+        use crate::fetch::HttpFetcher;
+
+        let alice = TestIdentity::generate("alice.example.com");
+        let server = serve_identity(HashMap::from([(
+            "/alice".to_string(),
+            ServedPage::html(alice.page.clone()),
+        )]))
+        .unwrap();
+        let location = server.location("/alice");
+        let headers: SimpleHeaderProvider = create_signed_headers(
+            &location,
+            "POST",
+            HOST,
+            "/notes",
+            b"hello",
+            &alice.signing_key,
+        )
+        .unwrap()
+        .into_iter()
+        .collect();
+
+        let resolver =
+            FetcherResolver::with_runtime(HttpFetcher::new(), tokio::runtime::Handle::current());
+        let authenticator =
+            Authenticator::new(resolver, VerifyOptions::new(Duration::from_secs(300)));
+        let authenticated = tokio::task::spawn_blocking(move || {
+            // The body isn't the one that was signed
+            assert!(authenticator
+                .authenticate("POST", HOST, "/notes", b"hello!".to_vec(), &headers)
+                .is_err());
+            authenticator.authenticate("POST", HOST, "/notes", b"hello".to_vec(), &headers)
+        })
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(authenticated.identity.id, alice.identity.id);
+        assert_eq!(authenticated.identity.location_url.as_str(), location);
+        let requests = server.requests();
+        assert!(!requests.is_empty());
+        assert!(requests.iter().all(|request| request.path == "/alice"));
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How many keys a [`MemoryTimestampStore`] tracks by default.
+pub const DEFAULT_TIMESTAMP_STORE_CAPACITY: usize = 100_000;
+
+/// A timestamp that isn't newer than the last one accepted for the same key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StaleTimestamp {
+    /// Seconds since the UNIX epoch
+    pub last_accepted: u64,
+}
+
+/// Remembers the last timestamp accepted for each signing key, so requests can be required to
+/// have strictly increasing timestamps (see [`VerifyOptions::with_monotonic_timestamps`]).
+///
+/// [`VerifyOptions::with_monotonic_timestamps`]: crate::VerifyOptions::with_monotonic_timestamps
+pub trait TimestampStore: fmt::Debug + Send + Sync {
+    /// Accepts `timestamp` for `key_id` and records it if it is newer than the last one
+    /// accepted. Checking and recording must be atomic, so two concurrent requests with the
+    /// same timestamp can't both be accepted.
+    ///
+    /// # Errors
+    /// Returns the last accepted timestamp if `timestamp` isn't newer.
+    fn check_and_update(&self, key_id: &str, timestamp: u64) -> Result<(), StaleTimestamp>;
+}
+
+/// An in-memory [`TimestampStore`].
+///
+/// A timestamp only needs to be remembered while a request carrying it could still be
+/// accepted, so entries older than the TTL are forgotten. It tracks at most a fixed number of
+/// keys: once full, the keys with the oldest timestamps are forgotten first, and a request
+/// replayed for one of them within its TTL would be accepted again. The capacity should be
+/// above the number of keys that sign requests within a TTL.
+#[derive(Debug)]
+pub struct MemoryTimestampStore {
+    ttl: Duration,
+    capacity: usize,
+    last_accepted: Mutex<HashMap<String, u64>>,
+}
+
+impl MemoryTimestampStore {
+    /// Remembers timestamps for `ttl`, which should be the `max_age` of the verification
+    /// (plus its clock uncertainty).
+    pub fn new(ttl: Duration) -> Self {
+        MemoryTimestampStore {
+            ttl,
+            capacity: DEFAULT_TIMESTAMP_STORE_CAPACITY,
+            last_accepted: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sets how many keys are tracked ([`DEFAULT_TIMESTAMP_STORE_CAPACITY`] by default).
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    fn make_room(&self, last_accepted: &mut HashMap<String, u64>) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let ttl = self.ttl.as_secs();
+        last_accepted.retain(|_, timestamp| now.saturating_sub(*timestamp) <= ttl);
+        if last_accepted.len() < self.capacity {
+            return;
+        }
+
+        // Forget the oldest quarter at once, like MemoryRateLimiter
+        let mut timestamps: Vec<u64> = last_accepted.values().copied().collect();
+        let index = timestamps.len() / 4;
+        let (_, cutoff, _) = timestamps.select_nth_unstable(index);
+        let cutoff = *cutoff;
+        last_accepted.retain(|_, timestamp| *timestamp > cutoff);
+    }
+}
+
+impl TimestampStore for MemoryTimestampStore {
+    fn check_and_update(&self, key_id: &str, timestamp: u64) -> Result<(), StaleTimestamp> {
+        let mut last_accepted = self.last_accepted.lock().unwrap();
+        match last_accepted.get_mut(key_id) {
+            Some(last) if timestamp <= *last => Err(StaleTimestamp {
+                last_accepted: *last,
+            }),
+            Some(last) => {
+                *last = timestamp;
+                Ok(())
+            }
+            None => {
+                if last_accepted.len() >= self.capacity {
+                    self.make_room(&mut last_accepted);
+                }
+                last_accepted.insert(key_id.to_string(), timestamp);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn accepts_strictly_increasing_timestamps() {
+        let store = MemoryTimestampStore::new(Duration::from_secs(300));
+        let now = now();
+        store.check_and_update("alice", now).unwrap();
+        assert_eq!(
+            store.check_and_update("alice", now),
+            Err(StaleTimestamp { last_accepted: now })
+        );
+        assert_eq!(
+            store.check_and_update("alice", now - 1),
+            Err(StaleTimestamp { last_accepted: now })
+        );
+        store.check_and_update("bob", now).unwrap();
+        store.check_and_update("alice", now + 1).unwrap();
+    }
+
+    #[test]
+    fn accepts_one_of_concurrent_requests_with_the_same_timestamp() {
+        let store = MemoryTimestampStore::new(Duration::from_secs(300));
+        let now = now();
+
+        let accepted = thread::scope(|scope| {
+            let threads: Vec<_> = (0..16)
+                .map(|_| scope.spawn(|| store.check_and_update("alice", now).is_ok()))
+                .collect();
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .filter(|accepted| *accepted)
+                .count()
+        });
+        assert_eq!(accepted, 1);
+    }
+
+    #[test]
+    fn stays_bounded() {
+        let store = MemoryTimestampStore::new(Duration::from_secs(300)).with_capacity(100);
+        let now = now();
+        for i in 0..1_000 {
+            store.check_and_update(&format!("key-{}", i), now).unwrap();
+        }
+        assert!(store.last_accepted.lock().unwrap().len() <= 100);
+    }
+}
@@ -0,0 +1,293 @@
+use super::error::{SignatureError, WebIdentityError};
+use super::identity::{location_from_url, Identity};
+use super::resolve::{resolve_location_url, IdentityResolver};
+use super::sign::{verify_identity_signature, RequestSigner};
+use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The claims of a token issued with [`issue_token`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenClaims {
+    /// The location of the identity that issued the token
+    #[serde(rename = "loc")]
+    pub location: String,
+    /// The service the token is meant for, usually its host
+    #[serde(rename = "aud")]
+    pub audience: String,
+    /// Seconds since the UNIX epoch
+    #[serde(rename = "iat")]
+    pub issued_at: u64,
+    /// Seconds since the UNIX epoch after which the token is no longer accepted
+    #[serde(rename = "exp")]
+    pub expires_at: u64,
+}
+
+impl TokenClaims {
+    /// Decodes the claims of `token` without verifying its signature.
+    ///
+    /// # Errors
+    /// Returns `Err` if the token is malformed.
+    pub fn decode(token: &str) -> Result<TokenClaims, WebIdentityError> {
+        let (payload, _) = split_token(token)?;
+        decode_claims(payload)
+    }
+}
+
+/// Issues a short-lived bearer token for `audience`, signed by the identity at `location`.
+///
+/// This suits clients that can't sign every request, like browsers: the server verifies the
+/// token once with [`verify_token`] and can trust it until it expires. Anyone holding the token
+/// can use it, so `ttl` should be kept short.
+///
+/// The token is `<payload>.<signature>`, both base64url-encoded without padding. The payload is
+/// the JSON-encoded [`TokenClaims`], and the signature covers `WebIdentity-Token\n<payload>` so
+/// a token can never be mistaken for a signed request or challenge.
+pub fn issue_token(
+    location: &str,
+    audience: &str,
+    ttl: Duration,
+    signer: &impl RequestSigner,
+) -> Result<String, WebIdentityError> {
+    let issued_at = now();
+    let claims = TokenClaims {
+        location: location.to_string(),
+        audience: audience.to_string(),
+        issued_at,
+        expires_at: issued_at.saturating_add(ttl.as_secs()),
+    };
+    let json =
+        serde_json::to_vec(&claims).map_err(|e| SignatureError::InvalidToken(e.to_string()))?;
+
+    let payload = BASE64_URL_SAFE_NO_PAD.encode(json);
+    let signature = signer.sign_message(&signing_message(&payload))?;
+    Ok(format!(
+        "{}.{}",
+        payload,
+        BASE64_URL_SAFE_NO_PAD.encode(signature)
+    ))
+}
+
+/// Verifies a token issued with [`issue_token`] for `audience`, resolving the identity that
+/// issued it with `resolver`.
+///
+/// # Errors
+/// Returns `Err` if the token is malformed, expired, meant for another audience, its identity
+/// can't be resolved, or the signature is incorrect.
+pub fn verify_token(
+    token: &str,
+    audience: &str,
+    resolver: &impl IdentityResolver,
+) -> Result<Arc<Identity>, WebIdentityError> {
+    let claims = TokenClaims::decode(token)?;
+    check_claims(&claims, audience)?;
+
+    let identity = resolver.resolve_identity(&claims.location)?;
+    verify_token_for_identity(token, audience, &identity)?;
+    Ok(identity)
+}
+
+/// Verifies a token issued with [`issue_token`] for `audience` against an identity that was
+/// already resolved, returning its claims.
+///
+/// The token may be signed by any key listed on the identity. It has a single signer, so it
+/// can't authenticate an identity whose threshold is above one.
+///
+/// # Errors
+/// Returns `Err` if the token is malformed, expired, meant for another audience, was issued by
+/// another identity, the signature is incorrect, or the identity requires several signers.
+pub fn verify_token_for_identity(
+    token: &str,
+    audience: &str,
+    identity: &Identity,
+) -> Result<TokenClaims, WebIdentityError> {
+    let (payload, signature) = split_token(token)?;
+    let claims = decode_claims(payload)?;
+    check_claims(&claims, audience)?;
+
+    let location = location_from_url(&resolve_location_url(&claims.location)?);
+    if location != identity.location {
+        return Err(SignatureError::SignatureMismatch.into());
+    }
+
+    let signature = BASE64_URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| SignatureError::InvalidToken("Invalid signature encoding.".into()))?;
+    verify_identity_signature(identity, &signing_message(payload), &signature)?;
+
+    Ok(claims)
+}
+
+fn split_token(token: &str) -> Result<(&str, &str), WebIdentityError> {
+    token
+        .trim()
+        .split_once('.')
+        .ok_or_else(|| SignatureError::InvalidToken("Missing signature.".into()).into())
+}
+
+fn decode_claims(payload: &str) -> Result<TokenClaims, WebIdentityError> {
+    let json = BASE64_URL_SAFE_NO_PAD
+        .decode(payload)
+        .map_err(|_| SignatureError::InvalidToken("Invalid payload encoding.".into()))?;
+    serde_json::from_slice(&json).map_err(|e| SignatureError::InvalidToken(e.to_string()).into())
+}
+
+fn check_claims(claims: &TokenClaims, audience: &str) -> Result<(), WebIdentityError> {
+    if claims.audience != audience {
+        return Err(SignatureError::AudienceMismatch.into());
+    }
+    if now() > claims.expires_at {
+        return Err(SignatureError::TokenExpired.into());
+    }
+    Ok(())
+}
+
+fn signing_message(payload: &str) -> Vec<u8> {
+    format!("WebIdentity-Token\n{}", payload).into_bytes()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{SharedTestIdentity, StaticFetcher, TestIdentity};
+
+    /// Signs `claims` as [`issue_token`] would, whatever their times.
+    fn token_for(claims: &TokenClaims, signer: &impl RequestSigner) -> String {
+        let payload = BASE64_URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).unwrap());
+        let signature = signer.sign_message(&signing_message(&payload)).unwrap();
+        format!("{}.{}", payload, BASE64_URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    #[test]
+    fn verifies_issued_tokens() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let token = issue_token(
+            "alice.example.com",
+            "api.example.com",
+            Duration::from_secs(60),
+            &alice.signing_key,
+        )
+        .unwrap();
+
+        let claims = TokenClaims::decode(&token).unwrap();
+        assert_eq!(claims.location, "alice.example.com");
+        assert_eq!(claims.expires_at, claims.issued_at + 60);
+        assert_eq!(
+            verify_token_for_identity(&token, "api.example.com", &alice.identity).unwrap(),
+            claims
+        );
+
+        let resolver = StaticFetcher::new().with_identity(&alice);
+        let identity = verify_token(&token, "api.example.com", &resolver).unwrap();
+        assert_eq!(identity.public_key, alice.identity.public_key);
+
+        // Long lifetimes saturate instead of overflowing
+        let token = issue_token(
+            "alice.example.com",
+            "api.example.com",
+            Duration::MAX,
+            &alice.signing_key,
+        )
+        .unwrap();
+        assert_eq!(TokenClaims::decode(&token).unwrap().expires_at, u64::MAX);
+    }
+
+    #[test]
+    fn rejects_invalid_tokens() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let bob = TestIdentity::generate("bob.example.com");
+        let claims = TokenClaims {
+            location: "alice.example.com".into(),
+            audience: "api.example.com".into(),
+            issued_at: now() - 120,
+            expires_at: now() - 60,
+        };
+        let verify = |token: &str| {
+            verify_token_for_identity(token, "api.example.com", &alice.identity).map(|_| ())
+        };
+
+        assert!(matches!(
+            verify(&token_for(&claims, &alice.signing_key)),
+            Err(WebIdentityError::Signature(SignatureError::TokenExpired))
+        ));
+
+        let claims = TokenClaims {
+            expires_at: now() + 60,
+            ..claims
+        };
+        let token = token_for(&claims, &alice.signing_key);
+        assert!(matches!(
+            verify_token_for_identity(&token, "other.example.com", &alice.identity),
+            Err(WebIdentityError::Signature(
+                SignatureError::AudienceMismatch
+            ))
+        ));
+        // Signed by another key, or checked against another identity
+        for (token, identity) in [
+            (token_for(&claims, &bob.signing_key), &alice.identity),
+            (token.clone(), &bob.identity),
+        ] {
+            assert!(matches!(
+                verify_token_for_identity(&token, "api.example.com", identity),
+                Err(WebIdentityError::Signature(
+                    SignatureError::SignatureMismatch
+                ))
+            ));
+        }
+
+        let (payload, _) = token.split_once('.').unwrap();
+        for malformed in [
+            payload.to_string(),
+            format!("{}.not base64", payload),
+            format!("e30.{}", token.split_once('.').unwrap().1),
+        ] {
+            assert!(
+                matches!(
+                    verify(&malformed),
+                    Err(WebIdentityError::Signature(SignatureError::InvalidToken(_)))
+                ),
+                "{}",
+                malformed
+            );
+        }
+    }
+
+    #[test]
+    fn one_signer_does_not_meet_a_threshold() {
+        let team = SharedTestIdentity::generate("team.example.com", 2, 2);
+        let token = issue_token(
+            &team.location,
+            "api.example.com",
+            Duration::from_secs(60),
+            &team.signing_keys[0],
+        )
+        .unwrap();
+        assert!(matches!(
+            verify_token_for_identity(&token, "api.example.com", &team.identity),
+            Err(WebIdentityError::Signature(
+                SignatureError::ThresholdNotMet {
+                    valid: 1,
+                    required: 2
+                }
+            ))
+        ));
+
+        let pair = SharedTestIdentity::generate("pair.example.com", 2, 1);
+        let token = issue_token(
+            &pair.location,
+            "api.example.com",
+            Duration::from_secs(60),
+            &pair.signing_keys[1],
+        )
+        .unwrap();
+        verify_token_for_identity(&token, "api.example.com", &pair.identity).unwrap();
+    }
+}
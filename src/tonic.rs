@@ -0,0 +1,155 @@
+use super::authenticate::Authenticator;
+use super::sign::HeaderProvider;
+use std::sync::Arc;
+use tonic::metadata::{KeyRef, MetadataMap};
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+/// The path gRPC calls are signed for, since interceptors can't see the method being called.
+pub const GRPC_SIGNED_PATH: &str = "/";
+
+/// Reads ASCII metadata, case-insensitively like any HTTP header. The `WebIdentity-*` headers
+/// are never binary, so `-bin` keys are not found.
+impl HeaderProvider for MetadataMap {
+    fn get_header(&self, name: &str) -> Option<&str> {
+        if name.to_ascii_lowercase().ends_with("-bin") {
+            return None;
+        }
+        self.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    fn has_duplicate_header(&self, name: &str) -> bool {
+        !name.to_ascii_lowercase().ends_with("-bin") && self.get_all(name).iter().nth(1).is_some()
+    }
+
+    /// Lists the ASCII keys, binary metadata can't be covered by a signature.
+    fn header_names(&self) -> Option<Vec<&str>> {
+        Some(
+            self.keys()
+                .filter_map(|key| match key {
+                    KeyRef::Ascii(key) => Some(key.as_str()),
+                    KeyRef::Binary(_) => None,
+                })
+                .collect(),
+        )
+    }
+}
+
+/// An interceptor that authenticates each call with `authenticator`, and attaches the
+/// [`AuthenticatedRequest`](crate::AuthenticatedRequest) to the request extensions for the
+/// service to read. Calls that aren't correctly signed are rejected as `UNAUTHENTICATED`.
+///
+/// Interceptors see neither the message nor the method being called, so clients sign calls
+/// with [`SignOptions::without_body_binding`](crate::SignOptions::without_body_binding), as a
+/// `POST` to `host` (the server's authority, or the one forwarded by the proxy with
+/// [`Authenticator::with_trusted_proxy`]) and [`GRPC_SIGNED_PATH`]. The authenticator must
+/// accept them with [`VerifyOptions::with_unbound_body`](crate::VerifyOptions::with_unbound_body).
+/// A signature isn't bound to one method, so a short maximum age and
+/// [monotonic timestamps](crate::VerifyOptions::with_monotonic_timestamps) limit replays.
+///
+/// Interceptors are synchronous, so the identity is resolved on the executor thread, see
+/// [`Authenticator::new`]. A resolver that fetches pages, like
+/// [`FetcherResolver::with_runtime`](crate::FetcherResolver::with_runtime), can't be used
+/// here: authenticate in the service with `tokio::task::spawn_blocking` instead.
+pub fn grpc_interceptor(
+    authenticator: Arc<Authenticator>,
+    host: impl Into<String>,
+) -> impl Interceptor + Clone {
+    let host = host.into();
+    move |mut request: Request<()>| {
+        let host = authenticator
+            .external_host(&host, request.metadata())
+            .map_err(|e| Status::unauthenticated(e.to_string()))?;
+        let authenticated = authenticator
+            .authenticate(
+                "POST",
+                &host,
+                GRPC_SIGNED_PATH,
+                Vec::new(),
+                request.metadata(),
+            )
+            .map_err(|e| Status::unauthenticated(e.to_string()))?;
+        request.extensions_mut().insert(authenticated);
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sign::{create_signed_headers_with_options, SignOptions, VerifyOptions};
+    use crate::testing::{StaticFetcher, TestIdentity};
+    use crate::AuthenticatedRequest;
+    use std::time::Duration;
+    use tonic::metadata::{MetadataKey, MetadataValue};
+    use tonic::Code;
+
+    fn signed_call(identity: &TestIdentity, host: &str) -> Request<()> {
+        let headers = create_signed_headers_with_options(
+            &identity.location,
+            "POST",
+            host,
+            GRPC_SIGNED_PATH,
+            b"",
+            &identity.signing_key,
+            &SignOptions::new().without_body_binding(),
+        )
+        .unwrap();
+        let mut request = Request::new(());
+        for (name, value) in headers {
+            request.metadata_mut().insert(
+                MetadataKey::from_bytes(name.as_bytes()).unwrap(),
+                MetadataValue::try_from(value.as_str()).unwrap(),
+            );
+        }
+        request
+    }
+
+    fn interceptor(alice: &TestIdentity) -> impl Interceptor + Clone {
+        let authenticator = Authenticator::new(
+            StaticFetcher::new().with_identity(alice),
+            VerifyOptions::new(Duration::from_secs(300)).with_unbound_body(),
+        );
+        grpc_interceptor(Arc::new(authenticator), "api.example.com")
+    }
+
+    #[test]
+    fn attaches_the_authenticated_identity() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let request = interceptor(&alice)
+            .call(signed_call(&alice, "api.example.com"))
+            .unwrap();
+        let authenticated = request.extensions().get::<AuthenticatedRequest>().unwrap();
+        assert_eq!(authenticated.identity.id, alice.identity.id);
+    }
+
+    #[test]
+    fn rejects_unsigned_calls_and_calls_for_another_host() {
+        let alice = TestIdentity::generate("alice.example.com");
+        let mut interceptor = interceptor(&alice);
+        for request in [Request::new(()), signed_call(&alice, "other.example.com")] {
+            let status = interceptor.call(request).unwrap_err();
+            assert_eq!(status.code(), Code::Unauthenticated);
+        }
+    }
+
+    #[test]
+    fn reads_ascii_metadata_only() {
+        let mut metadata = MetadataMap::new();
+        metadata.insert("webidentity-location", "alice.example.com".parse().unwrap());
+        metadata.append("x-trace", "a".parse().unwrap());
+        metadata.append("x-trace", "b".parse().unwrap());
+        metadata.insert_bin("x-blob-bin", MetadataValue::from_bytes(b"\x00\x01"));
+
+        assert_eq!(
+            metadata.get_header("WebIdentity-Location"),
+            Some("alice.example.com")
+        );
+        assert!(metadata.has_duplicate_header("X-Trace"));
+        assert!(!metadata.has_duplicate_header("webidentity-location"));
+        assert_eq!(metadata.get_header("x-blob-bin"), None);
+        let mut names = metadata.header_names().unwrap();
+        names.sort_unstable();
+        assert_eq!(names, ["webidentity-location", "x-trace"]);
+    }
+}
@@ -0,0 +1,192 @@
+use super::error::WebIdentityError;
+use super::identity::{location_from_url, parse_public_key, Identity};
+use super::public_key::PublicKey;
+use super::resolve::resolve_location_url;
+
+/// Lines longer than this many octets are folded (RFC 6350, section 3.2).
+const MAX_LINE_OCTETS: usize = 75;
+
+/// The location and key of an identity, read back from a vCard made by
+/// [`Identity::to_vcard`]. The key is unverified until the identity is resolved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VcardIdentityHint {
+    pub location: Option<String>,
+    pub public_key: PublicKey,
+}
+
+impl Identity {
+    /// Exports the identity as a vCard 4.0, for address books.
+    ///
+    /// The display name, avatar, description and location URL are mapped to `FN`, `PHOTO`,
+    /// `NOTE` and `URL`, and the primary key is kept in an `X-WEBIDENTITY-KEY` property so
+    /// [`identity_hint_from_vcard`] can find the identity again.
+    pub fn to_vcard(&self) -> String {
+        let mut vcard = String::new();
+        push_line(&mut vcard, "BEGIN:VCARD");
+        push_line(&mut vcard, "VERSION:4.0");
+        push_line(&mut vcard, &format!("FN:{}", escape(&self.display_name)));
+        if let Some(avatar) = &self.avatar {
+            push_line(&mut vcard, &format!("PHOTO:{}", avatar));
+        }
+        if let Some(description) = &self.description {
+            push_line(&mut vcard, &format!("NOTE:{}", escape(description)));
+        }
+        push_line(&mut vcard, &format!("URL:{}", self.location_url));
+        push_line(
+            &mut vcard,
+            &format!("X-WEBIDENTITY-KEY:{}", self.public_key.to_prefixed()),
+        );
+        push_line(&mut vcard, "END:VCARD");
+        vcard
+    }
+}
+
+/// Reads the location and key of an identity from a vCard made by [`Identity::to_vcard`].
+///
+/// The location is taken from the first `URL` property, if any.
+///
+/// # Errors
+/// Returns `Err` if the vCard has no valid `X-WEBIDENTITY-KEY` property.
+pub fn identity_hint_from_vcard(vcard: &str) -> Result<VcardIdentityHint, WebIdentityError> {
+    // Unfold continuation lines first
+    let unfolded = vcard
+        .replace("\r\n ", "")
+        .replace("\r\n\t", "")
+        .replace("\n ", "")
+        .replace("\n\t", "");
+
+    let mut location = None;
+    let mut public_key = None;
+    for line in unfolded.lines() {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        // Drop the group and parameters, e.g. `item1.URL;TYPE=home`
+        let name = name.split(';').next().unwrap_or(name);
+        let name = name.rsplit('.').next().unwrap_or(name);
+
+        if name.eq_ignore_ascii_case("X-WEBIDENTITY-KEY") && public_key.is_none() {
+            public_key = Some(parse_public_key(value.trim())?);
+        } else if name.eq_ignore_ascii_case("URL") && location.is_none() {
+            location = resolve_location_url(value.trim())
+                .ok()
+                .map(|url| location_from_url(&url));
+        }
+    }
+
+    Ok(VcardIdentityHint {
+        location,
+        public_key: public_key.ok_or(WebIdentityError::MissingPublicKey)?,
+    })
+}
+
+/// Escapes a text value (RFC 6350, section 3.4).
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ',' => escaped.push_str("\\,"),
+            ';' => escaped.push_str("\\;"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Appends a content line, folded so no line is longer than 75 octets without splitting a
+/// character.
+fn push_line(vcard: &mut String, line: &str) {
+    let mut octets = 0;
+    for c in line.chars() {
+        if octets + c.len_utf8() > MAX_LINE_OCTETS {
+            vcard.push_str("\r\n ");
+            // The leading space counts towards the next line
+            octets = 1;
+        }
+        vcard.push(c);
+        octets += c.len_utf8();
+    }
+    vcard.push_str("\r\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestIdentity;
+
+    #[test]
+    fn folds_lines_at_75_octets() {
+        let mut vcard = String::new();
+        push_line(&mut vcard, &format!("NOTE:{}", "a".repeat(200)));
+        let lines: Vec<&str> = vcard.trim_end_matches("\r\n").split("\r\n").collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|line| line.len() <= MAX_LINE_OCTETS));
+        assert_eq!(lines[0].len(), MAX_LINE_OCTETS);
+        assert!(lines[1..].iter().all(|line| line.starts_with(' ')));
+
+        // Multi-byte characters are moved to the next line whole
+        let line = format!("FN:a{}", "é".repeat(100));
+        let mut vcard = String::new();
+        push_line(&mut vcard, &line);
+        let lines: Vec<&str> = vcard.trim_end_matches("\r\n").split("\r\n").collect();
+        assert!(lines.iter().all(|line| line.len() <= MAX_LINE_OCTETS));
+        assert_eq!(lines[0].len(), MAX_LINE_OCTETS - 1);
+        assert_eq!(vcard.replace("\r\n ", ""), format!("{}\r\n", line));
+
+        let mut vcard = String::new();
+        push_line(&mut vcard, "VERSION:4.0");
+        assert_eq!(vcard, "VERSION:4.0\r\n");
+    }
+
+    #[test]
+    fn escapes_text_values() {
+        assert_eq!(escape(r"a,b;c\d"), r"a\,b\;c\\d");
+        assert_eq!(escape("one\r\ntwo\nthree"), r"one\ntwo\nthree");
+
+        let mut alice = TestIdentity::generate("alice.example").identity;
+        alice.display_name = "Smith, Alice; PhD".to_string();
+        alice.description = Some("Line one\nLine two".to_string());
+        let vcard = alice.to_vcard();
+        assert!(vcard.contains("\r\nFN:Smith\\, Alice\\; PhD\r\n"));
+        assert!(vcard.contains("\r\nNOTE:Line one\\nLine two\r\n"));
+    }
+
+    #[test]
+    fn reads_back_the_identity_from_its_vcard() {
+        let mut alice = TestIdentity::generate("alice.example").identity;
+        // Long enough that the key and the URL get folded
+        alice.description = Some("a".repeat(300));
+        let vcard = alice.to_vcard();
+        assert!(vcard.starts_with("BEGIN:VCARD\r\nVERSION:4.0\r\n"));
+        assert!(vcard.ends_with("END:VCARD\r\n"));
+        assert!(vcard.contains("\r\n "));
+
+        let hint = identity_hint_from_vcard(&vcard).unwrap();
+        assert_eq!(hint.location.as_deref(), Some("alice.example"));
+        assert_eq!(hint.public_key, alice.public_key);
+
+        // Groups, parameters and bare newlines from other address books
+        let vcard = format!(
+            "BEGIN:VCARD\nitem1.URL;TYPE=home:https://alice.example\nx-webidentity-key;PREF=1:{}\nEND:VCARD\n",
+            alice.public_key.to_prefixed()
+        );
+        let hint = identity_hint_from_vcard(&vcard).unwrap();
+        assert_eq!(hint.location.as_deref(), Some("alice.example"));
+        assert_eq!(hint.public_key, alice.public_key);
+    }
+
+    #[test]
+    fn rejects_vcards_without_a_valid_key() {
+        assert!(matches!(
+            identity_hint_from_vcard("BEGIN:VCARD\r\nFN:Alice\r\nEND:VCARD\r\n"),
+            Err(WebIdentityError::MissingPublicKey)
+        ));
+        assert!(identity_hint_from_vcard(
+            "BEGIN:VCARD\r\nX-WEBIDENTITY-KEY:ed25519-pub:zz\r\nEND:VCARD\r\n"
+        )
+        .is_err());
+    }
+}
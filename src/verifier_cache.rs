@@ -0,0 +1,189 @@
+use super::authorization::AuthorizationHeaders;
+use super::digest::RequestDigest;
+use super::error::WebIdentityError;
+use super::identity::{identity_id, Identity};
+use super::sign::{as_array, parse_verifying_key, HeaderProvider, SignedRequest, VerifyOptions};
+use ed25519_dalek::VerifyingKey;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, OnceLock};
+
+/// How many keys the cache from [`VerifierCache::global`] holds.
+pub const DEFAULT_VERIFIER_CACHE_CAPACITY: usize = 1024;
+
+#[derive(Debug, Default)]
+struct Entries {
+    keys: HashMap<[u8; 32], VerifyingKey>,
+    /// Insertion order, the oldest key is evicted first once the cache is full
+    order: VecDeque<[u8; 32]>,
+}
+
+/// A bounded cache of parsed public keys, so the same key isn't decompressed again for every
+/// request it signs.
+///
+/// [`verify_request_with_identity`] and [`CachingResolver`](crate::CachingResolver) use the
+/// shared [`VerifierCache::global`] cache. A separate cache can be created for use with the
+/// lower-level functions, like [`verify_request_with_key`](crate::verify_request_with_key).
+#[derive(Debug)]
+pub struct VerifierCache {
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+impl VerifierCache {
+    pub fn new(capacity: usize) -> Self {
+        VerifierCache {
+            capacity,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// The cache shared by the library, holding up to [`DEFAULT_VERIFIER_CACHE_CAPACITY`] keys.
+    pub fn global() -> &'static VerifierCache {
+        static GLOBAL: OnceLock<VerifierCache> = OnceLock::new();
+        GLOBAL.get_or_init(|| VerifierCache::new(DEFAULT_VERIFIER_CACHE_CAPACITY))
+    }
+
+    /// Returns the parsed key for `public_key`, parsing and caching it if needed.
+    ///
+    /// # Errors
+    /// Returns `Err` if `public_key` is not a valid Ed25519 key.
+    pub fn verifying_key(&self, public_key: &[u8]) -> Result<VerifyingKey, WebIdentityError> {
+        if let Some(bytes) = as_array::<u8, 32>(public_key) {
+            if let Some(key) = self.entries.lock().unwrap().keys.get(bytes) {
+                return Ok(*key);
+            }
+        }
+
+        let key = parse_verifying_key(public_key)?;
+        if self.capacity == 0 {
+            return Ok(key);
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.keys.insert(key.to_bytes(), key).is_none() {
+            entries.order.push_back(key.to_bytes());
+            while entries.order.len() > self.capacity {
+                if let Some(oldest) = entries.order.pop_front() {
+                    entries.keys.remove(&oldest);
+                }
+            }
+        }
+        Ok(key)
+    }
+
+    /// Removes a key, e.g. once it was rotated out or revoked.
+    pub fn evict(&self, public_key: &[u8]) {
+        if let Some(bytes) = as_array::<u8, 32>(public_key) {
+            let mut entries = self.entries.lock().unwrap();
+            if entries.keys.remove(bytes).is_some() {
+                entries.order.retain(|key| key != bytes);
+            }
+        }
+    }
+
+    /// Removes every key listed on `identity`.
+    pub fn evict_identity(&self, identity: &Identity) {
+        for key in &identity.public_keys {
+            self.evict(key);
+        }
+    }
+
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.keys.clear();
+        entries.order.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Verifies a signed request against an identity's keys, reusing the parsed keys from
+/// [`VerifierCache::global`].
+///
+/// Like [`SessionVerifier::verify`](crate::SessionVerifier::verify), a signature from any
+/// listed key is accepted, unless the identity has a [`threshold`](Identity::threshold) above
+/// one, in which case that many keys must have signed.
+///
+/// # Errors
+/// Returns `Err` if any header is missing, the timestamp is invalid or outside the
+/// allowed window, or the signature is incorrect or has too few signers.
+pub fn verify_request_with_identity(
+    http_method: &str,
+    host: &str,
+    path: &str,
+    body_digest: &RequestDigest,
+    headers: &impl HeaderProvider,
+    identity: &Identity,
+    options: &VerifyOptions,
+) -> Result<(), WebIdentityError> {
+    let keys = identity
+        .public_keys
+        .iter()
+        .map(|key| {
+            Ok((
+                identity_id(key),
+                VerifierCache::global().verifying_key(key)?,
+            ))
+        })
+        .collect::<Result<Vec<_>, WebIdentityError>>()?;
+    let headers = AuthorizationHeaders::new(headers)?;
+    let request = SignedRequest::parse(http_method, host, path, body_digest, &headers, options)?;
+    request.verify_for_keys(&keys, identity.threshold, options)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestIdentity;
+    use std::time::Duration;
+
+    #[test]
+    fn evicts_oldest_key_when_full() {
+        let cache = VerifierCache::new(2);
+        let keys: Vec<[u8; 32]> = ["a", "b", "c"]
+            .iter()
+            .map(|location| {
+                TestIdentity::generate(location)
+                    .signing_key
+                    .verifying_key()
+                    .to_bytes()
+            })
+            .collect();
+        for key in &keys {
+            cache.verifying_key(key).unwrap();
+        }
+        assert_eq!(cache.len(), 2);
+
+        cache.evict(&keys[2]);
+        assert_eq!(cache.len(), 1);
+        assert!(cache.verifying_key(&[0u8; 31]).is_err());
+    }
+
+    #[test]
+    fn verifies_requests_with_the_cached_keys() {
+        let identity = TestIdentity::generate("alice.example.com");
+        let headers = identity.signed_headers_for("GET", "api.example.com", "/", b"");
+        let verify = |path| {
+            verify_request_with_identity(
+                "GET",
+                "api.example.com",
+                path,
+                &RequestDigest::of(b""),
+                headers.headers(),
+                &identity.identity,
+                &VerifyOptions::new(Duration::from_secs(300)),
+            )
+        };
+
+        verify("/").unwrap();
+        // The key is cached now, a request that wasn't signed is still rejected
+        assert!(verify("/admin").is_err());
+    }
+}
@@ -0,0 +1,252 @@
+use super::error::WebIdentityError;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::future::Future;
+use url::Url;
+
+/// The WebFinger link relation pointing to a WebIdentity location.
+pub const WEBFINGER_LOCATION_REL: &str = "https://webidentity.dev/ns/location";
+
+/// The largest WebFinger response [`discover_webfinger`] accepts, in bytes.
+pub const MAX_JRD_SIZE: usize = 64 * 1024;
+
+/// Link relations used when a JRD has no [`WEBFINGER_LOCATION_REL`] link, in order.
+const FALLBACK_RELS: &[&str] = &["http://webfinger.net/rel/profile-page", "self"];
+
+#[derive(Deserialize)]
+struct Jrd {
+    #[serde(default)]
+    links: Vec<JrdLink>,
+}
+
+#[derive(Deserialize)]
+struct JrdLink {
+    rel: String,
+    href: Option<String>,
+}
+
+/// Finds the WebIdentity location of an account like `amy@carroted.org` with WebFinger.
+///
+/// `fetch` is called with the WebFinger URL of the account (always HTTPS) and returns the
+/// response body. The location is read from the link with [`WEBFINGER_LOCATION_REL`], falling
+/// back to the profile page and `self` links, and can then be given to a resolver.
+///
+/// # Errors
+/// Returns `Err` if the account is invalid, `fetch` fails, the response is larger than
+/// [`MAX_JRD_SIZE`] or isn't a JRD, or no usable HTTPS link is found.
+pub async fn discover_webfinger<F, Fut>(acct: &str, fetch: F) -> Result<String, WebIdentityError>
+where
+    F: FnOnce(Url) -> Fut,
+    Fut: Future<Output = Result<Vec<u8>, WebIdentityError>>,
+{
+    let jrd = fetch(webfinger_url(acct)?).await?;
+    location_from_jrd(&jrd)
+}
+
+/// Returns the WebFinger URL queried for an account, with or without the `acct:` prefix.
+///
+/// # Errors
+/// Returns `Err` if the account isn't `user@host`.
+pub fn webfinger_url(acct: &str) -> Result<Url, WebIdentityError> {
+    let (user, host) = parse_acct(acct)?;
+    let mut url = Url::parse(&format!("https://{}/.well-known/webfinger", host))
+        .map_err(|_| WebIdentityError::InvalidAccount(acct.to_string()))?;
+    url.query_pairs_mut()
+        .append_pair("resource", &format!("acct:{}@{}", user, host));
+    Ok(url)
+}
+
+/// Reads the WebIdentity location from a WebFinger response.
+///
+/// # Errors
+/// Returns `Err` if the response is larger than [`MAX_JRD_SIZE`] or isn't a JRD, or no usable
+/// HTTPS link is found.
+pub fn location_from_jrd(jrd: &[u8]) -> Result<String, WebIdentityError> {
+    if jrd.len() > MAX_JRD_SIZE {
+        return Err(WebIdentityError::BodyTooLarge(MAX_JRD_SIZE as u64));
+    }
+    let jrd: Jrd = serde_json::from_slice(jrd)
+        .map_err(|e| WebIdentityError::Parse(format!("Invalid WebFinger response: {}", e)))?;
+
+    let href = std::iter::once(WEBFINGER_LOCATION_REL)
+        .chain(FALLBACK_RELS.iter().copied())
+        .find_map(|rel| {
+            jrd.links
+                .iter()
+                .find(|link| link.rel == rel)
+                .and_then(|link| link.href.as_deref())
+        })
+        .ok_or(WebIdentityError::NoWebIdentityLink)?;
+
+    let url = Url::parse(href)?;
+    if url.scheme() != "https" {
+        return Err(WebIdentityError::UnsupportedProtocol(
+            url.scheme().to_string(),
+        ));
+    }
+    Ok(url.to_string())
+}
+
+/// Creates the JRD a site should serve at its WebFinger endpoint for `acct`, linking it to the
+/// identity at `location_url`.
+///
+/// # Errors
+/// Returns `Err` if the account isn't `user@host`.
+pub fn webfinger_jrd(acct: &str, location_url: &Url) -> Result<Value, WebIdentityError> {
+    let (user, host) = parse_acct(acct)?;
+    Ok(json!({
+        "subject": format!("acct:{}@{}", user, host),
+        "links": [
+            { "rel": WEBFINGER_LOCATION_REL, "href": location_url.as_str() },
+            {
+                "rel": "http://webfinger.net/rel/profile-page",
+                "type": "text/html",
+                "href": location_url.as_str(),
+            },
+        ],
+    }))
+}
+
+/// Splits `[acct:]user@host` into its user and lowercase host.
+fn parse_acct(acct: &str) -> Result<(&str, String), WebIdentityError> {
+    let invalid = || WebIdentityError::InvalidAccount(acct.to_string());
+
+    let acct = acct.trim();
+    let acct = acct.strip_prefix("acct:").unwrap_or(acct);
+    let (user, host) = acct.rsplit_once('@').ok_or_else(invalid)?;
+    let valid_user = !user.is_empty()
+        && !user
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || matches!(c, '/' | '?' | '#' | '@'));
+    if !valid_user {
+        return Err(invalid());
+    }
+
+    // The host must parse back to itself, which rules out paths, ports with junk and userinfo
+    let url = Url::parse(&format!("https://{}", host)).map_err(|_| invalid())?;
+    let authority = match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or(""), port),
+        None => url.host_str().unwrap_or("").to_string(),
+    };
+    if authority.is_empty() || !authority.eq_ignore_ascii_case(host) {
+        return Err(invalid());
+    }
+    Ok((user, authority))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_accounts() {
+        for acct in [
+            "amy@carroted.org",
+            "acct:amy@carroted.org",
+            " amy@Carroted.ORG ",
+        ] {
+            assert_eq!(
+                webfinger_url(acct).unwrap().as_str(),
+                "https://carroted.org/.well-known/webfinger?resource=acct%3Aamy%40carroted.org"
+            );
+        }
+        assert_eq!(
+            webfinger_url("amy@localhost:8443").unwrap().as_str(),
+            "https://localhost:8443/.well-known/webfinger?resource=acct%3Aamy%40localhost%3A8443"
+        );
+
+        for acct in [
+            "carroted.org",
+            "@carroted.org",
+            "amy@",
+            "a my@carroted.org",
+            "amy/x@carroted.org",
+            "amy@carroted.org/path",
+            "amy@bob@carroted.org",
+            "amy@carroted.org:port",
+        ] {
+            assert!(
+                matches!(
+                    webfinger_url(acct),
+                    Err(WebIdentityError::InvalidAccount(_))
+                ),
+                "{}",
+                acct
+            );
+        }
+    }
+
+    #[test]
+    fn reads_the_location_and_falls_back_to_the_profile_page() {
+        let location = Url::parse("https://amy.carroted.org/").unwrap();
+        let jrd = webfinger_jrd("amy@carroted.org", &location).unwrap();
+        assert_eq!(jrd["subject"], "acct:amy@carroted.org");
+        assert_eq!(
+            location_from_jrd(jrd.to_string().as_bytes()).unwrap(),
+            location.as_str()
+        );
+
+        let jrd = json!({
+            "links": [
+                { "rel": "self", "href": "https://amy.carroted.org/self" },
+                { "rel": "http://webfinger.net/rel/profile-page", "href": "https://amy.carroted.org/profile" },
+            ],
+        });
+        assert_eq!(
+            location_from_jrd(jrd.to_string().as_bytes()).unwrap(),
+            "https://amy.carroted.org/profile"
+        );
+
+        assert!(matches!(
+            location_from_jrd(br#"{"links": []}"#),
+            Err(WebIdentityError::NoWebIdentityLink)
+        ));
+        assert!(matches!(
+            location_from_jrd(b"<html></html>"),
+            Err(WebIdentityError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_links_that_are_not_https() {
+        let jrd = json!({
+            "links": [{ "rel": WEBFINGER_LOCATION_REL, "href": "http://amy.carroted.org" }],
+        });
+        assert!(matches!(
+            location_from_jrd(jrd.to_string().as_bytes()),
+            Err(WebIdentityError::UnsupportedProtocol(scheme)) if scheme == "http"
+        ));
+    }
+
+    #[test]
+    fn rejects_responses_over_the_size_limit() {
+        let location = Url::parse("https://amy.carroted.org/").unwrap();
+        let mut jrd = webfinger_jrd("amy@carroted.org", &location).unwrap();
+        jrd["padding"] = Value::String("a".repeat(MAX_JRD_SIZE));
+        assert!(matches!(
+            location_from_jrd(jrd.to_string().as_bytes()),
+            Err(WebIdentityError::BodyTooLarge(limit)) if limit == MAX_JRD_SIZE as u64
+        ));
+    }
+
+    #[tokio::test]
+    async fn discovers_the_location_over_https() {
+        let location = Url::parse("https://amy.carroted.org/").unwrap();
+        let discovered = discover_webfinger("acct:amy@carroted.org", |url| async move {
+            assert_eq!(url.scheme(), "https");
+            assert_eq!(url.host_str(), Some("carroted.org"));
+            let jrd = webfinger_jrd("amy@carroted.org", &location)?;
+            Ok(jrd.to_string().into_bytes())
+        })
+        .await
+        .unwrap();
+        assert_eq!(discovered, "https://amy.carroted.org/");
+
+        // Invalid accounts are rejected before anything is fetched
+        let result = discover_webfinger("carroted.org", |_| async {
+            panic!("fetched an invalid account")
+        })
+        .await;
+        assert!(matches!(result, Err(WebIdentityError::InvalidAccount(_))));
+    }
+}